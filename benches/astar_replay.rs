@@ -0,0 +1,50 @@
+use std::{path::Path, sync::Arc};
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use internet_roadtrip_pathfinder::{
+    astar::{self, PathSettings},
+    db::{Db, config::DbConfig},
+    model::Location,
+    streetview::fixtures::ReplayProvider,
+};
+
+/// The grid in `benches/fixtures/grid`, see `test_astar_on_replayed_grid`.
+fn grid_loc(row: u32, col: u32) -> Location {
+    Location::new_deg(40. + row as f64 * 0.00018, -100. + col as f64 * 0.00024)
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let path = std::env::temp_dir().join(format!("pathfinder-bench-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    let db = Db::new(DbConfig {
+        path: path.clone(),
+        map_size: 1 << 30,
+        ..DbConfig::default()
+    })
+    .unwrap();
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("benches/fixtures/grid");
+    db.set_pano_provider(Arc::new(ReplayProvider::new(fixtures)));
+    let rt = tokio::runtime::Runtime::new().unwrap();
+
+    let find_path = || {
+        rt.block_on(astar::astar(
+            &db,
+            grid_loc(0, 0),
+            None,
+            90.,
+            grid_loc(15, 15),
+            Default::default(),
+            PathSettings::default(),
+        ))
+        .unwrap()
+    };
+    // the first search reads the fixtures into the database, so only the search
+    // itself is measured
+    find_path();
+    c.bench_function("astar_replayed_grid", |b| b.iter(find_path));
+
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
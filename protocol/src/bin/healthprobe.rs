@@ -0,0 +1,156 @@
+//! Connects to a running pathfinder, requests a small path that should already
+//! be cached, and checks that the progress updates and the final result look
+//! right. Exits with a nonzero status code if anything is wrong, so it can be
+//! run from cron or an uptime monitor.
+//!
+//! ```sh
+//! healthprobe wss://ir.matdoes.dev/path 40.7128,-74.0060 90 40.7130,-74.0020 [timeout_seconds]
+//! ```
+
+use std::{env, process::ExitCode, time::Duration};
+
+use eyre::{bail, ensure, eyre};
+use pathfinder_protocol::{
+    GetPathQuery, PathState, PathfinderClient, ServerboundMessage, SocketEvent,
+};
+
+/// The final path has to end at least this close to the requested end, in
+/// meters.
+const MAX_END_DISTANCE: f64 = 100.;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let (url, query, timeout) = match parse_args(&args) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            eprintln!("{err}");
+            eprintln!(
+                "usage: healthprobe <ws-url> <start-lat>,<start-lng> <heading> <end-lat>,<end-lng> [timeout-seconds]"
+            );
+            return ExitCode::from(2);
+        }
+    };
+
+    match tokio::time::timeout(timeout, probe(&url, query)).await {
+        Ok(Ok(summary)) => {
+            println!("ok: {summary}");
+            ExitCode::SUCCESS
+        }
+        Ok(Err(err)) => {
+            eprintln!("failed: {err}");
+            ExitCode::FAILURE
+        }
+        Err(_) => {
+            eprintln!("failed: timed out after {timeout:?}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn parse_args(args: &[String]) -> eyre::Result<(String, GetPathQuery, Duration)> {
+    let [url, start, heading, end, rest @ ..] = args else {
+        bail!("not enough arguments");
+    };
+    let start = parse_latlng(start)?;
+    let heading = heading.parse::<f32>()?;
+    let end = parse_latlng(end)?;
+    let timeout = match rest.first() {
+        Some(t) => Duration::from_secs(t.parse()?),
+        None => Duration::from_secs(60),
+    };
+
+    let mut query = GetPathQuery::new(start, heading, end);
+    query.id = 1;
+
+    Ok((url.clone(), query, timeout))
+}
+
+fn parse_latlng(s: &str) -> eyre::Result<[f64; 2]> {
+    let (lat, lng) = s
+        .split_once(',')
+        .ok_or_else(|| eyre!("expected coordinates like lat,lng but got {s:?}"))?;
+    Ok([lat.trim().parse()?, lng.trim().parse()?])
+}
+
+async fn probe(url: &str, query: GetPathQuery) -> eyre::Result<String> {
+    let id = query.id;
+    let end = query.end.expect("end is always set by parse_args");
+
+    let mut client = PathfinderClient::connect(url).await?;
+    client.send(&ServerboundMessage::Path(query)).await?;
+
+    let mut state = PathState::default();
+    let mut updates = 0;
+    let mut last_percent_done = 0.;
+
+    while let Some(event) = client.next_event().await {
+        let progress = match event? {
+            SocketEvent::Progress(progress) => progress,
+            SocketEvent::Error(err) => bail!("server returned an error: {err}"),
+            SocketEvent::Job { .. } | SocketEvent::Queued { .. } | SocketEvent::Result(_) => {
+                continue;
+            }
+        };
+        updates += 1;
+
+        ensure!(
+            progress.id == id,
+            "got update for id {}, expected {id}",
+            progress.id
+        );
+        ensure!(!progress.is_clear(), "the path was cleared");
+        ensure!(
+            (0. ..=1.).contains(&progress.percent_done),
+            "percent_done out of range: {}",
+            progress.percent_done
+        );
+        ensure!(
+            progress.percent_done >= last_percent_done,
+            "percent_done went backwards ({last_percent_done} -> {})",
+            progress.percent_done
+        );
+        ensure!(
+            progress.best_path_keep_prefix_length <= state.best_path.len()
+                && progress.current_path_keep_prefix_length <= state.current_path.len(),
+            "prefix length is longer than the path we have"
+        );
+        last_percent_done = progress.percent_done;
+        state.apply(&progress);
+
+        if progress.is_done() {
+            ensure!(!state.best_path.is_empty(), "final path is empty");
+            ensure!(
+                progress.best_path_cost > 0.,
+                "final path cost is {}",
+                progress.best_path_cost
+            );
+            let [last_lng, last_lat] = *state.best_path.last().unwrap();
+            let end_distance = haversine(end, [last_lat as f64, last_lng as f64]);
+            ensure!(
+                end_distance <= MAX_END_DISTANCE,
+                "final path ends {end_distance:.0}m away from the requested end"
+            );
+
+            return Ok(format!(
+                "{} nodes, cost {}, {updates} updates in {:.1}s",
+                state.best_path.len(),
+                progress.best_path_cost,
+                progress.elapsed_seconds
+            ));
+        }
+    }
+
+    bail!("connection closed before the path was done")
+}
+
+/// The distance in meters between two `[lat, lng]` points.
+fn haversine(a: [f64; 2], b: [f64; 2]) -> f64 {
+    const EARTH_RADIUS: f64 = 6_378_137.;
+    let (a_lat, b_lat) = (a[0].to_radians(), b[0].to_radians());
+    let delta_lat = b_lat - a_lat;
+    let delta_lng = (b[1] - a[1]).to_radians();
+    let h =
+        (delta_lat / 2.).sin().powi(2) + a_lat.cos() * b_lat.cos() * (delta_lng / 2.).sin().powi(2);
+    2. * EARTH_RADIUS * h.sqrt().asin()
+}
@@ -0,0 +1,141 @@
+//! A thin async client for the `/path` WebSocket.
+
+use eyre::{Context, bail};
+use futures::{SinkExt, StreamExt, stream::SplitSink, stream::SplitStream};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    MaybeTlsStream, WebSocketStream, connect_async,
+    tungstenite::{self, client::IntoClientRequest},
+};
+
+use crate::{FullProgressUpdate, GetPathQuery, PathState, ServerboundMessage, SocketEvent};
+
+type Stream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+pub struct PathfinderClient {
+    sink: SplitSink<Stream, tungstenite::Message>,
+    stream: SplitStream<Stream>,
+}
+impl PathfinderClient {
+    /// Connect to a pathfinder's `/path` WebSocket, like
+    /// `wss://ir.matdoes.dev/path`.
+    pub async fn connect(url: &str) -> eyre::Result<Self> {
+        let request = url.into_client_request()?;
+        let (stream, _) = connect_async(request)
+            .await
+            .wrap_err("failed to connect to pathfinder")?;
+        let (sink, stream) = stream.split();
+        Ok(Self { sink, stream })
+    }
+
+    pub async fn send(&mut self, msg: &ServerboundMessage) -> eyre::Result<()> {
+        let text = simd_json::to_string(msg)?;
+        self.sink.send(tungstenite::Message::text(text)).await?;
+        Ok(())
+    }
+
+    /// Returns `None` when the connection is closed.
+    pub async fn next_event(&mut self) -> Option<eyre::Result<SocketEvent>> {
+        loop {
+            let msg = match self.stream.next().await? {
+                Ok(msg) => msg,
+                Err(err) => return Some(Err(err.into())),
+            };
+            match msg {
+                tungstenite::Message::Text(text) => {
+                    let mut bytes = text.as_bytes().to_vec();
+                    return Some(simd_json::from_slice(&mut bytes).map_err(Into::into));
+                }
+                tungstenite::Message::Close(_) => return None,
+                _ => continue,
+            }
+        }
+    }
+
+    /// Request a path and wait until it's done, returning the final update and
+    /// the full best path.
+    pub async fn find_path(
+        &mut self,
+        query: GetPathQuery,
+    ) -> eyre::Result<(FullProgressUpdate, Vec<[f32; 2]>)> {
+        let id = query.id;
+        self.send(&ServerboundMessage::Path(query)).await?;
+        self.wait_for_path(id).await
+    }
+
+    /// Reattach to a job after reconnecting and wait until it's done. The
+    /// server always sends the full path after a resume.
+    pub async fn resume(
+        &mut self,
+        job_id: &str,
+    ) -> eyre::Result<(FullProgressUpdate, Vec<[f32; 2]>)> {
+        self.send(&ServerboundMessage::Resume {
+            job_id: job_id.to_owned(),
+        })
+        .await?;
+        let id = self.wait_for_job().await?;
+        self.wait_for_path(id).await
+    }
+
+    /// Watch a job that was started by another connection and wait until it's
+    /// done. Like with a resume, the server sends the full path first.
+    pub async fn watch(
+        &mut self,
+        job_id: &str,
+    ) -> eyre::Result<(FullProgressUpdate, Vec<[f32; 2]>)> {
+        self.send(&ServerboundMessage::Watch {
+            id: job_id.to_owned(),
+        })
+        .await?;
+        let id = self.wait_for_job().await?;
+        self.wait_for_path(id).await
+    }
+
+    /// Wait for the job event that's sent after a resume or watch, returning
+    /// the query ID.
+    async fn wait_for_job(&mut self) -> eyre::Result<u32> {
+        loop {
+            match self.next_event().await {
+                Some(event) => match event? {
+                    SocketEvent::Job { id, .. } => return Ok(id),
+                    SocketEvent::Error(err) => bail!(err),
+                    SocketEvent::Progress(_)
+                    | SocketEvent::Queued { .. }
+                    | SocketEvent::Result(_) => continue,
+                },
+                None => bail!("connection closed before the job was resumed"),
+            }
+        }
+    }
+
+    async fn wait_for_path(
+        &mut self,
+        id: u32,
+    ) -> eyre::Result<(FullProgressUpdate, Vec<[f32; 2]>)> {
+        let mut state = PathState::default();
+        while let Some(event) = self.next_event().await {
+            match event? {
+                SocketEvent::Progress(progress) => {
+                    if progress.id != id {
+                        continue;
+                    }
+                    if progress.is_clear() {
+                        bail!("path was aborted");
+                    }
+                    state.apply(&progress);
+                    if progress.is_done() {
+                        return Ok((progress, state.best_path));
+                    }
+                }
+                SocketEvent::Error(err) => bail!(err),
+                SocketEvent::Job { .. } | SocketEvent::Queued { .. } | SocketEvent::Result(_) => {}
+            }
+        }
+
+        bail!("connection closed before the path was done")
+    }
+
+    pub async fn abort(&mut self, id: u32) -> eyre::Result<()> {
+        self.send(&ServerboundMessage::Abort { id }).await
+    }
+}
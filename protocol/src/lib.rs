@@ -0,0 +1,632 @@
+//! The messages that are sent over the pathfinder's `/path` WebSocket, shared
+//! between the server and Rust clients.
+
+pub mod client;
+
+use serde::{Deserialize, Serialize};
+
+pub use crate::client::PathfinderClient;
+
+/// The cost of a path, roughly in seconds.
+pub type Cost = f32;
+
+pub const RECOMMENDED_HEURISTIC_FACTOR: f64 = 3.3;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+#[serde(rename_all = "snake_case")]
+pub enum ServerboundMessage {
+    Path(GetPathQuery),
+    /// Stop calculating the current path.
+    Abort {
+        #[serde(default)]
+        id: u32,
+    },
+    /// Reattach to a job that was started on a connection that closed, so we
+    /// keep receiving its progress updates.
+    Resume {
+        job_id: String,
+    },
+    /// Receive the progress updates for a job that another connection started,
+    /// without taking it over. `id` is the job ID.
+    Watch {
+        id: String,
+    },
+    /// Find a path and keep it up to date as the car moves. When the car leaves
+    /// the path, the server repairs the search tree that found it and continues
+    /// the search from there instead of starting over.
+    Follow(FollowQuery),
+    /// The car's new position, for the path that's being followed.
+    Moved(CarPosition),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FollowQuery {
+    #[serde(default)]
+    pub id: u32,
+    /// Where the car currently is. If this isn't set, the path follows the
+    /// game's car and is updated automatically whenever it moves. Otherwise
+    /// the client reports the car's movements with
+    /// [`ServerboundMessage::Moved`].
+    #[serde(default)]
+    pub start: Option<CarPosition>,
+    /// Defaults to the terminus that was announced by the game.
+    #[serde(default)]
+    pub end: Option<[f64; 2]>,
+
+    #[serde(flatten)]
+    pub settings: SearchSettings,
+
+    #[serde(default)]
+    pub units: Units,
+    #[serde(default)]
+    pub locale: Option<String>,
+}
+impl FollowQuery {
+    /// The query for a path from the given position with the same settings.
+    pub fn path_query(&self, start: &CarPosition) -> GetPathQuery {
+        GetPathQuery {
+            id: self.id,
+            start: start.loc,
+            start_pano: start.pano.clone(),
+            end_pano: None,
+            end: self.end,
+            heading: start.heading,
+            stops: Vec::new(),
+            optimize_stop_order: false,
+            settings: self.settings.clone(),
+            anytime: false,
+            timeout_seconds: None,
+            progress_interval_ms: None,
+            include_current_path: true,
+            simplify_tolerance_meters: None,
+            decision_points_only: false,
+            units: self.units,
+            locale: self.locale.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CarPosition {
+    /// The ID of the path that's being followed.
+    #[serde(default)]
+    pub id: u32,
+    /// `[lat, lng]`
+    pub loc: [f64; 2],
+    #[serde(default)]
+    pub pano: Option<String>,
+    pub heading: f32,
+}
+
+/// A stop that a path has to go through before the end.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Stop {
+    /// `[lat, lng]`. The path goes exactly through the nearest pano.
+    Exact([f64; 2]),
+    /// The path only has to come within `radius` meters of `loc`, so it
+    /// doesn't have to slow down or turn around for it.
+    Soft { loc: [f64; 2], radius: f64 },
+}
+impl Stop {
+    pub fn loc(&self) -> [f64; 2] {
+        match *self {
+            Stop::Exact(loc) | Stop::Soft { loc, .. } => loc,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetPathQuery {
+    #[serde(default)]
+    pub id: u32,
+    pub start: [f64; 2],
+    /// Optionally allows us to set the start pano ID, which makes it not snap
+    /// the coordinates to the nearest pano.
+    #[serde(default)]
+    pub start_pano: Option<String>,
+    /// Defaults to the terminus that was announced by the game.
+    #[serde(default)]
+    pub end: Option<[f64; 2]>,
+    /// Optionally allows us to set the end pano ID, which makes the path end
+    /// exactly at that pano instead of anywhere near `end`. `end` should be
+    /// the pano's location, but it can be omitted if the pano is cached. If
+    /// the pano has never been seen, the path ends anywhere near `end`.
+    #[serde(default)]
+    pub end_pano: Option<String>,
+    pub heading: f32,
+    #[serde(default)]
+    pub stops: Vec<Stop>,
+    /// Visit the stops in whatever order makes the path shortest, instead of
+    /// the order that they were given in.
+    #[serde(default)]
+    pub optimize_stop_order: bool,
+
+    #[serde(flatten)]
+    pub settings: SearchSettings,
+    /// Send a rough path as soon as possible and then keep improving it until
+    /// it's as good as `heuristic_factor` would make it.
+    #[serde(default)]
+    pub anytime: bool,
+    /// Stop searching after this many seconds and return the best partial path
+    /// that was found.
+    #[serde(default)]
+    pub timeout_seconds: Option<f64>,
+
+    /// How many milliseconds to wait between progress updates. Defaults to 100.
+    #[serde(default)]
+    pub progress_interval_ms: Option<u64>,
+    /// Whether progress updates should include the path that's currently being
+    /// explored, which changes on nearly every update. Clients that only show
+    /// the best path can turn this off to save bandwidth.
+    #[serde(default = "return_true")]
+    pub include_current_path: bool,
+    /// If set, the paths in progress updates are simplified so that they're
+    /// never more than this many meters from the actual path, which makes long
+    /// paths much smaller to send. The final result isn't simplified.
+    #[serde(default)]
+    pub simplify_tolerance_meters: Option<f64>,
+    /// Only include the start, the end, and the nodes where the car has a
+    /// choice (or has to turn around) in the result, leaving out the nodes on
+    /// straight roads.
+    #[serde(default)]
+    pub decision_points_only: bool,
+
+    /// The unit system used for human-readable distances in summaries and
+    /// error messages.
+    #[serde(default)]
+    pub units: Units,
+    /// A language tag like `en-US`, which determines how numbers are written.
+    #[serde(default)]
+    pub locale: Option<String>,
+}
+impl GetPathQuery {
+    /// A query with the default settings.
+    pub fn new(start: [f64; 2], heading: f32, end: [f64; 2]) -> Self {
+        Self {
+            id: 0,
+            start,
+            start_pano: None,
+            end_pano: None,
+            end: Some(end),
+            heading,
+            stops: Vec::new(),
+            optimize_stop_order: false,
+            settings: SearchSettings::default(),
+            anytime: false,
+            timeout_seconds: None,
+            progress_interval_ms: None,
+            include_current_path: true,
+            simplify_tolerance_meters: None,
+            decision_points_only: false,
+            units: Units::default(),
+            locale: None,
+        }
+    }
+}
+/// The settings that change which path is found, which are the same for
+/// [`GetPathQuery`] and [`FollowQuery`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchSettings {
+    #[serde(default = "return_true")]
+    pub use_option_cache: bool,
+    /// Don't take portals/wormholes. This is the same as setting
+    /// `max_jump_meters` to 500, and it's ignored if `max_jump_meters` is set.
+    #[serde(default)]
+    pub no_long_jumps: bool,
+    /// If set, the path never jumps further than this many meters between two
+    /// panos. Something like 150 still allows short ferry-style hops while
+    /// avoiding wormholes that are kilometers long.
+    #[serde(default)]
+    pub max_jump_meters: Option<f64>,
+    #[serde(default = "get_recommended_heuristic_factor")]
+    pub heuristic_factor: f64,
+    #[serde(default)]
+    pub forward_penalty_on_intersections: Cost,
+    #[serde(default)]
+    pub non_sharp_turn_penalty: Cost,
+    /// How likely it is that votes at intersections go to a random option
+    /// instead of the planned one, between 0 and 1. Higher values make the
+    /// path avoid intersections where the vote could go wrong.
+    #[serde(default)]
+    pub intersection_uncertainty: f32,
+    /// Polygons (as lists of `[lat, lng]` points) that the path must never
+    /// enter.
+    #[serde(default)]
+    pub avoid: Vec<Vec<[f64; 2]>>,
+    /// Pano IDs that the path must never go through, like broken panos or
+    /// portals that the game refuses to take.
+    #[serde(default)]
+    pub exclude_panos: Vec<String>,
+    /// If set, the path can't go further than this many meters away from the
+    /// straight line between the start and end of each segment.
+    #[serde(default)]
+    pub corridor_width_meters: Option<f64>,
+    /// If set, headings at the same pano that are within this many degrees of
+    /// each other are considered the same, which makes long paths a lot faster
+    /// to find at the cost of a little accuracy. 5 is a good value.
+    #[serde(default)]
+    pub heading_bucket_degrees: Option<f32>,
+    /// If set, the path avoids panos whose imagery was taken before this year.
+    /// Very old coverage (like from 2009) often has broken links. Panos with
+    /// an unknown date are allowed.
+    #[serde(default)]
+    pub min_capture_year: Option<u16>,
+    /// Don't go through photospheres, which the game sometimes handles badly.
+    #[serde(default)]
+    pub avoid_photospheres: bool,
+    /// If set along with `avoid_photospheres`, going to a photosphere costs this
+    /// much more instead of not being allowed at all.
+    #[serde(default)]
+    pub photosphere_penalty: Option<Cost>,
+    /// Whether to use the precomputed graph in regions that were baked, which
+    /// is a lot faster but rounds headings to a few degrees, so the paths can
+    /// differ from unbaked searches. Off by default.
+    #[serde(default)]
+    pub use_baked_graph: bool,
+}
+impl Default for SearchSettings {
+    fn default() -> Self {
+        Self {
+            use_option_cache: true,
+            no_long_jumps: false,
+            max_jump_meters: None,
+            heuristic_factor: RECOMMENDED_HEURISTIC_FACTOR,
+            forward_penalty_on_intersections: 0.,
+            non_sharp_turn_penalty: 0.,
+            intersection_uncertainty: 0.,
+            avoid: Vec::new(),
+            exclude_panos: Vec::new(),
+            corridor_width_meters: None,
+            heading_bucket_degrees: None,
+            min_capture_year: None,
+            avoid_photospheres: false,
+            photosphere_penalty: None,
+            use_baked_graph: false,
+        }
+    }
+}
+
+fn get_recommended_heuristic_factor() -> f64 {
+    RECOMMENDED_HEURISTIC_FACTOR
+}
+fn return_true() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Units {
+    #[default]
+    Metric,
+    Imperial,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum SocketEvent {
+    Progress(FullProgressUpdate),
+    Error(SocketError),
+    /// Sent when a path starts being calculated (or is resumed). The job ID can
+    /// be used to resume the job if the connection is lost.
+    Job {
+        id: u32,
+        job_id: String,
+    },
+    /// Sent while the server is running too many searches to start this one.
+    /// `position` is 1 when this search is next.
+    Queued {
+        id: u32,
+        position: usize,
+    },
+    /// The full path, sent after the final progress update.
+    Result(PathResult),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SocketError {
+    #[serde(default)]
+    pub code: ErrorCode,
+    /// A human-readable description of the error.
+    pub message: String,
+
+    /// The `[lat, lng]` that the error is about, like the stop that doesn't
+    /// have a nearby pano.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<[f64; 2]>,
+    /// The limit that the query went over, in the same units as `actual`
+    /// (meters for distances).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub actual: Option<f64>,
+    /// How long to wait before trying again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_seconds: Option<f64>,
+    /// How much of its daily quota the client has used, for `ratelimited`
+    /// errors.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota: Option<Box<QuotaStatus>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuotaStatus {
+    /// The number of nodes that the client's searches considered today.
+    pub used_nodes: u64,
+    pub limit_nodes: u64,
+    pub resets_in_seconds: u64,
+}
+impl SocketError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            location: None,
+            limit: None,
+            actual: None,
+            retry_after_seconds: None,
+            quota: None,
+        }
+    }
+
+    pub fn with_location(self, location: [f64; 2]) -> Self {
+        Self {
+            location: Some(location),
+            ..self
+        }
+    }
+
+    pub fn with_limit(self, limit: f64, actual: f64) -> Self {
+        Self {
+            limit: Some(limit),
+            actual: Some(actual),
+            ..self
+        }
+    }
+
+    pub fn with_retry_after(self, seconds: f64) -> Self {
+        Self {
+            retry_after_seconds: Some(seconds),
+            ..self
+        }
+    }
+
+    pub fn with_quota(self, quota: QuotaStatus) -> Self {
+        Self {
+            quota: Some(Box::new(quota)),
+            ..self
+        }
+    }
+}
+impl std::fmt::Display for SocketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+impl std::error::Error for SocketError {}
+
+/// What kind of error happened, so clients can react to it without matching on
+/// the message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The message couldn't be parsed, or it doesn't make sense right now.
+    InvalidRequest,
+    /// One of the points in the query isn't close enough to any pano.
+    NoNearbyPano,
+    TooManyStops,
+    /// The path is longer than the server allows.
+    PathTooLong,
+    /// Street View or the game couldn't be reached, or the game hasn't told us
+    /// what we need yet.
+    UpstreamUnavailable,
+    Ratelimited,
+    #[default]
+    Internal,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FullProgressUpdate {
+    pub id: u32,
+
+    /// Between 0 and 1
+    pub percent_done: f64,
+    pub estimated_seconds_remaining: f64,
+    pub best_path_cost: Cost,
+    pub nodes_considered: usize,
+    pub elapsed_seconds: f64,
+    /// How close the search is to running out of memory, between 0 and 1. If
+    /// it reaches 1 the search stops with the closest path it found.
+    #[serde(default)]
+    pub memory_pressure: f64,
+
+    pub best_path_keep_prefix_length: usize,
+    pub best_path_append: Box<[[f32; 2]]>,
+    /// The cost of the path up to each point in `best_path_append`, which is
+    /// roughly how many seconds it'll take the car to get there. The costs
+    /// before `best_path_keep_prefix_length` stay the same.
+    #[serde(default)]
+    pub best_path_costs_append: Box<[Cost]>,
+
+    pub current_path_keep_prefix_length: usize,
+    pub current_path_append: Box<[[f32; 2]]>,
+
+    /// A human-readable description of the path, only present once the path
+    /// is done.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+
+    /// The progress of each segment of the path (split by its exact stops),
+    /// in order. Empty for paths that don't have segments, like followed
+    /// paths.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub segments: Vec<SegmentProgress>,
+
+    /// Human-readable problems that might make the path worse than it should
+    /// be, like parts of the map that couldn't be fully downloaded.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+impl FullProgressUpdate {
+    pub fn clear(id: u32) -> Self {
+        Self {
+            id,
+            // this makes it easy to check on the client
+            percent_done: -1.,
+            estimated_seconds_remaining: -1.,
+            best_path_cost: 0 as Cost,
+            nodes_considered: 0,
+            elapsed_seconds: 0.,
+            memory_pressure: 0.,
+            best_path_keep_prefix_length: 0,
+            best_path_append: Box::new([]),
+            best_path_costs_append: Box::new([]),
+            current_path_keep_prefix_length: 0,
+            current_path_append: Box::new([]),
+            summary: None,
+            segments: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Whether this update means that the path was cleared (i.e. aborted).
+    pub fn is_clear(&self) -> bool {
+        self.percent_done < -0.1
+    }
+    pub fn is_done(&self) -> bool {
+        self.percent_done == 1.
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SegmentProgress {
+    pub index: usize,
+    /// Between 0 and 1
+    pub percent_done: f64,
+    pub estimated_seconds_remaining: f64,
+    /// The cost of the best path for this segment, not including the segments
+    /// before it.
+    pub best_path_cost: Cost,
+    pub nodes_considered: usize,
+    /// The range of points in the combined best path that belong to this
+    /// segment, as `[start, end)`. `None` if this segment isn't part of the
+    /// best path yet because it or a segment before it is still being found.
+    pub best_path_range: Option<[usize; 2]>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PathResult {
+    pub id: u32,
+    pub nodes: Vec<PathResultNode>,
+    /// What to vote for at every node in the path except the last one.
+    #[serde(default)]
+    pub instructions: Vec<VoteInstruction>,
+    /// Whether the search was stopped before it reached the end (for example
+    /// because of `timeout_seconds`), in which case the path ends at the
+    /// closest pano that was found.
+    #[serde(default)]
+    pub partial: bool,
+    /// The short ID that the path was saved under, which can be shared so
+    /// others can get it from `GET /route/{id}` without finding it again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub route_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PathResultNode {
+    /// The Streetview pano ID.
+    pub pano_id: String,
+    /// `[lat, lng]`
+    pub loc: [f64; 2],
+    /// The heading that the car will be facing when it's at this pano.
+    pub heading: f32,
+    /// The cost of the path from the start up to this node.
+    pub cost: Cost,
+    /// How many options the car has at this node, or 0 if it isn't known.
+    #[serde(default)]
+    pub option_count: u8,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VoteInstruction {
+    /// The index of the node in [`PathResult::nodes`] that the vote happens
+    /// at.
+    pub node_index: usize,
+    /// The option to vote for, counting from the leftmost one. This is `None`
+    /// if the next node wasn't one of the options, which can happen if the
+    /// options changed after the path was found.
+    pub option_index: Option<usize>,
+    pub option_count: usize,
+    pub direction: VoteDirection,
+    /// The heading of the option to vote for.
+    pub heading: f32,
+    /// The name of the road that the option is on, so the instruction can say
+    /// something like "turn left onto Route 9". This is only set when the road
+    /// is different from the one the car is currently on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub road_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VoteDirection {
+    Left,
+    Straight,
+    Right,
+    /// There were no options ahead, so the car has to turn around.
+    Turnaround,
+}
+
+/// The messages sent over the `/recommendation` WebSocket.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum RecommendationEvent {
+    Recommendation(Recommendation),
+    /// The car is at the destination, so there's nothing left to vote for.
+    Arrived,
+    Error {
+        message: String,
+    },
+}
+
+/// The option that the car should take from where it is now.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Recommendation {
+    /// The Streetview pano ID of the option.
+    pub pano_id: String,
+    pub heading: f32,
+    pub direction: VoteDirection,
+    /// The option to vote for, counting from the leftmost one, if it was one of
+    /// the options we expected the game to give.
+    pub option_index: Option<usize>,
+    /// The cost of the rest of the path from the car's position, roughly in
+    /// seconds.
+    pub remaining_cost: Cost,
+}
+
+/// Applies the incremental progress updates that the server sends to
+/// reconstruct the full paths.
+#[derive(Debug, Clone, Default)]
+pub struct PathState {
+    pub best_path: Vec<[f32; 2]>,
+    /// The cost of the best path up to each of its points.
+    pub best_path_costs: Vec<Cost>,
+    pub current_path: Vec<[f32; 2]>,
+}
+impl PathState {
+    pub fn apply(&mut self, update: &FullProgressUpdate) {
+        self.best_path.truncate(update.best_path_keep_prefix_length);
+        self.best_path.extend_from_slice(&update.best_path_append);
+        self.best_path_costs
+            .truncate(update.best_path_keep_prefix_length);
+        self.best_path_costs
+            .extend_from_slice(&update.best_path_costs_append);
+        self.current_path
+            .truncate(update.current_path_keep_prefix_length);
+        self.current_path
+            .extend_from_slice(&update.current_path_append);
+    }
+}
@@ -1,37 +1,44 @@
 use std::{
     cmp::{self},
-    collections::BinaryHeap,
+    collections::{BinaryHeap, hash_map::Entry},
     hash::{BuildHasherDefault, Hash, Hasher},
-    sync::Arc,
-    time::Instant,
+    sync::{Arc, atomic::AtomicU64},
+    time::{Duration, Instant},
 };
 
-use eyre::{OptionExt, bail};
+use eyre::bail;
 use indexmap::{IndexMap, IndexSet};
 use parking_lot::Mutex;
-use rustc_hash::FxHasher;
+use rustc_hash::{FxHashMap, FxHashSet, FxHasher};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, info};
 
 use crate::{
-    ProgressUpdate,
-    db::DB,
-    math::{self, approx_distance_sqr},
-    model::{Location, Pano},
-    roadtrip, streetview,
+    ProgressUpdate, bake,
+    cost::{CostModel, DefaultCostModel, Edge, straightest_option_index},
+    db::Db,
+    landmarks::GoalLandmarks,
+    math::{self, Polygon, approx_distance_sqr},
+    model::{Location, Pano, PanoId},
+    roadtrip::{self, PanoOptionRes},
+    streetview::{self, prefetch::SpeculativePrefetcher},
 };
 
 pub type FxIndexMap<K, V> = IndexMap<K, V, BuildHasherDefault<FxHasher>>;
 pub type FxIndexSet<T> = IndexSet<T, BuildHasherDefault<FxHasher>>;
 
 pub const MIN_HEURISTIC_FACTOR: f64 = 1.;
-pub const RECOMMENDED_HEURISTIC_FACTOR: f64 = 3.3;
+pub use pathfinder_protocol::RECOMMENDED_HEURISTIC_FACTOR;
 pub const MAX_HEURISTIC_FACTOR: f64 = 4.;
+/// The jump limit that's used for `no_long_jumps`.
+pub const NO_LONG_JUMPS_METERS: f64 = 500.;
 
 #[derive(Clone)]
 pub struct PathSettings {
     pub heuristic_factor: f64,
-    /// Disables portals/wormholes
-    pub no_long_jumps: bool,
+    /// Neighbors that are further than this many meters away are skipped,
+    /// which disables portals/wormholes.
+    pub max_jump_meters: Option<f64>,
     /// Whether we should use the cache that returns the allowed options per
     /// node. This is meant for debugging/benchmarking purposes.
     pub use_option_cache: bool,
@@ -41,51 +48,571 @@ pub struct PathSettings {
     /// A cost penalty that's applied when we make a turn that isn't sharp
     /// enough. This is meant to help avoid wiggling.
     pub non_sharp_turn_penalty: Cost,
+    /// How likely it is that the vote at an intersection goes to a random
+    /// option instead of the one we planned, between 0 and 1. Intersection
+    /// costs are multiplied by the expected number of votes it takes to get
+    /// the option we want, which favors paths with fewer chances to go wrong.
+    pub intersection_uncertainty: f32,
+    /// Give up after considering this many nodes.
+    pub max_nodes: Option<usize>,
+    /// Stop once this many nodes are stored, since every node we've seen is
+    /// kept in memory and very long searches can use tens of gigabytes. The
+    /// search fails with [`Incomplete`], which has the path to the node that
+    /// got closest to the goal.
+    pub node_budget: Option<usize>,
+    /// Stop once the search has taken this long, failing with [`Incomplete`]
+    /// like for `node_budget`.
+    pub timeout: Option<Duration>,
+    /// Cancelling this makes the search stop soon after, failing with
+    /// [`Incomplete`] like for `node_budget`.
+    pub cancel: CancellationToken,
+    /// Areas that the path must never enter.
+    pub avoid_areas: Arc<[Polygon]>,
+    /// Panos that the path must never go through.
+    pub exclude_panos: Arc<FxHashSet<PanoId>>,
+    /// Find a path quickly with [`MIN_HEURISTIC_FACTOR`] first, and then keep
+    /// refining it with higher factors until `heuristic_factor` is reached.
+    pub anytime: bool,
+    /// Neighbors that are further than this many meters from the line between
+    /// the start and the goal are skipped.
+    pub corridor_width: Option<f64>,
+    /// Areas that the path has to pass through in order before it reaches the
+    /// goal, without having to stop at them.
+    pub waypoints: Arc<[Waypoint]>,
+    /// If set, the goal is only reached at exactly this pano, instead of at any
+    /// pano that's close enough to the goal location.
+    pub goal_pano: Option<PanoId>,
+    /// If set, nodes at the same pano whose headings are in the same bucket of
+    /// this many degrees are treated as the same node. This makes the search a
+    /// lot smaller, at the cost of slightly less accurate options.
+    pub heading_bucket_size: Option<f32>,
+    /// Panos whose imagery is known to be older than this year are skipped.
+    pub min_capture_year: Option<u16>,
+    /// What to do when a neighbor is a photosphere.
+    pub photospheres: PhotosphereAvoidance,
+    /// Don't go into dead-end branches that can't contain the goal or a
+    /// waypoint, see [`crate::dead_ends`].
+    pub prune_dead_ends: bool,
+    /// Look up the options of nodes in baked regions instead of calculating
+    /// them, see [`crate::bake`].
+    pub use_baked_graph: bool,
+    /// Use the landmarks for the heuristic in baked regions, see
+    /// [`crate::landmarks`]. Needs `use_baked_graph`.
+    pub use_landmarks: bool,
+    /// Replaces the default cost of taking an option, which makes
+    /// `forward_penalty_on_intersections` and `non_sharp_turn_penalty` do
+    /// nothing unless the model uses them.
+    pub cost_model: Option<Arc<dyn CostModel>>,
+    /// Counts the smallest-size tiles that this search had to download with
+    /// some of their panos missing, so the path might miss some roads.
+    pub truncated_tiles: Arc<AtomicU64>,
+}
+
+impl Default for PathSettings {
+    /// The same settings as a `/path` query that doesn't set anything, without
+    /// any limits.
+    fn default() -> Self {
+        Self {
+            heuristic_factor: RECOMMENDED_HEURISTIC_FACTOR,
+            max_jump_meters: None,
+            use_option_cache: true,
+            forward_penalty_on_intersections: 0.,
+            non_sharp_turn_penalty: 0.,
+            intersection_uncertainty: 0.,
+            max_nodes: None,
+            node_budget: None,
+            timeout: None,
+            cancel: CancellationToken::new(),
+            avoid_areas: Arc::new([]),
+            exclude_panos: Arc::default(),
+            anytime: false,
+            corridor_width: None,
+            waypoints: Arc::new([]),
+            goal_pano: None,
+            heading_bucket_size: None,
+            min_capture_year: None,
+            photospheres: PhotosphereAvoidance::Allow,
+            prune_dead_ends: false,
+            use_baked_graph: false,
+            use_landmarks: false,
+            cost_model: None,
+            truncated_tiles: Arc::default(),
+        }
+    }
+}
+
+/// Whether the path can go through photospheres, which the game sometimes
+/// handles badly.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum PhotosphereAvoidance {
+    #[default]
+    Allow,
+    /// Photospheres are never used as neighbors.
+    Skip,
+    /// Going to a photosphere costs this much more.
+    Penalty(Cost),
+}
+impl PhotosphereAvoidance {
+    /// The extra cost of going to the pano, or `None` if it can't be used at
+    /// all.
+    pub fn penalty(self, pano_id: PanoId) -> Option<Cost> {
+        if !pano_id.is_photosphere() {
+            return Some(0 as Cost);
+        }
+        match self {
+            Self::Allow => Some(0 as Cost),
+            Self::Skip => None,
+            Self::Penalty(penalty) => Some(penalty),
+        }
+    }
+}
+
+/// A soft stop, which the path has to come within `radius` meters of.
+#[derive(Debug, Clone, Copy)]
+pub struct Waypoint {
+    pub loc: Location,
+    pub radius: f64,
+}
+
+/// The waypoints and the goal, which the heuristic estimates the remaining
+/// distance through.
+struct Targets<'a> {
+    goal: Location,
+    waypoints: &'a [Waypoint],
+    /// The minimum distance from each waypoint's area to the goal, through
+    /// the rest of the waypoints.
+    remaining_after: Box<[f64]>,
+    /// Tighter bounds for the cost to the goal, if it's in a baked region.
+    landmarks: Option<GoalLandmarks<'a>>,
+}
+impl<'a> Targets<'a> {
+    fn new(
+        goal: Location,
+        waypoints: &'a [Waypoint],
+        landmarks: Option<GoalLandmarks<'a>>,
+    ) -> Self {
+        let mut remaining_after = vec![0.; waypoints.len()].into_boxed_slice();
+        let mut remaining = 0.;
+        let mut next = (goal, 0.);
+        for (i, waypoint) in waypoints.iter().enumerate().rev() {
+            remaining += (math::distance(waypoint.loc, next.0) - waypoint.radius - next.1).max(0.);
+            remaining_after[i] = remaining;
+            next = (waypoint.loc, waypoint.radius);
+        }
+        Self {
+            goal,
+            waypoints,
+            remaining_after,
+            landmarks,
+        }
+    }
+
+    /// Where the node should be heading next.
+    fn next_target(&self, node: &NodeIdent) -> Location {
+        match self.waypoints.get(node.waypoints_reached as usize) {
+            Some(waypoint) => waypoint.loc,
+            None => self.goal,
+        }
+    }
+
+    /// A lower bound for the distance from the node to the goal, through the
+    /// waypoints that it hasn't reached yet.
+    fn distance(&self, node: &NodeIdent) -> f64 {
+        match self.waypoints.get(node.waypoints_reached as usize) {
+            Some(waypoint) => {
+                (math::distance(node.pano.loc, waypoint.loc) - waypoint.radius).max(0.)
+                    + self.remaining_after[node.waypoints_reached as usize]
+            }
+            None => math::distance(node.pano.loc, self.goal),
+        }
+    }
+
+    /// Whether the goal or any of the waypoints might be within `distance`
+    /// meters of the location.
+    fn any_within(&self, loc: Location, distance: f64) -> bool {
+        // the goal counts as reached from a little further away, see is_goal_reached
+        math::distance(loc, self.goal) <= distance + 30.
+            || self
+                .waypoints
+                .iter()
+                .any(|waypoint| math::distance(loc, waypoint.loc) <= distance + waypoint.radius)
+    }
+
+    /// The number of waypoints that were reached after moving to `loc`.
+    fn waypoints_reached_at(&self, loc: Location, waypoints_reached: u16) -> u16 {
+        waypoints_reached_at(self.waypoints, loc, waypoints_reached)
+    }
+}
+
+fn waypoints_reached_at(waypoints: &[Waypoint], loc: Location, mut waypoints_reached: u16) -> u16 {
+    while let Some(waypoint) = waypoints.get(waypoints_reached as usize)
+        && math::distance(loc, waypoint.loc) <= waypoint.radius
+    {
+        waypoints_reached += 1;
+    }
+    waypoints_reached
+}
+
+/// The search stopped before it reached the goal.
+#[derive(Debug)]
+pub struct Incomplete {
+    pub reason: IncompleteReason,
+    /// The path to the node that got closest to the goal.
+    pub partial_path: Vec<RouteNode>,
+}
+#[derive(Debug)]
+pub enum IncompleteReason {
+    /// [`PathSettings::node_budget`] was reached.
+    NodeBudget(usize),
+    /// [`PathSettings::timeout`] was reached.
+    Timeout(Duration),
+    /// [`PathSettings::cancel`] was cancelled.
+    Cancelled,
+}
+impl std::fmt::Display for Incomplete {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.reason {
+            IncompleteReason::NodeBudget(node_budget) => write!(
+                f,
+                "Ran out of memory after storing {node_budget} nodes, try a shorter path"
+            ),
+            IncompleteReason::Timeout(timeout) => {
+                write!(
+                    f,
+                    "Gave up after {}s, try a shorter path",
+                    timeout.as_secs_f64()
+                )
+            }
+            IncompleteReason::Cancelled => write!(f, "Cancelled"),
+        }
+    }
+}
+impl std::error::Error for Incomplete {}
+
+/// How much the heuristic factor is increased by for every refinement in
+/// anytime mode.
+const ANYTIME_FACTOR_STEP: f64 = 0.5;
+
+/// Everything a search knows about the graph: the nodes it found with their g
+/// scores, and the ones it still has to expand. The start is always the node
+/// at index 0.
+pub struct SearchTree {
+    nodes: FxIndexMap<NodeIdent, NodeData>,
+    /// The f scores are recalculated at the start of every search, since they
+    /// depend on the goal and the heuristic factor.
+    open_set: BinaryHeap<WeightedNode>,
+    /// The first node we found in every heading bucket, if they're enabled.
+    heading_buckets: FxHashMap<(PanoId, u16, u16), u32>,
+    heading_bucket_size: Option<f32>,
+    /// We only allow the first attempted turnaround to work, since turnarounds
+    /// are only expected to be useful at the very beginning of a route.
+    allow_turnaround: bool,
+    /// The edges out of every node that was expanded with its current g score,
+    /// or `None` if the tree can't be repaired.
+    expansions: Option<FxHashMap<u32, Expansion>>,
+}
+struct Expansion {
+    /// How many options there were at the node, see
+    /// [`NodeData::came_from_option_count`].
+    option_count: u8,
+    /// The index of every neighbor that the settings allowed, and the cost of
+    /// going there.
+    edges: Box<[(u32, Cost)]>,
+}
+impl SearchTree {
+    pub fn new(start: Pano, heading: f32, settings: &PathSettings) -> Self {
+        let start = NodeIdent {
+            pano: start,
+            heading,
+            waypoints_reached: waypoints_reached_at(&settings.waypoints, start.loc, 0),
+        };
+
+        let mut open_set = BinaryHeap::new();
+        open_set.push(WeightedNode {
+            index: 0,
+            g_score: 0 as Cost,
+            f_score: 0 as Cost,
+        });
+
+        let mut heading_buckets = FxHashMap::default();
+        if let Some(bucket_size) = settings.heading_bucket_size {
+            heading_buckets.insert(
+                (
+                    start.pano.id,
+                    heading_bucket(heading, bucket_size),
+                    start.waypoints_reached,
+                ),
+                0,
+            );
+        }
+
+        let mut nodes: FxIndexMap<NodeIdent, NodeData> = IndexMap::default();
+        nodes.insert(
+            start,
+            NodeData {
+                came_from: u32::MAX,
+                g_score: 0 as Cost,
+                came_from_option_count: 0,
+            },
+        );
+
+        Self {
+            nodes,
+            open_set,
+            heading_buckets,
+            heading_bucket_size: settings.heading_bucket_size,
+            allow_turnaround: true,
+            expansions: None,
+        }
+    }
+
+    /// Like [`Self::new`], but the tree remembers the edges of every node it
+    /// expands so it can be repaired with [`Self::move_start`]. This takes more
+    /// memory, and baked shortcuts aren't taken. Searches in it shouldn't be in
+    /// anytime mode, since that skips edges that can't beat the path it already
+    /// has.
+    pub fn repairable(start: Pano, heading: f32, settings: &PathSettings) -> Self {
+        Self {
+            expansions: Some(FxHashMap::default()),
+            ..Self::new(start, heading, settings)
+        }
+    }
+
+    pub fn start(&self) -> &NodeIdent {
+        self.nodes.get_index(0).unwrap().0
+    }
+
+    /// Make the node at the pano with the closest heading (within
+    /// `heading_tolerance` degrees) the new start. Returns false if there's no
+    /// such node, or if the tree isn't repairable.
+    ///
+    /// The subtree below the new start keeps its g scores (minus the new
+    /// start's), since the cheapest way to any of those nodes went through it.
+    /// Everything else is dropped, except for the nodes that the subtree's
+    /// expanded nodes lead to, which are put back in the open set with their
+    /// cost through the subtree. The next search then only has to expand the
+    /// nodes whose g scores changed.
+    pub fn move_start(&mut self, pano_id: PanoId, heading: f32, heading_tolerance: f32) -> bool {
+        let Some(expansions) = &self.expansions else {
+            return false;
+        };
+        let Some(new_start) = self
+            .nodes
+            .keys()
+            .enumerate()
+            .filter(|(_, node)| node.pano.id == pano_id)
+            .map(|(i, node)| (i, math::calculate_heading_diff(node.heading, heading)))
+            .filter(|(_, diff)| *diff < heading_tolerance)
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(i, _)| i as u32)
+        else {
+            return false;
+        };
+        let start_g_score = self.nodes[new_start as usize].g_score;
+
+        // which nodes are in the subtree below the new start, found by walking up
+        // to the first node whose answer we already know
+        let mut in_subtree = vec![None; self.nodes.len()];
+        in_subtree[new_start as usize] = Some(true);
+        let mut chain = Vec::new();
+        for i in 0..self.nodes.len() as u32 {
+            let mut current = i;
+            let answer = loop {
+                if let Some(answer) = in_subtree[current as usize] {
+                    break answer;
+                }
+                chain.push(current);
+                match self.nodes[current as usize].came_from {
+                    u32::MAX => break false,
+                    came_from => current = came_from,
+                }
+            };
+            for node in chain.drain(..) {
+                in_subtree[node as usize] = Some(answer);
+            }
+        }
+
+        // the new start goes first, and the old indices are mapped to the new ones
+        let mut new_indices = vec![u32::MAX; self.nodes.len()];
+        let mut nodes = FxIndexMap::default();
+        let subtree = std::iter::once(new_start).chain(
+            (0..self.nodes.len() as u32)
+                .filter(|&i| i != new_start && in_subtree[i as usize] == Some(true)),
+        );
+        for old_index in subtree {
+            let (node, data) = self.nodes.get_index(old_index as usize).unwrap();
+            new_indices[old_index as usize] = nodes.len() as u32;
+            nodes.insert(
+                node.clone(),
+                NodeData {
+                    g_score: data.g_score - start_g_score,
+                    ..data.clone()
+                },
+            );
+        }
+        for data in nodes.values_mut() {
+            if data.came_from != u32::MAX {
+                data.came_from = new_indices[data.came_from as usize];
+            }
+        }
+        let start_data = &mut nodes[0];
+        start_data.came_from = u32::MAX;
+        start_data.came_from_option_count = 0;
+
+        // the nodes outside the subtree that it leads to are added without a g
+        // score, which the edges into them set below
+        let mut new_expansions = FxHashMap::default();
+        for (&old_index, expansion) in expansions {
+            let index = new_indices[old_index as usize];
+            if index == u32::MAX {
+                continue;
+            }
+            let edges = expansion
+                .edges
+                .iter()
+                .map(|&(to, cost)| {
+                    if new_indices[to as usize] == u32::MAX {
+                        let node = self.nodes.get_index(to as usize).unwrap().0;
+                        new_indices[to as usize] = nodes.len() as u32;
+                        nodes.insert(
+                            node.clone(),
+                            NodeData {
+                                came_from: u32::MAX,
+                                g_score: Cost::INFINITY,
+                                came_from_option_count: 0,
+                            },
+                        );
+                    }
+                    (new_indices[to as usize], cost)
+                })
+                .collect();
+            new_expansions.insert(
+                index,
+                Expansion {
+                    option_count: expansion.option_count,
+                    edges,
+                },
+            );
+        }
+        let mut improved = Vec::new();
+        for (&from, expansion) in &new_expansions {
+            let g_score = nodes[from as usize].g_score;
+            for &(to, cost) in &expansion.edges {
+                let to_data = &mut nodes[to as usize];
+                if g_score + cost < to_data.g_score {
+                    *to_data = NodeData {
+                        came_from: from,
+                        g_score: g_score + cost,
+                        came_from_option_count: expansion.option_count,
+                    };
+                    improved.push(to);
+                }
+            }
+        }
+        for index in improved {
+            new_expansions.remove(&index);
+        }
+
+        let mut heading_buckets = FxHashMap::default();
+        if let Some(bucket_size) = self.heading_bucket_size {
+            for (i, node) in nodes.keys().enumerate() {
+                heading_buckets
+                    .entry((
+                        node.pano.id,
+                        heading_bucket(node.heading, bucket_size),
+                        node.waypoints_reached,
+                    ))
+                    .or_insert(i as u32);
+            }
+        }
+
+        // everything that wasn't expanded with its current g score
+        let open_set = nodes
+            .values()
+            .enumerate()
+            .filter(|(i, _)| !new_expansions.contains_key(&(*i as u32)))
+            .map(|(i, data)| WeightedNode {
+                index: i as u32,
+                g_score: data.g_score,
+                f_score: data.g_score,
+            })
+            .collect();
+
+        info!(
+            "Repaired the search tree, keeping {} of {} nodes ({} expanded)",
+            nodes.len(),
+            self.nodes.len(),
+            new_expansions.len()
+        );
+        self.nodes = nodes;
+        self.open_set = open_set;
+        self.heading_buckets = heading_buckets;
+        self.expansions = Some(new_expansions);
+        true
+    }
 }
 
 pub async fn astar(
+    db: &Db,
     start: Location,
     start_pano_id: Option<String>,
     heading: f32,
     goal: Location,
     progress_update: Arc<Mutex<ProgressUpdate>>,
     settings: PathSettings,
-) -> eyre::Result<Vec<NodeIdent>> {
-    let start_pano = if let Some(start_pano_id) = start_pano_id {
-        Pano {
-            id: DB.get_pano_id(&start_pano_id),
-            loc: start,
-        }
-    } else {
-        streetview::get_nearest_pano(start, 500.)
-            .await
-            .unwrap_or_default()
-            .ok_or_eyre("start position isn't near a pano")?
-    };
+) -> eyre::Result<Vec<RouteNode>> {
+    let start_pano = streetview::get_start_pano(db, start, start_pano_id.as_deref()).await?;
+    let mut tree = SearchTree::new(start_pano, heading, &settings);
+    astar_with_tree(db, &mut tree, goal, progress_update, settings).await
+}
 
-    let start = NodeIdent {
-        pano: start_pano,
-        heading,
-    };
+/// Like [`astar`], but continues the search in the tree, which is left with
+/// everything the search found so it can be repaired with
+/// [`SearchTree::move_start`] later.
+pub async fn astar_with_tree(
+    db: &Db,
+    tree: &mut SearchTree,
+    goal: Location,
+    progress_update: Arc<Mutex<ProgressUpdate>>,
+    settings: PathSettings,
+) -> eyre::Result<Vec<RouteNode>> {
+    // the landmark costs were calculated with the default cost model
+    let landmarks =
+        if settings.use_landmarks && settings.use_baked_graph && settings.cost_model.is_none() {
+            GoalLandmarks::new(db, goal).await
+        } else {
+            None
+        };
+    let targets = Targets::new(goal, &settings.waypoints, landmarks);
 
-    let mut open_set = BinaryHeap::new();
-    open_set.push(WeightedNode {
-        index: 0,
-        g_score: 0 as Cost,
-        f_score: 0 as Cost,
-    });
+    let SearchTree {
+        nodes,
+        open_set,
+        heading_buckets,
+        heading_bucket_size: _,
+        allow_turnaround,
+        expansions,
+    } = tree;
+    let start = nodes.get_index(0).unwrap().0.clone();
+    let mut merged_nodes = 0_usize;
 
-    let mut nodes: FxIndexMap<NodeIdent, NodeData> = IndexMap::default();
-    nodes.insert(
-        start.clone(),
-        NodeData {
-            came_from: u32::MAX,
-            g_score: 0 as Cost,
-        },
-    );
+    // in anytime mode we start with the greediest factor and work our way up
+    let mut factor = if settings.anytime {
+        MIN_HEURISTIC_FACTOR.min(settings.heuristic_factor)
+    } else {
+        settings.heuristic_factor
+    };
+    let refinement_count =
+        ((settings.heuristic_factor - factor) / ANYTIME_FACTOR_STEP).ceil() as usize;
+    let mut refinements_done = 0;
+    // the route and cost of the best path to the goal that we've found so far
+    let mut best_goal: Option<(Vec<RouteNode>, Cost)> = None;
 
-    let overall_heuristic = heuristic(&start, goal, settings.heuristic_factor);
-    let overall_distance = math::distance(start.pano.loc, goal);
+    // a repaired tree's open set was scored for the previous search
+    rescore_open_set(open_set, nodes, &targets, factor);
+
+    let overall_heuristic = heuristic(&start, &targets, factor);
+    let overall_distance = targets.distance(&start);
 
     let mut best_node_index = 0;
     let mut heuristic_of_best_node = Cost::MAX;
@@ -99,32 +626,159 @@ pub async fn astar(
     let mut last_update = Instant::now();
     let mut last_log = Instant::now();
 
-    let mut allow_turnaround = true;
+    let mut prefetcher = SpeculativePrefetcher::new(db, settings.truncated_tiles.clone());
+    let cost_model = settings.cost_model.clone().unwrap_or_else(|| {
+        Arc::new(DefaultCostModel {
+            vote_delays: db.vote_delays(),
+            forward_penalty_on_intersections: settings.forward_penalty_on_intersections,
+            non_sharp_turn_penalty: settings.non_sharp_turn_penalty,
+            intersection_uncertainty: settings.intersection_uncertainty,
+        })
+    });
+    // the penalty for moving to the option from `from_loc`, or None if the
+    // settings don't allow it
+    let mut capture_dates = CaptureDateFilter::default();
+    let mut neighbor_penalty = |from_loc: Location,
+                                neighbor: &PanoOptionRes,
+                                can_prune_dead_ends: bool,
+                                approx_lng_m_per_degree: f64|
+     -> Option<Cost> {
+        let penalty = allowed_neighbor_penalty(
+            db,
+            &settings,
+            &mut capture_dates,
+            from_loc,
+            neighbor,
+            approx_lng_m_per_degree,
+        )?;
+        if can_prune_dead_ends
+            && let Some(reach) = db.dead_end_reach(neighbor.pano.id)
+            && !targets.any_within(neighbor.pano.loc, reach as f64)
+        {
+            return None;
+        }
+        if let Some(corridor_width) = settings.corridor_width
+            && math::cross_track_distance(neighbor.pano.loc, start.pano.loc, goal) > corridor_width
+        {
+            return None;
+        }
+        Some(penalty)
+    };
+    let mut shortcuts_taken = 0_usize;
 
-    while let Some(WeightedNode { index, g_score, .. }) = open_set.pop() {
+    while let Some(WeightedNode {
+        index,
+        g_score,
+        f_score,
+    }) = open_set.pop()
+    {
         nodes_considered += 1;
 
+        if let Some(max_nodes) = settings.max_nodes
+            && nodes_considered > max_nodes
+        {
+            if let Some((route, cost)) = best_goal {
+                // we're in anytime mode, so the path we have is good enough
+                set_done_progress(&progress_update, &route, cost, nodes_considered);
+                return Ok(route);
+            }
+            set_no_path_progress(&progress_update, nodes_considered);
+            bail!("Gave up after considering {max_nodes} nodes, try a shorter path");
+        }
+
+        let stop_reason = if settings.cancel.is_cancelled() {
+            Some(IncompleteReason::Cancelled)
+        } else if let Some(node_budget) = settings.node_budget
+            && nodes.len() > node_budget
+        {
+            Some(IncompleteReason::NodeBudget(node_budget))
+        } else if let Some(timeout) = settings.timeout
+            && start_time.elapsed() > timeout
+        {
+            Some(IncompleteReason::Timeout(timeout))
+        } else {
+            None
+        };
+        if let Some(reason) = stop_reason {
+            if let Some((route, cost)) = best_goal {
+                set_done_progress(&progress_update, &route, cost, nodes_considered);
+                return Ok(route);
+            }
+            let partial_path = reconstruct_path(nodes, best_node_index);
+            let partial_cost = partial_path.last().map(|n| n.cost).unwrap_or_default();
+            info!(
+                "Stopping search ({reason:?}), the closest we got was {:.2}km from the goal",
+                math::distance(partial_path.last().unwrap().pano.loc, goal) / 1000.
+            );
+            set_done_progress(
+                &progress_update,
+                &partial_path,
+                partial_cost,
+                nodes_considered,
+            );
+            bail!(Incomplete {
+                reason,
+                partial_path,
+            });
+        }
+
         let (node, node_data) = nodes.get_index(index as usize).unwrap();
-        if is_goal_reached(node, goal) {
+        if node.waypoints_reached as usize == settings.waypoints.len()
+            && is_goal_reached(node, goal, settings.goal_pano)
+        {
+            if best_goal
+                .as_ref()
+                .is_some_and(|(_, best_cost)| g_score >= *best_cost)
+            {
+                continue;
+            }
+
             info!("Found goal: {node:?}");
             info!("Pathfinder took: {:?}", start_time.elapsed());
+            info!("Cost: {g_score} ({} hours)", g_score / 3600.);
+            info!("Nodes considered: {nodes_considered}");
+            if shortcuts_taken > 0 {
+                info!("Shortcuts taken: {shortcuts_taken}");
+            }
+            if settings.heading_bucket_size.is_some() {
+                info!(
+                    "Merged {merged_nodes} nodes with similar headings ({} unique nodes)",
+                    nodes.len()
+                );
+            }
 
-            let route = reconstruct_path(&nodes, index);
+            let route = reconstruct_path(nodes, index);
 
-            let mut progress_update = progress_update.lock();
-            *progress_update = ProgressUpdate {
-                percent_done: 1.,
-                estimated_seconds_remaining: 0.,
-                nodes_considered,
-                best_path_cost: g_score,
-                best_path: route.iter().map(|n| n.pano.loc.to_geojson()).collect(),
-                current_path: Box::new([]),
-            };
+            if refinements_done >= refinement_count {
+                set_done_progress(&progress_update, &route, g_score, nodes_considered);
+                return Ok(route);
+            }
 
-            info!("Cost: {g_score} ({} hours)", g_score / 3600.);
-            info!("Nodes considered: {nodes_considered}");
+            // anytime mode, so send the path we have now and then keep looking for a
+            // better one with a less greedy heuristic
+            refinements_done += 1;
+            factor = (factor + ANYTIME_FACTOR_STEP).min(settings.heuristic_factor);
+            info!("Refining path with heuristic factor {factor}");
 
-            return Ok(route);
+            {
+                let mut progress_update = progress_update.lock();
+                *progress_update = ProgressUpdate {
+                    percent_done: anytime_percent_done(true, refinements_done, refinement_count),
+                    estimated_seconds_remaining: -1.,
+                    nodes_considered,
+                    memory_pressure: memory_pressure(&settings, nodes.len()),
+                    stored_nodes: nodes.len(),
+                    best_path_cost: g_score,
+                    best_path: route.iter().map(|n| n.pano.loc.to_geojson()).collect(),
+                    best_path_costs: route.iter().map(|n| n.cost).collect(),
+                    current_path: Box::new([]),
+                };
+            }
+            best_goal = Some((route, g_score));
+
+            // the f scores in the open set were calculated with the old factor
+            rescore_open_set(open_set, nodes, &targets, factor);
+            continue;
         }
 
         if g_score > node_data.g_score {
@@ -140,138 +794,291 @@ pub async fn astar(
             tokio::task::yield_now().await;
 
             last_update = Instant::now();
-            let percent = 1. - (heuristic_of_best_node as f64 / overall_heuristic as f64);
 
-            let mut progress_update = progress_update.lock();
+            let best_node = nodes.get_index(best_node_index as usize).unwrap().0;
+            prefetcher.prefetch_ahead(
+                best_node.pano.loc,
+                math::calculate_heading(best_node.pano.loc, targets.next_target(best_node)),
+            );
+            if let Some((_, best_cost)) = &best_goal {
+                // we're refining a path we already sent, so just keep it as the best path
+                let mut progress_update = progress_update.lock();
+                progress_update.nodes_considered = nodes_considered;
+                progress_update.memory_pressure = memory_pressure(&settings, nodes.len());
+                progress_update.stored_nodes = nodes.len();
+                progress_update.best_path_cost = *best_cost;
+                progress_update.current_path = reconstruct_path(nodes, index)
+                    .into_iter()
+                    .map(|n| n.pano.loc.to_geojson())
+                    .collect();
+                drop(progress_update);
+            } else {
+                let mut percent = 1. - (heuristic_of_best_node as f64 / overall_heuristic as f64);
+                if settings.anytime {
+                    percent *= anytime_percent_done(false, 0, refinement_count);
+                }
 
-            // estimate time remaining
-            let elapsed = start_time.elapsed();
-            let estimated_remaining = (elapsed.as_secs_f64() / percent) - elapsed.as_secs_f64();
+                let mut progress_update = progress_update.lock();
 
-            if last_log.elapsed().as_secs() > 5 {
-                last_log = Instant::now();
-                info!(
-                    "Visited {} nodes, best found: {:.2}%, distance remaining: {:.2}km",
+                // estimate time remaining
+                let elapsed = start_time.elapsed();
+                let estimated_remaining = (elapsed.as_secs_f64() / percent) - elapsed.as_secs_f64();
+
+                if last_log.elapsed().as_secs() > 5 {
+                    last_log = Instant::now();
+                    info!(
+                        "Visited {} nodes, best found: {:.2}%, distance remaining: {:.2}km",
+                        nodes_considered,
+                        percent * 100.,
+                        (overall_distance as f64 * (1. - percent)) / 1000.,
+                    );
+                    info!(
+                        "Estimated remaining time: {:.2} minutes",
+                        estimated_remaining / 60.
+                    );
+                }
+
+                let best_path = reconstruct_path(nodes, best_node_index);
+                *progress_update = ProgressUpdate {
+                    percent_done: percent,
+                    estimated_seconds_remaining: estimated_remaining,
+                    best_path_cost: nodes.get_index(best_node_index as usize).unwrap().1.g_score,
                     nodes_considered,
-                    percent * 100.,
-                    (overall_distance as f64 * (1. - percent)) / 1000.,
-                );
-                info!(
-                    "Estimated remaining time: {:.2} minutes",
-                    estimated_remaining / 60.
-                );
+                    memory_pressure: memory_pressure(&settings, nodes.len()),
+                    stored_nodes: nodes.len(),
+                    best_path: best_path.iter().map(|n| n.pano.loc.to_geojson()).collect(),
+                    best_path_costs: best_path.iter().map(|n| n.cost).collect(),
+                    current_path: reconstruct_path(nodes, index)
+                        .into_iter()
+                        .map(|n| n.pano.loc.to_geojson())
+                        .collect(),
+                };
             }
-
-            *progress_update = ProgressUpdate {
-                percent_done: percent,
-                estimated_seconds_remaining: estimated_remaining,
-                best_path_cost: nodes.get_index(best_node_index as usize).unwrap().1.g_score,
-                nodes_considered,
-                best_path: reconstruct_path(&nodes, best_node_index)
-                    .into_iter()
-                    .map(|n| n.pano.loc.to_geojson())
-                    .collect(),
-                current_path: reconstruct_path(&nodes, index)
-                    .into_iter()
-                    .map(|n| n.pano.loc.to_geojson())
-                    .collect(),
-            };
         }
 
-        let neighbors = roadtrip::get_options(
-            &node.pano,
-            node.heading,
-            allow_turnaround,
-            settings.use_option_cache,
-        )
-        .await?;
+        let baked = if settings.use_baked_graph {
+            bake::get_baked_options(db, &node.pano, node.heading, *allow_turnaround)
+        } else {
+            None
+        };
+        let is_baked = baked.is_some();
+        let neighbors = match baked {
+            Some(baked) => Ok(baked),
+            None => {
+                prefetcher
+                    .run_while(streetview::api::count_truncated_tiles(
+                        settings.truncated_tiles.clone(),
+                        roadtrip::get_options(
+                            db,
+                            &node.pano,
+                            node.heading,
+                            *allow_turnaround,
+                            settings.use_option_cache,
+                            &settings.cancel,
+                        ),
+                    ))
+                    .await
+            }
+        };
+        let neighbors = match neighbors {
+            Ok(neighbors) => neighbors,
+            Err(err) if err.is::<streetview::Cancelled>() => {
+                // put the node back so the check at the start of the loop stops the search
+                // with the partial path
+                open_set.push(WeightedNode {
+                    index,
+                    g_score,
+                    f_score,
+                });
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
 
         if neighbors.turnaround {
             // we only allow the first attempted turnaround to work, since turnarounds are
             // only expected to be useful at the very beginning of a route.
-            allow_turnaround = false;
+            *allow_turnaround = false;
         }
 
-        let neighbor_count = neighbors.options.len();
-        let node_loc = node.pano.loc;
-        let node_heading = node.heading;
-        let approx_lng_m_per_degree = if settings.no_long_jumps {
-            node_loc.calculate_lng_m_per_degree()
+        let approx_lng_m_per_degree = if settings.max_jump_meters.is_some() {
+            node.pano.loc.calculate_lng_m_per_degree()
         } else {
             // don't bother calculating it if we're not gonna use it
             0.
         };
 
-        // the base delays are 5 and 9, but we add a little extra to account for
-        // latency (these numbers were obtained by analyzing historical data)
-        let base_neighbor_cost: Cost = if neighbor_count == 1 { 5.875 } else { 9.625 };
+        let mut from = node.clone();
+        let mut came_from = index;
+        let mut g_score = g_score;
+        let mut neighbors = neighbors;
 
-        let mut straightest_option_idx = None;
-        if neighbor_count > 1
-            && (settings.forward_penalty_on_intersections > 0.
-                || settings.non_sharp_turn_penalty > 0.)
-        {
-            let mut smallest_heading_diff = 180.;
-            for (i, neighbor) in neighbors.options.iter().enumerate() {
-                let heading_diff = (neighbor.heading - node_heading).abs();
+        // once we're in a dead end, the only way out is through other dead-end panos
+        let can_prune_dead_ends =
+            settings.prune_dead_ends && db.dead_end_reach(node.pano.id).is_none();
 
-                if heading_diff < smallest_heading_diff {
-                    smallest_heading_diff = heading_diff;
-                    straightest_option_idx = Some(i);
+        if is_baked
+            && !neighbors.turnaround
+            && neighbors.options.len() == 1
+            // the skipped nodes are never expanded, so a repaired tree wouldn't
+            // know where they lead
+            && expansions.is_none()
+            && let Some(chain) =
+                db.lookup_shortcut(from.pano.id, bake::heading_bucket(from.heading))
+        {
+            // skip to the end of the chain that this node leads into, as long as the
+            // search would've gone through every node in it without stopping
+            let mut skipped = Vec::with_capacity(chain.len());
+            let mut prev = from.clone();
+            for option in &chain[..chain.len() - 1] {
+                if targets.any_within(option.pano.loc, 0.) {
+                    break;
                 }
+                let Some(penalty) = neighbor_penalty(
+                    prev.pano.loc,
+                    option,
+                    can_prune_dead_ends,
+                    approx_lng_m_per_degree,
+                ) else {
+                    break;
+                };
+                let cost = cost_model.edge_cost(&Edge {
+                    from: &prev,
+                    to: option,
+                    option_index: 0,
+                    option_count: 1,
+                    straightest_option_index: None,
+                }) + penalty;
+                prev = NodeIdent {
+                    pano: option.pano,
+                    heading: option.heading,
+                    waypoints_reached: from.waypoints_reached,
+                };
+                skipped.push((prev.clone(), cost));
             }
-        }
 
-        for (i, neighbor) in neighbors.options.into_iter().enumerate() {
-            if settings.no_long_jumps {
-                let neighbor_approx_distance_sqr =
-                    approx_distance_sqr(node_loc, neighbor.pano.loc, approx_lng_m_per_degree);
-                let jump_limit = 500.0_f64;
-                if neighbor_approx_distance_sqr > jump_limit.powi(2) {
+            if skipped.len() == chain.len() - 1 {
+                // the skipped nodes are stored so the path can be reconstructed, but never
+                // expanded
+                let mut dominated = false;
+                for (skipped_node, cost) in skipped {
+                    g_score += cost;
+                    let data = NodeData {
+                        came_from,
+                        g_score,
+                        came_from_option_count: 1,
+                    };
+                    came_from = match nodes.entry(skipped_node) {
+                        indexmap::map::Entry::Occupied(mut e) => {
+                            if e.get().g_score <= g_score {
+                                // the rest of the chain was already reached more cheaply
+                                dominated = true;
+                                break;
+                            }
+                            e.insert(data);
+                            e.index() as u32
+                        }
+                        indexmap::map::Entry::Vacant(e) => {
+                            let skipped_index = e.index() as u32;
+                            e.insert(data);
+                            skipped_index
+                        }
+                    };
+                }
+                if dominated {
                     continue;
                 }
+                shortcuts_taken += 1;
+                from = prev;
+                neighbors.options = Box::new([chain.last().unwrap().clone()]);
             }
+        }
 
-            let mut neighbor_cost = base_neighbor_cost;
+        let neighbor_count = neighbors.options.len();
+        let came_from_option_count = neighbor_count.min(u8::MAX as usize) as u8;
+        let node_heading = from.heading;
+        let straightest_option_idx = straightest_option_index(&neighbors.options, node_heading);
+        let mut edges = Vec::new();
 
-            // tiebreaker, prefer going forwards (usually the first option)
-            if i == 0 && neighbor_count > 1 {
-                neighbor_cost -= 0.001;
-            }
+        for (i, neighbor) in neighbors.options.into_iter().enumerate() {
+            let Some(penalty) = neighbor_penalty(
+                from.pano.loc,
+                &neighbor,
+                can_prune_dead_ends,
+                approx_lng_m_per_degree,
+            ) else {
+                continue;
+            };
 
-            if let Some(straightest_option_idx) = straightest_option_idx {
-                let heading_diff = (neighbor.heading - node_heading).abs();
-                if settings.forward_penalty_on_intersections > 0.
-                    && (i == straightest_option_idx && heading_diff < 45.)
-                {
-                    neighbor_cost += settings.forward_penalty_on_intersections;
-                }
-                if settings.non_sharp_turn_penalty > 0.
-                    && (heading_diff > 20. && heading_diff < 80. && i != straightest_option_idx)
-                {
-                    neighbor_cost += settings.non_sharp_turn_penalty;
-                }
-            }
+            let neighbor_cost = cost_model.edge_cost(&Edge {
+                from: &from,
+                to: &neighbor,
+                option_index: i,
+                option_count: neighbor_count,
+                straightest_option_index: straightest_option_idx,
+            }) + penalty;
 
             let tentative_g_score = g_score + neighbor_cost;
-
-            let neighbor_node = NodeIdent {
+            let mut neighbor_node = NodeIdent {
                 pano: neighbor.pano,
                 heading: neighbor.heading,
+                waypoints_reached: targets
+                    .waypoints_reached_at(neighbor.pano.loc, from.waypoints_reached),
             };
+            if let Some((_, best_cost)) = &best_goal
+                && tentative_g_score
+                    + (targets.distance(&neighbor_node) / settings.heuristic_factor) as Cost
+                    >= *best_cost
+            {
+                // this can't lead to a better path than the one we already have
+                continue;
+            }
+
+            let mut new_bucket = None;
+            if let Some(bucket_size) = settings.heading_bucket_size {
+                let bucket = (
+                    neighbor_node.pano.id,
+                    heading_bucket(neighbor.heading, bucket_size),
+                    neighbor_node.waypoints_reached,
+                );
+                match heading_buckets.entry(bucket) {
+                    Entry::Occupied(e) => {
+                        // treat it as the node we already have with a similar heading
+                        let existing = nodes.get_index(*e.get() as usize).unwrap().0;
+                        if existing.heading != neighbor_node.heading {
+                            merged_nodes += 1;
+                            neighbor_node = existing.clone();
+                        }
+                    }
+                    Entry::Vacant(e) => new_bucket = Some(e.into_key()),
+                }
+            }
 
             let neighbor_heuristic;
             let neighbor_index;
 
-            match nodes.entry(neighbor_node) {
+            let entry = nodes.entry(neighbor_node);
+            if let Some(bucket) = new_bucket {
+                // the node might already exist even though the bucket didn't
+                heading_buckets.insert(bucket, entry.index() as u32);
+            }
+            if expansions.is_some() {
+                edges.push((entry.index() as u32, neighbor_cost));
+            }
+            match entry {
                 indexmap::map::Entry::Occupied(mut e) => {
                     if tentative_g_score < e.get().g_score {
-                        neighbor_heuristic = heuristic(e.key(), goal, settings.heuristic_factor);
+                        neighbor_heuristic = heuristic(e.key(), &targets, factor);
                         neighbor_index = e.index() as u32;
                         e.insert(NodeData {
-                            came_from: index,
+                            came_from,
                             g_score: tentative_g_score,
+                            came_from_option_count,
                         });
+                        if let Some(expansions) = expansions {
+                            // it has to be expanded again with its new g score
+                            expansions.remove(&neighbor_index);
+                        }
                     } else {
                         continue;
                     }
@@ -280,11 +1087,12 @@ pub async fn astar(
                     // unknown neighbors have a default g_score of infinity, so we always "replace"
                     // them
 
-                    neighbor_heuristic = heuristic(e.key(), goal, settings.heuristic_factor);
+                    neighbor_heuristic = heuristic(e.key(), &targets, factor);
                     neighbor_index = e.index() as u32;
                     e.insert(NodeData {
-                        came_from: index,
+                        came_from,
                         g_score: tentative_g_score,
+                        came_from_option_count,
                     });
                 }
             }
@@ -300,43 +1108,215 @@ pub async fn astar(
                 f_score: tentative_g_score + neighbor_heuristic,
             });
         }
+
+        if let Some(expansions) = expansions {
+            expansions.insert(
+                came_from,
+                Expansion {
+                    option_count: came_from_option_count,
+                    edges: edges.into(),
+                },
+            );
+        }
     }
 
+    if let Some((route, cost)) = best_goal {
+        // we ran out of nodes while refining, so the path we have is the best one
+        set_done_progress(&progress_update, &route, cost, nodes_considered);
+        return Ok(route);
+    }
+
+    set_no_path_progress(&progress_update, nodes_considered);
+
+    bail!("No path found")
+}
+
+/// Mark the search as done with the given path.
+fn set_done_progress(
+    progress_update: &Mutex<ProgressUpdate>,
+    route: &[RouteNode],
+    cost: Cost,
+    nodes_considered: usize,
+) {
     let mut progress_update = progress_update.lock();
     *progress_update = ProgressUpdate {
         percent_done: 1.,
         estimated_seconds_remaining: 0.,
         nodes_considered,
+        memory_pressure: 0.,
+        stored_nodes: 0,
+        best_path_cost: cost,
+        best_path: route.iter().map(|n| n.pano.loc.to_geojson()).collect(),
+        best_path_costs: route.iter().map(|n| n.cost).collect(),
+        current_path: Box::new([]),
+    };
+}
+
+/// Recalculate the f scores of the open set, after the heuristic changed.
+fn rescore_open_set(
+    open_set: &mut BinaryHeap<WeightedNode>,
+    nodes: &FxIndexMap<NodeIdent, NodeData>,
+    targets: &Targets,
+    factor: f64,
+) {
+    *open_set = std::mem::take(open_set)
+        .into_iter()
+        .map(|n| WeightedNode {
+            f_score: n.g_score
+                + heuristic(
+                    nodes.get_index(n.index as usize).unwrap().0,
+                    targets,
+                    factor,
+                ),
+            ..n
+        })
+        .collect();
+}
+
+/// Roughly how many bytes a search uses to store this many nodes.
+pub fn estimated_memory_usage(stored_nodes: usize) -> usize {
+    // every node is in the index map (with its hash) and usually in the open set
+    let per_node = size_of::<NodeIdent>()
+        + size_of::<NodeData>()
+        + size_of::<u64>()
+        + size_of::<WeightedNode>();
+    stored_nodes * per_node
+}
+
+/// The penalty for going to the neighbor, or `None` if the settings don't allow
+/// going there at all. These are the checks that don't depend on the goal, so
+/// [`crate::isochrone`] uses them too.
+pub fn allowed_neighbor_penalty(
+    db: &Db,
+    settings: &PathSettings,
+    capture_dates: &mut CaptureDateFilter,
+    from_loc: Location,
+    neighbor: &PanoOptionRes,
+    approx_lng_m_per_degree: f64,
+) -> Option<Cost> {
+    if settings.exclude_panos.contains(&neighbor.pano.id) {
+        return None;
+    }
+    if settings
+        .avoid_areas
+        .iter()
+        .any(|area| area.contains(neighbor.pano.loc))
+    {
+        return None;
+    }
+    if capture_dates.is_too_old(db, settings, neighbor.pano.id) {
+        return None;
+    }
+    let photosphere_penalty = settings.photospheres.penalty(neighbor.pano.id)?;
+    if let Some(jump_limit) = settings.max_jump_meters
+        && approx_distance_sqr(from_loc, neighbor.pano.loc, approx_lng_m_per_degree)
+            > jump_limit.powi(2)
+    {
+        return None;
+    }
+    Some(photosphere_penalty)
+}
+
+/// Remembers which panos are too old for [`PathSettings::min_capture_year`],
+/// so a search only looks up each pano's capture date once instead of every
+/// time it's a neighbor.
+#[derive(Default)]
+pub struct CaptureDateFilter {
+    too_old: FxHashMap<PanoId, bool>,
+}
+impl CaptureDateFilter {
+    /// Whether the pano's imagery is known to be older than `min_capture_year`.
+    fn is_too_old(&mut self, db: &Db, settings: &PathSettings, pano_id: PanoId) -> bool {
+        let Some(min_year) = settings.min_capture_year else {
+            return false;
+        };
+        *self.too_old.entry(pano_id).or_insert_with(|| {
+            db.lookup_capture_date(&pano_id)
+                .is_some_and(|date| date.year < min_year)
+        })
+    }
+}
+
+/// How much of the node budget has been used, between 0 and 1.
+fn memory_pressure(settings: &PathSettings, stored_nodes: usize) -> f64 {
+    match settings.node_budget {
+        Some(node_budget) => (stored_nodes as f64 / node_budget.max(1) as f64).min(1.),
+        None => 0.,
+    }
+}
+
+/// In anytime mode, the first half of the progress bar is for finding the
+/// initial path and the second half is for the refinements.
+fn anytime_percent_done(found_path: bool, refinements_done: usize, refinement_count: usize) -> f64 {
+    if !found_path {
+        return 0.5;
+    }
+    0.5 + 0.5 * (refinements_done as f64 / (refinement_count + 1) as f64)
+}
+
+/// Mark the search as done without having found a path.
+fn set_no_path_progress(progress_update: &Mutex<ProgressUpdate>, nodes_considered: usize) {
+    let mut progress_update = progress_update.lock();
+    *progress_update = ProgressUpdate {
+        percent_done: 1.,
+        estimated_seconds_remaining: 0.,
+        nodes_considered,
+        memory_pressure: 0.,
+        stored_nodes: 0,
         best_path: Box::new([]),
+        best_path_costs: Box::new([]),
         best_path_cost: 0 as Cost,
         current_path: Box::new([]),
     };
-
-    bail!("No path found")
 }
 
-pub type Cost = f32;
+pub use pathfinder_protocol::Cost;
 
-fn reconstruct_path(nodes: &FxIndexMap<NodeIdent, NodeData>, mut current: u32) -> Vec<NodeIdent> {
+fn reconstruct_path(nodes: &FxIndexMap<NodeIdent, NodeData>, mut current: u32) -> Vec<RouteNode> {
     let mut full_path = Vec::new();
+    // the last node was never left, so we don't know its options
+    let mut option_count = 0;
     while let Some((node, node_data)) = nodes.get_index(current as usize) {
         if node_data.came_from == u32::MAX {
             break;
         }
 
         current = node_data.came_from;
-        full_path.push(node.clone());
+        full_path.push(RouteNode::new(node, node_data, option_count));
+        option_count = node_data.came_from_option_count;
     }
-    full_path.push(nodes.get_index(current as usize).unwrap().0.clone());
+    let (start, start_data) = nodes.get_index(current as usize).unwrap();
+    full_path.push(RouteNode::new(start, start_data, option_count));
 
     full_path.reverse();
     full_path
 }
 
-fn heuristic(current: &NodeIdent, goal: Location, factor: f64) -> Cost {
-    (math::distance(current.pano.loc, goal) / factor) as Cost
+fn heuristic(current: &NodeIdent, targets: &Targets, factor: f64) -> Cost {
+    let estimate = (targets.distance(current) / factor) as Cost;
+    // the landmarks only know about the cost to the goal, so they can't be used
+    // while there are still waypoints to visit
+    if current.waypoints_reached as usize == targets.waypoints.len()
+        && let Some(landmarks) = &targets.landmarks
+        && let Some(bound) = landmarks.lower_bound(current)
+    {
+        // scaled like the distance, so the factor still trades accuracy for speed
+        return estimate.max((bound as f64 / factor) as Cost);
+    }
+    estimate
+}
+
+/// Which bucket the heading is in, for [`PathSettings::heading_bucket_size`].
+fn heading_bucket(heading: f32, bucket_size: f32) -> u16 {
+    let bucket_count = (360. / bucket_size).round().max(1.) as u16;
+    (heading.rem_euclid(360.) / bucket_size).round() as u16 % bucket_count
 }
-fn is_goal_reached(node: &NodeIdent, goal: Location) -> bool {
+
+fn is_goal_reached(node: &NodeIdent, goal: Location, goal_pano: Option<PanoId>) -> bool {
+    if let Some(goal_pano) = goal_pano {
+        return node.pano.id == goal_pano;
+    }
+
     let dist = math::distance(node.pano.loc, goal);
     if dist < 30. {
         debug!("Node {node:?} is near goal {goal:?}: distance={dist}");
@@ -363,6 +1343,31 @@ pub struct NodeIdent {
     /// In degrees. This is necessary because the pathfinder takes heading into
     /// consideration.
     pub heading: f32,
+    /// The number of [`PathSettings::waypoints`] that the path to this node
+    /// went through, so the same pano can be visited again after a waypoint.
+    pub waypoints_reached: u16,
+}
+
+/// A node in a path that was found by the pathfinder.
+#[derive(Debug, Clone)]
+pub struct RouteNode {
+    pub pano: Pano,
+    pub heading: f32,
+    /// The cost of the path from the start up to this node.
+    pub cost: Cost,
+    /// How many options the car had at this node when the path was found, or
+    /// 0 if that isn't known (like for the last node, which is never left).
+    pub option_count: u8,
+}
+impl RouteNode {
+    fn new(node: &NodeIdent, node_data: &NodeData, option_count: u8) -> Self {
+        Self {
+            pano: node.pano,
+            heading: node.heading,
+            cost: node_data.g_score,
+            option_count,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -371,6 +1376,9 @@ pub struct NodeData {
     /// The cost of the currently known cheapest path from the start to this
     /// node
     pub g_score: Cost,
+    /// How many options there were at `came_from`, see
+    /// [`RouteNode::option_count`].
+    pub came_from_option_count: u8,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -385,11 +1393,14 @@ impl Hash for NodeIdent {
         // either id or loc could be used here, but PanoId hashes faster than Location
         self.pano.id.hash(state);
         self.heading.to_bits().hash(state);
+        self.waypoints_reached.hash(state);
     }
 }
 impl PartialEq for NodeIdent {
     fn eq(&self, other: &Self) -> bool {
-        self.pano.id == other.pano.id && self.heading == other.heading
+        self.pano.id == other.pano.id
+            && self.heading == other.heading
+            && self.waypoints_reached == other.waypoints_reached
     }
 }
 impl Eq for NodeIdent {}
@@ -0,0 +1,121 @@
+//! Copying the database to a file while the server keeps running, so the cache
+//! can be backed up (and compacted) without stopping it. LMDB doesn't report
+//! how far along a copy is, so the progress is estimated from the size of the
+//! file it's writing.
+
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Instant,
+};
+
+use parking_lot::Mutex;
+use tracing::{error, info};
+
+use crate::db::{Db, MAX_MAP_FULLNESS_FOR_COPY};
+
+#[derive(Debug)]
+pub struct BackupProgress {
+    pub path: PathBuf,
+    pub compact: bool,
+    /// About how big the copy will be, in bytes.
+    pub expected_bytes: u64,
+    pub finished: AtomicBool,
+    /// Set if the copy failed.
+    pub error: Mutex<Option<String>>,
+    started_at: Instant,
+}
+impl BackupProgress {
+    pub fn elapsed_seconds(&self) -> f64 {
+        self.started_at.elapsed().as_secs_f64()
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        fs::metadata(&self.path)
+            .map(|m| m.len())
+            .unwrap_or_default()
+    }
+
+    /// Between 0 and 1
+    pub fn percent_done(&self) -> f64 {
+        if self.finished.load(Ordering::Relaxed) {
+            return 1.;
+        }
+        (self.bytes_written() as f64 / self.expected_bytes.max(1) as f64).min(1.)
+    }
+}
+
+/// The backup that's currently running (or the last one that finished). Only
+/// one can run at a time.
+static BACKUP: LazyLock<Mutex<Option<Arc<BackupProgress>>>> = LazyLock::new(Mutex::default);
+
+/// Why a backup couldn't be started.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BackupError {
+    AlreadyRunning,
+    /// The map is so full that it'd probably have to grow during the copy,
+    /// which would block writes until the copy is done. See
+    /// [`Db::copy_to_path`].
+    MapNearlyFull {
+        fullness: f64,
+    },
+}
+
+/// Start copying the database to `path` in the background.
+pub fn start_backup(
+    db: &'static Db,
+    path: PathBuf,
+    compact: bool,
+) -> Result<Arc<BackupProgress>, BackupError> {
+    let mut backup = BACKUP.lock();
+    if let Some(running) = &*backup
+        && !running.finished.load(Ordering::Relaxed)
+    {
+        return Err(BackupError::AlreadyRunning);
+    }
+    let fullness = db.map_fullness();
+    if fullness > MAX_MAP_FULLNESS_FOR_COPY {
+        return Err(BackupError::MapNearlyFull { fullness });
+    }
+
+    let progress = Arc::new(BackupProgress {
+        path,
+        compact,
+        expected_bytes: if compact {
+            db.used_bytes()
+        } else {
+            db.disk_bytes()
+        },
+        finished: AtomicBool::new(false),
+        error: Mutex::new(None),
+        started_at: Instant::now(),
+    });
+    let task_progress = progress.clone();
+    tokio::task::spawn_blocking(move || {
+        let progress = task_progress;
+        info!("Backing up the database to {}", progress.path.display());
+        match db.copy_to_path(&progress.path, progress.compact) {
+            Ok(()) => info!(
+                "Finished backing up {} bytes in {:.0}s",
+                progress.bytes_written(),
+                progress.elapsed_seconds()
+            ),
+            Err(err) => {
+                error!("Failed to back up the database: {err}");
+                *progress.error.lock() = Some(err.to_string());
+            }
+        }
+        progress.finished.store(true, Ordering::Relaxed);
+    });
+    *backup = Some(progress.clone());
+
+    Ok(progress)
+}
+
+pub fn backup_progress() -> Option<Arc<BackupProgress>> {
+    BACKUP.lock().clone()
+}
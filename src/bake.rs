@@ -0,0 +1,292 @@
+//! Baking the graph of a region ahead of time. Every node (a pano and a heading
+//! bucket) that can be reached in the region gets its options calculated once
+//! and stored in the database, so searches there can look them up instead of
+//! calling [`roadtrip::get_options`] for every node.
+//!
+//! Headings are rounded to [`HEADING_BUCKET_SIZE`] degrees, which is the same
+//! tradeoff as [`crate::astar::PathSettings::heading_bucket_size`]. The costs
+//! of the edges still depend on the query's settings, so they're calculated
+//! during the search. Learned options are applied during the search too, so
+//! they don't go stale.
+
+use std::{
+    collections::VecDeque,
+    hash::BuildHasherDefault,
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::Instant,
+};
+
+use parking_lot::Mutex;
+use quick_cache::{UnitWeighter, sync::Cache};
+use rustc_hash::{FxHashSet, FxHasher};
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{
+    db::Db,
+    landmarks,
+    learned_options::apply_learned_options,
+    model::{Pano, PanoId},
+    roadtrip::{self, BasePanoOptionsRes, PanoOptionsRes},
+    streetview::{self, prefetch::BoundingBox},
+};
+
+/// How many degrees of heading each baked node covers.
+pub const HEADING_BUCKET_SIZE: f32 = 5.;
+/// Regions with more nodes than this are only partially baked.
+const MAX_BAKE_NODES: usize = 5_000_000;
+/// How many nodes are written to the database at once.
+const SAVE_BATCH_SIZE: usize = 1024;
+
+/// The baked options of each pano and heading bucket, or `None` if it wasn't
+/// baked.
+pub type BakedCache =
+    Cache<(PanoId, u16), Option<BasePanoOptionsRes>, UnitWeighter, BuildHasherDefault<FxHasher>>;
+
+/// Holds up to `size` baked nodes (including ones that weren't baked), so
+/// searches don't need a transaction for every node.
+pub fn new_baked_cache(size: usize) -> BakedCache {
+    Cache::with(
+        size,
+        size as u64,
+        Default::default(),
+        Default::default(),
+        Default::default(),
+    )
+}
+
+pub fn heading_bucket(heading: f32) -> u16 {
+    let bucket_count = (360. / HEADING_BUCKET_SIZE) as u16;
+    (heading.rem_euclid(360.) / HEADING_BUCKET_SIZE).round() as u16 % bucket_count
+}
+
+pub fn bucket_heading(bucket: u16) -> f32 {
+    bucket as f32 * HEADING_BUCKET_SIZE
+}
+
+/// The options for the node if it was baked, like [`roadtrip::get_options`].
+pub fn get_baked_options(
+    db: &Db,
+    pano: &Pano,
+    heading: f32,
+    allow_turnaround: bool,
+) -> Option<PanoOptionsRes> {
+    let mut res = baked_options_no_turnaround(db, pano.id, heading)?;
+    let mut turnaround = false;
+    if allow_turnaround && res.options.is_empty() {
+        res = baked_options_no_turnaround(db, pano.id, heading + 180.)?;
+        turnaround = true;
+    }
+    Some(PanoOptionsRes {
+        options: res.options,
+        turnaround,
+    })
+}
+
+fn baked_options_no_turnaround(
+    db: &Db,
+    pano_id: PanoId,
+    heading: f32,
+) -> Option<BasePanoOptionsRes> {
+    let mut res = db.lookup_baked_options(pano_id, heading_bucket(heading))?;
+    apply_learned_options(db, pano_id, heading, &mut res);
+    Some(res)
+}
+
+#[derive(Debug, Serialize)]
+pub struct BakeProgress {
+    pub bbox: BoundingBox,
+    pub nodes_baked: AtomicUsize,
+    /// Nodes that are waiting to be baked.
+    pub nodes_queued: AtomicUsize,
+    pub nodes_failed: AtomicUsize,
+    pub finished: AtomicBool,
+    #[serde(skip)]
+    started_at: Instant,
+}
+impl BakeProgress {
+    pub fn elapsed_seconds(&self) -> f64 {
+        self.started_at.elapsed().as_secs_f64()
+    }
+}
+
+struct RunningBake {
+    progress: Arc<BakeProgress>,
+    cancel: CancellationToken,
+}
+
+/// The bake that's currently running (or the last one that finished). Only one
+/// can run at a time.
+static BAKE: LazyLock<Mutex<Option<RunningBake>>> = LazyLock::new(Mutex::default);
+
+/// Start baking the region in the background. Returns `None` if a bake is
+/// already running.
+pub fn start_bake(db: &'static Db, bbox: BoundingBox) -> Option<Arc<BakeProgress>> {
+    let mut bake = BAKE.lock();
+    if let Some(running) = &*bake
+        && !running.progress.finished.load(Ordering::Relaxed)
+    {
+        return None;
+    }
+
+    let progress = Arc::new(BakeProgress {
+        bbox,
+        nodes_baked: AtomicUsize::new(0),
+        nodes_queued: AtomicUsize::new(0),
+        nodes_failed: AtomicUsize::new(0),
+        finished: AtomicBool::new(false),
+        started_at: Instant::now(),
+    });
+    let cancel = CancellationToken::new();
+    tokio::spawn(bake_region(db, progress.clone(), cancel.clone()));
+    *bake = Some(RunningBake {
+        progress: progress.clone(),
+        cancel,
+    });
+
+    Some(progress)
+}
+
+pub fn bake_progress() -> Option<Arc<BakeProgress>> {
+    BAKE.lock().as_ref().map(|b| b.progress.clone())
+}
+
+/// Stop the running bake, returning false if there wasn't one. The nodes that
+/// were already baked are kept.
+pub fn cancel_bake() -> bool {
+    let bake = BAKE.lock();
+    let Some(running) = &*bake else {
+        return false;
+    };
+    if running.progress.finished.swap(true, Ordering::Relaxed) {
+        return false;
+    }
+    running.cancel.cancel();
+    info!("Cancelled bake");
+    true
+}
+
+async fn bake_region(db: &'static Db, progress: Arc<BakeProgress>, cancel: CancellationToken) {
+    let bbox = progress.bbox;
+    info!("Baking {bbox:?}");
+
+    // start from every cached pano in the region, facing the directions that its
+    // links go in
+    let mut queue = VecDeque::new();
+    let mut queued = FxHashSet::<(PanoId, u16)>::default();
+    let tiles = db.iter_tiles_in_bbox(&bbox);
+    for tile in tiles {
+        let Some(Some(panos)) = db.lookup_listentityphotos(&tile) else {
+            continue;
+        };
+        for pano in panos.iter() {
+            let Some((loc, links)) = db.lookup_getmetadata(&pano.id) else {
+                continue;
+            };
+            if !bbox.contains(loc) {
+                continue;
+            }
+            for link in links {
+                let node = (pano.id, heading_bucket(link.heading));
+                if queued.insert(node) {
+                    queue.push_back((Pano { id: pano.id, loc }, node.1));
+                }
+            }
+        }
+    }
+
+    let mut batch = Vec::new();
+    while let Some((pano, bucket)) = queue.pop_front() {
+        progress.nodes_queued.store(queue.len(), Ordering::Relaxed);
+        if cancel.is_cancelled() {
+            break;
+        }
+
+        let res = roadtrip::emulate_options(db, &pano, bucket_heading(bucket), true, &cancel).await;
+        let res = match res {
+            Ok(res) => res,
+            Err(err) if err.is::<streetview::Cancelled>() => break,
+            Err(err) => {
+                warn!(
+                    "Failed to bake {pano:?} at {}: {err}",
+                    bucket_heading(bucket)
+                );
+                progress.nodes_failed.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+        };
+
+        let mut next = res
+            .options
+            .iter()
+            .filter(|option| bbox.contains(option.pano.loc))
+            .map(|option| (option.pano, heading_bucket(option.heading)))
+            .collect::<Vec<_>>();
+        if res.options.is_empty() {
+            // the search will turn around here
+            next.push((pano, heading_bucket(bucket_heading(bucket) + 180.)));
+        }
+        for (next_pano, next_bucket) in next {
+            if queued.len() < MAX_BAKE_NODES && queued.insert((next_pano.id, next_bucket)) {
+                queue.push_back((next_pano, next_bucket));
+            }
+        }
+
+        batch.push((pano.id, bucket, res));
+        if batch.len() >= SAVE_BATCH_SIZE {
+            save_batch(db, &mut batch, &progress);
+        }
+    }
+    save_batch(db, &mut batch, &progress);
+
+    if queued.len() >= MAX_BAKE_NODES {
+        warn!("Stopped baking {bbox:?} after {MAX_BAKE_NODES} nodes");
+    }
+    if cancel.is_cancelled() {
+        return;
+    }
+    progress.finished.store(true, Ordering::Relaxed);
+    info!(
+        "Finished baking {} nodes in {:.0}s",
+        progress.nodes_baked.load(Ordering::Relaxed),
+        progress.elapsed_seconds()
+    );
+    // the landmarks depend on the whole graph, so they have to be calculated again
+    landmarks::spawn_precompute(db);
+}
+
+fn save_batch(
+    db: &Db,
+    batch: &mut Vec<(PanoId, u16, BasePanoOptionsRes)>,
+    progress: &BakeProgress,
+) {
+    if let Err(err) = db.save_baked_options(batch) {
+        warn!("Failed to save {} baked nodes: {err}", batch.len());
+        progress
+            .nodes_failed
+            .fetch_add(batch.len(), Ordering::Relaxed);
+    } else {
+        progress
+            .nodes_baked
+            .fetch_add(batch.len(), Ordering::Relaxed);
+    }
+    batch.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heading_bucket_wraps() {
+        assert_eq!(heading_bucket(0.), 0);
+        assert_eq!(heading_bucket(358.), 0);
+        assert_eq!(heading_bucket(-2.), 0);
+        assert_eq!(heading_bucket(92.), 18);
+        assert_eq!(bucket_heading(heading_bucket(92.)), 90.);
+    }
+}
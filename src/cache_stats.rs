@@ -0,0 +1,87 @@
+//! How well the in-memory caches are doing, for tuning their sizes. See
+//! `/stats/caches`.
+
+use std::sync::{
+    Arc,
+    atomic::{AtomicU64, Ordering},
+};
+
+use quick_cache::{Lifecycle, Weighter, sync::Cache};
+use serde::Serialize;
+
+use crate::db::Db;
+
+/// A cache lifecycle that counts how many items were evicted.
+#[derive(Debug, Clone, Default)]
+pub struct EvictionCounter(Arc<AtomicU64>);
+impl EvictionCounter {
+    pub fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn count(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+impl<Key, Val> Lifecycle<Key, Val> for EvictionCounter {
+    type RequestState = ();
+
+    fn begin_request(&self) -> Self::RequestState {}
+
+    fn on_evict(&self, _state: &mut Self::RequestState, _key: Key, _val: Val) {
+        self.increment();
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    /// The total weight of the entries, which is what `capacity` limits.
+    pub weight: u64,
+    pub capacity: u64,
+    pub hits: u64,
+    pub misses: u64,
+    /// Between 0 and 1, or 0 if the cache wasn't used yet.
+    pub hit_rate: f64,
+    pub evictions: u64,
+}
+impl CacheStats {
+    pub fn of<Key, Val, We, B, L>(cache: &Cache<Key, Val, We, B, L>, evictions: u64) -> Self
+    where
+        Key: Eq + std::hash::Hash,
+        Val: Clone,
+        We: Weighter<Key, Val> + Clone,
+        B: std::hash::BuildHasher + Clone,
+        L: Lifecycle<Key, Val> + Clone,
+    {
+        let hits = cache.hits();
+        let misses = cache.misses();
+        Self {
+            entries: cache.len(),
+            weight: cache.weight(),
+            capacity: cache.capacity(),
+            hits,
+            misses,
+            hit_rate: hits as f64 / (hits + misses).max(1) as f64,
+            evictions,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AllCacheStats {
+    /// The panos in each tile, weighted by the number of panos. See
+    /// [`crate::streetview::get_panos_at_tile`].
+    pub tiles: CacheStats,
+    /// The options of each pano and heading, see [`crate::roadtrip`].
+    pub options: CacheStats,
+}
+
+pub fn cache_stats(db: &Db) -> AllCacheStats {
+    AllCacheStats {
+        tiles: CacheStats::of(
+            &db.panos_at_tile_cache,
+            db.panos_at_tile_cache_evictions.count(),
+        ),
+        options: CacheStats::of(&db.options_cache, db.options_cache_evictions.count()),
+    }
+}
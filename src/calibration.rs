@@ -0,0 +1,220 @@
+//! Measuring how long the car actually waits at each pano, so the pathfinder's
+//! costs match reality.
+//!
+//! The car moves once the vote is over, and the vote is shorter when there's
+//! only one option. We time how long it stays at each pano (from the IRT
+//! WebSocket, or from an imported log) and group the delays by the number of
+//! options it had there.
+
+use std::{collections::VecDeque, time::Instant};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{astar::Cost, db::Db};
+
+/// Delays are tracked separately for up to this many options, and everything
+/// above it shares the last bucket.
+pub const MAX_OPTION_COUNT: usize = 8;
+/// The number of samples that a bucket needs before we trust its average.
+const MIN_SAMPLES: u64 = 20;
+/// Delays longer than this are assumed to be the game being stuck or restarting
+/// rather than a normal vote, so they're ignored.
+const MAX_DELAY: f64 = 60.;
+/// How many of the car's most recent delays are used to measure its pace, see
+/// [`recent_pace`].
+const RECENT_SAMPLE_COUNT: usize = 50;
+/// The pace isn't measured until there are at least this many recent delays.
+const MIN_RECENT_SAMPLES: usize = 10;
+
+static RECENT_SAMPLES: Mutex<VecDeque<DelaySample>> = Mutex::new(VecDeque::new());
+
+/// How long the car waits at a pano, depending on the number of options it
+/// has there.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VoteDelays {
+    /// The delay for 1 option is at index 0.
+    pub by_option_count: [Cost; MAX_OPTION_COUNT],
+}
+impl Default for VoteDelays {
+    fn default() -> Self {
+        // the base delays are 5 and 9, but we add a little extra to account for
+        // latency (these numbers were obtained by analyzing historical data)
+        let mut by_option_count = [9.625; MAX_OPTION_COUNT];
+        by_option_count[0] = 5.875;
+        Self { by_option_count }
+    }
+}
+impl VoteDelays {
+    pub fn for_option_count(&self, option_count: usize) -> Cost {
+        self.by_option_count[bucket(option_count)]
+    }
+}
+
+/// The sum and count of the observed delays for every option count.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DelaySamples {
+    pub buckets: [(f64, u64); MAX_OPTION_COUNT],
+}
+impl DelaySamples {
+    /// Returns false if the sample was ignored.
+    pub fn add(&mut self, sample: DelaySample) -> bool {
+        if !sample.is_valid() {
+            return false;
+        }
+        let (sum, count) = &mut self.buckets[bucket(sample.option_count)];
+        *sum += sample.delay;
+        *count += 1;
+        true
+    }
+
+    /// The average delays, falling back to the defaults for buckets that don't
+    /// have enough samples.
+    pub fn delays(&self) -> VoteDelays {
+        let mut delays = VoteDelays::default();
+        for (delay, &(sum, count)) in delays.by_option_count.iter_mut().zip(&self.buckets) {
+            if count >= MIN_SAMPLES {
+                *delay = (sum / count as f64) as Cost;
+            }
+        }
+        delays
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct DelaySample {
+    pub option_count: usize,
+    /// In seconds.
+    pub delay: f64,
+}
+
+impl DelaySample {
+    fn is_valid(&self) -> bool {
+        self.option_count > 0 && (0. ..=MAX_DELAY).contains(&self.delay)
+    }
+}
+
+/// Remember a delay that the car just had, for [`recent_pace`].
+pub fn record_recent_sample(sample: DelaySample) {
+    if !sample.is_valid() {
+        return;
+    }
+    let mut recent = RECENT_SAMPLES.lock();
+    if recent.len() >= RECENT_SAMPLE_COUNT {
+        recent.pop_front();
+    }
+    recent.push_back(sample);
+}
+
+/// How long the car has recently been taking compared to what `delays`
+/// predicts, like 1.2 if it's been 20% slower. `None` if it hasn't moved
+/// enough since the pathfinder started.
+pub fn recent_pace(delays: &VoteDelays) -> Option<f64> {
+    pace(RECENT_SAMPLES.lock().iter(), delays)
+}
+
+fn pace<'a>(
+    samples: impl ExactSizeIterator<Item = &'a DelaySample>,
+    delays: &VoteDelays,
+) -> Option<f64> {
+    if samples.len() < MIN_RECENT_SAMPLES {
+        return None;
+    }
+    let (actual, expected) = samples.fold((0., 0.), |(actual, expected), sample| {
+        (
+            actual + sample.delay,
+            expected + delays.for_option_count(sample.option_count) as f64,
+        )
+    });
+    (expected > 0.).then(|| actual / expected)
+}
+
+fn bucket(option_count: usize) -> usize {
+    option_count.clamp(1, MAX_OPTION_COUNT) - 1
+}
+
+/// Add the samples to the ones in the database and start using the new delays.
+/// Returns the number of samples that weren't ignored.
+pub fn record_samples(db: &Db, samples: &[DelaySample]) -> eyre::Result<usize> {
+    // so two updates can't overwrite each other
+    static LOCK: Mutex<()> = Mutex::new(());
+    let _guard = LOCK.lock();
+
+    let mut all_samples = db.get_delay_samples();
+    let accepted = samples.iter().filter(|s| all_samples.add(**s)).count();
+    if accepted > 0 {
+        db.save_delay_samples(&all_samples)?;
+        *db.vote_delays.write() = all_samples.delays();
+    }
+    Ok(accepted)
+}
+
+/// Times how long the car stays at each pano, using the messages from the IRT
+/// WebSocket.
+#[derive(Default)]
+pub struct CarTimer {
+    /// The pano the car is at, when it got there, and how many options it had.
+    current: Option<(String, Instant, usize)>,
+}
+impl CarTimer {
+    /// Returns a sample when the car moves to a new pano.
+    pub fn update(&mut self, pano: &str, option_count: usize) -> Option<DelaySample> {
+        if let Some((current_pano, ..)) = &self.current
+            && current_pano == pano
+        {
+            return None;
+        }
+
+        let now = Instant::now();
+        let previous = self.current.replace((pano.to_owned(), now, option_count));
+        let (_, arrived_at, option_count) = previous?;
+        Some(DelaySample {
+            option_count,
+            delay: now.duration_since(arrived_at).as_secs_f64(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delays_need_enough_samples() {
+        let mut samples = DelaySamples::default();
+        for _ in 0..MIN_SAMPLES - 1 {
+            assert!(samples.add(DelaySample {
+                option_count: 1,
+                delay: 6.,
+            }));
+        }
+        assert!(!samples.add(DelaySample {
+            option_count: 2,
+            delay: 600.,
+        }));
+        assert_eq!(samples.delays(), VoteDelays::default());
+
+        samples.add(DelaySample {
+            option_count: 1,
+            delay: 6.,
+        });
+        assert_eq!(samples.delays().for_option_count(1), 6.);
+        assert_eq!(samples.delays().for_option_count(3), 9.625);
+    }
+
+    #[test]
+    fn test_pace_compares_recent_delays_to_expected() {
+        let delays = VoteDelays::default();
+        let samples = [1, 3]
+            .repeat(MIN_RECENT_SAMPLES / 2)
+            .into_iter()
+            .map(|option_count| DelaySample {
+                option_count,
+                delay: delays.for_option_count(option_count) as f64 * 1.5,
+            })
+            .collect::<Vec<_>>();
+        let measured = pace(samples.iter(), &delays).unwrap();
+        assert!((measured - 1.5).abs() < 1e-6, "{measured}");
+        assert_eq!(pace(samples[1..].iter(), &delays), None);
+    }
+}
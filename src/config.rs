@@ -0,0 +1,176 @@
+//! Settings for everything other than the database and the web server, which
+//! are in [`crate::db::config`] and [`crate::web::config`].
+
+use std::{env, path::PathBuf, str::FromStr, sync::LazyLock, time::Duration};
+
+use tracing::warn;
+
+/// The pathfinder's settings, configured from the environment.
+pub static CONFIG: LazyLock<Config> = LazyLock::new(Config::from_env);
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Whether searches skip dead-end branches that can't contain the goal,
+    /// see [`crate::dead_ends`]. Set with `PATHFINDER_PRUNE_DEAD_ENDS=1`,
+    /// defaults to false.
+    pub prune_dead_ends: bool,
+    /// How often the dead ends are found again, since the cache keeps growing.
+    /// Set with `PATHFINDER_DEAD_END_INTERVAL_SECS`, defaults to 6 hours.
+    pub dead_end_interval: Duration,
+    /// Whether searches use the landmarks for their heuristic, see
+    /// [`crate::landmarks`]. Set with `PATHFINDER_USE_LANDMARKS=1`, defaults
+    /// to false.
+    pub use_landmarks: bool,
+    /// How many landmarks are picked, between 1 and 255. More landmarks give
+    /// better bounds, but every one of them takes 8 bytes per baked node. Set
+    /// with `PATHFINDER_LANDMARK_COUNT`, defaults to 8.
+    pub landmark_count: usize,
+    /// The car is considered to have left the active route once it's further
+    /// than this many meters from it. Set with
+    /// `PATHFINDER_DEVIATION_CORRIDOR_METERS`, defaults to 50.
+    pub deviation_corridor_meters: f64,
+    /// The IRT WebSocket that tells us where the car is. Set with
+    /// `PATHFINDER_IRT_WEBSOCKET_URL`.
+    pub irt_websocket_url: String,
+    /// How often the cache around the car is cleared, so we notice new panos
+    /// there. We also wait this long before connecting at startup. Set with
+    /// `PATHFINDER_IRT_CACHE_CLEAR_SECS`, defaults to 3 minutes.
+    pub irt_cache_clear_interval: Duration,
+    /// The most requests per second that we send to Google, or 0 for no limit.
+    /// Set with `PATHFINDER_GOOGLE_MAX_RPS`, unlimited by default.
+    pub google_max_rps: f64,
+    /// How many requests can be sent to Google at once after a quiet period.
+    /// Set with `PATHFINDER_GOOGLE_BURST`, defaults to `google_max_rps`.
+    pub google_burst: f64,
+    /// How many times a request to Google is tried, including the first time.
+    /// Set with `PATHFINDER_GOOGLE_MAX_ATTEMPTS`, defaults to 5.
+    pub google_max_attempts: u32,
+    /// How long the first retry waits, which doubles for every retry after it.
+    /// Set with `PATHFINDER_GOOGLE_RETRY_BASE_MS`, defaults to 500ms.
+    pub google_retry_base_delay: Duration,
+    /// The longest that a retry waits. Set with `PATHFINDER_GOOGLE_RETRY_MAX_MS`,
+    /// defaults to 30 seconds.
+    pub google_retry_max_delay: Duration,
+    /// The number of retries allowed per request to Google. Set with
+    /// `PATHFINDER_GOOGLE_RETRY_RATIO`, defaults to 0.2.
+    pub google_retry_ratio: f64,
+    /// The proxies that requests to Google are spread between, see
+    /// [`crate::streetview::proxy`]. Set with a comma-separated
+    /// `PATHFINDER_GOOGLE_PROXIES`.
+    pub google_proxies: Vec<String>,
+    /// Where panos are replayed from instead of asking Google, see
+    /// [`crate::streetview::fixtures`]. Set with `PATHFINDER_REPLAY_DIR`.
+    pub replay_dir: Option<PathBuf>,
+    /// Where the panos from Google are recorded to, unless `replay_dir` is set.
+    /// Set with `PATHFINDER_RECORD_DIR`.
+    pub record_dir: Option<PathBuf>,
+}
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            prune_dead_ends: false,
+            dead_end_interval: Duration::from_secs(6 * 60 * 60),
+            use_landmarks: false,
+            landmark_count: 8,
+            deviation_corridor_meters: 50.,
+            irt_websocket_url: "wss://internet-roadtrip-listen-eqzms.ondigitalocean.app".to_owned(),
+            irt_cache_clear_interval: Duration::from_secs(3 * 60),
+            google_max_rps: 0.,
+            google_burst: 0.,
+            google_max_attempts: 5,
+            google_retry_base_delay: Duration::from_millis(500),
+            google_retry_max_delay: Duration::from_secs(30),
+            google_retry_ratio: 0.2,
+            google_proxies: Vec::new(),
+            replay_dir: None,
+            record_dir: None,
+        }
+    }
+}
+impl Config {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+
+        let prune_dead_ends =
+            env_bool("PATHFINDER_PRUNE_DEAD_ENDS").unwrap_or(default.prune_dead_ends);
+        let dead_end_interval = env_parse("PATHFINDER_DEAD_END_INTERVAL_SECS")
+            .map(Duration::from_secs)
+            .unwrap_or(default.dead_end_interval);
+        let use_landmarks = env_bool("PATHFINDER_USE_LANDMARKS").unwrap_or(default.use_landmarks);
+        let landmark_count = env_parse::<usize>("PATHFINDER_LANDMARK_COUNT")
+            .unwrap_or(default.landmark_count)
+            .clamp(1, u8::MAX as usize);
+        let deviation_corridor_meters = env_parse("PATHFINDER_DEVIATION_CORRIDOR_METERS")
+            .unwrap_or(default.deviation_corridor_meters);
+        let irt_websocket_url =
+            env::var("PATHFINDER_IRT_WEBSOCKET_URL").unwrap_or(default.irt_websocket_url);
+        let irt_cache_clear_interval = env_parse("PATHFINDER_IRT_CACHE_CLEAR_SECS")
+            .map(Duration::from_secs)
+            .unwrap_or(default.irt_cache_clear_interval);
+        let google_max_rps =
+            env_parse("PATHFINDER_GOOGLE_MAX_RPS").unwrap_or(default.google_max_rps);
+        let google_burst = env_parse("PATHFINDER_GOOGLE_BURST").unwrap_or(google_max_rps);
+        let google_max_attempts = env_parse::<u32>("PATHFINDER_GOOGLE_MAX_ATTEMPTS")
+            .unwrap_or(default.google_max_attempts)
+            .max(1);
+        let google_retry_base_delay = env_parse("PATHFINDER_GOOGLE_RETRY_BASE_MS")
+            .map(Duration::from_millis)
+            .unwrap_or(default.google_retry_base_delay);
+        let google_retry_max_delay = env_parse("PATHFINDER_GOOGLE_RETRY_MAX_MS")
+            .map(Duration::from_millis)
+            .unwrap_or(default.google_retry_max_delay);
+        let google_retry_ratio =
+            env_parse("PATHFINDER_GOOGLE_RETRY_RATIO").unwrap_or(default.google_retry_ratio);
+        let google_proxies = match env::var("PATHFINDER_GOOGLE_PROXIES") {
+            Ok(proxies) => proxies
+                .split(',')
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            Err(_) => default.google_proxies,
+        };
+        let replay_dir = env::var("PATHFINDER_REPLAY_DIR")
+            .map(PathBuf::from)
+            .ok()
+            .or(default.replay_dir);
+        let record_dir = env::var("PATHFINDER_RECORD_DIR")
+            .map(PathBuf::from)
+            .ok()
+            .or(default.record_dir);
+
+        Self {
+            prune_dead_ends,
+            dead_end_interval,
+            use_landmarks,
+            landmark_count,
+            deviation_corridor_meters,
+            irt_websocket_url,
+            irt_cache_clear_interval,
+            google_max_rps,
+            google_burst,
+            google_max_attempts,
+            google_retry_base_delay,
+            google_retry_max_delay,
+            google_retry_ratio,
+            google_proxies,
+            replay_dir,
+            record_dir,
+        }
+    }
+}
+
+/// The parsed variable, or `None` if it isn't set. Values that can't be parsed
+/// are logged and ignored.
+fn env_parse<T: FromStr>(key: &str) -> Option<T> {
+    let value = env::var(key).ok()?;
+    let parsed = value.parse().ok();
+    if parsed.is_none() {
+        warn!("Ignoring {key}={value:?}, since it isn't valid");
+    }
+    parsed
+}
+
+fn env_bool(key: &str) -> Option<bool> {
+    env::var(key).ok().map(|v| v == "1" || v == "true")
+}
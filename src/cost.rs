@@ -0,0 +1,114 @@
+//! How much it costs to take each option, which is what the pathfinder
+//! minimizes. Costs are roughly in seconds, since the heuristic assumes that.
+
+use crate::{
+    astar::{Cost, NodeIdent},
+    calibration::VoteDelays,
+    roadtrip::PanoOptionRes,
+};
+
+/// An option that the pathfinder is considering taking.
+pub struct Edge<'a> {
+    pub from: &'a NodeIdent,
+    pub to: &'a PanoOptionRes,
+    /// The index of `to` in the options.
+    pub option_index: usize,
+    pub option_count: usize,
+    /// The index of the option whose heading is closest to `from`'s heading,
+    /// or `None` if there's only one option.
+    pub straightest_option_index: Option<usize>,
+}
+impl Edge<'_> {
+    /// The difference between the heading of the option and the current
+    /// heading, in degrees.
+    pub fn heading_diff(&self) -> f32 {
+        (self.to.heading - self.from.heading).abs()
+    }
+}
+
+/// The index of the option whose heading is closest to `heading`, or `None`
+/// if there's only one option.
+pub fn straightest_option_index(options: &[PanoOptionRes], heading: f32) -> Option<usize> {
+    if options.len() <= 1 {
+        return None;
+    }
+    let mut straightest_option_index = None;
+    let mut smallest_heading_diff = 180.;
+    for (i, option) in options.iter().enumerate() {
+        let heading_diff = (option.heading - heading).abs();
+        if heading_diff < smallest_heading_diff {
+            smallest_heading_diff = heading_diff;
+            straightest_option_index = Some(i);
+        }
+    }
+    straightest_option_index
+}
+
+pub trait CostModel: Send + Sync {
+    fn edge_cost(&self, edge: &Edge<'_>) -> Cost;
+}
+
+/// The cost model that's used unless [`crate::astar::PathSettings::cost_model`]
+/// is set. Every option costs as much as the car waits for the vote, plus
+/// optional penalties for going straight at intersections and for turns that
+/// aren't sharp enough.
+pub struct DefaultCostModel {
+    pub vote_delays: VoteDelays,
+    pub forward_penalty_on_intersections: Cost,
+    pub non_sharp_turn_penalty: Cost,
+    /// See [`crate::astar::PathSettings::intersection_uncertainty`].
+    pub intersection_uncertainty: f32,
+}
+impl CostModel for DefaultCostModel {
+    fn edge_cost(&self, edge: &Edge<'_>) -> Cost {
+        let mut cost = self.vote_delays.for_option_count(edge.option_count);
+
+        // tiebreaker, prefer going forwards (usually the first option)
+        if edge.option_index == 0 && edge.option_count > 1 {
+            cost -= 0.001;
+        }
+
+        if let Some(straightest_option_index) = edge.straightest_option_index {
+            let heading_diff = edge.heading_diff();
+            let is_straightest = edge.option_index == straightest_option_index;
+            if self.forward_penalty_on_intersections > 0. && is_straightest && heading_diff < 45. {
+                cost += self.forward_penalty_on_intersections;
+            }
+            if self.non_sharp_turn_penalty > 0.
+                && heading_diff > 20.
+                && heading_diff < 80.
+                && !is_straightest
+            {
+                cost += self.non_sharp_turn_penalty;
+            }
+        }
+
+        cost * expected_attempts(edge.option_count, self.intersection_uncertainty)
+    }
+}
+
+/// The expected number of votes it takes for the option we want to win, if
+/// the winner is random with the given probability (and otherwise the one we
+/// want).
+fn expected_attempts(option_count: usize, uncertainty: f32) -> Cost {
+    if option_count <= 1 || uncertainty <= 0. {
+        return 1.;
+    }
+    let n = option_count as f32;
+    let win_probability = 1. - uncertainty.min(1.) * (n - 1.) / n;
+    1. / win_probability
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expected_attempts() {
+        assert_eq!(expected_attempts(1, 1.), 1.);
+        assert_eq!(expected_attempts(3, 0.), 1.);
+        // fully random with 2 options means we win half the time
+        assert_eq!(expected_attempts(2, 1.), 2.);
+        assert_eq!(expected_attempts(4, 1.), 4.);
+    }
+}
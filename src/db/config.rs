@@ -0,0 +1,193 @@
+//! Where the database is stored and how big it's allowed to get.
+
+use std::{env, path::PathBuf, str::FromStr, time::Duration};
+
+pub const GB: usize = 1024 * 1024 * 1024;
+const DAY_SECS: u64 = 60 * 60 * 24;
+
+/// The number of named databases that [`super::Db`] uses, which `max_dbs` is
+/// never allowed to be below.
+const REQUIRED_DBS: u32 = 16;
+
+#[derive(Debug, Clone)]
+pub struct DbConfig {
+    /// The directory that LMDB stores its files in. Set with
+    /// `PATHFINDER_CACHE_DIR`, defaults to `./cache`.
+    pub path: PathBuf,
+    /// The initial size of the memory map in bytes. Set with
+    /// `PATHFINDER_DB_MAP_SIZE_GB`, defaults to 128GB.
+    pub map_size: usize,
+    /// How much the map is grown by when LMDB runs out of space. Set with
+    /// `PATHFINDER_DB_MAP_SIZE_STEP_GB`, defaults to 16GB.
+    pub map_size_step: usize,
+    /// The map won't be grown past this size. Set with
+    /// `PATHFINDER_DB_MAX_MAP_SIZE_GB`, defaults to 1024GB.
+    pub max_map_size: usize,
+    /// The maximum number of named databases. Set with `PATHFINDER_DB_MAX_DBS`,
+    /// defaults to 16.
+    pub max_dbs: u32,
+    /// Whether the options that are calculated for every pano are saved in the
+    /// database, so they don't have to be recalculated after a restart. Set
+    /// with `PATHFINDER_PERSIST_OPTIONS=1`, defaults to false.
+    pub persist_options: bool,
+    /// How old a cached tile can get before it's downloaded again when it's
+    /// next needed, so that new coverage shows up. Set with
+    /// `PATHFINDER_TILE_TTL_DAYS` (0 to never download tiles again), defaults
+    /// to 90 days.
+    pub tile_ttl: Option<Duration>,
+    /// Like `tile_ttl`, but for tiles that had no panos. These are downloaded
+    /// again sooner since coverage is often added to empty areas. Set with
+    /// `PATHFINDER_EMPTY_TILE_TTL_DAYS` (0 to never download them again),
+    /// defaults to 14 days.
+    pub empty_tile_ttl: Option<Duration>,
+    /// The zstd level that new tiles and GetMetadata responses are compressed
+    /// with, or `None` to store them uncompressed. Set with
+    /// `PATHFINDER_DB_COMPRESSION_LEVEL` (0 to disable), defaults to disabled.
+    /// Entries that were already saved keep the compression they were saved
+    /// with.
+    pub compression_level: Option<i32>,
+    /// How long the car's positions are kept for `GET /car/history`. Set with
+    /// `PATHFINDER_CAR_HISTORY_RETENTION_DAYS` (0 to keep them forever),
+    /// defaults to 30 days.
+    pub car_history_retention: Option<Duration>,
+    /// How long saved routes (`GET /route/{id}`) and the paths of finished
+    /// jobs (`GET /path/{job_id}/gpx`) are kept. Set with
+    /// `PATHFINDER_SAVED_ROUTE_RETENTION_DAYS` (0 to keep them forever),
+    /// defaults to 30 days. The active route is never deleted.
+    pub saved_route_retention: Option<Duration>,
+    /// How many panos the in-memory tile cache can hold in total. Set with
+    /// `PATHFINDER_TILE_CACHE_PANOS`, defaults to about a million.
+    pub tile_cache_panos: u64,
+    /// How many options are kept in memory. Set with
+    /// `PATHFINDER_OPTIONS_CACHE_SIZE`, defaults to about 8 million.
+    pub options_cache_size: usize,
+    /// How many baked nodes (including ones that weren't baked) are kept in
+    /// memory, so searches don't need a transaction for every node. Set with
+    /// `PATHFINDER_BAKED_CACHE_SIZE`, defaults to about a million.
+    pub baked_cache_size: usize,
+    /// Tiles that were refreshed more recently than this are skipped when
+    /// refreshing the cache around the car. Set with
+    /// `PATHFINDER_TILE_REFRESH_INTERVAL_SECS`, defaults to 30 minutes.
+    pub tile_refresh_interval: Duration,
+}
+impl Default for DbConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("./cache"),
+            map_size: 128 * GB,
+            map_size_step: 16 * GB,
+            max_map_size: 1024 * GB,
+            max_dbs: 16,
+            persist_options: false,
+            tile_ttl: Some(Duration::from_secs(90 * DAY_SECS)),
+            empty_tile_ttl: Some(Duration::from_secs(14 * DAY_SECS)),
+            compression_level: None,
+            car_history_retention: Some(Duration::from_secs(30 * DAY_SECS)),
+            saved_route_retention: Some(Duration::from_secs(30 * DAY_SECS)),
+            tile_cache_panos: 1 << 20,
+            options_cache_size: 1024 * 1024 * 8,
+            baked_cache_size: 1024 * 1024,
+            tile_refresh_interval: Duration::from_secs(30 * 60),
+        }
+    }
+}
+impl DbConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+
+        let path = env::var("PATHFINDER_CACHE_DIR")
+            .map(PathBuf::from)
+            .unwrap_or(default.path);
+        let map_size = env_gb("PATHFINDER_DB_MAP_SIZE_GB").unwrap_or(default.map_size);
+        let map_size_step =
+            env_gb("PATHFINDER_DB_MAP_SIZE_STEP_GB").unwrap_or(default.map_size_step);
+        let max_map_size = env_gb("PATHFINDER_DB_MAX_MAP_SIZE_GB")
+            .unwrap_or(default.max_map_size)
+            .max(map_size);
+        let max_dbs = env::var("PATHFINDER_DB_MAX_DBS")
+            .ok()
+            .and_then(|v| v.parse::<u32>().ok())
+            .unwrap_or(default.max_dbs)
+            .max(REQUIRED_DBS);
+        let persist_options = env::var("PATHFINDER_PERSIST_OPTIONS")
+            .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+            .unwrap_or(default.persist_options);
+        let tile_ttl = env_ttl_days("PATHFINDER_TILE_TTL_DAYS").unwrap_or(default.tile_ttl);
+        let empty_tile_ttl =
+            env_ttl_days("PATHFINDER_EMPTY_TILE_TTL_DAYS").unwrap_or(default.empty_tile_ttl);
+        let compression_level = match env::var("PATHFINDER_DB_COMPRESSION_LEVEL") {
+            Ok(v) => v.parse::<i32>().ok().filter(|level| *level != 0),
+            Err(_) => default.compression_level,
+        };
+        let car_history_retention = env_ttl_days("PATHFINDER_CAR_HISTORY_RETENTION_DAYS")
+            .unwrap_or(default.car_history_retention);
+        let saved_route_retention = env_ttl_days("PATHFINDER_SAVED_ROUTE_RETENTION_DAYS")
+            .unwrap_or(default.saved_route_retention);
+        let tile_cache_panos =
+            env_parse("PATHFINDER_TILE_CACHE_PANOS").unwrap_or(default.tile_cache_panos);
+        let options_cache_size =
+            env_parse("PATHFINDER_OPTIONS_CACHE_SIZE").unwrap_or(default.options_cache_size);
+        let baked_cache_size =
+            env_parse("PATHFINDER_BAKED_CACHE_SIZE").unwrap_or(default.baked_cache_size);
+        let tile_refresh_interval = env_parse("PATHFINDER_TILE_REFRESH_INTERVAL_SECS")
+            .map(Duration::from_secs)
+            .unwrap_or(default.tile_refresh_interval);
+
+        Self {
+            path,
+            map_size,
+            map_size_step,
+            max_map_size,
+            max_dbs,
+            persist_options,
+            tile_ttl,
+            empty_tile_ttl,
+            compression_level,
+            car_history_retention,
+            saved_route_retention,
+            tile_cache_panos,
+            options_cache_size,
+            baked_cache_size,
+            tile_refresh_interval,
+        }
+    }
+
+    /// Where the database is moved to while it's being migrated from the given
+    /// version, like `./cache-v5`.
+    pub fn old_version_path(&self, version: u32) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_owned();
+        name.push(format!("-v{version}"));
+        self.path.with_file_name(name)
+    }
+}
+
+fn env_parse<T: FromStr>(key: &str) -> Option<T> {
+    env::var(key).ok().and_then(|v| v.parse().ok())
+}
+
+fn env_gb(key: &str) -> Option<usize> {
+    env::var(key)
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .map(|gb| gb * GB)
+}
+
+/// `Some(None)` if the variable is 0, which disables the TTL.
+fn env_ttl_days(key: &str) -> Option<Option<Duration>> {
+    let days = env::var(key).ok()?.parse::<u64>().ok()?;
+    Some((days > 0).then(|| Duration::from_secs(days * DAY_SECS)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_old_version_path() {
+        let config = DbConfig {
+            path: PathBuf::from("/data/cache"),
+            ..Default::default()
+        };
+        assert_eq!(config.old_version_path(5), PathBuf::from("/data/cache-v5"));
+    }
+}
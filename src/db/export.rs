@@ -0,0 +1,458 @@
+//! Exporting the cached tiles and GetMetadata responses in a bounding box to a
+//! file that another instance can import, so someone running their own
+//! pathfinder can seed their cache without copying the whole database.
+//!
+//! Our internal pano IDs only mean something in the database they came from,
+//! so the file has a table of the Streetview pano IDs and everything else
+//! refers to panos by their index in it. They're given new internal IDs when
+//! they're imported.
+//!
+//! The file is `IRPCACHE`, a format version byte, and then the zstd-compressed
+//! body:
+//! - The pano ID table, as a u32 count and then each ID as a u16 length and
+//!   the UTF-8 bytes.
+//! - The tiles, as a u32 count and then each tile's size, x, y and fetch time.
+//!   That's followed by a 0 if it had too many panos, or a 1, the u32 number
+//!   of panos and each pano's index, search location and actual location.
+//! - The GetMetadata responses, as a u32 count and then each one's pano index,
+//!   location, capture date, links (a u32 count and each link's pano index,
+//!   heading and location) and road name.
+
+use std::io::{Cursor, Read, Write};
+
+use byteorder::{LE, ReadBytesExt, WriteBytesExt};
+use eyre::{bail, eyre};
+use rustc_hash::FxHashMap;
+use serde::Serialize;
+use tracing::info;
+
+use crate::{
+    db::{
+        Db, decode_listentityphotos, decode_listentityphotos_fetched_at, encode_getmetadata,
+        encode_listentityphotos, read_capture_date, read_location, read_road_name,
+        write_capture_date, write_location, write_road_name,
+    },
+    model::{
+        CaptureDate, GetMetadataResponse, LARGEST_TILE_SIZE, Location, Pano, PanoId, PanoLink,
+        PanoWithBothLocations, SMALL_TILE_SIZE, SizedTile,
+    },
+    roadtrip,
+    streetview::{prefetch::BoundingBox, spatial_index::Region},
+};
+
+const MAGIC: &[u8; 8] = b"IRPCACHE";
+const FORMAT_VERSION: u8 = 1;
+const COMPRESSION_LEVEL: i32 = 9;
+
+#[derive(Debug, Default, Serialize)]
+pub struct ExportStats {
+    pub tiles: usize,
+    pub getmetadata: usize,
+    pub pano_ids: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportStats {
+    pub tiles: usize,
+    /// Tiles that weren't imported since ours were downloaded more recently.
+    pub skipped_tiles: usize,
+    pub getmetadata: usize,
+    /// GetMetadata responses that weren't imported since we already had them.
+    pub skipped_getmetadata: usize,
+}
+
+struct ImportedTile {
+    tile: SizedTile,
+    fetched_at: u64,
+    /// Each pano's index in the pano ID table and its search and actual
+    /// locations.
+    panos: Option<Vec<(u32, Location, Location)>>,
+}
+
+struct ImportedGetMetadata {
+    pano: u32,
+    loc: Location,
+    capture_date: Option<CaptureDate>,
+    links: Vec<(u32, f32, Location)>,
+    road_name: Option<String>,
+}
+
+/// Assigns every pano an index in the exported pano ID table.
+#[derive(Default)]
+struct PanoTable {
+    indexes: FxHashMap<PanoId, u32>,
+    ids: Vec<String>,
+}
+impl PanoTable {
+    fn index(&mut self, db: &Db, txn: &heed::RoTxn<'_>, pano_id: PanoId) -> Option<u32> {
+        if let Some(&index) = self.indexes.get(&pano_id) {
+            return Some(index);
+        }
+        let id = db.pano_id_strings_db.get(txn, &pano_id.0).unwrap()?;
+        let index = self.ids.len() as u32;
+        self.ids.push(id.to_owned());
+        self.indexes.insert(pano_id, index);
+        Some(index)
+    }
+}
+
+impl Db {
+    /// Write every cached tile in the bounding box, and the cached GetMetadata
+    /// responses of the panos in them, to `writer`. See the [module
+    /// docs](self) for the format.
+    pub fn export_cache(
+        &self,
+        bbox: &BoundingBox,
+        writer: impl Write,
+    ) -> eyre::Result<ExportStats> {
+        let tiles = self.iter_tiles_in_bbox(bbox);
+        let mut pano_table = PanoTable::default();
+        let mut stats = ExportStats::default();
+
+        let mut tiles_buf = Vec::new();
+        let mut getmetadata_buf = Vec::new();
+        let mut exported_panos = Vec::new();
+
+        let txn = self.read_txn();
+        for tile in &tiles {
+            let Some(data) = self.listentityphotos_db.get(&txn, tile).unwrap() else {
+                continue;
+            };
+            tiles_buf.write_u8(tile.size)?;
+            tiles_buf.write_u32::<LE>(tile.x)?;
+            tiles_buf.write_u32::<LE>(tile.y)?;
+            tiles_buf.write_u64::<LE>(decode_listentityphotos_fetched_at(data))?;
+            match decode_listentityphotos(&mut Cursor::new(data)) {
+                Some(panos) => {
+                    let panos = panos
+                        .iter()
+                        .filter_map(|p| Some((pano_table.index(self, &txn, p.id)?, p)))
+                        .collect::<Vec<_>>();
+                    tiles_buf.write_u8(1)?;
+                    tiles_buf.write_u32::<LE>(panos.len() as u32)?;
+                    for (index, pano) in panos {
+                        tiles_buf.write_u32::<LE>(index)?;
+                        write_location(&mut tiles_buf, pano.search_loc);
+                        write_location(&mut tiles_buf, pano.actual_loc);
+                        exported_panos.push(pano.id);
+                    }
+                }
+                None => tiles_buf.write_u8(0)?,
+            }
+            stats.tiles += 1;
+        }
+
+        exported_panos.sort_unstable_by_key(|p| p.0);
+        exported_panos.dedup();
+        for pano_id in exported_panos {
+            let Some((loc, links)) = self.lookup_getmetadata_with_txn(&txn, &pano_id) else {
+                continue;
+            };
+            let capture_date = self.lookup_capture_date_with_txn(&txn, &pano_id);
+            let road_name = self.lookup_road_name_with_txn(&txn, &pano_id);
+            let links = links
+                .iter()
+                .filter_map(|l| Some((pano_table.index(self, &txn, l.pano.id)?, l)))
+                .collect::<Vec<_>>();
+
+            getmetadata_buf.write_u32::<LE>(pano_table.indexes[&pano_id])?;
+            write_location(&mut getmetadata_buf, loc);
+            write_capture_date(&mut getmetadata_buf, capture_date);
+            getmetadata_buf.write_u32::<LE>(links.len() as u32)?;
+            for (index, link) in links {
+                getmetadata_buf.write_u32::<LE>(index)?;
+                getmetadata_buf.write_f32::<LE>(link.heading)?;
+                write_location(&mut getmetadata_buf, link.pano.loc);
+            }
+            write_road_name(&mut getmetadata_buf, road_name.as_deref());
+            stats.getmetadata += 1;
+        }
+        txn.commit().unwrap();
+        stats.pano_ids = pano_table.ids.len();
+
+        let mut body = Vec::new();
+        body.write_u32::<LE>(pano_table.ids.len() as u32)?;
+        for id in &pano_table.ids {
+            body.write_u16::<LE>(id.len().try_into()?)?;
+            body.extend_from_slice(id.as_bytes());
+        }
+        body.write_u32::<LE>(stats.tiles as u32)?;
+        body.extend_from_slice(&tiles_buf);
+        body.write_u32::<LE>(stats.getmetadata as u32)?;
+        body.extend_from_slice(&getmetadata_buf);
+
+        let mut writer = writer;
+        writer.write_all(MAGIC)?;
+        writer.write_u8(FORMAT_VERSION)?;
+        zstd::stream::copy_encode(&body[..], &mut writer, COMPRESSION_LEVEL)?;
+
+        info!(
+            "Exported {} tiles and {} GetMetadata responses in {bbox:?}",
+            stats.tiles, stats.getmetadata
+        );
+        Ok(stats)
+    }
+
+    /// Import a file written by [`Self::export_cache`]. Tiles are only
+    /// replaced if the imported one was downloaded more recently than ours.
+    pub fn import_cache(&self, reader: impl Read) -> eyre::Result<ImportStats> {
+        let mut reader = reader;
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            bail!("not an exported cache");
+        }
+        let version = reader.read_u8()?;
+        if version != FORMAT_VERSION {
+            bail!("unsupported export format version {version}");
+        }
+        let body = zstd::decode_all(reader)?;
+        let cur = &mut Cursor::new(&body[..]);
+
+        let pano_count = cur.read_u32::<LE>()?;
+        let mut pano_ids = Vec::new();
+        for _ in 0..pano_count {
+            let len = cur.read_u16::<LE>()? as usize;
+            let mut id = vec![0; len];
+            cur.read_exact(&mut id)?;
+            pano_ids.push(String::from_utf8(id)?);
+        }
+        let check_index = |index: u32| {
+            if index < pano_count {
+                Ok(index)
+            } else {
+                Err(eyre!("pano index {index} is out of bounds"))
+            }
+        };
+
+        let mut tiles = Vec::new();
+        for _ in 0..cur.read_u32::<LE>()? {
+            let tile = SizedTile {
+                size: cur.read_u8()?,
+                x: cur.read_u32::<LE>()?,
+                y: cur.read_u32::<LE>()?,
+            };
+            if !(LARGEST_TILE_SIZE..=SMALL_TILE_SIZE).contains(&tile.size) {
+                bail!("invalid tile size {}", tile.size);
+            }
+            let fetched_at = cur.read_u64::<LE>()?;
+            let panos = if cur.read_u8()? == 1 {
+                let mut panos = Vec::new();
+                for _ in 0..cur.read_u32::<LE>()? {
+                    let index = check_index(cur.read_u32::<LE>()?)?;
+                    panos.push((index, read_location(cur)?, read_location(cur)?));
+                }
+                Some(panos)
+            } else {
+                None
+            };
+            tiles.push(ImportedTile {
+                tile,
+                fetched_at,
+                panos,
+            });
+        }
+
+        let mut getmetadata = Vec::new();
+        for _ in 0..cur.read_u32::<LE>()? {
+            let pano = check_index(cur.read_u32::<LE>()?)?;
+            let loc = read_location(cur)?;
+            let capture_date = read_capture_date(cur)?;
+            let mut links = Vec::new();
+            for _ in 0..cur.read_u32::<LE>()? {
+                let index = check_index(cur.read_u32::<LE>()?)?;
+                let heading = cur.read_f32::<LE>()?;
+                links.push((index, heading, read_location(cur)?));
+            }
+            let road_name = read_road_name(cur)?;
+            getmetadata.push(ImportedGetMetadata {
+                pano,
+                loc,
+                capture_date,
+                links,
+                road_name,
+            });
+        }
+
+        let mut changed_panos = Vec::new();
+        let stats = self.write(|txn| {
+            let mut stats = ImportStats::default();
+            changed_panos.clear();
+
+            let ids = pano_ids
+                .iter()
+                .map(|id| self.get_pano_id_with_txn(txn, id))
+                .collect::<heed::Result<Vec<_>>>()?;
+
+            for imported in &tiles {
+                let ours = self.listentityphotos_db.get(txn, &imported.tile)?;
+                if ours
+                    .is_some_and(|d| decode_listentityphotos_fetched_at(d) >= imported.fetched_at)
+                {
+                    stats.skipped_tiles += 1;
+                    continue;
+                }
+                let panos = imported.panos.as_ref().map(|panos| {
+                    panos
+                        .iter()
+                        .map(|&(index, search_loc, actual_loc)| PanoWithBothLocations {
+                            id: ids[index as usize],
+                            search_loc,
+                            actual_loc,
+                        })
+                        .collect::<Vec<_>>()
+                });
+                // the options around both the old and the new panos might change
+                if let Some(Some(old_panos)) =
+                    ours.map(|d| decode_listentityphotos(&mut Cursor::new(d)))
+                {
+                    changed_panos.extend(old_panos.iter().cloned());
+                }
+                if let Some(panos) = &panos {
+                    changed_panos.extend(panos.iter().cloned());
+                }
+                let encoded = encode_listentityphotos(
+                    panos.map(Into::into),
+                    imported.fetched_at,
+                    self.config.compression_level,
+                );
+                self.listentityphotos_db
+                    .put(txn, &imported.tile, &encoded)?;
+                stats.tiles += 1;
+            }
+
+            for imported in &getmetadata {
+                let id = ids[imported.pano as usize];
+                if self.has_getmetadata_with_txn(txn, &id) {
+                    stats.skipped_getmetadata += 1;
+                    continue;
+                }
+                let res = GetMetadataResponse {
+                    id,
+                    loc: imported.loc,
+                    links: imported
+                        .links
+                        .iter()
+                        .map(|&(index, heading, loc)| PanoLink {
+                            pano: Pano {
+                                id: ids[index as usize],
+                                loc,
+                            },
+                            heading,
+                        })
+                        .collect(),
+                    capture_date: imported.capture_date,
+                    road_name: imported.road_name.clone(),
+                };
+                self.getmetadata_db.put(
+                    txn,
+                    &id.0,
+                    &encode_getmetadata(&res, self.config.compression_level),
+                )?;
+                stats.getmetadata += 1;
+            }
+
+            Ok(stats)
+        })?;
+
+        // the in-memory caches might have the old versions of the tiles
+        for imported in &tiles {
+            self.panos_at_tile_cache.remove(&imported.tile);
+            self.invalidate_region_index(Region::of_tile(&imported.tile));
+        }
+        self.bump_tile_generation();
+        roadtrip::invalidate_options_near(self, &changed_panos)?;
+
+        info!(
+            "Imported {} tiles and {} GetMetadata responses",
+            stats.tiles, stats.getmetadata
+        );
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::SmallTile;
+
+    fn loc(lat: f64, lng: f64) -> Location {
+        Location::new_deg(lat, lng)
+    }
+
+    #[test]
+    fn test_export_import_remaps_pano_ids() {
+        let a = Db::temp("export-a");
+        let b = Db::temp("export-b");
+
+        // make the ids differ between the two databases
+        b.get_pano_id("unrelated").unwrap();
+        let first = a.get_pano_id("first").unwrap();
+        let second = a.get_pano_id("second").unwrap();
+
+        let small = SmallTile::from_loc(loc(40.0, -75.0));
+        let tile = SizedTile {
+            size: SMALL_TILE_SIZE,
+            x: small.x,
+            y: small.y,
+        };
+        let panos: Vec<_> = [(first, 40.0001), (second, 40.0002)]
+            .into_iter()
+            .map(|(id, lat)| PanoWithBothLocations {
+                id,
+                search_loc: loc(lat, -75.0),
+                actual_loc: loc(lat, -75.0001),
+            })
+            .collect();
+        a.save_listentityphotos(&tile, Some(panos.into())).unwrap();
+        a.save_getmetadata(&GetMetadataResponse {
+            id: first,
+            loc: loc(40.0001, -75.0001),
+            links: vec![PanoLink {
+                pano: Pano {
+                    id: second,
+                    loc: loc(40.0002, -75.0001),
+                },
+                heading: 90.,
+            }],
+            capture_date: Some(CaptureDate {
+                year: 2020,
+                month: 6,
+            }),
+            road_name: Some("Route 9".to_owned()),
+        })
+        .unwrap();
+
+        let bbox = BoundingBox {
+            min_lat: 39.9,
+            min_lng: -75.1,
+            max_lat: 40.1,
+            max_lng: -74.9,
+        };
+        let mut file = Vec::new();
+        let exported = a.export_cache(&bbox, &mut file).unwrap();
+        assert_eq!(exported.tiles, 1);
+        assert_eq!(exported.getmetadata, 1);
+
+        let imported = b.import_cache(&file[..]).unwrap();
+        assert_eq!(imported.tiles, 1);
+        assert_eq!(imported.getmetadata, 1);
+
+        let b_first = b.get_pano_id("first").unwrap();
+        let b_second = b.get_pano_id("second").unwrap();
+        assert_ne!(b_first, first);
+        let b_panos = b.lookup_listentityphotos(&tile).unwrap().unwrap();
+        assert_eq!(
+            b_panos.iter().map(|p| p.id).collect::<Vec<_>>(),
+            [b_first, b_second]
+        );
+        let (_, links) = b.lookup_getmetadata(&b_first).unwrap();
+        assert_eq!(links[0].pano.id, b_second);
+        assert_eq!(b.lookup_road_name(&b_first).as_deref(), Some("Route 9"));
+
+        // importing it again doesn't replace anything
+        let imported = b.import_cache(&file[..]).unwrap();
+        assert_eq!(imported.tiles, 0);
+        assert_eq!(imported.skipped_tiles, 1);
+    }
+}
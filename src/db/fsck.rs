@@ -0,0 +1,368 @@
+//! Checking that every entry in the database can be decoded, so corruption is
+//! found (and optionally removed) up front instead of by a panic in the middle
+//! of a search.
+//!
+//! Entries are decoded with the fallible `try_decode_*` versions of the
+//! decoders, so a corrupt entry is reported instead of panicking.
+
+use std::io::Cursor;
+
+use heed::{BytesDecode, Database, types::Bytes};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{
+    db::{
+        Db, read_capture_date, read_links, read_location, read_maybe_compressed, read_road_name,
+        try_decode_car_history_entry, try_decode_landmark_costs, try_decode_learned_options,
+        try_decode_listentityphotos, try_decode_option_mismatch, try_decode_options,
+        try_decode_quota_usage, try_decode_saved_path,
+    },
+    learned_options::LearnedOptionsKey,
+    model::{LARGEST_TILE_SIZE, SMALL_TILE_SIZE, SavedRoute, SizedTile},
+};
+
+/// How many corrupt entries are listed in the report. The rest are still
+/// counted (and deleted).
+const MAX_REPORTED_ENTRIES: usize = 1000;
+
+#[derive(Debug, Default, Serialize)]
+pub struct FsckReport {
+    pub checked_entries: u64,
+    pub corrupt_entries: u64,
+    /// The first [`MAX_REPORTED_ENTRIES`] of the corrupt entries.
+    pub corrupt: Vec<CorruptEntry>,
+    /// How many corrupt entries were deleted, if we were repairing.
+    pub deleted_entries: u64,
+    /// Pano IDs whose reverse mapping was missing and was added back, if we
+    /// were repairing.
+    pub restored_pano_ids: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CorruptEntry {
+    /// The name of the LMDB database that the entry is in.
+    pub db: &'static str,
+    /// The key as a string if it's UTF-8, otherwise as hex.
+    pub key: String,
+    pub problem: String,
+}
+
+type Check = fn(&[u8], &[u8], u32) -> Result<(), String>;
+
+impl Db {
+    /// Decode every entry and check that the pano ID mappings agree with each
+    /// other. With `repair`, the entries that couldn't be decoded are deleted,
+    /// and missing reverse pano ID mappings are added back. Mappings that
+    /// disagree are only reported, since the tiles refer to the internal IDs.
+    pub fn fsck(&self, repair: bool) -> eyre::Result<FsckReport> {
+        let mut report = FsckReport::default();
+        let mut to_delete = Vec::new();
+        let mut to_restore = Vec::new();
+        let pano_count = self.get_pano_count();
+
+        let dbs: [(&'static str, Database<Bytes, Bytes>, Check); 13] = [
+            (
+                "getmetadata",
+                self.getmetadata_db.remap_types(),
+                check_getmetadata,
+            ),
+            (
+                "listentityphotos",
+                self.listentityphotos_db.remap_types(),
+                check_listentityphotos,
+            ),
+            (
+                "learnedoptions",
+                self.learned_options_db.remap_types(),
+                |key, data, _| {
+                    LearnedOptionsKey::bytes_decode(key).map_err(|err| err.to_string())?;
+                    check_decode(try_decode_learned_options, data)
+                },
+            ),
+            ("options", self.options_db.remap_types(), check_options),
+            (
+                "bakedgraph",
+                self.baked_graph_db.remap_types(),
+                check_options,
+            ),
+            ("shortcuts", self.shortcuts_db.remap_types(), check_options),
+            (
+                "landmarks",
+                self.landmarks_db.remap_types(),
+                |key, data, _| {
+                    check_len("key", key, 8)?;
+                    check_decode(try_decode_landmark_costs, data)
+                },
+            ),
+            ("paths", self.paths_db.remap_types(), |_, data, _| {
+                check_decode(try_decode_saved_path, data)
+            }),
+            ("routes", self.routes_db.remap_types(), |_, data, _| {
+                simd_json::serde::from_slice::<SavedRoute>(&mut data.to_vec())
+                    .map(|_| ())
+                    .map_err(|err| err.to_string())
+            }),
+            (
+                "optionmismatches",
+                self.option_mismatches_db.remap_types(),
+                |key, data, _| {
+                    check_len("key", key, 8)?;
+                    check_decode(try_decode_option_mismatch, data)
+                },
+            ),
+            ("quotas", self.quotas_db.remap_types(), |_, data, _| {
+                check_decode(try_decode_quota_usage, data)
+            }),
+            (
+                "carhistory",
+                self.car_history_db.remap_types(),
+                |key, data, _| {
+                    check_len("key", key, 8)?;
+                    check_decode(try_decode_car_history_entry, data)
+                },
+            ),
+            (
+                "deadends",
+                self.dead_ends_db.remap_types(),
+                |key, data, _| {
+                    check_len("key", key, 4)?;
+                    check_len("value", data, 4)
+                },
+            ),
+        ];
+
+        let txn = self.read_txn();
+        for (name, db, check) in dbs {
+            info!("Checking {name}");
+            for res in db.iter(&txn)? {
+                let (key, data) = res?;
+                report.checked_entries += 1;
+                if let Err(problem) = check(key, data, pano_count) {
+                    report.add_corrupt(name, key, problem);
+                    to_delete.push((db, key.to_vec()));
+                }
+            }
+        }
+
+        info!("Checking the pano ID mappings");
+        let pano_ids_db: Database<Bytes, Bytes> = self.pano_ids_db.remap_types();
+        for res in pano_ids_db.iter(&txn)? {
+            let (key, data) = res?;
+            report.checked_entries += 1;
+            let Ok(str_pano_id) = str::from_utf8(key) else {
+                report.add_corrupt("panoids", key, "the pano ID isn't UTF-8".to_owned());
+                to_delete.push((pano_ids_db, key.to_vec()));
+                continue;
+            };
+            let Ok(pano_id) = <[u8; 4]>::try_from(data).map(u32::from_le_bytes) else {
+                report.add_corrupt("panoids", key, format!("the value is {} bytes", data.len()));
+                to_delete.push((pano_ids_db, key.to_vec()));
+                continue;
+            };
+            match self.pano_id_strings_db.get(&txn, &pano_id) {
+                Ok(Some(reverse)) if reverse == str_pano_id => {}
+                Ok(Some(reverse)) => report.add_corrupt(
+                    "panoids",
+                    key,
+                    format!("it's mapped to {pano_id}, which is mapped back to {reverse}"),
+                ),
+                Ok(None) => {
+                    report.add_corrupt(
+                        "panoids",
+                        key,
+                        format!("{pano_id} isn't mapped back to it"),
+                    );
+                    to_restore.push((pano_id, str_pano_id.to_owned()));
+                }
+                Err(err) => {
+                    report.add_corrupt("panoidstrings", &pano_id.to_be_bytes(), err.to_string());
+                    let pano_id_strings_db = self.pano_id_strings_db.remap_types();
+                    to_delete.push((pano_id_strings_db, pano_id.to_be_bytes().to_vec()));
+                }
+            }
+        }
+        let pano_id_strings_db: Database<Bytes, Bytes> = self.pano_id_strings_db.remap_types();
+        for res in pano_id_strings_db.iter(&txn)? {
+            let (key, data) = res?;
+            report.checked_entries += 1;
+            let Ok(str_pano_id) = str::from_utf8(data) else {
+                report.add_corrupt("panoidstrings", key, "the pano ID isn't UTF-8".to_owned());
+                to_delete.push((pano_id_strings_db, key.to_vec()));
+                continue;
+            };
+            if key.len() != 4 {
+                report.add_corrupt(
+                    "panoidstrings",
+                    key,
+                    format!("the key is {} bytes", key.len()),
+                );
+                to_delete.push((pano_id_strings_db, key.to_vec()));
+                continue;
+            }
+            if self
+                .pano_ids_db
+                .get(&txn, str_pano_id)
+                .ok()
+                .flatten()
+                .is_none()
+            {
+                report.add_corrupt(
+                    "panoidstrings",
+                    key,
+                    format!("{str_pano_id} isn't mapped to an ID"),
+                );
+            }
+        }
+        txn.commit()?;
+
+        if repair && (!to_delete.is_empty() || !to_restore.is_empty()) {
+            self.write(|txn| {
+                for (db, key) in &to_delete {
+                    db.delete(txn, key)?;
+                }
+                for (pano_id, str_pano_id) in &to_restore {
+                    self.pano_id_strings_db.put(txn, pano_id, str_pano_id)?;
+                }
+                Ok(())
+            })?;
+            report.deleted_entries = to_delete.len() as u64;
+            report.restored_pano_ids = to_restore.len() as u64;
+
+            // the in-memory caches might have been made from the deleted entries
+            self.panos_at_tile_cache.clear();
+            self.invalidate_all_region_indexes();
+            self.options_cache.clear();
+            self.options_by_tile.clear();
+            self.baked_cache.clear();
+            self.bump_tile_generation();
+        }
+
+        if report.corrupt_entries > 0 {
+            warn!(
+                "Found {} corrupt entries out of {}",
+                report.corrupt_entries, report.checked_entries
+            );
+        } else {
+            info!(
+                "Checked {} entries, none were corrupt",
+                report.checked_entries
+            );
+        }
+        Ok(report)
+    }
+}
+
+impl FsckReport {
+    fn add_corrupt(&mut self, db: &'static str, key: &[u8], problem: String) {
+        self.corrupt_entries += 1;
+        if self.corrupt.len() < MAX_REPORTED_ENTRIES {
+            self.corrupt.push(CorruptEntry {
+                db,
+                key: format_key(key),
+                problem,
+            });
+        }
+    }
+}
+
+/// Also makes sure that nothing comes after the road name, since the number of
+/// links being wrong would usually show up as the data being too long or too
+/// short.
+fn check_getmetadata(key: &[u8], data: &[u8], _pano_count: u32) -> Result<(), String> {
+    check_len("key", key, 4)?;
+    let mut cur = Cursor::new(data);
+    read_location(&mut cur).map_err(|err| err.to_string())?;
+    read_capture_date(&mut cur).map_err(|err| err.to_string())?;
+    let body = read_maybe_compressed(&mut cur).map_err(|err| err.to_string())?;
+    let mut cur = Cursor::new(&body[..]);
+    read_links(&mut cur).map_err(|err| err.to_string())?;
+    read_road_name(&mut cur).map_err(|err| err.to_string())?;
+    let extra = body.len() as u64 - cur.position();
+    if extra > 0 {
+        return Err(format!("{extra} unexpected bytes after the road name"));
+    }
+    Ok(())
+}
+
+fn check_listentityphotos(key: &[u8], data: &[u8], pano_count: u32) -> Result<(), String> {
+    let tile = SizedTile::bytes_decode(key).map_err(|err| err.to_string())?;
+    if !(LARGEST_TILE_SIZE..=SMALL_TILE_SIZE).contains(&tile.size) {
+        return Err(format!("invalid tile size {}", tile.size));
+    }
+    match data.first() {
+        Some(0) => check_len("tile with too many panos", data, 1 + 8),
+        Some(1) => {
+            let panos = try_decode_listentityphotos(&mut Cursor::new(data))
+                .map_err(|err| err.to_string())?
+                .unwrap_or_default();
+            // the top bit is set for photospheres
+            if let Some(pano) = panos.iter().find(|p| p.id.0 & !(1 << 31) >= pano_count) {
+                return Err(format!("pano {} was never assigned", pano.id.0));
+            }
+            Ok(())
+        }
+        header => Err(format!("invalid header {header:?}")),
+    }
+}
+
+fn check_options(key: &[u8], data: &[u8], _pano_count: u32) -> Result<(), String> {
+    check_len("key", key, 8)?;
+    check_decode(try_decode_options, data)
+}
+
+fn check_decode<T>(
+    decode: fn(&mut Cursor<&[u8]>) -> std::io::Result<T>,
+    data: &[u8],
+) -> Result<(), String> {
+    decode(&mut Cursor::new(data))
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
+
+fn check_len(what: &str, data: &[u8], len: usize) -> Result<(), String> {
+    if data.len() != len {
+        return Err(format!(
+            "the {what} is {} bytes instead of {len}",
+            data.len()
+        ));
+    }
+    Ok(())
+}
+
+fn format_key(key: &[u8]) -> String {
+    match str::from_utf8(key) {
+        Ok(key) if !key.chars().any(char::is_control) => key.to_owned(),
+        _ => key.iter().map(|b| format!("{b:02x}")).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fsck_reports_truncated_entries() {
+        let db = Db::temp("fsck-truncated");
+        db.write(|txn| {
+            // says there's one option but has no data for it
+            db.options_db.put(txn, &1, &[1, 0])?;
+            // a varint that never ends
+            let tile = SizedTile {
+                size: SMALL_TILE_SIZE,
+                x: 0,
+                y: 0,
+            };
+            let mut data = vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+            data.extend([0xff; 16]);
+            db.listentityphotos_db.put(txn, &tile, &data)?;
+            Ok(())
+        })
+        .unwrap();
+
+        let report = db.fsck(true).unwrap();
+        assert_eq!(report.corrupt_entries, 2);
+        assert_eq!(report.deleted_entries, 2);
+        assert_eq!(db.fsck(false).unwrap().corrupt_entries, 0);
+    }
+}
@@ -1,15 +1,24 @@
 use tracing::info;
 
+use crate::db::config::DbConfig;
+
 mod v0_to_v1;
+mod v10_to_v11;
+mod v11_to_v12;
+mod v12_to_v13;
 mod v1_to_v2;
 mod v2_to_v3;
 mod v3_to_v4;
 mod v4_to_v5;
 mod v5_to_v6;
+mod v6_to_v7;
+mod v7_to_v8;
+mod v8_to_v9;
+mod v9_to_v10;
 
-pub const CURRENT_VERSION: u32 = 6;
+pub const CURRENT_VERSION: u32 = 13;
 
-pub fn try_migrate_from_version(old_version: u32) {
+pub fn try_migrate_from_version(old_version: u32, config: &DbConfig) {
     if old_version > CURRENT_VERSION {
         panic!(
             "Database version {old_version} is greater than the version in the code ({CURRENT_VERSION})."
@@ -19,21 +28,42 @@ pub fn try_migrate_from_version(old_version: u32) {
     info!("Migrating database from version {old_version} to {CURRENT_VERSION}.");
 
     if old_version < 1 {
-        v0_to_v1::migrate().unwrap();
+        v0_to_v1::migrate(config).unwrap();
     }
     if old_version < 2 {
-        v1_to_v2::migrate().unwrap();
+        v1_to_v2::migrate(config).unwrap();
     }
     if old_version < 3 {
-        v2_to_v3::migrate().unwrap();
+        v2_to_v3::migrate(config).unwrap();
     }
     if old_version < 4 {
-        v3_to_v4::migrate().unwrap();
+        v3_to_v4::migrate(config).unwrap();
     }
     if old_version < 5 {
-        v4_to_v5::migrate().unwrap();
+        v4_to_v5::migrate(config).unwrap();
     }
     if old_version < 6 {
-        v5_to_v6::migrate().unwrap();
+        v5_to_v6::migrate(config).unwrap();
+    }
+    if old_version < 7 {
+        v6_to_v7::migrate(config).unwrap();
+    }
+    if old_version < 8 {
+        v7_to_v8::migrate(config).unwrap();
+    }
+    if old_version < 9 {
+        v8_to_v9::migrate(config).unwrap();
+    }
+    if old_version < 10 {
+        v9_to_v10::migrate(config).unwrap();
+    }
+    if old_version < 11 {
+        v10_to_v11::migrate(config).unwrap();
+    }
+    if old_version < 12 {
+        v11_to_v12::migrate(config).unwrap();
+    }
+    if old_version < 13 {
+        v12_to_v13::migrate(config).unwrap();
     }
 }
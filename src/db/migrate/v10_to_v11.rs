@@ -0,0 +1,83 @@
+//! Key tiles by their size and then their position in Z-order, instead of
+//! their size, x and y, so tiles in a bounding box can be found with range
+//! reads. Both keys are 9 bytes, so every tile is first moved to a temporary
+//! 10-byte key to avoid overwriting tiles that haven't been moved yet. This is
+//! done in place.
+//!
+//! The older migrations use the new [`SizedTile`] codec on old keys, which is
+//! fine since they only copy keys, and any 9 bytes decode and encode back to
+//! the same bytes.
+
+use byteorder::{LE, ReadBytesExt};
+use heed::{
+    Database, EnvOpenOptions,
+    types::{Bytes, Str},
+};
+use tracing::info;
+
+use crate::{
+    db::{config::DbConfig, z_order},
+    model::SizedTile,
+};
+
+const NEW_VERSION: u32 = 11;
+
+/// Prepended to the old keys while they're being moved.
+const MOVING_PREFIX: u8 = 0xff;
+
+pub fn migrate(config: &DbConfig) -> eyre::Result<()> {
+    let env = unsafe {
+        EnvOpenOptions::new()
+            .max_dbs(config.max_dbs)
+            .map_size(config.map_size)
+            .open(&config.path)?
+    };
+    let mut wtxn = env.write_txn()?;
+
+    let settings_db: Database<Str, Bytes> = env.create_database(&mut wtxn, Some("settings"))?;
+    let listentityphotos_db: Database<Bytes, Bytes> =
+        env.create_database(&mut wtxn, Some("listentityphotos"))?;
+
+    info!("Rekeying listentityphotos_db in Z-order");
+    let old_keys = listentityphotos_db
+        .iter(&wtxn)?
+        .map(|entry| entry.map(|(key, _)| key.to_vec()))
+        .collect::<heed::Result<Vec<_>>>()?;
+    for old_key in &old_keys {
+        let Some(data) = listentityphotos_db.get(&wtxn, old_key)? else {
+            continue;
+        };
+        let data = data.to_vec();
+        let mut moving_key = vec![MOVING_PREFIX];
+        moving_key.extend_from_slice(old_key);
+        listentityphotos_db.delete(&mut wtxn, old_key)?;
+        listentityphotos_db.put(&mut wtxn, &moving_key, &data)?;
+    }
+    for old_key in &old_keys {
+        let mut moving_key = vec![MOVING_PREFIX];
+        moving_key.extend_from_slice(old_key);
+        let Some(data) = listentityphotos_db.get(&wtxn, &moving_key)? else {
+            continue;
+        };
+        let data = data.to_vec();
+        let tile = decode_old_key(old_key)?;
+        let mut new_key = vec![tile.size];
+        new_key.extend_from_slice(&z_order(tile.x, tile.y).to_be_bytes());
+        listentityphotos_db.delete(&mut wtxn, &moving_key)?;
+        listentityphotos_db.put(&mut wtxn, &new_key, &data)?;
+    }
+
+    settings_db.put(&mut wtxn, "version", NEW_VERSION.to_le_bytes().as_slice())?;
+
+    wtxn.commit()?;
+    env.prepare_for_closing().wait();
+
+    Ok(())
+}
+
+fn decode_old_key(mut bytes: &[u8]) -> eyre::Result<SizedTile> {
+    let size = bytes.read_u8()?;
+    let x = bytes.read_u32::<LE>()?;
+    let y = bytes.read_u32::<LE>()?;
+    Ok(SizedTile { size, x, y })
+}
@@ -0,0 +1,78 @@
+//! Add a flag byte before the parts of tiles and GetMetadata responses that
+//! can be compressed, which is 0 (uncompressed) for all the existing ones. For
+//! tiles it's after the header and the fetch time (and tiles that had too many
+//! panos don't get one), and for GetMetadata responses it's after the location
+//! and capture date. This is done in place.
+
+use byteorder::BE;
+use heed::{
+    Database, EnvOpenOptions,
+    types::{Bytes, Str, U32},
+};
+use tracing::info;
+
+use crate::db::config::DbConfig;
+
+const NEW_VERSION: u32 = 12;
+
+pub fn migrate(config: &DbConfig) -> eyre::Result<()> {
+    let env = unsafe {
+        EnvOpenOptions::new()
+            .max_dbs(config.max_dbs)
+            .map_size(config.map_size)
+            .open(&config.path)?
+    };
+    let mut wtxn = env.write_txn()?;
+
+    let settings_db: Database<Str, Bytes> = env.create_database(&mut wtxn, Some("settings"))?;
+    let listentityphotos_db: Database<Bytes, Bytes> =
+        env.create_database(&mut wtxn, Some("listentityphotos"))?;
+    let getmetadata_db: Database<U32<BE>, Bytes> =
+        env.create_database(&mut wtxn, Some("getmetadata"))?;
+
+    info!("Adding compression flags to listentityphotos_db");
+    let tiles = listentityphotos_db
+        .iter(&wtxn)?
+        .map(|entry| entry.map(|(tile, _)| tile.to_vec()))
+        .collect::<heed::Result<Vec<_>>>()?;
+    for tile in tiles {
+        let Some(data) = listentityphotos_db.get(&wtxn, &tile)? else {
+            continue;
+        };
+        if data[0] == 0 {
+            // the tile had too many panos, so there's nothing after the fetch time
+            continue;
+        }
+        let new_data = insert_flag(data, 1 + 8);
+        listentityphotos_db.put(&mut wtxn, &tile, &new_data)?;
+    }
+
+    info!("Adding compression flags to getmetadata_db");
+    let pano_ids = getmetadata_db
+        .iter(&wtxn)?
+        .map(|entry| entry.map(|(pano_id, _)| pano_id))
+        .collect::<heed::Result<Vec<_>>>()?;
+    for pano_id in pano_ids {
+        let Some(data) = getmetadata_db.get(&wtxn, &pano_id)? else {
+            continue;
+        };
+        // the location, then the capture date
+        let new_data = insert_flag(data, 8 + 3);
+        getmetadata_db.put(&mut wtxn, &pano_id, &new_data)?;
+    }
+
+    settings_db.put(&mut wtxn, "version", NEW_VERSION.to_le_bytes().as_slice())?;
+
+    wtxn.commit()?;
+    env.prepare_for_closing().wait();
+
+    Ok(())
+}
+
+fn insert_flag(data: &[u8], at: usize) -> Vec<u8> {
+    let mut new_data = Vec::with_capacity(data.len() + 1);
+    new_data.extend_from_slice(&data[..at]);
+    new_data.push(0);
+    new_data.extend_from_slice(&data[at..]);
+    new_data
+}
@@ -0,0 +1,106 @@
+//! Store the panos in each tile as varint differences from the previous pano
+//! instead of as full IDs and locations. The tiles are written uncompressed,
+//! even if they were compressed before. This is done in place.
+
+use std::io::{Cursor, Read};
+
+use byteorder::{LE, ReadBytesExt, WriteBytesExt};
+use heed::{
+    Database, EnvOpenOptions,
+    types::{Bytes, Str},
+};
+use tracing::info;
+
+use crate::db::config::DbConfig;
+
+const NEW_VERSION: u32 = 13;
+
+pub fn migrate(config: &DbConfig) -> eyre::Result<()> {
+    let env = unsafe {
+        EnvOpenOptions::new()
+            .max_dbs(config.max_dbs)
+            .map_size(config.map_size)
+            .open(&config.path)?
+    };
+    let mut wtxn = env.write_txn()?;
+
+    let settings_db: Database<Str, Bytes> = env.create_database(&mut wtxn, Some("settings"))?;
+    let listentityphotos_db: Database<Bytes, Bytes> =
+        env.create_database(&mut wtxn, Some("listentityphotos"))?;
+
+    info!("Delta-encoding the panos in listentityphotos_db");
+    let tiles = listentityphotos_db
+        .iter(&wtxn)?
+        .map(|entry| entry.map(|(tile, _)| tile.to_vec()))
+        .collect::<heed::Result<Vec<_>>>()?;
+    for tile in tiles {
+        let Some(data) = listentityphotos_db.get(&wtxn, &tile)? else {
+            continue;
+        };
+        if data[0] == 0 {
+            // the tile had too many panos, so there are no panos to encode
+            continue;
+        }
+        // the header, the fetch time, then the compression flag
+        let mut cur = Cursor::new(data);
+        cur.set_position(1 + 8);
+        let compressed = cur.read_u8()? == 1;
+        let mut body = Vec::new();
+        if compressed {
+            body = zstd::decode_all(&mut cur)?;
+        } else {
+            cur.read_to_end(&mut body)?;
+        }
+
+        let mut new_data = Vec::with_capacity(data.len());
+        new_data.extend_from_slice(&data[..1 + 8]);
+        new_data.push(0);
+        encode_panos(&mut new_data, &body)?;
+        listentityphotos_db.put(&mut wtxn, &tile, &new_data)?;
+    }
+
+    settings_db.put(&mut wtxn, "version", NEW_VERSION.to_le_bytes().as_slice())?;
+
+    wtxn.commit()?;
+    env.prepare_for_closing().wait();
+
+    Ok(())
+}
+
+/// Convert the old body (the ID, search location and actual location of each
+/// pano) to the new one.
+fn encode_panos(buf: &mut Vec<u8>, old_body: &[u8]) -> eyre::Result<()> {
+    let mut cur = Cursor::new(old_body);
+    let mut prev: Option<(i64, [i64; 2])> = None;
+    while cur.position() < old_body.len() as u64 {
+        let id = cur.read_u32::<LE>()? as i64;
+        let search_loc = [cur.read_i32::<LE>()? as i64, cur.read_i32::<LE>()? as i64];
+        let actual_loc = [cur.read_i32::<LE>()? as i64, cur.read_i32::<LE>()? as i64];
+
+        let (prev_id, prev_loc) = match prev {
+            Some(prev) => prev,
+            None => {
+                // the first search location is the base that everything is relative to
+                buf.write_i32::<LE>(search_loc[0] as i32)?;
+                buf.write_i32::<LE>(search_loc[1] as i32)?;
+                (0, search_loc)
+            }
+        };
+        write_signed_varint(buf, id - prev_id);
+        write_signed_varint(buf, search_loc[0] - prev_loc[0]);
+        write_signed_varint(buf, search_loc[1] - prev_loc[1]);
+        write_signed_varint(buf, actual_loc[0] - search_loc[0]);
+        write_signed_varint(buf, actual_loc[1] - search_loc[1]);
+        prev = Some((id, search_loc));
+    }
+    Ok(())
+}
+
+fn write_signed_varint(buf: &mut Vec<u8>, n: i64) {
+    let mut n = ((n << 1) ^ (n >> 63)) as u64;
+    while n >= 0x80 {
+        buf.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
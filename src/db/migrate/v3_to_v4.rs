@@ -1,7 +1,7 @@
 //! listentitymetadata responses are now stored with both types of coordinates
 //! to avoid the need to have to look up 'actual' coordinates separately.
 
-use std::{fs, io::Cursor, sync::Arc};
+use std::{fs, io::Cursor, path::Path, sync::Arc};
 
 use byteorder::{LE, ReadBytesExt, WriteBytesExt};
 use heed::{
@@ -10,7 +10,10 @@ use heed::{
 };
 use tracing::info;
 
-use crate::model::{PanoId, SizedTile};
+use crate::{
+    db::config::DbConfig,
+    model::{PanoId, SizedTile},
+};
 
 const OLD_VERSION: u32 = 3;
 const NEW_VERSION: u32 = 4;
@@ -32,12 +35,13 @@ pub struct NewDb {
     settings_db: Database<Str, Bytes>,
 }
 
-pub fn migrate() -> eyre::Result<()> {
-    fs::rename("cache", format!("cache-v{OLD_VERSION}")).unwrap();
-    fs::create_dir("cache").unwrap();
+pub fn migrate(config: &DbConfig) -> eyre::Result<()> {
+    let old_path = config.old_version_path(OLD_VERSION);
+    fs::rename(&config.path, &old_path).unwrap();
+    fs::create_dir(&config.path).unwrap();
 
-    let old_db = OldDb::new()?;
-    let new_db = NewDb::new()?;
+    let old_db = OldDb::new(config, &old_path)?;
+    let new_db = NewDb::new(config)?;
 
     let old_txn = old_db.env.read_txn()?;
     let mut new_txn = new_db.env.write_txn()?;
@@ -118,12 +122,12 @@ pub fn migrate() -> eyre::Result<()> {
 }
 
 impl OldDb {
-    fn new() -> eyre::Result<OldDb> {
+    fn new(config: &DbConfig, path: &Path) -> eyre::Result<OldDb> {
         let env = unsafe {
             EnvOpenOptions::new()
-                .max_dbs(4)
-                .map_size(1024 * 1024 * 1024 * 128)
-                .open(format!("./cache-v{OLD_VERSION}"))?
+                .max_dbs(config.max_dbs)
+                .map_size(config.map_size)
+                .open(path)?
         };
         let mut wtxn = env.write_txn()?;
         let settings_db = env.create_database(&mut wtxn, Some("settings"))?;
@@ -142,12 +146,12 @@ impl OldDb {
     }
 }
 impl NewDb {
-    fn new() -> eyre::Result<NewDb> {
+    fn new(config: &DbConfig) -> eyre::Result<NewDb> {
         let env = unsafe {
             EnvOpenOptions::new()
-                .max_dbs(4)
-                .map_size(1024 * 1024 * 1024 * 128)
-                .open("./cache")?
+                .max_dbs(config.max_dbs)
+                .map_size(config.map_size)
+                .open(&config.path)?
         };
         let mut wtxn = env.write_txn()?;
 
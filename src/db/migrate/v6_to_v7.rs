@@ -0,0 +1,44 @@
+//! Add a reverse mapping from our internal pano IDs back to Streetview pano
+//! IDs. This doesn't change any existing data, so it's done in place.
+
+use byteorder::{BE, LE};
+use heed::{
+    Database, EnvOpenOptions,
+    types::{Bytes, Str, U32},
+};
+use tracing::info;
+
+use crate::db::config::DbConfig;
+
+const NEW_VERSION: u32 = 7;
+
+pub fn migrate(config: &DbConfig) -> eyre::Result<()> {
+    let env = unsafe {
+        EnvOpenOptions::new()
+            .max_dbs(config.max_dbs)
+            .map_size(config.map_size)
+            .open(&config.path)?
+    };
+    let mut wtxn = env.write_txn()?;
+
+    let settings_db: Database<Str, Bytes> = env.create_database(&mut wtxn, Some("settings"))?;
+    let pano_ids_db: Database<Str, U32<LE>> = env.create_database(&mut wtxn, Some("panoids"))?;
+    let pano_id_strings_db: Database<U32<BE>, Str> =
+        env.create_database(&mut wtxn, Some("panoidstrings"))?;
+
+    info!("Building reverse pano ID mapping");
+    let pano_ids = pano_ids_db
+        .iter(&wtxn)?
+        .map(|entry| entry.map(|(key, id)| (id, key.to_owned())))
+        .collect::<heed::Result<Vec<_>>>()?;
+    for (id, key) in pano_ids {
+        pano_id_strings_db.put(&mut wtxn, &id, &key)?;
+    }
+
+    settings_db.put(&mut wtxn, "version", NEW_VERSION.to_le_bytes().as_slice())?;
+
+    wtxn.commit()?;
+    env.prepare_for_closing().wait();
+
+    Ok(())
+}
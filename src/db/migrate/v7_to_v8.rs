@@ -0,0 +1,72 @@
+//! Store when each tile was downloaded, so that old tiles can be downloaded
+//! again. We don't know when the existing tiles were downloaded, so each one is
+//! given a random time within its TTL before the migration. That way they don't
+//! all expire (and get downloaded again) on the same day. This is done in
+//! place.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use byteorder::{LE, WriteBytesExt};
+use heed::{
+    Database, EnvOpenOptions,
+    types::{Bytes, Str},
+};
+use tracing::info;
+
+use crate::{db::config::DbConfig, math::random::random_u64, model::SizedTile};
+
+const NEW_VERSION: u32 = 8;
+
+pub fn migrate(config: &DbConfig) -> eyre::Result<()> {
+    let env = unsafe {
+        EnvOpenOptions::new()
+            .max_dbs(config.max_dbs)
+            .map_size(config.map_size)
+            .open(&config.path)?
+    };
+    let mut wtxn = env.write_txn()?;
+
+    let settings_db: Database<Str, Bytes> = env.create_database(&mut wtxn, Some("settings"))?;
+    let listentityphotos_db: Database<SizedTile, Bytes> =
+        env.create_database(&mut wtxn, Some("listentityphotos"))?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    info!("Adding fetch times to listentityphotos_db");
+    let tiles = listentityphotos_db
+        .iter(&wtxn)?
+        .map(|entry| entry.map(|(tile, _)| tile))
+        .collect::<heed::Result<Vec<_>>>()?;
+    for tile in tiles {
+        let Some(data) = listentityphotos_db.get(&wtxn, &tile)? else {
+            continue;
+        };
+        // a full tile with no panos
+        let is_empty = data == [1];
+        let ttl = if is_empty {
+            config.empty_tile_ttl
+        } else {
+            config.tile_ttl
+        };
+        let fetched_at = match ttl {
+            Some(ttl) if ttl.as_secs() > 0 => now.saturating_sub(random_u64() % ttl.as_secs()),
+            _ => now,
+        };
+        // the header byte, then the time, then the panos (if there are any)
+        let mut new_data = Vec::with_capacity(data.len() + 8);
+        new_data.push(data[0]);
+        new_data.write_u64::<LE>(fetched_at)?;
+        new_data.extend_from_slice(&data[1..]);
+        listentityphotos_db.put(&mut wtxn, &tile, &new_data)?;
+    }
+
+    settings_db.put(&mut wtxn, "version", NEW_VERSION.to_le_bytes().as_slice())?;
+
+    wtxn.commit()?;
+    env.prepare_for_closing().wait();
+
+    Ok(())
+}
@@ -0,0 +1,51 @@
+//! Store the road name of each pano at the end of its GetMetadata response.
+//! The existing responses didn't keep it, so they get an empty name until the
+//! pano's metadata is fetched again. This is done in place.
+
+use byteorder::BE;
+use heed::{
+    Database, EnvOpenOptions,
+    types::{Bytes, Str, U32},
+};
+use tracing::info;
+
+use crate::db::config::DbConfig;
+
+const NEW_VERSION: u32 = 10;
+
+pub fn migrate(config: &DbConfig) -> eyre::Result<()> {
+    let env = unsafe {
+        EnvOpenOptions::new()
+            .max_dbs(config.max_dbs)
+            .map_size(config.map_size)
+            .open(&config.path)?
+    };
+    let mut wtxn = env.write_txn()?;
+
+    let settings_db: Database<Str, Bytes> = env.create_database(&mut wtxn, Some("settings"))?;
+    let getmetadata_db: Database<U32<BE>, Bytes> =
+        env.create_database(&mut wtxn, Some("getmetadata"))?;
+
+    info!("Adding road names to getmetadata_db");
+    let pano_ids = getmetadata_db
+        .iter(&wtxn)?
+        .map(|entry| entry.map(|(pano_id, _)| pano_id))
+        .collect::<heed::Result<Vec<_>>>()?;
+    for pano_id in pano_ids {
+        let Some(data) = getmetadata_db.get(&wtxn, &pano_id)? else {
+            continue;
+        };
+        // a length of 0 means that there's no name
+        let mut new_data = Vec::with_capacity(data.len() + 1);
+        new_data.extend_from_slice(data);
+        new_data.push(0);
+        getmetadata_db.put(&mut wtxn, &pano_id, &new_data)?;
+    }
+
+    settings_db.put(&mut wtxn, "version", NEW_VERSION.to_le_bytes().as_slice())?;
+
+    wtxn.commit()?;
+    env.prepare_for_closing().wait();
+
+    Ok(())
+}
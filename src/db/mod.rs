@@ -1,31 +1,107 @@
+pub mod config;
+pub mod export;
+pub mod fsck;
 pub mod migrate;
+pub mod txn;
+pub mod write_queue;
 
 use std::{
     borrow::Cow,
     fs,
-    io::Cursor,
-    path::PathBuf,
-    sync::{Arc, LazyLock},
+    io::{self, Cursor, Read},
+    ops::{Bound, Deref},
+    path::Path,
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicU32, AtomicU64, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use byteorder::{BE, LE, ReadBytesExt, WriteBytesExt};
+use eyre::bail;
 use heed::{
-    BoxedError, BytesDecode, BytesEncode, Database, Env, EnvOpenOptions, RoTxn, RwTxn, WithTls,
-    types::*,
+    BoxedError, BytesDecode, BytesEncode, CompactionOption, Database, Env, EnvOpenOptions,
+    MdbError, RoTxn, RwTxn, types::*,
 };
-use tracing::info;
+use parking_lot::{Mutex, RwLock};
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
 
 use crate::{
-    db::migrate::CURRENT_VERSION,
+    astar::RouteNode,
+    bake::{self, BakedCache},
+    cache_stats::EvictionCounter,
+    calibration::{DelaySamples, VoteDelays},
+    db::{
+        config::{DbConfig, GB},
+        migrate::CURRENT_VERSION,
+        txn::{ReadTxn, TxnGuard, WriteTxn},
+        write_queue::GetMetadataQueue,
+    },
+    landmarks::LandmarkCosts,
+    learned_options::{LearnedOptions, LearnedOptionsKey},
     math::angle::Angle,
     model::{
-        GetMetadataResponse, Location, Pano, PanoId, PanoLink, PanoWithBothLocations, SizedTile,
+        CaptureDate, CarHistoryEntry, GetMetadataResponse, LARGEST_TILE_SIZE, Location, Pano,
+        PanoId, PanoLink, PanoWithBothLocations, SMALL_TILE_SIZE, SavedPath, SavedRoute, SizedTile,
         SmallTile,
     },
-    streetview::api::{decode_protobuf_pano, is_third_party_pano},
+    option_accuracy::{AccuracyCounts, OptionMismatch},
+    roadtrip::{self, BasePanoOptionsRes, OptionsByTile, OptionsCache, PanoOptionRes},
+    roadtrip_api::OfficialStop,
+    streetview::{
+        self, PanosAtTileCache,
+        api::{decode_protobuf_pano, is_third_party_pano},
+        prefetch::BoundingBox,
+        provider::{GoogleProvider, PanoProvider},
+        spatial_index::{self, PanoIndexCache, Region, RegionVersions},
+    },
+    web::ratelimit::{QuotaUsage, RatelimitIp},
 };
 
-pub static DB: LazyLock<Db> = LazyLock::new(|| Db::new().unwrap());
+#[derive(Debug, Serialize)]
+pub struct DbStats {
+    pub databases: Vec<NamedDbStats>,
+    /// Every key in the settings database.
+    pub settings_keys: Vec<String>,
+    pub page_size: u64,
+    /// Pages that are in the file, whether or not they're in use.
+    pub allocated_pages: u64,
+    pub used_pages: u64,
+    /// Pages in the file that are free to be reused.
+    pub free_pages: u64,
+    pub map_size: u64,
+    /// How much the file can grow before the map has to be grown.
+    pub map_size_remaining: u64,
+    pub max_map_size: u64,
+    pub disk_bytes: u64,
+    pub readers: u32,
+    pub max_readers: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NamedDbStats {
+    pub name: &'static str,
+    pub entries: u64,
+    pub bytes: u64,
+    /// The height of the B-tree.
+    pub depth: u32,
+    pub branch_pages: u64,
+    pub leaf_pages: u64,
+    pub overflow_pages: u64,
+}
+
+/// A database configured from the environment, for convenience in the binary.
+/// Library users should create their own with [`Db::new`].
+pub static DB: LazyLock<Db> = LazyLock::new(|| Db::new(DbConfig::from_env()).unwrap());
+
+/// How many options are queued before they're written to the database.
+const OPTIONS_BATCH_SIZE: usize = 4096;
+
+/// [`Db::copy_to_path`] refuses to start if more of the map than this is used.
+pub const MAX_MAP_FULLNESS_FOR_COPY: f64 = 0.9;
 
 pub struct Db {
     env: Env,
@@ -33,25 +109,111 @@ pub struct Db {
     listentityphotos_db: Database<SizedTile, Bytes>,
     /// Mapping of Streetview pano IDs into our internal u32 representation.
     pub pano_ids_db: Database<Str, U32<LE>>,
+    /// The reverse of `pano_ids_db`, so we can tell clients which pano is which.
+    pano_id_strings_db: Database<U32<BE>, Str>,
     settings_db: Database<Str, Bytes>,
+    /// Corrections to our option emulation that were learned from watching the
+    /// car, see [`crate::learned_options`].
+    learned_options_db: Database<LearnedOptionsKey, Bytes>,
+    /// The options that were calculated for each pano and heading, see
+    /// [`Self::lookup_options`]. Only used if [`DbConfig::persist_options`] is
+    /// set.
+    options_db: Database<U64<BE>, Bytes>,
+    /// Completed paths, keyed by the ID of the job that found them.
+    paths_db: Database<Str, Bytes>,
+    /// Paths that can be shared, as JSON [`SavedRoute`]s keyed by their short
+    /// ID.
+    routes_db: Database<Str, Bytes>,
+    /// Times that our options didn't match the ones the game offered, keyed by
+    /// the time in milliseconds. See [`crate::option_accuracy`].
+    option_mismatches_db: Database<U64<BE>, Bytes>,
+    /// How much of their daily compute quota each IP has used, keyed by
+    /// [`RatelimitIp::to_bytes`].
+    quotas_db: Database<Bytes, Bytes>,
+    /// Every position the game reported for the car, keyed by the time in
+    /// milliseconds.
+    car_history_db: Database<U64<BE>, Bytes>,
+    /// Panos that are in dead-end branches, with how far (in meters) the branch
+    /// goes past them. See [`crate::dead_ends`].
+    dead_ends_db: Database<U32<BE>, U32<LE>>,
+    /// The options of every node in the regions that were baked, keyed by the
+    /// pano and heading bucket. See [`crate::bake`].
+    baked_graph_db: Database<U64<BE>, Bytes>,
+    /// The costs from and to every landmark for each node of the baked graph,
+    /// keyed like `baked_graph_db`. See [`crate::landmarks`].
+    landmarks_db: Database<U64<BE>, Bytes>,
+    /// Chains of baked nodes that only have one option, keyed like
+    /// `baked_graph_db` by the node before the chain. See [`crate::shortcuts`].
+    shortcuts_db: Database<U64<BE>, Bytes>,
+    /// Every transaction holds a shared lock on this, so taking the exclusive
+    /// lock guarantees that no transactions are active. See [`Self::grow_map`].
+    txn_lock: RwLock<()>,
+    /// Bumped whenever a tile or GetMetadata response changes, see
+    /// [`Self::tile_generation`].
+    tile_generation: AtomicU64,
+    /// When old car positions were last deleted, in milliseconds since the
+    /// Unix epoch. See [`Self::record_car_position`].
+    car_history_pruned_at: AtomicU64,
+    /// The last day (since the Unix epoch) that quotas from earlier days were
+    /// deleted on.
+    quotas_pruned_day: AtomicU32,
+    /// When the old saved routes and paths were last deleted, in seconds since
+    /// the Unix epoch.
+    saved_routes_pruned_at: AtomicU64,
+    config: DbConfig,
+
+    /// In-memory caches of things derived from the database. These live here
+    /// since our pano IDs are only meaningful for the database they came from.
+    pub(crate) panos_at_tile_cache: PanosAtTileCache,
+    pub(crate) panos_at_tile_cache_evictions: EvictionCounter,
+    pub(crate) options_cache: OptionsCache,
+    pub(crate) options_cache_evictions: EvictionCounter,
+    pub(crate) options_by_tile: Arc<OptionsByTile>,
+    /// See [`crate::streetview::spatial_index`].
+    pub(crate) pano_index_cache: PanoIndexCache,
+    pub(crate) pano_index_versions: RegionVersions,
+    /// See [`Self::lookup_baked_options`].
+    pub(crate) baked_cache: BakedCache,
+    /// Tiles that are currently being downloaded, see
+    /// [`crate::streetview::get_panos_at_tile`].
+    pub(crate) tiles_in_flight: Mutex<FxHashMap<SizedTile, Arc<tokio::sync::Mutex<()>>>>,
+    /// When each tile was last downloaded again by
+    /// [`crate::streetview::refresh_cache_nearby`].
+    pub(crate) refreshed_tiles: Mutex<FxHashMap<SizedTile, std::time::Instant>>,
+    /// Where tiles and pano metadata are downloaded from.
+    pano_provider: RwLock<Arc<dyn PanoProvider>>,
+    /// GetMetadata responses that haven't been written to `getmetadata_db`
+    /// yet, see [`write_queue`].
+    getmetadata_queue: GetMetadataQueue,
+    /// Options that haven't been written to `options_db` yet, since writing
+    /// them one at a time would be too slow.
+    pending_options: Mutex<FxHashMap<u64, Vec<u8>>>,
+    /// All the learned options, kept in memory since they're consulted for
+    /// every node in the search.
+    pub(crate) learned_options: RwLock<FxHashMap<LearnedOptionsKey, LearnedOptions>>,
+    /// The delays calculated from the samples in the settings database, see
+    /// [`crate::calibration`].
+    pub(crate) vote_delays: RwLock<VoteDelays>,
+    /// Everything in `dead_ends_db`, kept in memory since it's checked for
+    /// every neighbor in the search.
+    dead_ends: RwLock<FxHashMap<PanoId, u32>>,
 }
 impl Db {
-    pub fn new() -> eyre::Result<Self> {
-        info!("Initializing database");
+    pub fn new(config: DbConfig) -> eyre::Result<Self> {
+        info!("Initializing database at {}", config.path.display());
 
         let mut first_run = false;
 
-        let path = PathBuf::from("./cache");
-        if !path.exists() {
-            fs::create_dir(&path).unwrap();
+        if !config.path.exists() {
+            fs::create_dir_all(&config.path)?;
             first_run = true;
         }
         // SAFETY: The file shouldn't be modified by anything other than heed.
         let env = unsafe {
             EnvOpenOptions::new()
-                .max_dbs(4)
-                .map_size(1024 * 1024 * 1024 * 128)
-                .open(path)?
+                .max_dbs(config.max_dbs)
+                .map_size(config.map_size)
+                .open(&config.path)?
         };
 
         let mut wtxn = env.write_txn()?;
@@ -76,27 +238,104 @@ impl Db {
             if version != CURRENT_VERSION {
                 wtxn.abort();
                 env.prepare_for_closing().wait();
-                migrate::try_migrate_from_version(version);
+                migrate::try_migrate_from_version(version, &config);
                 // try again
-                return Db::new();
+                return Db::new(config);
             }
         }
 
         let getmetadata_db = env.create_database(&mut wtxn, Some("getmetadata"))?;
         let listentityphotos_db = env.create_database(&mut wtxn, Some("listentityphotos"))?;
         let pano_ids_db = env.create_database(&mut wtxn, Some("panoids"))?;
+        let pano_id_strings_db = env.create_database(&mut wtxn, Some("panoidstrings"))?;
+        let learned_options_db = env.create_database(&mut wtxn, Some("learnedoptions"))?;
+        let paths_db = env.create_database(&mut wtxn, Some("paths"))?;
+        let routes_db = env.create_database(&mut wtxn, Some("routes"))?;
+        let options_db = env.create_database(&mut wtxn, Some("options"))?;
+        let option_mismatches_db = env.create_database(&mut wtxn, Some("optionmismatches"))?;
+        let quotas_db = env.create_database(&mut wtxn, Some("quotas"))?;
+        let car_history_db = env.create_database(&mut wtxn, Some("carhistory"))?;
+        let dead_ends_db = env.create_database(&mut wtxn, Some("deadends"))?;
+        let baked_graph_db = env.create_database(&mut wtxn, Some("bakedgraph"))?;
+        let landmarks_db = env.create_database(&mut wtxn, Some("landmarks"))?;
+        let shortcuts_db = env.create_database(&mut wtxn, Some("shortcuts"))?;
 
         wtxn.commit().unwrap();
 
         info!("Finished initializing database");
 
-        Ok(Self {
+        let panos_at_tile_cache_evictions = EvictionCounter::default();
+        let options_cache_evictions = EvictionCounter::default();
+        let options_by_tile = Arc::new(OptionsByTile::default());
+        let mut db = Self {
             env,
             getmetadata_db,
             listentityphotos_db,
             settings_db,
             pano_ids_db,
-        })
+            pano_id_strings_db,
+            learned_options_db,
+            paths_db,
+            routes_db,
+            options_db,
+            option_mismatches_db,
+            quotas_db,
+            car_history_db,
+            dead_ends_db,
+            baked_graph_db,
+            landmarks_db,
+            shortcuts_db,
+            txn_lock: RwLock::new(()),
+            // starting at the current time means it's different after a
+            // restart, since there can't have been a write every nanosecond
+            tile_generation: AtomicU64::new(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_nanos() as u64,
+            ),
+            car_history_pruned_at: AtomicU64::new(0),
+            quotas_pruned_day: AtomicU32::new(0),
+            saved_routes_pruned_at: AtomicU64::new(0),
+            panos_at_tile_cache: streetview::new_panos_at_tile_cache(
+                config.tile_cache_panos,
+                panos_at_tile_cache_evictions.clone(),
+            ),
+            panos_at_tile_cache_evictions,
+            pano_index_cache: spatial_index::new_pano_index_cache(),
+            pano_index_versions: RegionVersions::default(),
+            baked_cache: bake::new_baked_cache(config.baked_cache_size),
+            options_cache: roadtrip::new_options_cache(
+                config.options_cache_size,
+                options_cache_evictions.clone(),
+                options_by_tile.clone(),
+            ),
+            options_cache_evictions,
+            options_by_tile,
+            getmetadata_queue: GetMetadataQueue::default(),
+            pending_options: Mutex::default(),
+            tiles_in_flight: Mutex::default(),
+            refreshed_tiles: Mutex::default(),
+            pano_provider: RwLock::new(Arc::new(GoogleProvider)),
+            learned_options: RwLock::default(),
+            vote_delays: RwLock::default(),
+            dead_ends: RwLock::default(),
+            config,
+        };
+
+        let learned = db.slow_list_learned_options();
+        info!("Loaded {} learned option corrections", learned.len());
+        db.learned_options = RwLock::new(learned.into_iter().collect());
+        db.vote_delays = RwLock::new(db.get_delay_samples().delays());
+        let dead_ends = db.slow_list_dead_ends();
+        info!("Loaded {} dead-end panos", dead_ends.len());
+        db.dead_ends = RwLock::new(dead_ends);
+
+        Ok(db)
+    }
+
+    pub fn config(&self) -> &DbConfig {
+        &self.config
     }
 
     /// Use the cache to convert a pano ID to its "game" coords and Streetview
@@ -112,8 +351,8 @@ impl Db {
         txn: &RoTxn<'_>,
         pano_id: &PanoId,
     ) -> Option<(Location, Box<[PanoLink]>)> {
-        let data = self.getmetadata_db.get(txn, &pano_id.0).unwrap()?;
-        Some(decode_getmetadata(&mut Cursor::new(data)))
+        let data = self.getmetadata_data(txn, pano_id)?;
+        Some(decode_getmetadata(&mut Cursor::new(&data[..])))
     }
 
     /// A faster alternative to [`Self::lookup_getmetadata`] that won't
@@ -131,24 +370,85 @@ impl Db {
         txn: &RoTxn<'_>,
         pano_id: &PanoId,
     ) -> Option<Location> {
-        let data = self.getmetadata_db.get(txn, &pano_id.0).unwrap()?;
-        Some(read_location(&mut Cursor::new(data)))
+        let data = self.getmetadata_data(txn, pano_id)?;
+        Some(read_location(&mut Cursor::new(&data[..])).unwrap())
     }
 
-    pub fn save_getmetadata(&self, res: &GetMetadataResponse) -> eyre::Result<()> {
-        let mut txn = self.write_txn();
-        let res = self.save_getmetadata_with_txn(&mut txn, res);
+    /// When the pano's imagery was taken, according to the cached GetMetadata
+    /// response. This is `None` if the pano isn't cached or if Streetview
+    /// didn't say.
+    pub fn lookup_capture_date(&self, pano_id: &PanoId) -> Option<CaptureDate> {
+        let txn = self.read_txn();
+        let res = self.lookup_capture_date_with_txn(&txn, pano_id);
+        txn.commit().unwrap();
+        res
+    }
+    pub fn lookup_capture_date_with_txn(
+        &self,
+        txn: &RoTxn<'_>,
+        pano_id: &PanoId,
+    ) -> Option<CaptureDate> {
+        let data = self.getmetadata_data(txn, pano_id)?;
+        let mut cur = Cursor::new(&data[..]);
+        read_location(&mut cur).unwrap();
+        read_capture_date(&mut cur).unwrap()
+    }
+
+    /// The name of the road that the pano is on, according to the cached
+    /// GetMetadata response.
+    pub fn lookup_road_name(&self, pano_id: &PanoId) -> Option<String> {
+        let txn = self.read_txn();
+        let res = self.lookup_road_name_with_txn(&txn, pano_id);
         txn.commit().unwrap();
         res
     }
+    pub fn lookup_road_name_with_txn(&self, txn: &RoTxn<'_>, pano_id: &PanoId) -> Option<String> {
+        let data = self.getmetadata_data(txn, pano_id)?;
+        let mut cur = Cursor::new(&data[..]);
+        read_location(&mut cur).unwrap();
+        read_capture_date(&mut cur).unwrap();
+        let body = read_maybe_compressed(&mut cur).unwrap();
+        let mut cur = Cursor::new(&body[..]);
+        // the road name is after the links
+        read_links(&mut cur).unwrap();
+        read_road_name(&mut cur).unwrap()
+    }
+
+    /// The encoded GetMetadata response, including ones that are still queued
+    /// to be written.
+    fn getmetadata_data<'t>(
+        &self,
+        txn: &'t RoTxn<'_>,
+        pano_id: &PanoId,
+    ) -> Option<GetMetadataData<'t>> {
+        if let Some(data) = self.getmetadata_queue.get(pano_id.0) {
+            return Some(GetMetadataData::Pending(data));
+        }
+        let data = self.getmetadata_db.get(txn, &pano_id.0).unwrap()?;
+        Some(GetMetadataData::Stored(data))
+    }
+    /// Whether the pano has a GetMetadata response, including ones that are
+    /// still queued to be written.
+    pub fn has_getmetadata_with_txn(&self, txn: &RoTxn<'_>, pano_id: &PanoId) -> bool {
+        self.getmetadata_data(txn, pano_id).is_some()
+    }
+
+    pub fn save_getmetadata(&self, res: &GetMetadataResponse) -> eyre::Result<()> {
+        self.write(|txn| self.save_getmetadata_with_txn(txn, res))?;
+        self.bump_tile_generation();
+        Ok(())
+    }
+    /// The caller has to call [`Self::bump_tile_generation`] after committing.
     pub fn save_getmetadata_with_txn(
         &self,
         txn: &mut RwTxn<'_>,
         res: &GetMetadataResponse,
-    ) -> eyre::Result<()> {
-        self.getmetadata_db
-            .put(txn, &res.id.0, &encode_getmetadata(res))?;
-        Ok(())
+    ) -> heed::Result<()> {
+        self.getmetadata_db.put(
+            txn,
+            &res.id.0,
+            &encode_getmetadata(res, self.config.compression_level),
+        )
     }
 
     pub fn lookup_listentityphotos(
@@ -170,18 +470,49 @@ impl Db {
 
         Some(decode_listentityphotos(&mut Cursor::new(data)))
     }
+    /// Like [`Self::lookup_listentityphotos`], but tiles that are older than
+    /// [`DbConfig::tile_ttl`] are treated as if they weren't cached.
+    pub fn lookup_fresh_listentityphotos(
+        &self,
+        tile: &SizedTile,
+    ) -> Option<Option<Arc<[PanoWithBothLocations]>>> {
+        let txn = self.read_txn();
+        let data = self.listentityphotos_db.get(&txn, tile).unwrap()?;
+        if self.is_tile_data_stale(data) {
+            return None;
+        }
+        Some(decode_listentityphotos(&mut Cursor::new(data)))
+    }
+    /// When the tile was downloaded, in seconds since the Unix epoch.
+    pub fn listentityphotos_fetched_at(&self, tile: &SizedTile) -> Option<u64> {
+        let txn = self.read_txn();
+        let data = self.listentityphotos_db.get(&txn, tile).unwrap()?;
+        Some(decode_listentityphotos_fetched_at(data))
+    }
+    fn is_tile_data_stale(&self, data: &[u8]) -> bool {
+        let ttl = if is_empty_listentityphotos(data) {
+            self.config.empty_tile_ttl
+        } else {
+            self.config.tile_ttl
+        };
+        let Some(ttl) = ttl else {
+            return false;
+        };
+        let age = unix_secs().saturating_sub(decode_listentityphotos_fetched_at(data));
+        age > ttl.as_secs()
+    }
     /// Returns true if the tile is fully cached (i.e. had less than 3000
-    /// items).
+    /// items) and isn't stale.
     pub fn is_sized_tile_cached(&self, txn: &RoTxn<'_>, tile: &SizedTile) -> bool {
         if let Some(res) = self.listentityphotos_db.get(txn, tile).unwrap() {
-            res[0] == 1
+            res[0] == 1 && !self.is_tile_data_stale(res)
         } else {
             false
         }
     }
     pub fn is_tile_cached(&self, txn: &RoTxn<'_>, tile: &SmallTile) -> bool {
         for tile_size in tile.get_all_sizes() {
-            if DB.is_sized_tile_cached(txn, &tile_size) {
+            if self.is_sized_tile_cached(txn, &tile_size) {
                 return true;
             }
         }
@@ -193,238 +524,1748 @@ impl Db {
         tile: &SizedTile,
         panos: Option<Arc<[PanoWithBothLocations]>>,
     ) -> eyre::Result<()> {
-        let mut txn = self.write_txn();
-        self.save_listentityphotos_with_txn(&mut txn, tile, panos)?;
-        txn.commit()?;
+        let encoded = encode_listentityphotos(panos, unix_secs(), self.config.compression_level);
+        self.write(|txn| self.listentityphotos_db.put(txn, tile, &encoded))?;
+        self.invalidate_region_index(Region::of_tile(tile));
+        self.bump_tile_generation();
         Ok(())
     }
+    /// The caller has to call [`Self::invalidate_region_index`] and
+    /// [`Self::bump_tile_generation`] after committing.
     pub fn save_listentityphotos_with_txn(
         &self,
         txn: &mut RwTxn<'_>,
         tile: &SizedTile,
         panos: Option<Arc<[PanoWithBothLocations]>>,
-    ) -> eyre::Result<()> {
-        self.listentityphotos_db
-            .put(txn, tile, &encode_listentityphotos(panos))?;
-
-        Ok(())
+    ) -> heed::Result<()> {
+        let encoded = encode_listentityphotos(panos, unix_secs(), self.config.compression_level);
+        self.listentityphotos_db.put(txn, tile, &encoded)
     }
 
     pub fn delete_listentityphotos(&self, tile: SizedTile) -> eyre::Result<()> {
-        let mut txn = self.write_txn();
-        self.listentityphotos_db.delete(&mut txn, &tile)?;
-        txn.commit()?;
+        self.write(|txn| self.listentityphotos_db.delete(txn, &tile))?;
+        self.invalidate_region_index(Region::of_tile(&tile));
+        self.bump_tile_generation();
 
         Ok(())
     }
 
-    pub fn slow_list_tiles(&self) -> Box<[SizedTile]> {
+    /// Every cached tile that's in the bounding box (at least partially). Only
+    /// the part of each size's Z-order range that's between the corners of the
+    /// box is read.
+    pub fn iter_tiles_in_bbox(&self, bbox: &BoundingBox) -> Box<[SizedTile]> {
+        let (top_left, bottom_right) = bbox.tile_bounds();
         let mut tiles = Vec::new();
 
         let txn = self.read_txn();
-        for res in self.listentityphotos_db.iter(&txn).unwrap() {
-            let (tile, _) = res.unwrap();
-            tiles.push(tile);
+        for size in LARGEST_TILE_SIZE..=SMALL_TILE_SIZE {
+            let shift = SMALL_TILE_SIZE - size;
+            let min = SizedTile {
+                size,
+                x: top_left.x >> shift,
+                y: top_left.y >> shift,
+            };
+            let max = SizedTile {
+                size,
+                x: bottom_right.x >> shift,
+                y: bottom_right.y >> shift,
+            };
+            for res in self.listentityphotos_db.range(&txn, &(min..=max)).unwrap() {
+                let (tile, _) = res.unwrap();
+                // the range also has tiles that are outside of the box on one axis
+                if (min.x..=max.x).contains(&tile.x) && (min.y..=max.y).contains(&tile.y) {
+                    tiles.push(tile);
+                }
+            }
         }
 
-        tiles.into()
-    }
-
-    pub fn get_pano_id(&self, str_pano_id: &str) -> PanoId {
-        let mut txn = self.write_txn();
-        let pano_id = self.get_pano_id_with_txn(&mut txn, str_pano_id);
-        txn.commit().unwrap();
-        pano_id
+        tiles.into_boxed_slice()
     }
-    pub fn get_pano_id_with_txn(&self, txn: &mut RwTxn<'_>, str_pano_id: &str) -> PanoId {
-        // try to decode it, just in case
-        let str_pano_id = decode_protobuf_pano(str_pano_id);
 
-        if let Some(pano_id) = self.pano_ids_db.get(txn, &str_pano_id).unwrap() {
-            return PanoId(pano_id);
+    /// Up to `limit` cached tiles in the order they're stored in, starting
+    /// after `after`. The last tile of a page is where the next page starts.
+    pub fn list_tiles_page(&self, after: Option<&SizedTile>, limit: usize) -> Vec<SizedTile> {
+        let start = match after {
+            Some(after) => Bound::Excluded(after),
+            None => Bound::Unbounded,
         };
 
-        let mut expected_pano_id = self.next_pano_id(txn);
-        assert!(expected_pano_id < 2u32.pow(31), "pano id overflow");
-        if is_third_party_pano(&str_pano_id) {
-            // set the top bit to a 1 so if it's a photosphere so we can cheaply check it
-            // later
-            expected_pano_id |= 1 << 31;
+        let txn = self.read_txn();
+        self.listentityphotos_db
+            .range(&txn, &(start, Bound::Unbounded))
+            .unwrap()
+            .take(limit)
+            .map(|res| res.unwrap().0)
+            .collect()
+    }
+    /// How many tiles are cached. LMDB keeps track of this, so it's cheap.
+    pub fn tile_count(&self) -> u64 {
+        let txn = self.read_txn();
+        self.listentityphotos_db.len(&txn).unwrap()
+    }
+
+    pub fn delete_tiles(&self, tiles: &[SizedTile]) -> eyre::Result<()> {
+        self.write(|txn| {
+            for tile in tiles {
+                self.listentityphotos_db.delete(txn, tile)?;
+            }
+            Ok(())
+        })?;
+        for tile in tiles {
+            self.invalidate_region_index(Region::of_tile(tile));
         }
+        self.bump_tile_generation();
+        Ok(())
+    }
 
-        self.pano_ids_db
-            .put(txn, &str_pano_id, &expected_pano_id)
-            .unwrap();
+    pub fn save_learned_options(
+        &self,
+        key: &LearnedOptionsKey,
+        learned: &LearnedOptions,
+    ) -> eyre::Result<()> {
+        let encoded = encode_learned_options(learned);
+        self.write(|txn| self.learned_options_db.put(txn, key, &encoded))
+    }
 
-        PanoId(expected_pano_id)
+    /// How far the dead-end branch goes past the pano, in meters, or `None` if
+    /// the pano isn't in one.
+    pub fn dead_end_reach(&self, pano_id: PanoId) -> Option<u32> {
+        self.dead_ends.read().get(&pano_id).copied()
     }
-    fn next_pano_id(&self, txn: &mut RwTxn<'_>) -> u32 {
-        let next_pano_id = self
-            .settings_db
-            .get(txn, "next-pano-id")
-            .unwrap()
-            .unwrap_or_default();
-        let next_pano_id = if next_pano_id.is_empty() {
-            0
-        } else {
-            u32::from_le_bytes(
-                next_pano_id
-                    .try_into()
-                    .expect("next-pano-id should be a valid u32"),
-            )
-        };
-        self.settings_db
-            .put(
-                txn,
-                "next-pano-id",
-                &(next_pano_id
-                    .checked_add(1)
-                    .expect("pano id overflow, maybe the internal pano id representation needs to be replaced with a u64?"))
-                .to_le_bytes(),
-            )
-            .unwrap();
-        next_pano_id
+
+    /// Replace all the dead ends with new ones.
+    pub fn save_dead_ends(&self, dead_ends: FxHashMap<PanoId, u32>) -> eyre::Result<()> {
+        self.write(|txn| {
+            self.dead_ends_db.clear(txn)?;
+            for (pano_id, reach) in &dead_ends {
+                self.dead_ends_db.put(txn, &pano_id.0, reach)?;
+            }
+            Ok(())
+        })?;
+        *self.dead_ends.write() = dead_ends;
+        Ok(())
     }
 
-    pub fn get_pano_count(&self) -> u32 {
+    fn slow_list_dead_ends(&self) -> FxHashMap<PanoId, u32> {
         let txn = self.read_txn();
-        let next_pano_id = self
-            .settings_db
-            .get(&txn, "next-pano-id")
+        self.dead_ends_db
+            .iter(&txn)
             .unwrap()
-            .unwrap_or_default();
-        let next_pano_id = if next_pano_id.is_empty() {
-            0
-        } else {
-            u32::from_le_bytes(
-                next_pano_id
-                    .try_into()
-                    .expect("next-pano-id should be a valid u32"),
-            )
-        };
-        txn.commit().unwrap();
-
-        next_pano_id
+            .map(|res| {
+                let (pano_id, reach) = res.unwrap();
+                (PanoId(pano_id), reach)
+            })
+            .collect()
     }
 
-    pub fn read_txn(&self) -> RoTxn<'_, WithTls> {
-        self.env.read_txn().expect("failed to get read txn")
-    }
-    pub fn write_txn(&self) -> RwTxn<'_> {
-        self.env.write_txn().expect("failed to get write txn")
+    /// Call `f` with every cached GetMetadata response's pano, location and
+    /// links. They're read in chunks with a new transaction for each, so old
+    /// pages aren't kept around and the map can grow while this runs.
+    ///
+    /// Responses that are still queued to be written are visited last, so if
+    /// a pano is visited twice the second one is the newest.
+    pub fn slow_for_each_getmetadata(&self, mut f: impl FnMut(PanoId, Location, Box<[PanoLink]>)) {
+        const CHUNK_SIZE: usize = 10_000;
+
+        let mut after = None;
+        loop {
+            let chunk = {
+                let txn = self.read_txn();
+                let range = match after {
+                    Some(after) => (Bound::Excluded(after), Bound::Unbounded),
+                    None => (Bound::Unbounded, Bound::Unbounded),
+                };
+                self.getmetadata_db
+                    .range(&txn, &range)
+                    .unwrap()
+                    .take(CHUNK_SIZE)
+                    .map(|res| {
+                        let (pano_id, data) = res.unwrap();
+                        (pano_id, decode_getmetadata(&mut Cursor::new(data)))
+                    })
+                    .collect::<Vec<_>>()
+            };
+            let Some(&(last, _)) = chunk.last() else {
+                break;
+            };
+            after = Some(last);
+            for (pano_id, (loc, links)) in chunk {
+                f(PanoId(pano_id), loc, links);
+            }
+        }
+
+        for (pano_id, data) in self.getmetadata_queue.snapshot() {
+            let (loc, links) = decode_getmetadata(&mut Cursor::new(&data[..]));
+            f(PanoId(pano_id), loc, links);
+        }
     }
-}
 
-pub fn encode_getmetadata(res: &GetMetadataResponse) -> Vec<u8> {
-    let mut buf = Vec::new();
+    pub fn slow_list_learned_options(&self) -> Box<[(LearnedOptionsKey, LearnedOptions)]> {
+        let mut learned = Vec::new();
 
-    write_location(&mut buf, res.loc);
+        let txn = self.read_txn();
+        for res in self.learned_options_db.iter(&txn).unwrap() {
+            let (key, data) = res.unwrap();
+            learned.push((key, decode_learned_options(&mut Cursor::new(data))));
+        }
 
-    let num_links = res.links.len();
-    if res.links.len() >= 255 {
-        // rarely, we encounter panos with more than 255 links. here's an example:
-        // CAoSF0NJSE0wb2dLRUlDQWdJRGE4X3lMd2dF
-        buf.write_u8(255).unwrap();
-        buf.write_u32::<LE>(num_links.try_into().unwrap()).unwrap();
-    } else {
-        buf.write_u8(num_links as u8).unwrap();
+        learned.into()
     }
 
-    for link in &res.links {
-        write_pano_id(&mut buf, &link.pano.id);
-        buf.write_f32::<LE>(link.heading).unwrap();
-        write_location(&mut buf, link.pano.loc);
+    /// Update the totals in [`Self::option_accuracy`], and save the mismatch
+    /// if there was one.
+    pub fn record_option_accuracy(&self, mismatch: Option<&OptionMismatch>) -> eyre::Result<()> {
+        self.write(|txn| {
+            let mut counts = self
+                .settings_db
+                .get(txn, "option-accuracy")?
+                .map(|data| decode_accuracy_counts(&mut Cursor::new(data)))
+                .unwrap_or_default();
+            counts.observations += 1;
+            match mismatch {
+                Some(mismatch) => {
+                    counts.missing_options += mismatch.missing.len() as u64;
+                    counts.extra_options += mismatch.extra.len() as u64;
+                    self.option_mismatches_db.put(
+                        txn,
+                        &mismatch.timestamp,
+                        &encode_option_mismatch(mismatch),
+                    )?;
+                }
+                None => counts.exact_matches += 1,
+            }
+            self.settings_db
+                .put(txn, "option-accuracy", &encode_accuracy_counts(&counts))
+        })
+    }
+    pub fn option_accuracy(&self) -> AccuracyCounts {
+        let txn = self.read_txn();
+        self.settings_db
+            .get(&txn, "option-accuracy")
+            .unwrap()
+            .map(|data| decode_accuracy_counts(&mut Cursor::new(data)))
+            .unwrap_or_default()
+    }
+    /// The most recent mismatches, newest first.
+    pub fn recent_option_mismatches(&self, limit: usize) -> Vec<OptionMismatch> {
+        let txn = self.read_txn();
+        self.option_mismatches_db
+            .rev_iter(&txn)
+            .unwrap()
+            .take(limit)
+            .map(|res| {
+                let (_, data) = res.unwrap();
+                decode_option_mismatch(&mut Cursor::new(data))
+            })
+            .collect()
     }
 
-    buf
-}
-pub fn decode_getmetadata(cur: &mut Cursor<&[u8]>) -> (Location, Box<[PanoLink]>) {
-    let this_loc = read_location(cur);
+    /// Save a position of the car. The pano is given as a Streetview pano ID.
+    /// Positions that are older than [`DbConfig::car_history_retention`] are
+    /// deleted every so often.
+    pub fn record_car_position(
+        &self,
+        timestamp: u64,
+        loc: Location,
+        heading: f32,
+        pano: Option<&str>,
+    ) -> eyre::Result<()> {
+        const PRUNE_INTERVAL_MS: u64 = 60 * 60 * 1000;
 
-    let mut links = Vec::new();
-    let mut link_count = cur.read_u8().unwrap() as u32;
-    if link_count == 255 {
-        link_count = cur.read_u32::<LE>().unwrap();
+        let prune_before = self.config.car_history_retention.and_then(|retention| {
+            let pruned_at = self.car_history_pruned_at.load(Ordering::Relaxed);
+            (timestamp.saturating_sub(pruned_at) >= PRUNE_INTERVAL_MS)
+                .then(|| timestamp.saturating_sub(retention.as_millis() as u64))
+        });
+        self.write(|txn| {
+            if let Some(prune_before) = prune_before {
+                let deleted = self.car_history_db.delete_range(txn, &(..prune_before))?;
+                if deleted > 0 {
+                    debug!("Deleted {deleted} old car positions");
+                }
+            }
+            let pano = pano
+                .map(|pano| self.get_pano_id_with_txn(txn, pano))
+                .transpose()?;
+            let entry = CarHistoryEntry {
+                timestamp,
+                loc,
+                heading,
+                pano,
+            };
+            self.car_history_db
+                .put(txn, &timestamp, &encode_car_history_entry(&entry))
+        })?;
+        if prune_before.is_some() {
+            self.car_history_pruned_at
+                .store(timestamp, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+    /// The car's positions between the two times (in milliseconds, inclusive),
+    /// oldest first.
+    pub fn car_history(&self, from: u64, to: u64, limit: usize) -> Vec<CarHistoryEntry> {
+        let txn = self.read_txn();
+        self.car_history_db
+            .range(&txn, &(from..=to))
+            .unwrap()
+            .take(limit)
+            .map(|res| {
+                let (_, data) = res.unwrap();
+                decode_car_history_entry(&mut Cursor::new(data))
+            })
+            .collect()
     }
 
-    for _ in 0..link_count {
-        let id = read_pano_id(cur);
-        let heading = cur.read_f32::<LE>().unwrap();
-        let loc = read_location(cur);
-        links.push(PanoLink {
-            pano: Pano { id, loc },
-            heading,
-        });
+    pub fn get_quota_usage(&self, ip: RatelimitIp) -> QuotaUsage {
+        let txn = self.read_txn();
+        self.quotas_db
+            .get(&txn, &ip.to_bytes())
+            .unwrap()
+            .map(|data| decode_quota_usage(&mut Cursor::new(data)))
+            .unwrap_or_default()
+    }
+    /// Add to the number of nodes that the IP used today, resetting it if the
+    /// last usage was on a different day. The first call of each day also
+    /// deletes the usage of every IP from earlier days, since it doesn't count
+    /// anymore.
+    pub fn add_quota_usage(
+        &self,
+        ip: RatelimitIp,
+        day: u32,
+        nodes: u64,
+    ) -> eyre::Result<QuotaUsage> {
+        let key = ip.to_bytes();
+        let prune = self.quotas_pruned_day.load(Ordering::Relaxed) != day;
+        let usage = self.write(|txn| {
+            if prune {
+                let old_keys = self
+                    .quotas_db
+                    .iter(txn)?
+                    .filter_map(|res| {
+                        let (key, data) = res.ok()?;
+                        let usage = try_decode_quota_usage(&mut Cursor::new(data)).ok()?;
+                        (usage.day < day).then(|| key.to_vec())
+                    })
+                    .collect::<Vec<_>>();
+                for old_key in &old_keys {
+                    self.quotas_db.delete(txn, old_key)?;
+                }
+                if !old_keys.is_empty() {
+                    debug!("Deleted {} old quotas", old_keys.len());
+                }
+            }
+            let mut usage = self
+                .quotas_db
+                .get(txn, &key)?
+                .map(|data| decode_quota_usage(&mut Cursor::new(data)))
+                .unwrap_or_default();
+            if usage.day != day {
+                usage = QuotaUsage { day, nodes: 0 };
+            }
+            usage.nodes += nodes;
+            self.quotas_db.put(txn, &key, &encode_quota_usage(&usage))?;
+            Ok(usage)
+        })?;
+        if prune {
+            self.quotas_pruned_day.store(day, Ordering::Relaxed);
+        }
+        Ok(usage)
     }
 
-    (this_loc, links.into())
-}
+    /// Download panos from somewhere other than Google. Tiles that were already
+    /// cached are still used.
+    pub fn set_pano_provider(&self, provider: Arc<dyn PanoProvider>) {
+        *self.pano_provider.write() = provider;
+    }
+    pub fn pano_provider(&self) -> Arc<dyn PanoProvider> {
+        self.pano_provider.read().clone()
+    }
 
-pub fn encode_listentityphotos(panos: Option<Arc<[PanoWithBothLocations]>>) -> Vec<u8> {
-    let mut buf = Vec::new();
+    pub fn persists_options(&self) -> bool {
+        self.config.persist_options
+    }
 
-    if let Some(panos) = panos {
-        // 1 = normal
-        buf.write_u8(1).unwrap();
-        for pano in panos.iter() {
-            write_pano_id(&mut buf, &pano.id);
-            write_location(&mut buf, pano.search_loc);
-            write_location(&mut buf, pano.actual_loc);
+    /// Look up options that were saved with [`Self::save_options`].
+    pub fn lookup_options(&self, pano_id: PanoId, heading: f32) -> Option<BasePanoOptionsRes> {
+        let key = options_key(pano_id, heading);
+        if let Some(data) = self.pending_options.lock().get(&key) {
+            return Some(decode_options(&mut Cursor::new(data)));
         }
-    } else {
-        // 0 = too big, smaller pano should be checked
-        buf.write_u8(0).unwrap();
+
+        let txn = self.read_txn();
+        let data = self.options_db.get(&txn, &key).unwrap()?;
+        Some(decode_options(&mut Cursor::new(data)))
     }
+    /// Queue the options to be saved. They're written in batches, so they might
+    /// be lost if the process exits before the next batch is written.
+    pub fn save_options(&self, pano_id: PanoId, heading: f32, res: &BasePanoOptionsRes) {
+        let mut pending = self.pending_options.lock();
+        pending.insert(options_key(pano_id, heading), encode_options(res));
+        if pending.len() < OPTIONS_BATCH_SIZE {
+            return;
+        }
+        let batch = std::mem::take(&mut *pending);
+        drop(pending);
 
-    buf
-}
-pub fn decode_listentityphotos(cur: &mut Cursor<&[u8]>) -> Option<Arc<[PanoWithBothLocations]>> {
-    let mut panos = Vec::new();
+        if let Err(err) = self.write(|txn| {
+            for (key, data) in &batch {
+                self.options_db.put(txn, key, data)?;
+            }
+            Ok(())
+        }) {
+            warn!("Failed to save options: {err}");
+        }
+    }
+    /// Forget the saved options for the given panos, for when their tiles were
+    /// refetched.
+    pub fn delete_options_for_panos(&self, pano_ids: &[PanoId]) -> eyre::Result<()> {
+        if pano_ids.is_empty() {
+            return Ok(());
+        }
+        let pano_id_set = pano_ids.iter().copied().collect::<FxHashSet<_>>();
+        let is_deleted = |key: u64| pano_id_set.contains(&PanoId((key >> 32) as u32));
+        self.pending_options
+            .lock()
+            .retain(|key, _| !is_deleted(*key));
 
-    let header = cur.read_u8().unwrap();
-    if header == 0 {
-        return None;
+        self.write(|txn| {
+            for pano_id in pano_ids {
+                let start = options_key(*pano_id, 0.) & !0xffff_ffff;
+                self.options_db
+                    .delete_range(txn, &(start..=start | 0xffff_ffff))?;
+            }
+            Ok(())
+        })
     }
 
-    while cur.position() < cur.get_ref().len() as u64 {
-        let id = read_pano_id(cur);
-        let search_loc = read_location(cur);
-        let actual_loc = read_location(cur);
-        let pano = PanoWithBothLocations {
-            id,
-            search_loc,
-            actual_loc,
+    /// The options that were baked for the pano and heading bucket, see
+    /// [`crate::bake`].
+    pub fn lookup_baked_options(
+        &self,
+        pano_id: PanoId,
+        heading_bucket: u16,
+    ) -> Option<BasePanoOptionsRes> {
+        let key = (pano_id, heading_bucket);
+        if let Some(res) = self.baked_cache.get(&key) {
+            return res;
+        }
+        let res = {
+            let txn = self.read_txn();
+            self.baked_graph_db
+                .get(&txn, &baked_key(pano_id, heading_bucket))
+                .unwrap()
+                .map(|data| decode_options(&mut Cursor::new(data)))
         };
-        panos.push(pano);
+        self.baked_cache.insert(key, res.clone());
+        res
+    }
+    pub fn save_baked_options(
+        &self,
+        nodes: &[(PanoId, u16, BasePanoOptionsRes)],
+    ) -> eyre::Result<()> {
+        self.write(|txn| {
+            for (pano_id, heading_bucket, res) in nodes {
+                self.baked_graph_db.put(
+                    txn,
+                    &baked_key(*pano_id, *heading_bucket),
+                    &encode_options(res),
+                )?;
+            }
+            Ok(())
+        })?;
+        for (pano_id, heading_bucket, res) in nodes {
+            self.baked_cache
+                .insert((*pano_id, *heading_bucket), Some(res.clone()));
+        }
+        Ok(())
+    }
+    /// Forget the baked nodes of the panos, and all the landmark costs since
+    /// they might depend on them.
+    pub fn delete_baked_options_for_panos(&self, pano_ids: &[PanoId]) -> eyre::Result<()> {
+        if pano_ids.is_empty() {
+            return Ok(());
+        }
+        self.write(|txn| {
+            for pano_id in pano_ids {
+                let start = baked_key(*pano_id, 0);
+                self.baked_graph_db
+                    .delete_range(txn, &(start..=start | 0xffff))?;
+            }
+            // the cheapest paths between any of the nodes might have gone through the
+            // panos, so none of the landmark costs can be trusted anymore
+            self.landmarks_db.clear(txn)?;
+            Ok(())
+        })?;
+        let bucket_count = (360. / bake::HEADING_BUCKET_SIZE) as u16;
+        for pano_id in pano_ids {
+            for bucket in 0..bucket_count {
+                self.baked_cache.remove(&(*pano_id, bucket));
+            }
+        }
+        Ok(())
+    }
+    /// Delete everything that was baked, returning how many nodes there were.
+    pub fn clear_baked_graph(&self) -> eyre::Result<u64> {
+        self.write(|txn| {
+            let len = self.baked_graph_db.len(txn)?;
+            self.baked_graph_db.clear(txn)?;
+            self.landmarks_db.clear(txn)?;
+            self.shortcuts_db.clear(txn)?;
+            Ok(len)
+        })
+        .inspect(|_| self.baked_cache.clear())
+    }
+    pub fn baked_node_count(&self) -> u64 {
+        let txn = self.read_txn();
+        self.baked_graph_db.len(&txn).unwrap()
+    }
+    /// Call `f` with the pano, heading bucket and options of every baked node.
+    pub fn slow_for_each_baked_node(&self, mut f: impl FnMut(PanoId, u16, BasePanoOptionsRes)) {
+        let txn = self.read_txn();
+        for res in self.baked_graph_db.iter(&txn).unwrap() {
+            let (key, data) = res.unwrap();
+            let res = decode_options(&mut Cursor::new(data));
+            f(PanoId((key >> 32) as u32), key as u16, res);
+        }
     }
 
-    Some(panos.into())
-}
-
-fn write_pano_id(buf: &mut Vec<u8>, pano_id: &PanoId) {
-    buf.write_u32::<LE>(pano_id.0).unwrap();
-}
-fn read_pano_id(cur: &mut Cursor<&[u8]>) -> PanoId {
-    PanoId(cur.read_u32::<LE>().unwrap())
-}
+    /// The costs from and to each landmark for the baked node, see
+    /// [`crate::landmarks`].
+    pub fn lookup_landmark_costs(
+        &self,
+        pano_id: PanoId,
+        heading_bucket: u16,
+    ) -> Option<Box<[LandmarkCosts]>> {
+        let txn = self.read_txn();
+        let data = self
+            .landmarks_db
+            .get(&txn, &baked_key(pano_id, heading_bucket))
+            .unwrap()?;
+        Some(decode_landmark_costs(&mut Cursor::new(data)))
+    }
+    /// The landmark costs of every baked node at the pano, for all headings.
+    pub fn lookup_landmark_costs_for_pano(&self, pano_id: PanoId) -> Vec<Box<[LandmarkCosts]>> {
+        let txn = self.read_txn();
+        let start = baked_key(pano_id, 0);
+        self.landmarks_db
+            .range(&txn, &(start..=start | 0xffff))
+            .unwrap()
+            .map(|res| decode_landmark_costs(&mut Cursor::new(res.unwrap().1)))
+            .collect()
+    }
+    /// Replace all the landmark costs. `costs` has the costs for every
+    /// landmark of each node in order.
+    pub fn save_landmark_costs(
+        &self,
+        nodes: &[(PanoId, u16)],
+        costs: &[LandmarkCosts],
+    ) -> eyre::Result<()> {
+        let landmark_count = costs.len() / nodes.len().max(1);
+        self.write(|txn| self.landmarks_db.clear(txn))?;
+        for (chunk_index, chunk) in nodes.chunks(100_000).enumerate() {
+            self.write(|txn| {
+                for (i, (pano_id, heading_bucket)) in chunk.iter().enumerate() {
+                    let start = (chunk_index * 100_000 + i) * landmark_count;
+                    let node_costs = &costs[start..start + landmark_count];
+                    self.landmarks_db.put(
+                        txn,
+                        &baked_key(*pano_id, *heading_bucket),
+                        &encode_landmark_costs(node_costs),
+                    )?;
+                }
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    /// The chain of nodes that follows the baked node, if it leads into one.
+    /// See [`crate::shortcuts`].
+    pub fn lookup_shortcut(
+        &self,
+        pano_id: PanoId,
+        heading_bucket: u16,
+    ) -> Option<Box<[PanoOptionRes]>> {
+        let txn = self.read_txn();
+        let data = self
+            .shortcuts_db
+            .get(&txn, &baked_key(pano_id, heading_bucket))
+            .unwrap()?;
+        Some(decode_options(&mut Cursor::new(data)).options)
+    }
+    /// Replace all the shortcuts.
+    pub fn save_shortcuts(
+        &self,
+        shortcuts: &[(PanoId, u16, BasePanoOptionsRes)],
+    ) -> eyre::Result<()> {
+        self.write(|txn| self.shortcuts_db.clear(txn))?;
+        for chunk in shortcuts.chunks(10_000) {
+            self.write(|txn| {
+                for (pano_id, heading_bucket, chain) in chunk {
+                    self.shortcuts_db.put(
+                        txn,
+                        &baked_key(*pano_id, *heading_bucket),
+                        &encode_options(chain),
+                    )?;
+                }
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+    /// Delete the shortcuts that start at or go through any of the panos,
+    /// returning how many were deleted.
+    pub fn delete_shortcuts_through_panos(&self, pano_ids: &[PanoId]) -> eyre::Result<usize> {
+        if pano_ids.is_empty() {
+            return Ok(0);
+        }
+        let pano_ids = pano_ids.iter().copied().collect::<FxHashSet<_>>();
+        self.write(|txn| {
+            let mut deleted = Vec::new();
+            for res in self.shortcuts_db.iter(txn)? {
+                let (key, data) = res?;
+                let chain = decode_options(&mut Cursor::new(data)).options;
+                if pano_ids.contains(&PanoId((key >> 32) as u32))
+                    || chain
+                        .iter()
+                        .any(|option| pano_ids.contains(&option.pano.id))
+                {
+                    deleted.push(key);
+                }
+            }
+            for key in &deleted {
+                self.shortcuts_db.delete(txn, key)?;
+            }
+            Ok(deleted.len())
+        })
+    }
+    pub fn shortcut_count(&self) -> u64 {
+        let txn = self.read_txn();
+        self.shortcuts_db.len(&txn).unwrap()
+    }
+
+    pub fn get_route(&self, id: &str) -> Option<SavedRoute> {
+        let txn = self.read_txn();
+        let data = self.routes_db.get(&txn, id).unwrap()?;
+        simd_json::serde::from_slice(&mut data.to_vec()).ok()
+    }
+    pub fn has_route(&self, id: &str) -> bool {
+        let txn = self.read_txn();
+        self.routes_db.get(&txn, id).unwrap().is_some()
+    }
+    pub fn save_route(&self, route: &SavedRoute) -> eyre::Result<()> {
+        self.prune_saved_routes_if_due()?;
+        let encoded = simd_json::to_vec(route)?;
+        self.write(|txn| self.routes_db.put(txn, &route.id, &encoded))
+    }
+
+    pub fn get_saved_path(&self, job_id: &str) -> Option<SavedPath> {
+        let txn = self.read_txn();
+        let data = self.paths_db.get(&txn, job_id).unwrap()?;
+        Some(decode_saved_path(&mut Cursor::new(data)))
+    }
+    pub fn save_path(&self, job_id: &str, path: &SavedPath) -> eyre::Result<()> {
+        self.prune_saved_routes_if_due()?;
+        let encoded = encode_saved_path(path);
+        self.write(|txn| self.paths_db.put(txn, job_id, &encoded))
+    }
+    /// Delete the saved routes and paths that are older than
+    /// [`DbConfig::saved_route_retention`], except for the active route. This
+    /// goes through all of them, so it's only done once an hour.
+    fn prune_saved_routes_if_due(&self) -> eyre::Result<()> {
+        const PRUNE_INTERVAL_SECS: u64 = 60 * 60;
+
+        let Some(retention) = self.config.saved_route_retention else {
+            return Ok(());
+        };
+        let now = unix_secs();
+        let pruned_at = self.saved_routes_pruned_at.load(Ordering::Relaxed);
+        if now.saturating_sub(pruned_at) < PRUNE_INTERVAL_SECS
+            || self
+                .saved_routes_pruned_at
+                .compare_exchange(pruned_at, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return Ok(());
+        }
+        let prune_before = now.saturating_sub(retention.as_secs());
+
+        #[derive(Deserialize)]
+        struct CreatedAt {
+            created_at: u64,
+        }
+        let active_route = self.get_active_route_id();
+        let (old_routes, old_paths) = {
+            let txn = self.read_txn();
+            let old_routes = self
+                .routes_db
+                .iter(&txn)?
+                .filter_map(|res| {
+                    let (id, data) = res.ok()?;
+                    let route =
+                        simd_json::serde::from_slice::<CreatedAt>(&mut data.to_vec()).ok()?;
+                    (route.created_at < prune_before && active_route.as_deref() != Some(id))
+                        .then(|| id.to_owned())
+                })
+                .collect::<Vec<_>>();
+            let old_paths = self
+                .paths_db
+                .iter(&txn)?
+                .filter_map(|res| {
+                    let (id, data) = res.ok()?;
+                    let created_at = Cursor::new(data).read_u64::<LE>().ok()?;
+                    (created_at < prune_before).then(|| id.to_owned())
+                })
+                .collect::<Vec<_>>();
+            txn.commit()?;
+            (old_routes, old_paths)
+        };
+        if old_routes.is_empty() && old_paths.is_empty() {
+            return Ok(());
+        }
+
+        self.write(|txn| {
+            for id in &old_routes {
+                self.routes_db.delete(txn, id)?;
+            }
+            for id in &old_paths {
+                self.paths_db.delete(txn, id)?;
+            }
+            Ok(())
+        })?;
+        debug!(
+            "Deleted {} old saved routes and {} old paths",
+            old_routes.len(),
+            old_paths.len()
+        );
+        Ok(())
+    }
+
+    /// The stops that were last announced by the game, see
+    /// [`crate::roadtrip_api::official_stops`].
+    pub fn get_official_stops(&self) -> Option<Vec<OfficialStop>> {
+        let txn = self.read_txn();
+        let data = self.settings_db.get(&txn, "official-stops").unwrap()?;
+        simd_json::serde::from_slice(&mut data.to_vec()).ok()
+    }
+    pub fn save_official_stops(&self, stops: &[OfficialStop]) -> eyre::Result<()> {
+        let encoded = simd_json::to_vec(stops)?;
+        self.write(|txn| self.settings_db.put(txn, "official-stops", &encoded))
+    }
+
+    /// The tiles that were in the in-memory tile cache when it was last saved,
+    /// see [`crate::streetview::warm_start`].
+    pub fn get_warm_tiles(&self) -> Vec<SizedTile> {
+        let txn = self.read_txn();
+        let Some(data) = self.settings_db.get(&txn, "warm-tiles").unwrap() else {
+            return Vec::new();
+        };
+        data.chunks_exact(1 + 4 + 4)
+            .map(|mut chunk| SizedTile {
+                size: chunk.read_u8().unwrap(),
+                x: chunk.read_u32::<LE>().unwrap(),
+                y: chunk.read_u32::<LE>().unwrap(),
+            })
+            .collect()
+    }
+    pub fn save_warm_tiles(&self, tiles: &[SizedTile]) -> eyre::Result<()> {
+        let mut encoded = Vec::with_capacity(tiles.len() * (1 + 4 + 4));
+        for tile in tiles {
+            encoded.write_u8(tile.size)?;
+            encoded.write_u32::<LE>(tile.x)?;
+            encoded.write_u32::<LE>(tile.y)?;
+        }
+        self.write(|txn| self.settings_db.put(txn, "warm-tiles", &encoded))
+    }
+
+    /// The ID of the route that the car is being compared to, see
+    /// [`crate::deviation`].
+    pub fn get_active_route_id(&self) -> Option<String> {
+        let txn = self.read_txn();
+        let data = self.settings_db.get(&txn, "active-route").unwrap()?;
+        String::from_utf8(data.to_vec()).ok()
+    }
+    pub fn set_active_route_id(&self, route_id: Option<&str>) -> eyre::Result<()> {
+        self.write(|txn| match route_id {
+            Some(route_id) => self
+                .settings_db
+                .put(txn, "active-route", route_id.as_bytes()),
+            None => self.settings_db.delete(txn, "active-route").map(|_| ()),
+        })
+    }
+
+    /// Write a value and read it back, to make sure that the database is still
+    /// usable.
+    pub fn check_health(&self, timestamp: u64) -> eyre::Result<()> {
+        let expected = timestamp.to_le_bytes();
+        self.write(|txn| self.settings_db.put(txn, "health-check", &expected))?;
+        let txn = self.read_txn();
+        let actual = self.settings_db.get(&txn, "health-check")?;
+        if actual != Some(&expected[..]) {
+            bail!("read back a different health check value than was written");
+        }
+        Ok(())
+    }
+
+    pub fn get_delay_samples(&self) -> DelaySamples {
+        let txn = self.read_txn();
+        let Some(data) = self.settings_db.get(&txn, "vote-delay-samples").unwrap() else {
+            return DelaySamples::default();
+        };
+        decode_delay_samples(&mut Cursor::new(data))
+    }
+    pub fn save_delay_samples(&self, samples: &DelaySamples) -> eyre::Result<()> {
+        let encoded = encode_delay_samples(samples);
+        self.write(|txn| self.settings_db.put(txn, "vote-delay-samples", &encoded))
+    }
+    /// The delays that the pathfinder should use for its costs.
+    pub fn vote_delays(&self) -> VoteDelays {
+        self.vote_delays.read().clone()
+    }
+
+    pub fn get_pano_id(&self, str_pano_id: &str) -> eyre::Result<PanoId> {
+        self.write(|txn| self.get_pano_id_with_txn(txn, str_pano_id))
+    }
+    pub fn get_pano_id_with_txn(
+        &self,
+        txn: &mut RwTxn<'_>,
+        str_pano_id: &str,
+    ) -> heed::Result<PanoId> {
+        // try to decode it, just in case
+        let str_pano_id = decode_protobuf_pano(str_pano_id);
+
+        if let Some(pano_id) = self.pano_ids_db.get(txn, &str_pano_id)? {
+            return Ok(PanoId(pano_id));
+        };
+
+        let mut expected_pano_id = self.next_pano_id(txn)?;
+        assert!(expected_pano_id < 2u32.pow(31), "pano id overflow");
+        if is_third_party_pano(&str_pano_id) {
+            // set the top bit to a 1 so if it's a photosphere so we can cheaply check it
+            // later
+            expected_pano_id |= 1 << 31;
+        }
+
+        self.pano_ids_db.put(txn, &str_pano_id, &expected_pano_id)?;
+        self.pano_id_strings_db
+            .put(txn, &expected_pano_id, &str_pano_id)?;
+
+        Ok(PanoId(expected_pano_id))
+    }
+    /// Like [`Self::get_pano_id`], but panos that don't have an internal ID
+    /// yet aren't given one.
+    pub fn lookup_pano_id(&self, str_pano_id: &str) -> Option<PanoId> {
+        let txn = self.read_txn();
+        self.lookup_pano_id_with_txn(&txn, str_pano_id)
+    }
+    pub fn lookup_pano_id_with_txn(&self, txn: &RoTxn<'_>, str_pano_id: &str) -> Option<PanoId> {
+        let str_pano_id = decode_protobuf_pano(str_pano_id);
+        self.pano_ids_db
+            .get(txn, &str_pano_id)
+            .ok()
+            .flatten()
+            .map(PanoId)
+    }
+    /// Convert our internal pano ID back into a Streetview pano ID.
+    pub fn lookup_pano_id_string(&self, pano_id: PanoId) -> Option<String> {
+        let txn = self.read_txn();
+        self.lookup_pano_id_string_with_txn(&txn, pano_id)
+    }
+    pub fn lookup_pano_id_string_with_txn(
+        &self,
+        txn: &RoTxn<'_>,
+        pano_id: PanoId,
+    ) -> Option<String> {
+        self.pano_id_strings_db
+            .get(txn, &pano_id.0)
+            .ok()
+            .flatten()
+            .map(str::to_owned)
+    }
+
+    fn next_pano_id(&self, txn: &mut RwTxn<'_>) -> heed::Result<u32> {
+        let next_pano_id = self
+            .settings_db
+            .get(txn, "next-pano-id")?
+            .unwrap_or_default();
+        let next_pano_id = if next_pano_id.is_empty() {
+            0
+        } else {
+            u32::from_le_bytes(
+                next_pano_id
+                    .try_into()
+                    .expect("next-pano-id should be a valid u32"),
+            )
+        };
+        self.settings_db
+            .put(
+                txn,
+                "next-pano-id",
+                &(next_pano_id
+                    .checked_add(1)
+                    .expect("pano id overflow, maybe the internal pano id representation needs to be replaced with a u64?"))
+                .to_le_bytes(),
+            )?;
+        Ok(next_pano_id)
+    }
+
+    /// Copy the whole database to a new file at `path`, which can be used as
+    /// the `data.mdb` of another cache directory. This is a consistent snapshot
+    /// and doesn't block other transactions, except that the map can't grow
+    /// until it's done.
+    ///
+    /// The copy holds `txn_lock` the whole time (LMDB's copy is a read
+    /// transaction, and the map can't be resized while one is open), so if the
+    /// map fills up during the copy, [`Self::grow_map`] waits for it to finish
+    /// and so does every write after it. To make that unlikely, the copy isn't
+    /// started if the map is more than [`MAX_MAP_FULLNESS_FOR_COPY`] full.
+    ///
+    /// With `compact`, free pages are left out.
+    pub fn copy_to_path(&self, path: &Path, compact: bool) -> eyre::Result<()> {
+        let fullness = self.map_fullness();
+        if fullness > MAX_MAP_FULLNESS_FOR_COPY {
+            bail!(
+                "The database map is {:.0}% full, so it might have to grow during the copy, which would block writes until the copy is done",
+                fullness * 100.
+            );
+        }
+        let _guard = TxnGuard::new(&self.txn_lock);
+        let option = if compact {
+            CompactionOption::Enabled
+        } else {
+            CompactionOption::Disabled
+        };
+        self.env.copy_to_path(path, option)?;
+        Ok(())
+    }
+    /// How much of the memory map has been allocated, between 0 and 1. LMDB
+    /// returns `MapFull` once it reaches 1, and then the map has to grow.
+    pub fn map_fullness(&self) -> f64 {
+        let txn = self.read_txn();
+        let page_size = self.settings_db.stat(&txn).unwrap().page_size;
+        let info = self.env.info();
+        txn.commit().unwrap();
+        let allocated_bytes = (info.last_page_number as u64 + 1) * page_size as u64;
+        allocated_bytes as f64 / info.map_size.max(1) as f64
+    }
+    /// How many bytes of the database are in use, which is about how big a
+    /// compacted copy is.
+    pub fn used_bytes(&self) -> u64 {
+        let _guard = TxnGuard::new(&self.txn_lock);
+        self.env.non_free_pages_size().unwrap_or_default()
+    }
+    /// How big the database file is.
+    pub fn disk_bytes(&self) -> u64 {
+        let _guard = TxnGuard::new(&self.txn_lock);
+        self.env.real_disk_size().unwrap_or_default()
+    }
+
+    /// How big each named database is and how full the map is.
+    pub fn stats(&self) -> DbStats {
+        let named_dbs: [(&'static str, Database<Bytes, Bytes>); 16] = [
+            ("getmetadata", self.getmetadata_db.remap_types()),
+            ("listentityphotos", self.listentityphotos_db.remap_types()),
+            ("panoids", self.pano_ids_db.remap_types()),
+            ("panoidstrings", self.pano_id_strings_db.remap_types()),
+            ("settings", self.settings_db.remap_types()),
+            ("learnedoptions", self.learned_options_db.remap_types()),
+            ("paths", self.paths_db.remap_types()),
+            ("routes", self.routes_db.remap_types()),
+            ("options", self.options_db.remap_types()),
+            ("optionmismatches", self.option_mismatches_db.remap_types()),
+            ("quotas", self.quotas_db.remap_types()),
+            ("carhistory", self.car_history_db.remap_types()),
+            ("deadends", self.dead_ends_db.remap_types()),
+            ("bakedgraph", self.baked_graph_db.remap_types()),
+            ("landmarks", self.landmarks_db.remap_types()),
+            ("shortcuts", self.shortcuts_db.remap_types()),
+        ];
+
+        let txn = self.read_txn();
+        let mut page_size = 0;
+        let databases = named_dbs
+            .into_iter()
+            .map(|(name, db)| {
+                let stat = db.stat(&txn).unwrap();
+                page_size = stat.page_size;
+                let pages = stat.branch_pages + stat.leaf_pages + stat.overflow_pages;
+                NamedDbStats {
+                    name,
+                    entries: stat.entries as u64,
+                    bytes: pages as u64 * stat.page_size as u64,
+                    depth: stat.depth,
+                    branch_pages: stat.branch_pages as u64,
+                    leaf_pages: stat.leaf_pages as u64,
+                    overflow_pages: stat.overflow_pages as u64,
+                }
+            })
+            .collect();
+        let settings_keys = self
+            .settings_db
+            .iter(&txn)
+            .unwrap()
+            .map(|res| res.unwrap().0.to_owned())
+            .collect();
+        let info = self.env.info();
+        let used_bytes = self.env.non_free_pages_size().unwrap_or_default();
+        let disk_bytes = self.env.real_disk_size().unwrap_or_default();
+        txn.commit().unwrap();
+
+        let page_size = page_size.max(1) as u64;
+        // the last page number is the highest page that was ever written to
+        let allocated_pages = info.last_page_number as u64 + 1;
+        let used_pages = used_bytes / page_size;
+        DbStats {
+            databases,
+            settings_keys,
+            page_size,
+            allocated_pages,
+            used_pages,
+            free_pages: allocated_pages.saturating_sub(used_pages),
+            map_size: info.map_size as u64,
+            map_size_remaining: (info.map_size as u64).saturating_sub(allocated_pages * page_size),
+            max_map_size: self.config.max_map_size as u64,
+            disk_bytes,
+            readers: info.number_of_readers,
+            max_readers: info.maximum_number_of_readers,
+        }
+    }
+
+    pub fn get_pano_count(&self) -> u32 {
+        let txn = self.read_txn();
+        let next_pano_id = self
+            .settings_db
+            .get(&txn, "next-pano-id")
+            .unwrap()
+            .unwrap_or_default();
+        let next_pano_id = if next_pano_id.is_empty() {
+            0
+        } else {
+            u32::from_le_bytes(
+                next_pano_id
+                    .try_into()
+                    .expect("next-pano-id should be a valid u32"),
+            )
+        };
+        txn.commit().unwrap();
+
+        next_pano_id
+    }
+
+    pub fn read_txn(&self) -> ReadTxn<'_> {
+        let guard = TxnGuard::new(&self.txn_lock);
+        ReadTxn {
+            txn: self.env.read_txn().expect("failed to get read txn"),
+            _guard: guard,
+        }
+    }
+    pub fn write_txn(&self) -> WriteTxn<'_> {
+        let guard = TxnGuard::new(&self.txn_lock);
+        WriteTxn {
+            txn: self.env.write_txn().expect("failed to get write txn"),
+            _guard: guard,
+        }
+    }
+
+    /// Run the function in a write transaction and commit it. If LMDB runs
+    /// out of space, the map is grown and the function is retried, unless this
+    /// thread has another transaction open, since growing it has to wait for
+    /// every transaction to finish.
+    pub fn write<T>(
+        &self,
+        mut f: impl FnMut(&mut RwTxn<'_>) -> heed::Result<T>,
+    ) -> eyre::Result<T> {
+        loop {
+            let map_size = self.env.info().map_size;
+
+            let mut txn = self.write_txn();
+            let res = match f(&mut txn) {
+                Ok(res) => txn.commit().map(|()| res),
+                Err(err) => {
+                    txn.abort();
+                    Err(err)
+                }
+            };
+
+            match res {
+                Err(heed::Error::Mdb(MdbError::MapFull)) if txn::thread_holds_guard() => {
+                    bail!(
+                        "The database is full, and it can't grow while this thread has a transaction open"
+                    );
+                }
+                Err(heed::Error::Mdb(MdbError::MapFull)) => self.grow_map(map_size)?,
+                Ok(res) => return Ok(res),
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// A number that changes whenever a tile or GetMetadata response is
+    /// written or deleted, including after a restart. Responses made from them
+    /// can be cached for as long as it stays the same. Other tables (like the
+    /// car history) change too often for their writes to count.
+    pub fn tile_generation(&self) -> u64 {
+        self.tile_generation.load(Ordering::Relaxed)
+    }
+    pub fn bump_tile_generation(&self) {
+        self.tile_generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Grow the map by [`DbConfig::map_size_step`], unless it was already
+    /// grown by someone else since it was `full_size`.
+    fn grow_map(&self, full_size: usize) -> eyre::Result<()> {
+        // wait for all the other transactions to finish
+        let _guard = self.txn_lock.write();
+
+        let map_size = self.env.info().map_size;
+        if map_size > full_size {
+            return Ok(());
+        }
+        if map_size >= self.config.max_map_size {
+            bail!(
+                "The database is full and already at the maximum map size ({}GB)",
+                map_size / GB
+            );
+        }
+
+        let new_map_size = (map_size + self.config.map_size_step).min(self.config.max_map_size);
+        warn!(
+            "The database is full, growing the map from {}GB to {}GB",
+            map_size / GB,
+            new_map_size / GB
+        );
+        // SAFETY: We hold the exclusive lock, so no transactions are active.
+        unsafe { self.env.resize(new_map_size)? };
+
+        Ok(())
+    }
+}
+
+/// The location and capture date are never compressed, so they can be read
+/// quickly.
+pub fn encode_getmetadata(res: &GetMetadataResponse, compression_level: Option<i32>) -> Vec<u8> {
+    let mut buf = Vec::new();
+
+    write_location(&mut buf, res.loc);
+    write_capture_date(&mut buf, res.capture_date);
+
+    let mut body = Vec::new();
+    write_links(&mut body, &res.links);
+    write_road_name(&mut body, res.road_name.as_deref());
+    write_maybe_compressed(&mut buf, &body, compression_level);
+
+    buf
+}
+pub fn decode_getmetadata(cur: &mut Cursor<&[u8]>) -> (Location, Box<[PanoLink]>) {
+    try_decode_getmetadata(cur).unwrap()
+}
+pub fn try_decode_getmetadata(cur: &mut Cursor<&[u8]>) -> io::Result<(Location, Box<[PanoLink]>)> {
+    let this_loc = read_location(cur)?;
+    read_capture_date(cur)?;
+
+    let body = read_maybe_compressed(cur)?;
+    let links = read_links(&mut Cursor::new(&body[..]))?;
+
+    Ok((this_loc, links))
+}
+
+fn write_links(buf: &mut Vec<u8>, links: &[PanoLink]) {
+    let num_links = links.len();
+    if links.len() >= 255 {
+        // rarely, we encounter panos with more than 255 links. here's an example:
+        // CAoSF0NJSE0wb2dLRUlDQWdJRGE4X3lMd2dF
+        buf.write_u8(255).unwrap();
+        buf.write_u32::<LE>(num_links.try_into().unwrap()).unwrap();
+    } else {
+        buf.write_u8(num_links as u8).unwrap();
+    }
+
+    for link in links {
+        write_pano_id(buf, &link.pano.id);
+        buf.write_f32::<LE>(link.heading).unwrap();
+        write_location(buf, link.pano.loc);
+    }
+}
+fn read_links(cur: &mut Cursor<&[u8]>) -> io::Result<Box<[PanoLink]>> {
+    let mut links = Vec::new();
+    let mut link_count = cur.read_u8()? as u32;
+    if link_count == 255 {
+        link_count = cur.read_u32::<LE>()?;
+    }
+
+    for _ in 0..link_count {
+        let id = read_pano_id(cur)?;
+        let heading = cur.read_f32::<LE>()?;
+        let loc = read_location(cur)?;
+        links.push(PanoLink {
+            pano: Pano { id, loc },
+            heading,
+        });
+    }
+
+    Ok(links.into())
+}
+
+/// Write a flag byte that says whether the body is compressed, and then the
+/// body. It's only compressed if a level is given and it makes the body
+/// smaller.
+fn write_maybe_compressed(buf: &mut Vec<u8>, body: &[u8], compression_level: Option<i32>) {
+    if let Some(level) = compression_level
+        && let Ok(compressed) = zstd::bulk::compress(body, level)
+        && compressed.len() < body.len()
+    {
+        // 1 = zstd
+        buf.write_u8(1).unwrap();
+        buf.extend_from_slice(&compressed);
+    } else {
+        // 0 = uncompressed
+        buf.write_u8(0).unwrap();
+        buf.extend_from_slice(body);
+    }
+}
+/// An encoded GetMetadata response, which is either borrowed from LMDB or still
+/// queued to be written.
+enum GetMetadataData<'t> {
+    Stored(&'t [u8]),
+    Pending(Arc<[u8]>),
+}
+impl Deref for GetMetadataData<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            GetMetadataData::Stored(data) => data,
+            GetMetadataData::Pending(data) => data,
+        }
+    }
+}
+
+/// Read the rest of the data, which was written with
+/// [`write_maybe_compressed`].
+fn read_maybe_compressed<'a>(cur: &mut Cursor<&'a [u8]>) -> io::Result<Cow<'a, [u8]>> {
+    let compressed = cur.read_u8()? == 1;
+    let data: &'a [u8] = cur.get_ref();
+    let rest = &data[cur.position() as usize..];
+    cur.set_position(data.len() as u64);
+    Ok(if compressed {
+        Cow::Owned(zstd::decode_all(rest)?)
+    } else {
+        Cow::Borrowed(rest)
+    })
+}
+
+/// `fetched_at` is in seconds since the Unix epoch.
+/// The header and fetch time are never compressed, so they can be read quickly.
+pub fn encode_listentityphotos(
+    panos: Option<Arc<[PanoWithBothLocations]>>,
+    fetched_at: u64,
+    compression_level: Option<i32>,
+) -> Vec<u8> {
+    let mut buf = Vec::new();
+
+    if let Some(panos) = panos {
+        // 1 = normal
+        buf.write_u8(1).unwrap();
+        buf.write_u64::<LE>(fetched_at).unwrap();
+        let mut body = Vec::with_capacity(8 + panos.len() * 10);
+        write_tile_panos(&mut body, &panos);
+        write_maybe_compressed(&mut buf, &body, compression_level);
+    } else {
+        // 0 = too big, smaller pano should be checked
+        buf.write_u8(0).unwrap();
+        buf.write_u64::<LE>(fetched_at).unwrap();
+    }
+
+    buf
+}
+pub fn decode_listentityphotos(cur: &mut Cursor<&[u8]>) -> Option<Arc<[PanoWithBothLocations]>> {
+    try_decode_listentityphotos(cur).unwrap()
+}
+pub fn try_decode_listentityphotos(
+    cur: &mut Cursor<&[u8]>,
+) -> io::Result<Option<Arc<[PanoWithBothLocations]>>> {
+    let mut panos = Vec::new();
+
+    let header = cur.read_u8()?;
+    if header == 0 {
+        return Ok(None);
+    }
+    let _fetched_at = cur.read_u64::<LE>()?;
+
+    let body = read_maybe_compressed(cur)?;
+    let cur = &mut Cursor::new(&body[..]);
+    if body.is_empty() {
+        return Ok(Some(panos.into()));
+    }
+    let mut prev_id = 0;
+    let mut prev_loc = read_location(cur)?;
+    while cur.position() < cur.get_ref().len() as u64 {
+        let id = (prev_id as i64 + read_signed_varint(cur)?) as u32;
+        let search_loc = read_location_delta(cur, prev_loc)?;
+        let actual_loc = read_location_delta(cur, search_loc)?;
+        panos.push(PanoWithBothLocations {
+            id: PanoId(id),
+            search_loc,
+            actual_loc,
+        });
+        prev_id = id;
+        prev_loc = search_loc;
+    }
+
+    Ok(Some(panos.into()))
+}
+
+/// The panos in a tile are close together (and their IDs usually are too),
+/// so after the first location everything is stored as varint differences:
+/// the ID and search location from the previous pano's, and the actual
+/// location from the search location.
+fn write_tile_panos(buf: &mut Vec<u8>, panos: &[PanoWithBothLocations]) {
+    let Some(first) = panos.first() else {
+        return;
+    };
+    let mut prev_id = 0;
+    let mut prev_loc = first.search_loc;
+    write_location(buf, prev_loc);
+    for pano in panos {
+        write_signed_varint(buf, pano.id.0 as i64 - prev_id as i64);
+        write_location_delta(buf, pano.search_loc, prev_loc);
+        write_location_delta(buf, pano.actual_loc, pano.search_loc);
+        prev_id = pano.id.0;
+        prev_loc = pano.search_loc;
+    }
+}
+fn write_location_delta(buf: &mut Vec<u8>, loc: Location, base: Location) {
+    write_signed_varint(buf, loc.lat.to_bits() as i64 - base.lat.to_bits() as i64);
+    write_signed_varint(buf, loc.lng.to_bits() as i64 - base.lng.to_bits() as i64);
+}
+fn read_location_delta(cur: &mut Cursor<&[u8]>, base: Location) -> io::Result<Location> {
+    let lat = (base.lat.to_bits() as i64 + read_signed_varint(cur)?) as i32;
+    let lng = (base.lng.to_bits() as i64 + read_signed_varint(cur)?) as i32;
+    Ok(Location {
+        lat: Angle::from_bits(lat),
+        lng: Angle::from_bits(lng),
+    })
+}
+
+/// A LEB128 varint of the zigzag-encoded number, so small negative numbers
+/// are short too.
+fn write_signed_varint(buf: &mut Vec<u8>, n: i64) {
+    let mut n = ((n << 1) ^ (n >> 63)) as u64;
+    while n >= 0x80 {
+        buf.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+fn read_signed_varint(cur: &mut Cursor<&[u8]>) -> io::Result<i64> {
+    let mut n = 0_u64;
+    let mut shift = 0;
+    loop {
+        let byte = cur.read_u8()?;
+        n |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+        if shift >= 64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "varint is too long",
+            ));
+        }
+    }
+    Ok(((n >> 1) as i64) ^ -((n & 1) as i64))
+}
+
+pub fn decode_listentityphotos_fetched_at(data: &[u8]) -> u64 {
+    let mut cur = Cursor::new(data);
+    cur.set_position(1);
+    cur.read_u64::<LE>().unwrap()
+}
+/// Whether the tile was fully downloaded and had no panos. This only reads the
+/// header, since an empty body is never compressed (that can't make it any
+/// smaller).
+fn is_empty_listentityphotos(data: &[u8]) -> bool {
+    let mut cur = Cursor::new(data);
+    let is_full = cur.read_u8().is_ok_and(|header| header == 1);
+    let _fetched_at = cur.read_u64::<LE>();
+    let is_uncompressed = cur.read_u8().is_ok_and(|compression| compression == 0);
+    is_full && is_uncompressed && cur.position() == data.len() as u64
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub fn encode_learned_options(learned: &LearnedOptions) -> Vec<u8> {
+    let mut buf = Vec::new();
+
+    buf.write_u32::<LE>(learned.observations).unwrap();
+
+    buf.write_u32::<LE>(learned.restrictions.len() as u32)
+        .unwrap();
+    for (pano_id, count) in &learned.restrictions {
+        write_pano_id(&mut buf, pano_id);
+        buf.write_u32::<LE>(*count).unwrap();
+    }
+
+    buf.write_u32::<LE>(learned.additions.len() as u32).unwrap();
+    for (option, count) in &learned.additions {
+        write_pano_id(&mut buf, &option.pano.id);
+        buf.write_f32::<LE>(option.heading).unwrap();
+        write_location(&mut buf, option.pano.loc);
+        buf.write_u32::<LE>(*count).unwrap();
+    }
+
+    buf
+}
+pub fn decode_learned_options(cur: &mut Cursor<&[u8]>) -> LearnedOptions {
+    try_decode_learned_options(cur).unwrap()
+}
+pub fn try_decode_learned_options(cur: &mut Cursor<&[u8]>) -> io::Result<LearnedOptions> {
+    let observations = cur.read_u32::<LE>()?;
+
+    let restriction_count = cur.read_u32::<LE>()?;
+    let mut restrictions = Vec::with_capacity(capacity_for(cur, restriction_count, 4 + 4));
+    for _ in 0..restriction_count {
+        let pano_id = read_pano_id(cur)?;
+        let count = cur.read_u32::<LE>()?;
+        restrictions.push((pano_id, count));
+    }
+
+    let addition_count = cur.read_u32::<LE>()?;
+    let mut additions = Vec::with_capacity(capacity_for(cur, addition_count, 4 + 4 + 8 + 4));
+    for _ in 0..addition_count {
+        let id = read_pano_id(cur)?;
+        let heading = cur.read_f32::<LE>()?;
+        let loc = read_location(cur)?;
+        let count = cur.read_u32::<LE>()?;
+        additions.push((
+            PanoOptionRes {
+                pano: Pano { id, loc },
+                heading,
+            },
+            count,
+        ));
+    }
+
+    Ok(LearnedOptions {
+        observations,
+        restrictions,
+        additions,
+    })
+}
+
+/// The pano ID is in the upper bits, so all the options for a pano are next to
+/// each other.
+fn options_key(pano_id: PanoId, heading: f32) -> u64 {
+    ((pano_id.0 as u64) << 32) | heading.to_bits() as u64
+}
+fn baked_key(pano_id: PanoId, heading_bucket: u16) -> u64 {
+    ((pano_id.0 as u64) << 32) | heading_bucket as u64
+}
+
+pub fn encode_landmark_costs(costs: &[LandmarkCosts]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(1 + costs.len() * 8);
+    buf.write_u8(costs.len() as u8).unwrap();
+    for costs in costs {
+        buf.write_f32::<LE>(costs.from_landmark).unwrap();
+        buf.write_f32::<LE>(costs.to_landmark).unwrap();
+    }
+    buf
+}
+pub fn decode_landmark_costs(cur: &mut Cursor<&[u8]>) -> Box<[LandmarkCosts]> {
+    try_decode_landmark_costs(cur).unwrap()
+}
+pub fn try_decode_landmark_costs(cur: &mut Cursor<&[u8]>) -> io::Result<Box<[LandmarkCosts]>> {
+    let count = cur.read_u8()?;
+    (0..count)
+        .map(|_| {
+            Ok(LandmarkCosts {
+                from_landmark: cur.read_f32::<LE>()?,
+                to_landmark: cur.read_f32::<LE>()?,
+            })
+        })
+        .collect()
+}
+
+pub fn encode_options(res: &BasePanoOptionsRes) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(2 + res.options.len() * (4 + 8 + 4));
+
+    buf.write_u16::<LE>(res.options.len() as u16).unwrap();
+    for option in &res.options {
+        write_pano_id(&mut buf, &option.pano.id);
+        write_location(&mut buf, option.pano.loc);
+        buf.write_f32::<LE>(option.heading).unwrap();
+    }
+
+    buf
+}
+pub fn decode_options(cur: &mut Cursor<&[u8]>) -> BasePanoOptionsRes {
+    try_decode_options(cur).unwrap()
+}
+pub fn try_decode_options(cur: &mut Cursor<&[u8]>) -> io::Result<BasePanoOptionsRes> {
+    let option_count = cur.read_u16::<LE>()?;
+    let mut options = Vec::with_capacity(option_count as usize);
+    for _ in 0..option_count {
+        let id = read_pano_id(cur)?;
+        let loc = read_location(cur)?;
+        let heading = cur.read_f32::<LE>()?;
+        options.push(PanoOptionRes {
+            pano: Pano { id, loc },
+            heading,
+        });
+    }
+
+    Ok(BasePanoOptionsRes {
+        options: options.into(),
+    })
+}
+
+pub fn encode_saved_path(path: &SavedPath) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(8 + 4 + path.nodes.len() * (4 + 8 + 4 + 4));
+
+    buf.write_u64::<LE>(path.created_at).unwrap();
+    buf.write_u32::<LE>(path.nodes.len() as u32).unwrap();
+    for node in &path.nodes {
+        write_pano_id(&mut buf, &node.pano.id);
+        write_location(&mut buf, node.pano.loc);
+        buf.write_f32::<LE>(node.heading).unwrap();
+        buf.write_f32::<LE>(node.cost).unwrap();
+    }
+
+    buf
+}
+pub fn decode_saved_path(cur: &mut Cursor<&[u8]>) -> SavedPath {
+    try_decode_saved_path(cur).unwrap()
+}
+pub fn try_decode_saved_path(cur: &mut Cursor<&[u8]>) -> io::Result<SavedPath> {
+    let created_at = cur.read_u64::<LE>()?;
+    let node_count = cur.read_u32::<LE>()?;
+    let mut nodes = Vec::with_capacity(capacity_for(cur, node_count, 4 + 8 + 4 + 4));
+    for _ in 0..node_count {
+        let id = read_pano_id(cur)?;
+        let loc = read_location(cur)?;
+        let heading = cur.read_f32::<LE>()?;
+        let cost = cur.read_f32::<LE>()?;
+        nodes.push(RouteNode {
+            pano: Pano { id, loc },
+            heading,
+            cost,
+            // saved paths are only used for exporting, so this isn't stored
+            option_count: 0,
+        });
+    }
+
+    Ok(SavedPath {
+        created_at,
+        nodes: nodes.into(),
+    })
+}
+
+fn encode_delay_samples(samples: &DelaySamples) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(1 + samples.buckets.len() * (8 + 8));
+    buf.push(samples.buckets.len() as u8);
+    for &(sum, count) in &samples.buckets {
+        buf.write_f64::<LE>(sum).unwrap();
+        buf.write_u64::<LE>(count).unwrap();
+    }
+    buf
+}
+fn decode_delay_samples(cur: &mut Cursor<&[u8]>) -> DelaySamples {
+    let mut samples = DelaySamples::default();
+    let bucket_count = cur.read_u8().unwrap() as usize;
+    for i in 0..bucket_count {
+        let sum = cur.read_f64::<LE>().unwrap();
+        let count = cur.read_u64::<LE>().unwrap();
+        // in case the number of buckets changed
+        if let Some(bucket) = samples.buckets.get_mut(i) {
+            *bucket = (sum, count);
+        }
+    }
+    samples
+}
+
+fn encode_quota_usage(usage: &QuotaUsage) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(4 + 8);
+    buf.write_u32::<LE>(usage.day).unwrap();
+    buf.write_u64::<LE>(usage.nodes).unwrap();
+    buf
+}
+fn decode_quota_usage(cur: &mut Cursor<&[u8]>) -> QuotaUsage {
+    try_decode_quota_usage(cur).unwrap()
+}
+fn try_decode_quota_usage(cur: &mut Cursor<&[u8]>) -> io::Result<QuotaUsage> {
+    Ok(QuotaUsage {
+        day: cur.read_u32::<LE>()?,
+        nodes: cur.read_u64::<LE>()?,
+    })
+}
+
+fn encode_accuracy_counts(counts: &AccuracyCounts) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(8 * 4);
+    buf.write_u64::<LE>(counts.observations).unwrap();
+    buf.write_u64::<LE>(counts.exact_matches).unwrap();
+    buf.write_u64::<LE>(counts.missing_options).unwrap();
+    buf.write_u64::<LE>(counts.extra_options).unwrap();
+    buf
+}
+fn decode_accuracy_counts(cur: &mut Cursor<&[u8]>) -> AccuracyCounts {
+    AccuracyCounts {
+        observations: cur.read_u64::<LE>().unwrap(),
+        exact_matches: cur.read_u64::<LE>().unwrap(),
+        missing_options: cur.read_u64::<LE>().unwrap(),
+        extra_options: cur.read_u64::<LE>().unwrap(),
+    }
+}
+
+pub fn encode_option_mismatch(mismatch: &OptionMismatch) -> Vec<u8> {
+    let mut buf =
+        Vec::with_capacity(8 + 4 + 4 + 2 + 2 + (mismatch.missing.len() + mismatch.extra.len()) * 4);
+
+    buf.write_u64::<LE>(mismatch.timestamp).unwrap();
+    write_pano_id(&mut buf, &mismatch.pano);
+    buf.write_f32::<LE>(mismatch.heading).unwrap();
+    for pano_ids in [&mismatch.missing, &mismatch.extra] {
+        buf.write_u16::<LE>(pano_ids.len() as u16).unwrap();
+        for pano_id in pano_ids {
+            write_pano_id(&mut buf, pano_id);
+        }
+    }
+
+    buf
+}
+pub fn decode_option_mismatch(cur: &mut Cursor<&[u8]>) -> OptionMismatch {
+    try_decode_option_mismatch(cur).unwrap()
+}
+pub fn try_decode_option_mismatch(cur: &mut Cursor<&[u8]>) -> io::Result<OptionMismatch> {
+    let timestamp = cur.read_u64::<LE>()?;
+    let pano = read_pano_id(cur)?;
+    let heading = cur.read_f32::<LE>()?;
+    let mut read_pano_ids = || {
+        let count = cur.read_u16::<LE>()?;
+        (0..count)
+            .map(|_| read_pano_id(cur))
+            .collect::<io::Result<Vec<_>>>()
+    };
+    let missing = read_pano_ids()?;
+    let extra = read_pano_ids()?;
+
+    Ok(OptionMismatch {
+        timestamp,
+        pano,
+        heading,
+        missing,
+        extra,
+    })
+}
+
+pub fn encode_car_history_entry(entry: &CarHistoryEntry) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(8 + 8 + 4 + 1 + 4);
+
+    buf.write_u64::<LE>(entry.timestamp).unwrap();
+    write_location(&mut buf, entry.loc);
+    buf.write_f32::<LE>(entry.heading).unwrap();
+    match &entry.pano {
+        Some(pano) => {
+            buf.write_u8(1).unwrap();
+            write_pano_id(&mut buf, pano);
+        }
+        None => buf.write_u8(0).unwrap(),
+    }
+
+    buf
+}
+pub fn decode_car_history_entry(cur: &mut Cursor<&[u8]>) -> CarHistoryEntry {
+    try_decode_car_history_entry(cur).unwrap()
+}
+pub fn try_decode_car_history_entry(cur: &mut Cursor<&[u8]>) -> io::Result<CarHistoryEntry> {
+    let timestamp = cur.read_u64::<LE>()?;
+    let loc = read_location(cur)?;
+    let heading = cur.read_f32::<LE>()?;
+    let pano = match cur.read_u8()? {
+        0 => None,
+        _ => Some(read_pano_id(cur)?),
+    };
+
+    Ok(CarHistoryEntry {
+        timestamp,
+        loc,
+        heading,
+        pano,
+    })
+}
+
+fn write_pano_id(buf: &mut Vec<u8>, pano_id: &PanoId) {
+    buf.write_u32::<LE>(pano_id.0).unwrap();
+}
+fn read_pano_id(cur: &mut Cursor<&[u8]>) -> io::Result<PanoId> {
+    Ok(PanoId(cur.read_u32::<LE>()?))
+}
 
 fn write_location(buf: &mut Vec<u8>, loc: Location) {
     buf.write_i32::<LE>(loc.lat.to_bits()).unwrap();
     buf.write_i32::<LE>(loc.lng.to_bits()).unwrap();
 }
-fn read_location(cur: &mut Cursor<&[u8]>) -> Location {
-    let lat = Angle::from_bits(cur.read_i32::<LE>().unwrap());
-    let lng = Angle::from_bits(cur.read_i32::<LE>().unwrap());
-    Location { lat, lng }
+fn read_location(cur: &mut Cursor<&[u8]>) -> io::Result<Location> {
+    let lat = Angle::from_bits(cur.read_i32::<LE>()?);
+    let lng = Angle::from_bits(cur.read_i32::<LE>()?);
+    Ok(Location { lat, lng })
+}
+
+/// How much to preallocate for `count` entries of `len` bytes each, so a
+/// corrupt count can't make us allocate more than the data could hold.
+fn capacity_for(cur: &Cursor<&[u8]>, count: u32, len: usize) -> usize {
+    let remaining = (cur.get_ref().len() as u64).saturating_sub(cur.position());
+    (count as usize).min(remaining as usize / len)
 }
 
+/// A year of 0 means that the date is unknown.
+fn write_capture_date(buf: &mut Vec<u8>, date: Option<CaptureDate>) {
+    let date = date.unwrap_or(CaptureDate { year: 0, month: 0 });
+    buf.write_u16::<LE>(date.year).unwrap();
+    buf.write_u8(date.month).unwrap();
+}
+fn read_capture_date(cur: &mut Cursor<&[u8]>) -> io::Result<Option<CaptureDate>> {
+    let year = cur.read_u16::<LE>()?;
+    let month = cur.read_u8()?;
+    Ok((year != 0).then_some(CaptureDate { year, month }))
+}
+
+/// The length as a u8 and then the UTF-8 bytes, where an empty name means that
+/// there isn't one. Names longer than 255 bytes are cut off.
+fn write_road_name(buf: &mut Vec<u8>, name: Option<&str>) {
+    let mut name = name.unwrap_or_default();
+    if name.len() > u8::MAX as usize {
+        let mut end = u8::MAX as usize;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name = &name[..end];
+    }
+    buf.write_u8(name.len() as u8).unwrap();
+    buf.extend_from_slice(name.as_bytes());
+}
+fn read_road_name(cur: &mut Cursor<&[u8]>) -> io::Result<Option<String>> {
+    let len = cur.read_u8()? as usize;
+    let mut name = vec![0; len];
+    cur.read_exact(&mut name)?;
+    Ok((len > 0).then(|| String::from_utf8_lossy(&name).into_owned()))
+}
+
+/// Tiles are keyed by their size and then their position in Z-order, so the
+/// tiles of each size that are close to each other are also close in the
+/// database. See [`Db::iter_tiles_in_bbox`].
 impl BytesEncode<'_> for SizedTile {
     type EItem = SizedTile;
     fn bytes_encode(item: &Self::EItem) -> Result<Cow<'_, [u8]>, BoxedError> {
-        let mut buf = Vec::with_capacity(1 + 4 + 4);
+        let mut buf = Vec::with_capacity(1 + 8);
         buf.push(item.size);
-        buf.write_u32::<LE>(item.x)?;
-        buf.write_u32::<LE>(item.y)?;
+        buf.write_u64::<BE>(z_order(item.x, item.y))?;
         Ok(buf.into())
     }
 }
@@ -432,8 +2273,237 @@ impl BytesDecode<'_> for SizedTile {
     type DItem = SizedTile;
     fn bytes_decode(mut bytes: &'_ [u8]) -> Result<Self::DItem, BoxedError> {
         let size = bytes.read_u8()?;
-        let x = bytes.read_u32::<LE>()?;
-        let y = bytes.read_u32::<LE>()?;
+        let (x, y) = from_z_order(bytes.read_u64::<BE>()?);
         Ok(SizedTile { size, x, y })
     }
 }
+
+/// Interleave the bits of x and y, with x in the lower bit of each pair.
+pub fn z_order(x: u32, y: u32) -> u64 {
+    fn spread(v: u32) -> u64 {
+        let mut v = v as u64;
+        v = (v | (v << 16)) & 0x0000_ffff_0000_ffff;
+        v = (v | (v << 8)) & 0x00ff_00ff_00ff_00ff;
+        v = (v | (v << 4)) & 0x0f0f_0f0f_0f0f_0f0f;
+        v = (v | (v << 2)) & 0x3333_3333_3333_3333;
+        (v | (v << 1)) & 0x5555_5555_5555_5555
+    }
+    spread(x) | (spread(y) << 1)
+}
+pub fn from_z_order(z: u64) -> (u32, u32) {
+    fn compact(mut v: u64) -> u32 {
+        v &= 0x5555_5555_5555_5555;
+        v = (v | (v >> 1)) & 0x3333_3333_3333_3333;
+        v = (v | (v >> 2)) & 0x0f0f_0f0f_0f0f_0f0f;
+        v = (v | (v >> 4)) & 0x00ff_00ff_00ff_00ff;
+        v = (v | (v >> 8)) & 0x0000_ffff_0000_ffff;
+        (v | (v >> 16)) as u32
+    }
+    (compact(z), compact(z >> 1))
+}
+
+impl BytesEncode<'_> for LearnedOptionsKey {
+    type EItem = LearnedOptionsKey;
+    fn bytes_encode(item: &Self::EItem) -> Result<Cow<'_, [u8]>, BoxedError> {
+        let mut buf = Vec::with_capacity(4 + 1);
+        buf.write_u32::<BE>(item.pano.0)?;
+        buf.push(item.heading_bucket);
+        Ok(buf.into())
+    }
+}
+impl BytesDecode<'_> for LearnedOptionsKey {
+    type DItem = LearnedOptionsKey;
+    fn bytes_decode(mut bytes: &'_ [u8]) -> Result<Self::DItem, BoxedError> {
+        let pano = PanoId(bytes.read_u32::<BE>()?);
+        let heading_bucket = bytes.read_u8()?;
+        Ok(LearnedOptionsKey {
+            pano,
+            heading_bucket,
+        })
+    }
+}
+
+/// An empty database in the temp dir, for tests.
+#[cfg(test)]
+impl Db {
+    pub fn temp(name: &str) -> Db {
+        let path = std::env::temp_dir().join(format!("pathfinder-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        Db::new(DbConfig {
+            path,
+            map_size: 1 << 30,
+            ..DbConfig::default()
+        })
+        .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_z_order() {
+        assert_eq!(z_order(0b11, 0b00), 0b0101);
+        assert_eq!(z_order(0b00, 0b11), 0b1010);
+        for (x, y) in [(0, 0), (12345, 67890), (u32::MAX, 1), (1 << 16, u32::MAX)] {
+            assert_eq!(from_z_order(z_order(x, y)), (x, y));
+        }
+        // everything in a box is between its corners
+        assert!(z_order(5, 6) > z_order(4, 4) && z_order(5, 6) < z_order(7, 7));
+    }
+
+    #[test]
+    fn test_full_map_doesnt_deadlock_with_open_txn() {
+        let path = std::env::temp_dir().join(format!("pathfinder-full-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let db = Db::new(DbConfig {
+            path,
+            map_size: 1 << 20,
+            map_size_step: 1 << 20,
+            max_map_size: 1 << 30,
+            ..DbConfig::default()
+        })
+        .unwrap();
+
+        let big = vec![0; 1 << 21];
+        let txn = db.read_txn();
+        assert!(
+            db.write(|txn| db.settings_db.put(txn, "big", &big))
+                .is_err()
+        );
+        txn.commit().unwrap();
+        // without the open txn, it can grow
+        db.write(|txn| db.settings_db.put(txn, "big", &big))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_tile_generation_only_changes_with_tiles() {
+        let db = Db::temp("tile-generation");
+        let generation = db.tile_generation();
+        db.record_car_position(0, Location::new_deg(0., 0.), 0., None)
+            .unwrap();
+        assert_eq!(db.tile_generation(), generation);
+
+        let tile = SizedTile {
+            size: SMALL_TILE_SIZE,
+            x: 0,
+            y: 0,
+        };
+        db.save_listentityphotos(&tile, None).unwrap();
+        assert_ne!(db.tile_generation(), generation);
+    }
+
+    #[test]
+    fn test_old_car_positions_are_deleted() {
+        const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+        let db = Db::temp("car-history-retention");
+        let loc = Location::new_deg(0., 0.);
+
+        db.record_car_position(DAY_MS, loc, 0., None).unwrap();
+        db.record_car_position(2 * DAY_MS, loc, 0., None).unwrap();
+        assert_eq!(db.car_history(0, u64::MAX, 10).len(), 2);
+
+        // the default retention is 30 days
+        db.record_car_position(32 * DAY_MS, loc, 0., None).unwrap();
+        let history = db.car_history(0, u64::MAX, 10);
+        assert_eq!(
+            history.iter().map(|e| e.timestamp).collect::<Vec<_>>(),
+            [2 * DAY_MS, 32 * DAY_MS]
+        );
+    }
+
+    #[test]
+    fn test_old_quotas_are_deleted() {
+        let db = Db::temp("quota-retention");
+        let ip = |ip: &str| RatelimitIp::new(ip.parse().unwrap(), 24, 56);
+
+        db.add_quota_usage(ip("1.1.1.1"), 10, 100).unwrap();
+        db.add_quota_usage(ip("2.2.2.2"), 10, 100).unwrap();
+        assert_eq!(db.get_quota_usage(ip("2.2.2.2")).nodes, 100);
+
+        db.add_quota_usage(ip("1.1.1.1"), 11, 5).unwrap();
+        assert_eq!(db.get_quota_usage(ip("1.1.1.1")).nodes, 5);
+        assert_eq!(db.get_quota_usage(ip("2.2.2.2")).nodes, 0);
+        assert_eq!(db.quotas_db.len(&db.read_txn()).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_delete_shortcuts_through_panos() {
+        let db = Db::temp("shortcuts");
+        let chain = |ids: &[u32]| BasePanoOptionsRes {
+            options: ids
+                .iter()
+                .map(|id| PanoOptionRes {
+                    pano: Pano {
+                        id: PanoId(*id),
+                        loc: Location::new_deg(0., *id as f64 * 0.0001),
+                    },
+                    heading: 90.,
+                })
+                .collect(),
+        };
+        db.save_shortcuts(&[
+            (PanoId(1), 0, chain(&[2, 3, 4])),
+            (PanoId(10), 0, chain(&[11, 12, 13])),
+            (PanoId(20), 0, chain(&[21, 22, 23])),
+        ])
+        .unwrap();
+
+        let deleted = db
+            .delete_shortcuts_through_panos(&[PanoId(3), PanoId(20)])
+            .unwrap();
+        assert_eq!(deleted, 2);
+        assert!(db.lookup_shortcut(PanoId(1), 0).is_none());
+        assert!(db.lookup_shortcut(PanoId(10), 0).is_some());
+        assert!(db.lookup_shortcut(PanoId(20), 0).is_none());
+    }
+
+    #[test]
+    fn test_empty_listentityphotos() {
+        let empty: Arc<[PanoWithBothLocations]> = Arc::new([]);
+        let pano = PanoWithBothLocations {
+            id: PanoId(1),
+            search_loc: Location::new_deg(1., 2.),
+            actual_loc: Location::new_deg(1., 2.),
+        };
+        for level in [None, Some(3)] {
+            assert!(is_empty_listentityphotos(&encode_listentityphotos(
+                Some(empty.clone()),
+                0,
+                level
+            )));
+            assert!(!is_empty_listentityphotos(&encode_listentityphotos(
+                Some(Arc::new([pano.clone()])),
+                0,
+                level
+            )));
+            assert!(!is_empty_listentityphotos(&encode_listentityphotos(
+                None, 0, level
+            )));
+        }
+    }
+
+    #[test]
+    fn test_compressed_listentityphotos() {
+        let panos = (0..100)
+            .map(|i| PanoWithBothLocations {
+                id: PanoId(i),
+                search_loc: Location::new_deg(1., i as f64 * 0.0001),
+                actual_loc: Location::new_deg(1., i as f64 * 0.0001),
+            })
+            .collect::<Arc<[_]>>();
+        let raw = encode_listentityphotos(Some(panos.clone()), 123, None);
+        let compressed = encode_listentityphotos(Some(panos.clone()), 123, Some(3));
+        assert!(compressed.len() < raw.len());
+        for data in [raw, compressed] {
+            assert_eq!(decode_listentityphotos_fetched_at(&data), 123);
+            let decoded = decode_listentityphotos(&mut Cursor::new(&data)).unwrap();
+            assert_eq!(decoded.len(), panos.len());
+            assert_eq!(decoded[99].id, panos[99].id);
+            assert_eq!(decoded[99].search_loc, panos[99].search_loc);
+            assert_eq!(decoded[99].actual_loc, panos[99].actual_loc);
+        }
+    }
+}
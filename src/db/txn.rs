@@ -0,0 +1,83 @@
+//! Wrappers around heed's transactions that hold a shared lock for as long as
+//! they're alive. This lets us know when no transactions are active, which is
+//! required for safely growing the map when LMDB runs out of space.
+
+use std::{
+    cell::Cell,
+    ops::{Deref, DerefMut},
+};
+
+use heed::{RoTxn, RwTxn, WithTls};
+use parking_lot::{RwLock, RwLockReadGuard};
+
+thread_local! {
+    static HELD_GUARDS: Cell<u32> = const { Cell::new(0) };
+}
+
+/// A shared lock on `Db::txn_lock` that also keeps track of how many of them
+/// the current thread holds, since growing the map while holding one would
+/// deadlock.
+pub struct TxnGuard<'a> {
+    _guard: RwLockReadGuard<'a, ()>,
+}
+impl<'a> TxnGuard<'a> {
+    pub fn new(lock: &'a RwLock<()>) -> Self {
+        let guard = lock.read_recursive();
+        HELD_GUARDS.with(|held| held.set(held.get() + 1));
+        Self { _guard: guard }
+    }
+}
+impl Drop for TxnGuard<'_> {
+    fn drop(&mut self) {
+        HELD_GUARDS.with(|held| held.set(held.get() - 1));
+    }
+}
+
+/// Whether the current thread has a transaction (or anything else that holds
+/// a [`TxnGuard`]) open.
+pub fn thread_holds_guard() -> bool {
+    HELD_GUARDS.with(|held| held.get() > 0)
+}
+
+pub struct ReadTxn<'a> {
+    // the transaction has to be dropped before the guard
+    pub(super) txn: RoTxn<'a, WithTls>,
+    pub(super) _guard: TxnGuard<'a>,
+}
+impl ReadTxn<'_> {
+    pub fn commit(self) -> heed::Result<()> {
+        self.txn.commit()
+    }
+}
+impl<'a> Deref for ReadTxn<'a> {
+    type Target = RoTxn<'a, WithTls>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.txn
+    }
+}
+
+pub struct WriteTxn<'a> {
+    pub(super) txn: RwTxn<'a>,
+    pub(super) _guard: TxnGuard<'a>,
+}
+impl WriteTxn<'_> {
+    pub fn commit(self) -> heed::Result<()> {
+        self.txn.commit()
+    }
+    pub fn abort(self) {
+        self.txn.abort()
+    }
+}
+impl<'a> Deref for WriteTxn<'a> {
+    type Target = RwTxn<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.txn
+    }
+}
+impl DerefMut for WriteTxn<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.txn
+    }
+}
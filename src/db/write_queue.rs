@@ -0,0 +1,179 @@
+//! Saving GetMetadata responses in the background, so downloading a tile
+//! doesn't have to wait for LMDB to commit hundreds of them. The responses are
+//! kept in memory until they're written, and lookups check there first.
+//!
+//! The queue is only used once [`Db::write_queued_getmetadata`] is running.
+//! Until then (like in tests) the responses are written right away.
+
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use parking_lot::{Mutex, RwLock};
+use rustc_hash::FxHashMap;
+use tokio::sync::mpsc;
+use tracing::{debug, error, info};
+
+use crate::{
+    db::{Db, encode_getmetadata},
+    model::GetMetadataResponse,
+};
+
+/// How many batches can be waiting for the writer before queueing more has to
+/// wait.
+const QUEUE_CAPACITY: usize = 256;
+/// The most responses that are written in one transaction.
+const MAX_BATCH_SIZE: usize = 16_384;
+
+pub struct GetMetadataQueue {
+    tx: mpsc::Sender<Vec<u32>>,
+    rx: Mutex<Option<mpsc::Receiver<Vec<u32>>>>,
+    writer_running: AtomicBool,
+    /// The encoded responses that haven't been written yet, keyed by pano ID.
+    pending: RwLock<FxHashMap<u32, Arc<[u8]>>>,
+    /// The length of `pending`, so lookups can skip the lock when nothing is
+    /// queued, which is most of the time.
+    pending_len: AtomicUsize,
+}
+impl Default for GetMetadataQueue {
+    fn default() -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        Self {
+            tx,
+            rx: Mutex::new(Some(rx)),
+            writer_running: AtomicBool::new(false),
+            pending: RwLock::default(),
+            pending_len: AtomicUsize::new(0),
+        }
+    }
+}
+impl GetMetadataQueue {
+    /// The encoded response if it's still waiting to be written.
+    pub(super) fn get(&self, pano_id: u32) -> Option<Arc<[u8]>> {
+        if self.pending_len.load(Ordering::Acquire) == 0 {
+            return None;
+        }
+        self.pending.read().get(&pano_id).cloned()
+    }
+
+    /// Every response that's still waiting to be written.
+    pub(super) fn snapshot(&self) -> Vec<(u32, Arc<[u8]>)> {
+        let pending = self.pending.read();
+        pending
+            .iter()
+            .map(|(id, data)| (*id, data.clone()))
+            .collect()
+    }
+}
+
+impl Db {
+    /// Save the responses, in the background if the writer is running. They
+    /// can be looked up right away either way.
+    pub async fn queue_getmetadata(&self, responses: &[GetMetadataResponse]) -> eyre::Result<()> {
+        let queue = &self.getmetadata_queue;
+        if !queue.writer_running.load(Ordering::Relaxed) {
+            self.write(|txn| {
+                for res in responses {
+                    self.save_getmetadata_with_txn(txn, res)?;
+                }
+                Ok(())
+            })?;
+            self.bump_tile_generation();
+            return Ok(());
+        }
+
+        let mut pano_ids = Vec::with_capacity(responses.len());
+        {
+            let mut pending = queue.pending.write();
+            for res in responses {
+                let encoded = encode_getmetadata(res, self.config.compression_level);
+                pending.insert(res.id.0, encoded.into());
+                pano_ids.push(res.id.0);
+            }
+            queue.pending_len.store(pending.len(), Ordering::Release);
+        }
+        // they can be looked up now, even though they weren't written yet
+        self.bump_tile_generation();
+        // this waits if the writer is falling behind
+        queue
+            .tx
+            .send(pano_ids)
+            .await
+            .map_err(|_| eyre::eyre!("the GetMetadata writer stopped"))
+    }
+
+    /// Write the queued GetMetadata responses as they come in, combining the
+    /// batches that arrive while the previous one is being written. Only one
+    /// writer can run.
+    pub async fn write_queued_getmetadata(&'static self) {
+        let queue = &self.getmetadata_queue;
+        let Some(mut rx) = queue.rx.lock().take() else {
+            return;
+        };
+        queue.writer_running.store(true, Ordering::Relaxed);
+
+        while let Some(mut pano_ids) = rx.recv().await {
+            while pano_ids.len() < MAX_BATCH_SIZE
+                && let Ok(more) = rx.try_recv()
+            {
+                pano_ids.extend(more);
+            }
+            let res =
+                tokio::task::spawn_blocking(move || self.write_pending_getmetadata(&pano_ids))
+                    .await;
+            match res {
+                Ok(Ok(())) => {}
+                // they're still pending, so they'll be written when the queue is flushed
+                Ok(Err(err)) => error!("Failed to save GetMetadata responses: {err}"),
+                Err(err) => error!("Saving GetMetadata responses panicked: {err}"),
+            }
+        }
+    }
+
+    /// Write every response that's still queued. This should be called before
+    /// exiting, since they're lost otherwise.
+    pub fn flush_queued_getmetadata(&self) -> eyre::Result<()> {
+        let pano_ids = self
+            .getmetadata_queue
+            .pending
+            .read()
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        if pano_ids.is_empty() {
+            return Ok(());
+        }
+        info!("Writing {} queued GetMetadata responses", pano_ids.len());
+        self.write_pending_getmetadata(&pano_ids)
+    }
+
+    fn write_pending_getmetadata(&self, pano_ids: &[u32]) -> eyre::Result<()> {
+        let entries = {
+            let pending = self.getmetadata_queue.pending.read();
+            pano_ids
+                .iter()
+                .filter_map(|id| Some((*id, pending.get(id)?.clone())))
+                .collect::<Vec<_>>()
+        };
+        self.write(|txn| {
+            for (pano_id, data) in &entries {
+                self.getmetadata_db.put(txn, pano_id, data)?;
+            }
+            Ok(())
+        })?;
+        debug!("Wrote {} queued GetMetadata responses", entries.len());
+
+        // the ones that were queued again while we were writing are still pending
+        let mut pending = self.getmetadata_queue.pending.write();
+        for (pano_id, data) in entries {
+            if pending.get(&pano_id).is_some_and(|d| Arc::ptr_eq(d, &data)) {
+                pending.remove(&pano_id);
+            }
+        }
+        self.getmetadata_queue
+            .pending_len
+            .store(pending.len(), Ordering::Release);
+        Ok(())
+    }
+}
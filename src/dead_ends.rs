@@ -0,0 +1,134 @@
+//! Finding dead-end branches, like cul-de-sacs, from the cached GetMetadata
+//! links. A branch starts at a pano with only one link and goes back until an
+//! intersection. Once the search goes into one it can only come back out the
+//! way it came, so [`crate::astar`] doesn't go into branches that can't contain
+//! the goal.
+//!
+//! This only looks at the links, and the game can also give options that aren't
+//! linked (see [`crate::roadtrip`]), so a branch might not really be a dead
+//! end. That's why it's only used for pruning when `PATHFINDER_PRUNE_DEAD_ENDS`
+//! is enabled.
+
+use std::time::{Duration, Instant};
+
+use rustc_hash::{FxHashMap, FxHashSet};
+use tracing::{error, info};
+
+use crate::{
+    db::Db,
+    math,
+    model::{Location, PanoId},
+};
+
+/// Find the dead ends every `interval`, starting now.
+pub async fn precompute_periodically(db: &'static Db, interval: Duration) {
+    loop {
+        let res = tokio::task::spawn_blocking(move || precompute(db)).await;
+        match res {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => error!("Failed to find dead ends: {err}"),
+            Err(err) => error!("Finding dead ends panicked: {err}"),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Find the dead ends in the cache and save them, replacing the old ones.
+pub fn precompute(db: &Db) -> eyre::Result<()> {
+    let start = Instant::now();
+
+    let mut graph = FxHashMap::default();
+    db.slow_for_each_getmetadata(|pano_id, loc, links| {
+        let mut neighbors = links
+            .iter()
+            .map(|link| link.pano.id)
+            .filter(|id| *id != pano_id)
+            .collect::<Vec<_>>();
+        neighbors.sort_unstable_by_key(|id| id.0);
+        neighbors.dedup();
+        graph.insert(pano_id, (loc, neighbors));
+    });
+
+    let dead_ends = find_dead_ends(&graph);
+    info!(
+        "Found {} dead-end panos out of {} in {:?}",
+        dead_ends.len(),
+        graph.len(),
+        start.elapsed()
+    );
+    db.save_dead_ends(dead_ends)
+}
+
+/// Every pano that's in a dead-end branch, with how far the branch goes past
+/// it in meters (following the links, so it's never less than the straight
+/// distance to the end).
+fn find_dead_ends(graph: &FxHashMap<PanoId, (Location, Vec<PanoId>)>) -> FxHashMap<PanoId, u32> {
+    let mut dead_ends = FxHashMap::default();
+    for (&tip, (_, neighbors)) in graph {
+        let [first] = neighbors[..] else {
+            continue;
+        };
+        dead_ends.insert(tip, 0);
+
+        let mut visited = FxHashSet::from_iter([tip]);
+        let mut prev = tip;
+        let mut cur = first;
+        let mut reach = 0.;
+        // go back towards the rest of the network until there's a choice
+        while let Some((cur_loc, cur_neighbors)) = graph.get(&cur)
+            && cur_neighbors.len() <= 2
+            && visited.insert(cur)
+        {
+            reach += math::distance(graph[&prev].0, *cur_loc);
+            let reach_m = reach.ceil() as u32;
+            // a branch can be reached from both of its ends if it's a line that isn't
+            // connected to anything, so keep the longer reach
+            let entry = dead_ends.entry(cur).or_insert(reach_m);
+            *entry = (*entry).max(reach_m);
+
+            let Some(&next) = cur_neighbors.iter().find(|id| **id != prev) else {
+                break;
+            };
+            prev = cur;
+            cur = next;
+        }
+    }
+    dead_ends
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_dead_ends() {
+        // 1 - 2 - 3 - 4 - 5
+        //             |
+        //             6
+        let loc = |i: u32| Location::new_deg(0., i as f64 * 0.0001);
+        let graph = [
+            (1, vec![2]),
+            (2, vec![1, 3]),
+            (3, vec![2, 4]),
+            (4, vec![3, 5, 6]),
+            (5, vec![4]),
+            (6, vec![4]),
+        ]
+        .into_iter()
+        .map(|(id, neighbors)| {
+            (
+                PanoId(id),
+                (loc(id), neighbors.into_iter().map(PanoId).collect()),
+            )
+        })
+        .collect::<FxHashMap<_, _>>();
+
+        let dead_ends = find_dead_ends(&graph);
+        assert_eq!(dead_ends.get(&PanoId(1)), Some(&0));
+        // about 11m per pano
+        assert_eq!(dead_ends.get(&PanoId(3)), Some(&23));
+        assert_eq!(dead_ends.get(&PanoId(4)), None);
+        assert_eq!(dead_ends.get(&PanoId(5)), Some(&0));
+        assert_eq!(dead_ends.get(&PanoId(6)), Some(&0));
+    }
+}
@@ -0,0 +1,234 @@
+//! Comparing where the car actually goes with an "active" saved route, so we
+//! can tell when it has left the route and a new one should be found.
+
+use std::sync::LazyLock;
+
+use parking_lot::Mutex;
+use pathfinder_protocol::{CarPosition, PathResultNode};
+use serde::Serialize;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::{
+    config::CONFIG,
+    db::Db,
+    math,
+    model::{Location, SavedRoute},
+    roadtrip_api,
+};
+
+/// Only this many of the car's positions are kept for the active route.
+const MAX_POSITIONS: usize = 10_000;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Deviation {
+    pub route_id: String,
+    /// `[lat, lng]`
+    pub car: [f64; 2],
+    /// How far the car is from the route, in meters.
+    pub distance: f64,
+    /// Whether the car is further than `PATHFINDER_DEVIATION_CORRIDOR_METERS`
+    /// from the route.
+    pub off_route: bool,
+    /// The index of the last node of the route that the car was on (or close
+    /// to), if it's been on it at all.
+    pub last_on_route_node: Option<usize>,
+    /// How many of the car's positions were recorded since the route became
+    /// active.
+    pub positions_recorded: usize,
+}
+
+struct Tracker {
+    route: SavedRoute,
+    /// `[lat, lng]`
+    positions: Vec<[f64; 2]>,
+    last_on_route_node: Option<usize>,
+}
+
+/// Loaded from the database by [`track_car`].
+static TRACKER: Mutex<Option<Tracker>> = Mutex::new(None);
+
+static DEVIATION: LazyLock<watch::Sender<Option<Deviation>>> =
+    LazyLock::new(|| watch::Sender::new(None));
+
+fn new_tracker(route: SavedRoute) -> Tracker {
+    Tracker {
+        route,
+        positions: Vec::new(),
+        last_on_route_node: None,
+    }
+}
+
+/// Start comparing the car with the saved route, or stop comparing it with
+/// anything if `route_id` is `None`. Returns false if the route doesn't exist.
+pub fn set_active_route(db: &Db, route_id: Option<&str>) -> eyre::Result<bool> {
+    let route = match route_id {
+        Some(route_id) => match db.get_route(route_id) {
+            Some(route) => Some(route),
+            None => return Ok(false),
+        },
+        None => None,
+    };
+    db.set_active_route_id(route_id)?;
+    info!("Active route set to {route_id:?}");
+
+    *TRACKER.lock() = route.map(new_tracker);
+    DEVIATION.send_replace(None);
+    if let Some(position) = roadtrip_api::car_position() {
+        update(&position);
+    }
+    Ok(true)
+}
+
+/// The car's positions since the active route was set.
+pub fn active_route_positions() -> Option<(String, Vec<[f64; 2]>)> {
+    let tracker = TRACKER.lock();
+    let tracker = tracker.as_ref()?;
+    Some((tracker.route.id.clone(), tracker.positions.clone()))
+}
+
+/// How far the car is from the active route, or `None` if there's no active
+/// route or the car hasn't moved since it was set.
+pub fn current() -> Option<Deviation> {
+    DEVIATION.borrow().clone()
+}
+
+/// Get notified whenever the deviation is updated.
+pub fn subscribe() -> watch::Receiver<Option<Deviation>> {
+    DEVIATION.subscribe()
+}
+
+/// Compare the car with the active route every time it moves, starting with
+/// the route that was active when the pathfinder was last running.
+pub async fn track_car(db: &'static Db) {
+    let route = db.get_active_route_id().and_then(|id| db.get_route(&id));
+    {
+        let mut tracker = TRACKER.lock();
+        // don't replace a route that was set while we were starting up
+        if tracker.is_none() {
+            *tracker = route.map(new_tracker);
+        }
+    }
+
+    let mut positions = roadtrip_api::subscribe_car_position();
+    loop {
+        if positions.changed().await.is_err() {
+            warn!("Car position channel closed, stopping deviation tracking");
+            return;
+        }
+        let position = positions.borrow_and_update().clone();
+        if let Some(position) = position {
+            update(&position);
+        }
+    }
+}
+
+fn update(position: &CarPosition) {
+    let mut tracker = TRACKER.lock();
+    let Some(tracker) = tracker.as_mut() else {
+        return;
+    };
+
+    if tracker.positions.len() >= MAX_POSITIONS {
+        tracker.positions.remove(0);
+    }
+    tracker.positions.push(position.loc);
+
+    let nodes = &tracker.route.result.nodes;
+    let from = tracker.last_on_route_node.unwrap_or_default();
+    let Some((node, distance)) = closest_point(nodes, from, position) else {
+        return;
+    };
+    let off_route = distance > CONFIG.deviation_corridor_meters;
+    if !off_route {
+        tracker.last_on_route_node = Some(node);
+    }
+
+    DEVIATION.send_replace(Some(Deviation {
+        route_id: tracker.route.id.clone(),
+        car: position.loc,
+        distance,
+        off_route,
+        last_on_route_node: tracker.last_on_route_node,
+        positions_recorded: tracker.positions.len(),
+    }));
+}
+
+/// The node at the start of the part of the route (starting at `from`) that's
+/// closest to the car, and how far away it is in meters. Routes only go
+/// forward, so the parts before `from` aren't considered.
+fn closest_point(
+    nodes: &[PathResultNode],
+    from: usize,
+    position: &CarPosition,
+) -> Option<(usize, f64)> {
+    let rest = nodes.get(from..)?;
+    if let Some(pano) = &position.pano
+        && let Some(i) = rest.iter().position(|node| node.pano_id == *pano)
+    {
+        return Some((from + i, 0.));
+    }
+
+    let car = Location::from_latlng(position.loc);
+    if let [node] = rest {
+        return Some((from, math::distance(car, Location::from_latlng(node.loc))));
+    }
+    rest.windows(2)
+        .enumerate()
+        .map(|(i, pair)| {
+            let distance = math::approx_distance_to_segment(
+                car,
+                Location::from_latlng(pair[0].loc),
+                Location::from_latlng(pair[1].loc),
+            );
+            (from + i, distance)
+        })
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(pano_id: &str, lat: f64, lng: f64) -> PathResultNode {
+        PathResultNode {
+            pano_id: pano_id.to_owned(),
+            loc: [lat, lng],
+            heading: 0.,
+            cost: 0.,
+            option_count: 1,
+        }
+    }
+
+    fn position(pano: Option<&str>, lat: f64, lng: f64) -> CarPosition {
+        CarPosition {
+            id: 0,
+            loc: [lat, lng],
+            pano: pano.map(str::to_owned),
+            heading: 0.,
+        }
+    }
+
+    #[test]
+    fn test_closest_point_on_route() {
+        let nodes = [
+            node("a", 0., 0.),
+            node("b", 0., 0.001),
+            node("c", 0., 0.002),
+        ];
+
+        // between b and c, about 11m to the north
+        let (index, distance) = closest_point(&nodes, 0, &position(None, 0.0001, 0.0015)).unwrap();
+        assert_eq!(index, 1);
+        assert!((distance - 11.1).abs() < 0.5, "{distance}");
+
+        // the pano ID wins over the location
+        assert_eq!(
+            closest_point(&nodes, 0, &position(Some("c"), 1., 1.)),
+            Some((2, 0.))
+        );
+        // parts of the route before `from` are ignored
+        let (index, _) = closest_point(&nodes, 1, &position(None, 0., 0.)).unwrap();
+        assert_eq!(index, 1);
+    }
+}
@@ -0,0 +1,81 @@
+//! Rendering saved paths as GPX tracks.
+
+use std::fmt::Write;
+
+use crate::model::SavedPath;
+
+/// Renders the path as a GPX track. The timestamps are the time the path was
+/// created plus the cost of reaching each point, so they're roughly when the
+/// car would get there if it followed the path.
+pub fn to_gpx(path: &SavedPath, name: &str) -> String {
+    let mut gpx = String::new();
+    gpx.push_str(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    gpx.push('\n');
+    gpx.push_str(
+        r#"<gpx version="1.1" creator="internet-roadtrip-pathfinder" xmlns="http://www.topografix.com/GPX/1/1">"#,
+    );
+    gpx.push('\n');
+    let _ = writeln!(
+        gpx,
+        "  <trk>\n    <name>{}</name>\n    <trkseg>",
+        escape_xml(name)
+    );
+
+    for node in &path.nodes {
+        let (lat, lng) = (node.pano.loc.lat_deg(), node.pano.loc.lng_deg());
+        let time = format_timestamp(path.created_at as f64 + node.cost as f64);
+        let _ = writeln!(
+            gpx,
+            "      <trkpt lat=\"{lat:.7}\" lon=\"{lng:.7}\"><time>{time}</time><extensions><heading>{:.1}</heading><cost>{:.3}</cost></extensions></trkpt>",
+            node.heading, node.cost
+        );
+    }
+
+    gpx.push_str("    </trkseg>\n  </trk>\n</gpx>\n");
+    gpx
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Formats seconds since the Unix epoch as an RFC 3339 timestamp in UTC, like
+/// `2025-05-17T12:34:56Z`.
+pub fn format_timestamp(unix_secs: f64) -> String {
+    let secs = unix_secs.max(0.) as u64;
+    let days = (secs / 86400) as i64;
+    let secs_of_day = secs % 86400;
+
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs_of_day / 3600,
+        (secs_of_day / 60) % 60,
+        secs_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0.), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(951_782_400.), "2000-02-29T00:00:00Z");
+        assert_eq!(format_timestamp(1_747_485_296.), "2025-05-17T12:34:56Z");
+    }
+}
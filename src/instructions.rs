@@ -0,0 +1,108 @@
+//! Turning a path into the votes that make the car follow it.
+
+pub use pathfinder_protocol::{VoteDirection, VoteInstruction};
+
+use tokio_util::sync::CancellationToken;
+
+use crate::{astar::RouteNode, db::Db, roadtrip};
+
+/// Options that are within this many degrees of the current heading are
+/// considered to be straight ahead.
+const STRAIGHT_THRESHOLD: f32 = 30.;
+
+/// Figure out which option has to be voted for at every node of the path to
+/// get to the next one.
+pub async fn vote_instructions(
+    db: &Db,
+    nodes: &[RouteNode],
+    use_option_cache: bool,
+) -> eyre::Result<Vec<VoteInstruction>> {
+    let mut instructions = Vec::new();
+
+    let cancel = CancellationToken::new();
+    for (node_index, pair) in nodes.windows(2).enumerate() {
+        let [node, next] = pair else { unreachable!() };
+        // the boundary between two segments of a path is the same pano twice
+        if node.pano.id == next.pano.id {
+            continue;
+        }
+
+        let res = roadtrip::get_options(
+            db,
+            &node.pano,
+            node.heading,
+            true,
+            use_option_cache,
+            &cancel,
+        )
+        .await?;
+
+        let mut options = res
+            .options
+            .iter()
+            .map(|option| (relative_heading(node.heading, option.heading), option))
+            .collect::<Vec<_>>();
+        options.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+        let option_index = options
+            .iter()
+            .position(|(_, option)| option.pano.id == next.pano.id);
+
+        let direction = if res.turnaround {
+            VoteDirection::Turnaround
+        } else {
+            direction_for(relative_heading(node.heading, next.heading))
+        };
+
+        let road_name = db.lookup_road_name(&next.pano.id);
+        let road_name =
+            road_name.filter(|name| db.lookup_road_name(&node.pano.id).as_ref() != Some(name));
+
+        instructions.push(VoteInstruction {
+            node_index,
+            option_index,
+            option_count: options.len(),
+            direction,
+            heading: next.heading,
+            road_name,
+        });
+    }
+
+    Ok(instructions)
+}
+
+/// The heading relative to the current one, between -180 (left) and 180
+/// (right).
+fn relative_heading(current: f32, heading: f32) -> f32 {
+    (heading - current + 540.).rem_euclid(360.) - 180.
+}
+
+fn direction_for(relative_heading: f32) -> VoteDirection {
+    if relative_heading < -STRAIGHT_THRESHOLD {
+        VoteDirection::Left
+    } else if relative_heading > STRAIGHT_THRESHOLD {
+        VoteDirection::Right
+    } else {
+        VoteDirection::Straight
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_direction_for() {
+        assert_eq!(
+            direction_for(relative_heading(350., 10.)),
+            VoteDirection::Straight
+        );
+        assert_eq!(
+            direction_for(relative_heading(10., 280.)),
+            VoteDirection::Left
+        );
+        assert_eq!(
+            direction_for(relative_heading(270., 0.)),
+            VoteDirection::Right
+        );
+    }
+}
@@ -0,0 +1,237 @@
+//! Everywhere that the car can reach within a cost budget. This is the same
+//! search as [`crate::astar`], except that it has no goal and it stops
+//! expanding nodes once they cost more than the budget.
+
+use std::{collections::BinaryHeap, sync::Arc};
+
+use geo::ConcaveHull;
+use indexmap::IndexMap;
+use rustc_hash::FxHashMap;
+use tracing::info;
+
+use crate::{
+    astar::{self, CaptureDateFilter, Cost, FxIndexMap, NodeIdent, PathSettings, WeightedNode},
+    cost::{DefaultCostModel, Edge, straightest_option_index},
+    db::Db,
+    model::{Location, Pano, PanoId},
+    roadtrip, streetview,
+};
+
+pub struct Reachable {
+    /// Every pano that can be reached within the budget.
+    pub panos: Vec<Pano>,
+    pub nodes_considered: usize,
+    /// Whether the search was stopped early (because of `max_nodes`,
+    /// `node_budget` or cancellation), which means that more panos might be
+    /// reachable.
+    pub truncated: bool,
+}
+
+/// Find every pano that can be reached from the start with a path that costs
+/// at most `budget`.
+pub async fn reachable(
+    db: &Db,
+    start: Pano,
+    heading: f32,
+    budget: Cost,
+    settings: &PathSettings,
+) -> eyre::Result<Reachable> {
+    let cost_model = settings.cost_model.clone().unwrap_or_else(|| {
+        Arc::new(DefaultCostModel {
+            vote_delays: db.vote_delays(),
+            forward_penalty_on_intersections: settings.forward_penalty_on_intersections,
+            non_sharp_turn_penalty: settings.non_sharp_turn_penalty,
+            intersection_uncertainty: settings.intersection_uncertainty,
+        })
+    });
+
+    let mut open_set = BinaryHeap::new();
+    open_set.push(WeightedNode {
+        index: 0,
+        g_score: 0 as Cost,
+        f_score: 0 as Cost,
+    });
+    // there's no path to reconstruct, so we only need the g scores
+    let mut nodes: FxIndexMap<NodeIdent, Cost> = IndexMap::default();
+    nodes.insert(
+        NodeIdent {
+            pano: start,
+            heading,
+            waypoints_reached: 0,
+        },
+        0 as Cost,
+    );
+    let mut panos = FxHashMap::<PanoId, Pano>::default();
+    let mut capture_dates = CaptureDateFilter::default();
+
+    let mut nodes_considered = 0_usize;
+    let mut truncated = false;
+    let mut allow_turnaround = true;
+
+    while let Some(WeightedNode { index, g_score, .. }) = open_set.pop() {
+        nodes_considered += 1;
+        if settings.cancel.is_cancelled()
+            || settings.max_nodes.is_some_and(|max| nodes_considered > max)
+            || settings.node_budget.is_some_and(|max| nodes.len() > max)
+        {
+            truncated = true;
+            break;
+        }
+
+        let (node, &node_g_score) = nodes.get_index(index as usize).unwrap();
+        if g_score > node_g_score {
+            continue;
+        }
+        panos.insert(node.pano.id, node.pano);
+
+        let neighbors = roadtrip::get_options(
+            db,
+            &node.pano,
+            node.heading,
+            allow_turnaround,
+            settings.use_option_cache,
+            &settings.cancel,
+        )
+        .await;
+        let neighbors = match neighbors {
+            Ok(neighbors) => neighbors,
+            Err(err) if err.is::<streetview::Cancelled>() => {
+                truncated = true;
+                break;
+            }
+            Err(err) => return Err(err),
+        };
+        if neighbors.turnaround {
+            allow_turnaround = false;
+        }
+
+        let from = node.clone();
+        let option_count = neighbors.options.len();
+        let straightest_option_index = straightest_option_index(&neighbors.options, from.heading);
+        let approx_lng_m_per_degree = from.pano.loc.calculate_lng_m_per_degree();
+
+        for (i, neighbor) in neighbors.options.iter().enumerate() {
+            let Some(penalty) = astar::allowed_neighbor_penalty(
+                db,
+                settings,
+                &mut capture_dates,
+                from.pano.loc,
+                neighbor,
+                approx_lng_m_per_degree,
+            ) else {
+                continue;
+            };
+
+            let tentative_g_score = g_score
+                + cost_model.edge_cost(&Edge {
+                    from: &from,
+                    to: neighbor,
+                    option_index: i,
+                    option_count,
+                    straightest_option_index,
+                })
+                + penalty;
+            if tentative_g_score > budget {
+                continue;
+            }
+
+            let neighbor_node = NodeIdent {
+                pano: neighbor.pano,
+                heading: neighbor.heading,
+                waypoints_reached: 0,
+            };
+            let neighbor_index = match nodes.entry(neighbor_node) {
+                indexmap::map::Entry::Occupied(mut e) => {
+                    if tentative_g_score >= *e.get() {
+                        continue;
+                    }
+                    e.insert(tentative_g_score);
+                    e.index()
+                }
+                indexmap::map::Entry::Vacant(e) => {
+                    let index = e.index();
+                    e.insert(tentative_g_score);
+                    index
+                }
+            };
+            open_set.push(WeightedNode {
+                index: neighbor_index as u32,
+                g_score: tentative_g_score,
+                f_score: tentative_g_score,
+            });
+        }
+    }
+
+    info!(
+        "Found {} reachable panos after considering {nodes_considered} nodes",
+        panos.len()
+    );
+
+    Ok(Reachable {
+        panos: panos.into_values().collect(),
+        nodes_considered,
+        truncated,
+    })
+}
+
+/// How closely the hull follows the reachable panos. Lower values give a
+/// tighter (but more jagged) outline, see [`ConcaveHull`].
+const HULL_CONCAVITY: f64 = 2.;
+
+/// A concave polygon that contains all of the locations, as a closed ring.
+/// Unlike a convex hull, it doesn't cover the unreachable areas between roads
+/// that go in different directions.
+pub fn hull(locations: &[Location]) -> Vec<Location> {
+    let points = locations
+        .iter()
+        .map(|loc| geo::Point::new(loc.lng_deg(), loc.lat_deg()))
+        .collect::<geo::MultiPoint>();
+    points
+        .concave_hull(HULL_CONCAVITY)
+        .exterior()
+        .coords()
+        .map(|c| Location::new_deg(c.y, c.x))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::Contains;
+
+    use super::*;
+
+    #[test]
+    fn test_hull_skips_inner_points() {
+        let hull = hull(&[
+            Location::new_deg(0., 0.),
+            Location::new_deg(0., 1.),
+            Location::new_deg(1., 1.),
+            Location::new_deg(1., 0.),
+            Location::new_deg(0.5, 0.5),
+        ]);
+        // 4 corners, and the first one is repeated to close the ring
+        assert_eq!(hull.len(), 5);
+        assert_eq!(hull.first(), hull.last());
+        assert!(!hull.contains(&Location::new_deg(0.5, 0.5)));
+    }
+
+    #[test]
+    fn test_hull_follows_an_l_shape() {
+        // two roads that meet at the origin, one going north and one going east
+        let mut locations = Vec::new();
+        for i in 0..=20 {
+            let d = i as f64 * 0.0005;
+            locations.push(Location::new_deg(d, 0.));
+            locations.push(Location::new_deg(0., d));
+        }
+        let hull = hull(&locations);
+        let polygon = geo::Polygon::new(
+            hull.iter()
+                .map(|loc| geo::coord! { x: loc.lng_deg(), y: loc.lat_deg() })
+                .collect(),
+            vec![],
+        );
+        // the corner between the ends of the roads isn't reachable
+        assert!(!polygon.contains(&geo::Point::new(0.008, 0.008)));
+    }
+}
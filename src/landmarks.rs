@@ -0,0 +1,314 @@
+//! Landmarks for the ALT (A*, landmarks, triangle inequality) heuristic. A few
+//! nodes of the baked graph (see [`crate::bake`]) are picked as landmarks, and
+//! the cheapest costs from and to each of them are stored for every baked
+//! node. Since a path can't be cheaper than going around a landmark allows,
+//!
+//! `cost(node, goal) >= cost(landmark, goal) - cost(landmark, node)` and
+//! `cost(node, goal) >= cost(node, landmark) - cost(goal, landmark)`,
+//!
+//! which is usually a much tighter bound than the straight-line distance.
+//!
+//! The costs are calculated with the default cost model and no penalties, and
+//! penalties only ever make paths more expensive, so the bounds still hold for
+//! queries that have them. They don't hold for custom cost models, and paths
+//! that leave the baked region and come back, or that use learned options, can
+//! be cheaper than the bound. The costs are also between heading buckets
+//! instead of real headings. Since the bound can overestimate, it's only used
+//! when `PATHFINDER_USE_LANDMARKS` is enabled.
+
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Instant,
+};
+
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
+use tracing::{debug, error, info};
+
+use crate::{
+    astar::{Cost, NodeIdent},
+    bake,
+    config::CONFIG,
+    cost::{CostModel, DefaultCostModel, Edge, straightest_option_index},
+    db::Db,
+    model::{Location, Pano, PanoId},
+    streetview,
+};
+
+type BakedNode = (PanoId, u16);
+
+/// Panos this close to the goal might be where the search ends, see
+/// `is_goal_reached` in [`crate::astar`].
+const GOAL_RADIUS: f64 = 30.;
+
+/// The cheapest costs between a node and one of the landmarks, which are
+/// infinite if there's no path.
+#[derive(Debug, Clone, Copy)]
+pub struct LandmarkCosts {
+    pub from_landmark: f32,
+    pub to_landmark: f32,
+}
+
+/// The costs between the landmarks and the nodes that the search might end
+/// at, for bounding the cost from any node to the goal.
+pub struct GoalLandmarks<'a> {
+    db: &'a Db,
+    /// The cheapest cost from each landmark to any of the goal nodes.
+    from_landmark: Box<[f32]>,
+    /// The most expensive cost from any of the goal nodes to each landmark.
+    to_landmark: Box<[f32]>,
+    /// The costs of the nodes that were already looked up in this search.
+    node_costs: Mutex<FxHashMap<BakedNode, Option<Box<[LandmarkCosts]>>>>,
+}
+impl<'a> GoalLandmarks<'a> {
+    /// Returns `None` if there are no landmarks near the goal, which happens if
+    /// it isn't in a baked region.
+    pub async fn new(db: &'a Db, goal: Location) -> Option<Self> {
+        let panos = streetview::get_nearby_panos(db, goal, GOAL_RADIUS)
+            .await
+            .ok()?;
+        let mut from_landmark: Option<Box<[f32]>> = None;
+        let mut to_landmark: Option<Box<[f32]>> = None;
+        for pano in panos.iter() {
+            for costs in db.lookup_landmark_costs_for_pano(pano.id) {
+                let from =
+                    from_landmark.get_or_insert_with(|| vec![f32::INFINITY; costs.len()].into());
+                let to = to_landmark.get_or_insert_with(|| vec![0.; costs.len()].into());
+                if from.len() != costs.len() {
+                    // the landmarks are being replaced
+                    return None;
+                }
+                for (i, costs) in costs.iter().enumerate() {
+                    from[i] = from[i].min(costs.from_landmark);
+                    to[i] = to[i].max(costs.to_landmark);
+                }
+            }
+        }
+        Some(Self {
+            db,
+            from_landmark: from_landmark?,
+            to_landmark: to_landmark?,
+            node_costs: Mutex::default(),
+        })
+    }
+
+    /// A lower bound for the cost from the node to the goal, if the node was
+    /// baked.
+    pub fn lower_bound(&self, node: &NodeIdent) -> Option<Cost> {
+        let key = (node.pano.id, bake::heading_bucket(node.heading));
+        let mut node_costs = self.node_costs.lock();
+        let costs = node_costs
+            .entry(key)
+            .or_insert_with(|| self.db.lookup_landmark_costs(key.0, key.1))
+            .as_deref()?;
+        if costs.len() != self.from_landmark.len() {
+            return None;
+        }
+        let mut bound: f32 = 0.;
+        for (i, costs) in costs.iter().enumerate() {
+            // infinite costs don't tell us anything, since the path might leave the
+            // baked region
+            let before = self.from_landmark[i] - costs.from_landmark;
+            if before.is_finite() {
+                bound = bound.max(before);
+            }
+            let after = costs.to_landmark - self.to_landmark[i];
+            if after.is_finite() {
+                bound = bound.max(after);
+            }
+        }
+        Some(bound as Cost)
+    }
+}
+
+/// The baked graph, with edges in both directions.
+struct Graph {
+    nodes: Vec<(PanoId, u16)>,
+    forward: Vec<Vec<(u32, f32)>>,
+    backward: Vec<Vec<(u32, f32)>>,
+}
+
+/// Pick up to `landmark_count` landmarks and save the costs from and to them
+/// for every baked node, replacing the old ones. Returns the number of nodes.
+pub fn precompute(db: &Db, landmark_count: usize) -> eyre::Result<usize> {
+    let start = Instant::now();
+    let graph = load_graph(db);
+    let node_count = graph.nodes.len();
+    if node_count == 0 {
+        return Ok(0);
+    }
+    let landmark_count = landmark_count.min(node_count);
+
+    let mut costs = vec![
+        LandmarkCosts {
+            from_landmark: f32::INFINITY,
+            to_landmark: f32::INFINITY,
+        };
+        node_count * landmark_count
+    ];
+    // farthest landmark selection: each landmark is the node that's furthest from
+    // the ones that were already picked, starting with the node furthest from an
+    // arbitrary one
+    let mut closest_landmark = dijkstra(&graph.forward, 0);
+    for landmark_index in 0..landmark_count {
+        let landmark = farthest(&closest_landmark);
+        let from_landmark = dijkstra(&graph.forward, landmark);
+        let to_landmark = dijkstra(&graph.backward, landmark);
+        for node in 0..node_count {
+            costs[node * landmark_count + landmark_index] = LandmarkCosts {
+                from_landmark: from_landmark[node],
+                to_landmark: to_landmark[node],
+            };
+            if landmark_index == 0 {
+                closest_landmark[node] = from_landmark[node];
+            } else {
+                closest_landmark[node] = closest_landmark[node].min(from_landmark[node]);
+            }
+        }
+        debug!(
+            "Landmark {landmark_index} is {:?}",
+            graph.nodes[landmark as usize]
+        );
+    }
+
+    db.save_landmark_costs(&graph.nodes, &costs)?;
+    info!(
+        "Calculated costs for {landmark_count} landmarks and {node_count} nodes in {:?}",
+        start.elapsed()
+    );
+    Ok(node_count)
+}
+
+fn load_graph(db: &Db) -> Graph {
+    let cost_model = DefaultCostModel {
+        vote_delays: db.vote_delays(),
+        forward_penalty_on_intersections: 0.,
+        non_sharp_turn_penalty: 0.,
+        intersection_uncertainty: 0.,
+    };
+
+    let mut options = Vec::new();
+    db.slow_for_each_baked_node(|pano_id, bucket, res| options.push((pano_id, bucket, res)));
+    let indexes = options
+        .iter()
+        .enumerate()
+        .map(|(i, (pano_id, bucket, _))| ((*pano_id, *bucket), i as u32))
+        .collect::<FxHashMap<_, _>>();
+
+    let mut graph = Graph {
+        nodes: options
+            .iter()
+            .map(|(pano_id, bucket, _)| (*pano_id, *bucket))
+            .collect(),
+        forward: vec![Vec::new(); options.len()],
+        backward: vec![Vec::new(); options.len()],
+    };
+    for (from_index, (pano_id, bucket, res)) in options.iter().enumerate() {
+        let heading = bake::bucket_heading(*bucket);
+        // the search turns around at nodes without options, which we can treat as
+        // if the options from the other direction were this node's
+        let res = if res.options.is_empty() {
+            let turnaround = (*pano_id, bake::heading_bucket(heading + 180.));
+            match indexes.get(&turnaround) {
+                Some(&i) => &options[i as usize].2,
+                None => continue,
+            }
+        } else {
+            res
+        };
+
+        let from = NodeIdent {
+            pano: Pano {
+                id: *pano_id,
+                loc: Location::new_deg(0., 0.),
+            },
+            heading,
+            waypoints_reached: 0,
+        };
+        let straightest_option_index = straightest_option_index(&res.options, heading);
+        for (i, option) in res.options.iter().enumerate() {
+            let Some(&to_index) =
+                indexes.get(&(option.pano.id, bake::heading_bucket(option.heading)))
+            else {
+                continue;
+            };
+            let cost = cost_model
+                .edge_cost(&Edge {
+                    from: &from,
+                    to: option,
+                    option_index: i,
+                    option_count: res.options.len(),
+                    straightest_option_index,
+                })
+                .max(0.);
+            graph.forward[from_index].push((to_index, cost));
+            graph.backward[to_index as usize].push((from_index as u32, cost));
+        }
+    }
+    graph
+}
+
+/// The cheapest cost from `start` to every node, or infinity if there's no
+/// path.
+fn dijkstra(edges: &[Vec<(u32, f32)>], start: u32) -> Vec<f32> {
+    let mut costs = vec![f32::INFINITY; edges.len()];
+    costs[start as usize] = 0.;
+    // costs are never negative, so their bits sort the same way they do
+    let mut open_set = BinaryHeap::from([Reverse((0_u32, start))]);
+    while let Some(Reverse((cost_bits, node))) = open_set.pop() {
+        let cost = f32::from_bits(cost_bits);
+        if cost > costs[node as usize] {
+            continue;
+        }
+        for &(neighbor, edge_cost) in &edges[node as usize] {
+            let new_cost = cost + edge_cost;
+            if new_cost < costs[neighbor as usize] {
+                costs[neighbor as usize] = new_cost;
+                open_set.push(Reverse((new_cost.to_bits(), neighbor)));
+            }
+        }
+    }
+    costs
+}
+
+/// The node with the highest finite cost.
+fn farthest(costs: &[f32]) -> u32 {
+    costs
+        .iter()
+        .enumerate()
+        .filter(|(_, cost)| cost.is_finite())
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(i, _)| i as u32)
+        .unwrap_or_default()
+}
+
+/// Calculate the landmarks in the background, logging any errors. Does
+/// nothing if they're already being calculated.
+pub fn spawn_precompute(db: &'static Db) {
+    static RUNNING: AtomicBool = AtomicBool::new(false);
+    if RUNNING.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    tokio::task::spawn_blocking(move || {
+        if let Err(err) = precompute(db, CONFIG.landmark_count) {
+            error!("Failed to calculate landmarks: {err}");
+        }
+        RUNNING.store(false, Ordering::Relaxed);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dijkstra() {
+        // 0 -> 1 -> 2, and a slower 0 -> 2
+        let edges = vec![vec![(1, 1.), (2, 5.)], vec![(2, 1.)], vec![]];
+        assert_eq!(dijkstra(&edges, 0), vec![0., 1., 2.]);
+        assert_eq!(dijkstra(&edges, 2), vec![f32::INFINITY, f32::INFINITY, 0.]);
+        assert_eq!(farthest(&dijkstra(&edges, 0)), 2);
+    }
+}
@@ -0,0 +1,452 @@
+//! Corrections to our emulation of the game's options, learned by comparing the
+//! options that the game actually offered the car with the ones that
+//! [`roadtrip::get_options`] would've generated at the same pano and heading.
+//!
+//! An option that we keep generating but the game never offers becomes a
+//! restriction, and an option that the game keeps offering but we never
+//! generate becomes an addition.
+//!
+//! The options are compared live as the car moves (see
+//! [`spawn_observation_recorder`]), and [`learn_from_history`] also goes over
+//! the recorded car history for the moves that happened while we weren't
+//! watching.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use rustc_hash::FxHashMap;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    db::Db,
+    model::{Location, Pano, PanoId},
+    option_accuracy,
+    roadtrip::{self, BasePanoOptionsRes, PanoOptionRes},
+};
+
+/// Headings are grouped into buckets of this many degrees, since the car's
+/// heading won't always be exactly the same when it reaches a pano.
+const HEADING_BUCKET_SIZE: f32 = 10.;
+/// The number of times a difference has to be observed before we trust it.
+const MIN_OBSERVATIONS: u32 = 2;
+/// The fraction of observations that have to agree for a difference to be
+/// applied.
+const MIN_AGREEMENT: f32 = 0.9;
+/// How many observations can be waiting to be compared before new ones are
+/// dropped.
+const MAX_QUEUED_OBSERVATIONS: usize = 64;
+/// Consecutive car history entries that are further apart than this (in
+/// milliseconds) aren't treated as a move from one to the other, since we
+/// might've missed the positions in between.
+const MAX_MOVE_GAP_MS: u64 = 60_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LearnedOptionsKey {
+    pub pano: PanoId,
+    pub heading_bucket: u8,
+}
+impl LearnedOptionsKey {
+    pub fn new(pano: PanoId, heading: f32) -> Self {
+        let bucket_count = (360. / HEADING_BUCKET_SIZE) as u32;
+        let heading_bucket =
+            (heading.rem_euclid(360.) / HEADING_BUCKET_SIZE).round() as u32 % bucket_count;
+        Self {
+            pano,
+            heading_bucket: heading_bucket as u8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LearnedOptions {
+    /// The number of times the car was seen at this pano and heading.
+    pub observations: u32,
+    /// Options that we generated but the game didn't offer, and the number of
+    /// times that happened.
+    pub restrictions: Vec<(PanoId, u32)>,
+    /// Options that the game offered but we didn't generate, and the number of
+    /// times that happened.
+    pub additions: Vec<(PanoOptionRes, u32)>,
+}
+impl LearnedOptions {
+    fn is_trusted(&self, count: u32) -> bool {
+        count >= MIN_OBSERVATIONS && count as f32 >= self.observations as f32 * MIN_AGREEMENT
+    }
+
+    /// Modify the emulated options so they match what the game was observed to
+    /// do.
+    pub fn apply(&self, options: &mut Vec<PanoOptionRes>) {
+        for &(pano_id, count) in &self.restrictions {
+            if self.is_trusted(count) {
+                options.retain(|o| o.pano.id != pano_id);
+            }
+        }
+        for (option, count) in &self.additions {
+            if self.is_trusted(*count) && !options.iter().any(|o| o.pano.id == option.pano.id) {
+                options.push(option.clone());
+            }
+        }
+    }
+
+    /// Record the options that the car was seen taking from here, and how many
+    /// times. That only shows that they were offered, so they can become
+    /// additions and stop being restrictions, but nothing new is restricted and
+    /// it doesn't count as an observation. Counts are raised to the number of
+    /// times instead of added to, so going over the same history again doesn't
+    /// count the moves twice. Returns whether anything changed.
+    fn record_taken<'a>(
+        &mut self,
+        emulated: &[PanoOptionRes],
+        taken: impl IntoIterator<Item = &'a (PanoOptionRes, u32)>,
+    ) -> bool {
+        let mut changed = false;
+        for (option, times) in taken {
+            let restrictions = self.restrictions.len();
+            self.restrictions.retain(|(id, _)| *id != option.pano.id);
+            changed |= self.restrictions.len() != restrictions;
+
+            if emulated.iter().any(|o| o.pano.id == option.pano.id) {
+                continue;
+            }
+            match self
+                .additions
+                .iter_mut()
+                .find(|(o, _)| o.pano.id == option.pano.id)
+            {
+                Some((_, count)) if *count >= *times => {}
+                Some((_, count)) => {
+                    *count = *times;
+                    changed = true;
+                }
+                None => {
+                    self.additions.push((option.clone(), *times));
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+
+    fn record(&mut self, emulated: &[PanoOptionRes], offered: &[PanoOptionRes]) {
+        self.observations += 1;
+
+        for option in emulated {
+            if offered.iter().any(|o| o.pano.id == option.pano.id) {
+                continue;
+            }
+            match self
+                .restrictions
+                .iter_mut()
+                .find(|(id, _)| *id == option.pano.id)
+            {
+                Some((_, count)) => *count += 1,
+                None => self.restrictions.push((option.pano.id, 1)),
+            }
+        }
+        for option in offered {
+            if emulated.iter().any(|o| o.pano.id == option.pano.id) {
+                continue;
+            }
+            match self
+                .additions
+                .iter_mut()
+                .find(|(o, _)| o.pano.id == option.pano.id)
+            {
+                Some((_, count)) => *count += 1,
+                None => self.additions.push((option.clone(), 1)),
+            }
+        }
+    }
+}
+
+/// Apply the learned corrections (if any) for the given pano and heading.
+pub fn apply_learned_options(db: &Db, pano_id: PanoId, heading: f32, res: &mut BasePanoOptionsRes) {
+    let learned = db.learned_options.read();
+    if let Some(learned) = learned.get(&LearnedOptionsKey::new(pano_id, heading)) {
+        let mut options = res.options.to_vec();
+        learned.apply(&mut options);
+        res.options = options.into();
+    }
+}
+
+/// A pano and heading that the car was at, and the options that the game
+/// offered it there.
+pub struct CarObservation {
+    pub pano: Pano,
+    pub heading: f32,
+    pub offered: Vec<PanoOptionRes>,
+}
+
+/// Compare the observations that are sent to the returned channel in the
+/// background, so the IRT WebSocket doesn't have to wait for the options to be
+/// generated. Observations are dropped if too many are waiting.
+pub fn spawn_observation_recorder(db: &'static Db) -> mpsc::Sender<CarObservation> {
+    let (tx, mut rx) = mpsc::channel(MAX_QUEUED_OBSERVATIONS);
+    tokio::spawn(async move {
+        while let Some(observation) = rx.recv().await {
+            if let Err(e) = record_observation(db, observation).await {
+                warn!("Failed to record car observation: {e}");
+            }
+        }
+    });
+    tx
+}
+
+/// Compare the options that the game offered with the ones we would've
+/// generated, and save any differences.
+pub async fn record_observation(db: &Db, observation: CarObservation) -> eyre::Result<()> {
+    let CarObservation {
+        pano,
+        heading,
+        offered,
+    } = observation;
+
+    // this is what the pathfinder would've used, corrections and all
+    let cancel = CancellationToken::new();
+    let predicted = roadtrip::get_options_no_turnaround(db, &pano, heading, true, &cancel).await?;
+    option_accuracy::record(db, pano.id, heading, &predicted.options, &offered)?;
+
+    // we intentionally use the raw emulation here so the corrections don't
+    // affect what we're comparing against
+    let emulated = roadtrip::emulate_options(db, &pano, heading, false, &cancel).await?;
+
+    let key = LearnedOptionsKey::new(pano.id, heading);
+    let mut learned = db
+        .learned_options
+        .read()
+        .get(&key)
+        .cloned()
+        .unwrap_or_default();
+    learned.record(&emulated.options, &offered);
+
+    if !learned.restrictions.is_empty() || !learned.additions.is_empty() {
+        debug!("Learned options at {pano:?} (heading {heading}): {learned:?}");
+    }
+
+    db.save_learned_options(&key, &learned)?;
+    db.learned_options.write().insert(key, learned);
+
+    Ok(())
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct HistoryLearningStats {
+    /// The number of panos and headings that the car was seen leaving.
+    pub junctions: usize,
+    /// How many of them had their learned options changed.
+    pub changed: usize,
+}
+
+/// Go over the car history and learn from the moves that the car made, see
+/// [`LearnedOptions::record_taken`]. This can be done any number of times,
+/// since it doesn't count the same moves twice.
+pub async fn learn_from_history(db: &Db) -> eyre::Result<HistoryLearningStats> {
+    let history = db.car_history(0, u64::MAX, usize::MAX);
+
+    // where the car went from each pano and heading, and how many times
+    let mut moves =
+        FxHashMap::<LearnedOptionsKey, (Pano, f32, Vec<(PanoOptionRes, u32)>)>::default();
+    for pair in history.windows(2) {
+        let [from, to] = pair else { unreachable!() };
+        let (Some(from_pano), Some(to_pano)) = (from.pano, to.pano) else {
+            continue;
+        };
+        if from_pano == to_pano || to.timestamp.saturating_sub(from.timestamp) > MAX_MOVE_GAP_MS {
+            continue;
+        }
+        let (_, _, taken) = moves
+            .entry(LearnedOptionsKey::new(from_pano, from.heading))
+            .or_insert_with(|| {
+                let loc = db
+                    .lookup_getmetadata_location(&from_pano)
+                    .unwrap_or(from.loc);
+                (Pano { id: from_pano, loc }, from.heading, Vec::new())
+            });
+        match taken.iter_mut().find(|(o, _)| o.pano.id == to_pano) {
+            Some((_, times)) => *times += 1,
+            None => taken.push((
+                PanoOptionRes {
+                    pano: Pano {
+                        id: to_pano,
+                        loc: db.lookup_getmetadata_location(&to_pano).unwrap_or(to.loc),
+                    },
+                    // the car faces the way the option went
+                    heading: to.heading,
+                },
+                1,
+            )),
+        }
+    }
+
+    let mut stats = HistoryLearningStats {
+        junctions: moves.len(),
+        ..Default::default()
+    };
+    let cancel = CancellationToken::new();
+    for (key, (pano, heading, taken)) in moves {
+        let emulated = roadtrip::emulate_options(db, &pano, heading, false, &cancel).await?;
+        let learned = {
+            let mut learned_options = db.learned_options.write();
+            let mut learned = learned_options.get(&key).cloned().unwrap_or_default();
+            if !learned.record_taken(&emulated.options, &taken) {
+                continue;
+            }
+            learned_options.insert(key, learned.clone());
+            learned
+        };
+        db.save_learned_options(&key, &learned)?;
+        stats.changed += 1;
+    }
+    info!(
+        "Learned options from the car history at {} of {} junctions",
+        stats.changed, stats.junctions
+    );
+    Ok(stats)
+}
+
+/// Run [`learn_from_history`] in the background, logging any errors. Does
+/// nothing if it's already running.
+pub fn spawn_learn_from_history(db: &'static Db) {
+    static RUNNING: AtomicBool = AtomicBool::new(false);
+    if RUNNING.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    tokio::spawn(async move {
+        if let Err(err) = learn_from_history(db).await {
+            error!("Failed to learn options from the car history: {err}");
+        }
+        RUNNING.store(false, Ordering::Relaxed);
+    });
+}
+
+/// Parse the car's pano and options from a message from the IRT WebSocket.
+pub fn parse_car_observation(db: &Db, data: &simd_json::OwnedValue) -> Option<CarObservation> {
+    use simd_json::{
+        base::{ValueAsArray, ValueAsScalar},
+        derived::ValueObjectAccess,
+    };
+
+    let as_f64 = |v: &simd_json::OwnedValue| v.as_f64().or_else(|| v.as_i64().map(|i| i as f64));
+
+    let pano_id = data.get("pano")?.as_str()?;
+    let heading = as_f64(data.get("heading")?)? as f32;
+    let car_loc = Location::new_deg(as_f64(data.get("lat")?)?, as_f64(data.get("lng")?)?);
+
+    let pano_id = db.get_pano_id(pano_id).ok()?;
+    let pano = Pano {
+        id: pano_id,
+        loc: db.lookup_getmetadata_location(&pano_id).unwrap_or(car_loc),
+    };
+
+    let mut offered = Vec::new();
+    for option in data.get("options")?.as_array()? {
+        let Some(option_pano_id) = option.get("pano").and_then(|p| p.as_str()) else {
+            continue;
+        };
+        let Some(option_heading) = option.get("heading").and_then(as_f64) else {
+            continue;
+        };
+        let Ok(option_pano_id) = db.get_pano_id(option_pano_id) else {
+            continue;
+        };
+        let option_loc = match (
+            option.get("lat").and_then(as_f64),
+            option.get("lng").and_then(as_f64),
+        ) {
+            (Some(lat), Some(lng)) => Some(Location::new_deg(lat, lng)),
+            _ => db.lookup_getmetadata_location(&option_pano_id),
+        };
+        let Some(option_loc) = option_loc else {
+            // we need to know where the option goes for it to be usable
+            continue;
+        };
+        offered.push(PanoOptionRes {
+            pano: Pano {
+                id: option_pano_id,
+                loc: option_loc,
+            },
+            heading: option_heading as f32,
+        });
+    }
+
+    Some(CarObservation {
+        pano,
+        heading,
+        offered,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn option(id: u32, heading: f32) -> PanoOptionRes {
+        PanoOptionRes {
+            pano: Pano {
+                id: PanoId(id),
+                loc: Location::new_deg(0., 0.),
+            },
+            heading,
+        }
+    }
+
+    #[test]
+    fn test_learned_options_need_repeated_observations() {
+        let emulated = [option(1, 0.), option(2, 90.)];
+        let offered = [option(1, 0.), option(3, 270.)];
+
+        let mut learned = LearnedOptions::default();
+        learned.record(&emulated, &offered);
+
+        let mut options = emulated.to_vec();
+        learned.apply(&mut options);
+        assert_eq!(
+            options.iter().map(|o| o.pano.id.0).collect::<Vec<_>>(),
+            [1, 2]
+        );
+
+        learned.record(&emulated, &offered);
+        let mut options = emulated.to_vec();
+        learned.apply(&mut options);
+        assert_eq!(
+            options.iter().map(|o| o.pano.id.0).collect::<Vec<_>>(),
+            [1, 3]
+        );
+    }
+
+    #[test]
+    fn test_taken_options_are_only_counted_once() {
+        let emulated = [option(1, 0.), option(2, 90.)];
+        let taken = [(option(2, 90.), 1), (option(3, 270.), 2)];
+
+        let mut learned = LearnedOptions {
+            restrictions: vec![(PanoId(2), 5)],
+            ..Default::default()
+        };
+        assert!(learned.record_taken(&emulated, &taken));
+        // the car went there, so it can't be restricted
+        assert!(learned.restrictions.is_empty());
+        assert_eq!(learned.additions.len(), 1);
+        assert_eq!(learned.additions[0].1, 2);
+
+        // going over the same history again doesn't change anything
+        assert!(!learned.record_taken(&emulated, &taken));
+        assert_eq!(learned.additions[0].1, 2);
+
+        let mut options = emulated.to_vec();
+        learned.apply(&mut options);
+        assert_eq!(
+            options.iter().map(|o| o.pano.id.0).collect::<Vec<_>>(),
+            [1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn test_heading_buckets_wrap() {
+        let a = LearnedOptionsKey::new(PanoId(0), 359.);
+        let b = LearnedOptionsKey::new(PanoId(0), 1.);
+        assert_eq!(a, b);
+    }
+}
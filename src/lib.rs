@@ -1,12 +1,32 @@
-use serde::{Deserialize, Serialize};
+pub use pathfinder_protocol::FullProgressUpdate;
 
 pub mod astar;
+pub mod backup;
+pub mod bake;
+pub mod cache_stats;
+pub mod calibration;
+pub mod config;
+pub mod cost;
 pub mod db;
+pub mod dead_ends;
+pub mod deviation;
+pub mod gpx;
+pub mod instructions;
+pub mod isochrone;
+pub mod landmarks;
+pub mod learned_options;
 pub mod math;
 pub mod model;
+pub mod mvt;
+pub mod option_accuracy;
+pub mod portals;
+pub mod replan;
 pub mod roadtrip;
 pub mod roadtrip_api;
+pub mod shortcuts;
+pub mod stop_order;
 pub mod streetview;
+pub mod units;
 pub mod web;
 
 pub struct ProgressUpdate {
@@ -15,28 +35,17 @@ pub struct ProgressUpdate {
     pub estimated_seconds_remaining: f64,
     pub best_path_cost: astar::Cost,
     pub nodes_considered: usize,
+    /// How close the search is to its node budget, between 0 and 1. Always 0
+    /// if there's no budget.
+    pub memory_pressure: f64,
+    /// The number of nodes that the search is keeping in memory.
+    pub stored_nodes: usize,
     pub best_path: Box<[[f32; 2]]>,
+    /// The cost of the best path up to each of its points.
+    pub best_path_costs: Box<[astar::Cost]>,
     pub current_path: Box<[[f32; 2]]>,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct FullProgressUpdate {
-    pub id: u32,
-
-    /// Between 0 and 1
-    pub percent_done: f64,
-    pub estimated_seconds_remaining: f64,
-    pub best_path_cost: astar::Cost,
-    pub nodes_considered: usize,
-    pub elapsed_seconds: f64,
-
-    pub best_path_keep_prefix_length: usize,
-    pub best_path_append: Box<[[f32; 2]]>,
-
-    pub current_path_keep_prefix_length: usize,
-    pub current_path_append: Box<[[f32; 2]]>,
-}
-
 impl Default for ProgressUpdate {
     fn default() -> Self {
         Self {
@@ -44,26 +53,11 @@ impl Default for ProgressUpdate {
             estimated_seconds_remaining: -1.,
             best_path_cost: 0 as astar::Cost,
             nodes_considered: 0,
+            memory_pressure: 0.,
+            stored_nodes: 0,
             best_path: Box::new([]),
+            best_path_costs: Box::new([]),
             current_path: Box::new([]),
         }
     }
 }
-
-impl FullProgressUpdate {
-    pub fn clear(id: u32) -> Self {
-        Self {
-            id,
-            // this makes it easy to check on the client
-            percent_done: -1.,
-            estimated_seconds_remaining: -1.,
-            best_path_cost: 0 as astar::Cost,
-            nodes_considered: 0,
-            elapsed_seconds: 0.,
-            best_path_keep_prefix_length: 0,
-            best_path_append: Box::new([]),
-            current_path_keep_prefix_length: 0,
-            current_path_append: Box::new([]),
-        }
-    }
-}
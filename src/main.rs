@@ -1,4 +1,10 @@
-use internet_roadtrip_pathfinder::{db::DB, roadtrip_api, web};
+use internet_roadtrip_pathfinder::{
+    config::CONFIG,
+    db::DB,
+    dead_ends, deviation, roadtrip_api,
+    streetview::{fixtures, warm_start},
+    web,
+};
 use mimalloc::MiMalloc;
 
 #[global_allocator]
@@ -8,11 +14,32 @@ static GLOBAL: MiMalloc = MiMalloc;
 async fn main() -> eyre::Result<()> {
     tracing_subscriber::fmt::init();
 
+    // read the config now so invalid values are logged at startup
+    let _ = &*CONFIG;
+    let _ = &*web::config::CONFIG;
     // dereference the db to make sure it gets created
     let _ = &*DB;
+    fixtures::configure(
+        &DB,
+        CONFIG.replay_dir.as_deref(),
+        CONFIG.record_dir.as_deref(),
+    );
 
     tokio::spawn(roadtrip_api::watch_websocket());
+    tokio::spawn(deviation::track_car(&DB));
+    if CONFIG.prune_dead_ends {
+        tokio::spawn(dead_ends::precompute_periodically(
+            &DB,
+            CONFIG.dead_end_interval,
+        ));
+    }
+    tokio::spawn(DB.write_queued_getmetadata());
+    tokio::task::spawn_blocking(|| warm_start::warm_up(&DB));
+    tokio::spawn(warm_start::save_periodically(&DB));
     web::serve().await;
 
+    DB.flush_queued_getmetadata()?;
+    warm_start::save_cached_tiles(&DB)?;
+
     Ok(())
 }
@@ -1,8 +1,12 @@
 pub mod angle;
+pub mod random;
 
 use std::f64::consts::PI;
 
-use crate::model::{Location, LocationRadians};
+use crate::{
+    math::angle::Angle,
+    model::{Location, LocationRadians},
+};
 
 #[inline]
 pub fn calculate_heading(origin: Location, dest: Location) -> f32 {
@@ -120,6 +124,166 @@ pub fn distance(a: Location, b: Location) -> f64 {
     EARTH_RADIUS * (c as f64)
 }
 
+/// An approximation of the distance in meters from `p` to the closest point on
+/// the line segment between `a` and `b`. Only accurate for short segments.
+pub fn approx_distance_to_segment(p: Location, a: Location, b: Location) -> f64 {
+    let lng_m_per_degree = p.calculate_lng_m_per_degree();
+    // project everything onto a flat plane centered on p
+    let to_xy = |l: Location| {
+        (
+            (l.lng - p.lng).to_deg() * lng_m_per_degree,
+            (l.lat - p.lat).to_deg() * LAT_M_PER_DEGREE,
+        )
+    };
+    let (ax, ay) = to_xy(a);
+    let (bx, by) = to_xy(b);
+
+    let (dx, dy) = (bx - ax, by - ay);
+    let len_sqr = dx * dx + dy * dy;
+    let t = if len_sqr == 0. {
+        0.
+    } else {
+        (-(ax * dx + ay * dy) / len_sqr).clamp(0., 1.)
+    };
+    let (cx, cy) = (ax + t * dx, ay + t * dy);
+    (cx * cx + cy * cy).sqrt()
+}
+
+/// The distance in meters from `p` to the great circle that goes through `a`
+/// and `b`. Unlike [`approx_distance_to_segment`], this treats the line as
+/// infinitely long.
+pub fn cross_track_distance(p: Location, a: Location, b: Location) -> f64 {
+    if a == b {
+        return distance(a, p);
+    }
+
+    let angular_distance_to_p = distance(a, p) / EARTH_RADIUS;
+    let heading_to_p = calculate_heading_radians(a.to_radians(), p.to_radians()) as f64;
+    let heading_to_b = calculate_heading_radians(a.to_radians(), b.to_radians()) as f64;
+
+    (angular_distance_to_p.sin() * (heading_to_p - heading_to_b).sin())
+        .asin()
+        .abs()
+        * EARTH_RADIUS
+}
+
+/// Whether the location is inside the polygon, using the even-odd rule. The
+/// polygon doesn't have to be closed (the last point connects to the first).
+pub fn point_in_polygon(loc: Location, polygon: &[Location]) -> bool {
+    let (x, y) = (loc.lng.to_bits() as i64, loc.lat.to_bits() as i64);
+
+    let mut inside = false;
+    let mut j = polygon.len().wrapping_sub(1);
+    for i in 0..polygon.len() {
+        let (xi, yi) = (
+            polygon[i].lng.to_bits() as i64,
+            polygon[i].lat.to_bits() as i64,
+        );
+        let (xj, yj) = (
+            polygon[j].lng.to_bits() as i64,
+            polygon[j].lat.to_bits() as i64,
+        );
+
+        if (yi > y) != (yj > y) {
+            // the x coordinate where the edge crosses our latitude
+            let cross_x = xi as f64 + (y - yi) as f64 * (xj - xi) as f64 / (yj - yi) as f64;
+            if (x as f64) < cross_x {
+                inside = !inside;
+            }
+        }
+        j = i;
+    }
+
+    inside
+}
+
+/// A polygon with a precomputed bounding box, so checking whether points far
+/// away from it are inside is cheap.
+#[derive(Debug, Clone)]
+pub struct Polygon {
+    points: Box<[Location]>,
+    min: Location,
+    max: Location,
+}
+impl Polygon {
+    pub fn new(points: Box<[Location]>) -> Self {
+        let mut min = Location::new(Angle::from_bits(i32::MAX), Angle::from_bits(i32::MAX));
+        let mut max = Location::new(Angle::from_bits(i32::MIN), Angle::from_bits(i32::MIN));
+        for p in &points {
+            min = Location::new(min.lat.min(p.lat), min.lng.min(p.lng));
+            max = Location::new(max.lat.max(p.lat), max.lng.max(p.lng));
+        }
+        Self { points, min, max }
+    }
+
+    #[inline]
+    pub fn contains(&self, loc: Location) -> bool {
+        if loc.lat < self.min.lat
+            || loc.lat > self.max.lat
+            || loc.lng < self.min.lng
+            || loc.lng > self.max.lng
+        {
+            return false;
+        }
+        point_in_polygon(loc, &self.points)
+    }
+}
+
+/// The length in meters of a path made of `[lng, lat]` points.
+pub fn path_length(path: &[[f32; 2]]) -> f64 {
+    path.windows(2)
+        .map(|w| {
+            distance(
+                Location::new_deg(w[0][1] as f64, w[0][0] as f64),
+                Location::new_deg(w[1][1] as f64, w[1][0] as f64),
+            )
+        })
+        .sum()
+}
+
+/// Simplify a path made of `[lng, lat]` points with the Ramer–Douglas–Peucker
+/// algorithm, so that no removed point was more than `tolerance` meters away
+/// from the simplified path. The first and last points are always kept.
+pub fn simplify_path(path: &[[f32; 2]], tolerance: f64) -> Vec<[f32; 2]> {
+    simplify_path_indexes(path, tolerance)
+        .into_iter()
+        .map(|i| path[i])
+        .collect()
+}
+
+/// The indexes of the points that [`simplify_path`] keeps, in order.
+pub fn simplify_path_indexes(path: &[[f32; 2]], tolerance: f64) -> Vec<usize> {
+    if path.len() < 3 || tolerance <= 0. {
+        return (0..path.len()).collect();
+    }
+    let to_loc = |p: [f32; 2]| Location::new_deg(p[1] as f64, p[0] as f64);
+
+    let mut keep = vec![false; path.len()];
+    keep[0] = true;
+    keep[path.len() - 1] = true;
+    // a stack instead of recursion, since paths can have tens of thousands of points
+    let mut ranges = vec![(0, path.len() - 1)];
+    while let Some((start, end)) = ranges.pop() {
+        let (a, b) = (to_loc(path[start]), to_loc(path[end]));
+        let farthest = (start + 1..end)
+            .map(|i| (i, approx_distance_to_segment(to_loc(path[i]), a, b)))
+            .max_by(|(_, a), (_, b)| a.total_cmp(b));
+        if let Some((i, distance)) = farthest
+            && distance > tolerance
+        {
+            keep[i] = true;
+            ranges.push((start, i));
+            ranges.push((i, end));
+        }
+    }
+
+    keep.into_iter()
+        .enumerate()
+        .filter(|(_, keep)| *keep)
+        .map(|(i, _)| i)
+        .collect()
+}
+
 pub fn point_at_distance(pos: Location, direction: f32, distance: f64) -> Location {
     point_at_distance_radians(pos, (direction as f64).to_radians(), distance)
 }
@@ -148,6 +312,63 @@ pub fn point_at_distance_radians(pos: Location, direction_radians: f64, distance
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_point_in_polygon() {
+        // a concave "C" shape
+        let polygon = Polygon::new(
+            [
+                (0., 0.),
+                (0., 3.),
+                (1., 3.),
+                (1., 1.),
+                (2., 1.),
+                (2., 3.),
+                (3., 3.),
+                (3., 0.),
+            ]
+            .map(|(lat, lng)| Location::new_deg(lat, lng))
+            .into(),
+        );
+
+        assert!(polygon.contains(Location::new_deg(0.5, 0.5)));
+        assert!(polygon.contains(Location::new_deg(2.5, 2.5)));
+        assert!(!polygon.contains(Location::new_deg(1.5, 2.)));
+        assert!(!polygon.contains(Location::new_deg(-1., 1.)));
+    }
+
+    #[test]
+    fn test_simplify_path() {
+        // a straight line with a small wiggle and one big detour
+        let path = [
+            [0., 0.],
+            [0.001, 0.],
+            [0.002, 0.00001],
+            [0.003, 0.],
+            [0.004, 0.01],
+            [0.005, 0.],
+        ];
+        let simplified = simplify_path(&path, 10.);
+        assert_eq!(
+            simplified,
+            vec![[0., 0.], [0.003, 0.], [0.004, 0.01], [0.005, 0.]]
+        );
+        assert_eq!(simplify_path(&path, 0.), path.to_vec());
+    }
+
+    #[test]
+    fn test_cross_track_distance() {
+        let a = Location::new_deg(0., 0.);
+        let b = Location::new_deg(0., 1.);
+
+        // 0.01 degrees north of the equator
+        let xtd = cross_track_distance(Location::new_deg(0.01, 0.5), a, b);
+        assert!((xtd - 1113.2).abs() < 1., "{xtd}");
+        // the line extends past the endpoints
+        let xtd = cross_track_distance(Location::new_deg(-0.01, 2.), a, b);
+        assert!((xtd - 1113.2).abs() < 1., "{xtd}");
+        assert!(cross_track_distance(Location::new_deg(0., 0.3), a, b) < 0.01);
+    }
+
     #[test]
     fn test_overestimate_distance_sqr() {
         for lat in 20..60 {
@@ -0,0 +1,33 @@
+use std::{
+    hash::{BuildHasher, Hasher, RandomState},
+    time::Duration,
+};
+
+/// A random number that's different every time and hard to guess, without
+/// pulling in a dependency for it.
+pub fn random_u64() -> u64 {
+    // RandomState is randomly seeded, and every new one gets different keys
+    RandomState::new().build_hasher().finish()
+}
+
+/// Pick a random delay between half of the given one and all of it, so that
+/// things that failed together don't all retry at the same time.
+pub fn with_jitter(delay: Duration) -> Duration {
+    let fraction = (random_u64() % 1000) as f64 / 1000.;
+    delay.mul_f64(1. - fraction / 2.)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_jitter() {
+        let delay = Duration::from_secs(10);
+        for _ in 0..100 {
+            let jittered = with_jitter(delay);
+            assert!(jittered > delay / 2 && jittered <= delay);
+        }
+        assert_ne!(random_u64(), random_u64());
+    }
+}
@@ -4,10 +4,11 @@ use std::{
 };
 
 use compact_str::CompactString;
-use serde::Serialize;
+use pathfinder_protocol::{GetPathQuery, PathResult};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    db::DB,
+    astar::RouteNode,
     math::{self, angle::Angle},
 };
 
@@ -59,6 +60,9 @@ impl Location {
     pub fn from_latlng(latlng: [f64; 2]) -> Self {
         Location::new_deg(latlng[0], latlng[1])
     }
+    pub fn to_latlng(&self) -> [f64; 2] {
+        [self.lat_deg(), self.lng_deg()]
+    }
 
     #[inline]
     pub fn to_radians(&self) -> LocationRadians {
@@ -117,11 +121,6 @@ impl PanoId {
         (self.0 >> 31) == 1
     }
 }
-impl From<&str> for PanoId {
-    fn from(value: &str) -> Self {
-        DB.get_pano_id(value)
-    }
-}
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ApiPanoId(pub CompactString);
@@ -179,6 +178,18 @@ pub struct GetMetadataResponse {
     pub id: PanoId,
     pub loc: Location,
     pub links: Vec<PanoLink>,
+    /// When the pano's imagery was taken, if GetMetadata told us.
+    pub capture_date: Option<CaptureDate>,
+    /// The name of the road that the pano is on, like "Route 9".
+    pub road_name: Option<String>,
+}
+/// The month that a pano's imagery was taken in, which is as precise as
+/// Streetview gets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct CaptureDate {
+    pub year: u16,
+    /// Between 1 and 12
+    pub month: u8,
 }
 #[derive(Debug, Clone)]
 pub struct PanoLink {
@@ -186,6 +197,38 @@ pub struct PanoLink {
     pub pano: Pano,
     pub heading: f32,
 }
+/// A path that was completed by the pathfinder, which is saved so it can be
+/// downloaded later.
+#[derive(Debug, Clone)]
+pub struct SavedPath {
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+    /// The nodes of every segment of the path, with costs that are cumulative
+    /// over the whole path.
+    pub nodes: Box<[RouteNode]>,
+}
+
+/// A completed path and the query that found it, saved under a short ID so that
+/// it can be shared with `GET /route/{id}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedRoute {
+    pub id: String,
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+    pub query: GetPathQuery,
+    pub result: PathResult,
+}
+
+/// Where the car was at some point, as reported by the game.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CarHistoryEntry {
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub loc: Location,
+    pub heading: f32,
+    pub pano: Option<PanoId>,
+}
+
 #[derive(Debug, Clone)]
 pub struct PanoWithTile {
     pub id: PanoId,
@@ -0,0 +1,276 @@
+//! A small encoder for Mapbox Vector Tiles, which are protobufs of layers of
+//! points and lines in tile coordinates. Only what the `/mvt` endpoint needs is
+//! supported. See <https://github.com/mapbox/vector-tile-spec/tree/master/2.1>.
+
+use rustc_hash::FxHashMap;
+
+use crate::model::Location;
+
+/// The size of a tile in tile coordinates.
+pub const EXTENT: u32 = 4096;
+
+const VERSION: u32 = 2;
+
+const GEOM_POINT: u32 = 1;
+const GEOM_LINESTRING: u32 = 2;
+
+const CMD_MOVE_TO: u32 = 1;
+const CMD_LINE_TO: u32 = 2;
+
+const WIRE_VARINT: u32 = 0;
+const WIRE_64BIT: u32 = 1;
+const WIRE_LEN: u32 = 2;
+
+/// The Web Mercator tile at a zoom level, like the ones the map uses.
+#[derive(Debug, Clone, Copy)]
+pub struct TileId {
+    pub z: u8,
+    pub x: u32,
+    pub y: u32,
+}
+impl TileId {
+    /// Where the location is in this tile, in tile coordinates. Locations
+    /// outside of the tile are outside of `0..EXTENT`.
+    pub fn project(&self, loc: Location) -> (i32, i32) {
+        let scale = (1_u64 << self.z) as f64;
+        let x = (loc.lng_deg() + 180.) / 360. * scale;
+        let y = (1. - loc.lat_rad().tan().asinh() / std::f64::consts::PI) / 2. * scale;
+        (
+            ((x - self.x as f64) * EXTENT as f64).round() as i32,
+            ((y - self.y as f64) * EXTENT as f64).round() as i32,
+        )
+    }
+
+    /// The corners of the tile as `(min_lat, min_lng, max_lat, max_lng)` in
+    /// degrees.
+    pub fn bounds(&self) -> (f64, f64, f64, f64) {
+        let scale = (1_u64 << self.z) as f64;
+        let lng = |x: f64| x / scale * 360. - 180.;
+        let lat = |y: f64| {
+            (std::f64::consts::PI * (1. - 2. * y / scale))
+                .sinh()
+                .atan()
+                .to_degrees()
+        };
+        (
+            lat(self.y as f64 + 1.),
+            lng(self.x as f64),
+            lat(self.y as f64),
+            lng(self.x as f64 + 1.),
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    String(String),
+    Double(f64),
+    Uint(u64),
+    Bool(bool),
+}
+impl Value {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            Value::String(s) => write_bytes_field(&mut buf, 1, s.as_bytes()),
+            Value::Double(n) => {
+                write_tag(&mut buf, 3, WIRE_64BIT);
+                buf.extend_from_slice(&n.to_le_bytes());
+            }
+            Value::Uint(n) => {
+                write_tag(&mut buf, 5, WIRE_VARINT);
+                write_varint(&mut buf, *n);
+            }
+            Value::Bool(b) => {
+                write_tag(&mut buf, 7, WIRE_VARINT);
+                write_varint(&mut buf, *b as u64);
+            }
+        }
+        buf
+    }
+}
+
+pub struct Layer {
+    name: String,
+    features: Vec<Vec<u8>>,
+    keys: Vec<String>,
+    key_indexes: FxHashMap<String, u32>,
+    /// The encoded values, which are deduplicated like the keys.
+    values: Vec<Vec<u8>>,
+    value_indexes: FxHashMap<Vec<u8>, u32>,
+}
+impl Layer {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            features: Vec::new(),
+            keys: Vec::new(),
+            key_indexes: FxHashMap::default(),
+            values: Vec::new(),
+            value_indexes: FxHashMap::default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.features.is_empty()
+    }
+
+    pub fn add_point(&mut self, id: u64, point: (i32, i32), properties: &[(&str, Value)]) {
+        let geometry = [command(CMD_MOVE_TO, 1), zigzag(point.0), zigzag(point.1)];
+        self.add_feature(id, GEOM_POINT, &geometry, properties);
+    }
+
+    /// Lines need at least two points, shorter ones are ignored.
+    pub fn add_line(&mut self, id: u64, points: &[(i32, i32)], properties: &[(&str, Value)]) {
+        let [first, rest @ ..] = points else {
+            return;
+        };
+        if rest.is_empty() {
+            return;
+        }
+        let mut geometry = vec![
+            command(CMD_MOVE_TO, 1),
+            zigzag(first.0),
+            zigzag(first.1),
+            command(CMD_LINE_TO, rest.len() as u32),
+        ];
+        let mut cursor = *first;
+        for point in rest {
+            geometry.push(zigzag(point.0 - cursor.0));
+            geometry.push(zigzag(point.1 - cursor.1));
+            cursor = *point;
+        }
+        self.add_feature(id, GEOM_LINESTRING, &geometry, properties);
+    }
+
+    fn add_feature(
+        &mut self,
+        id: u64,
+        geom_type: u32,
+        geometry: &[u32],
+        properties: &[(&str, Value)],
+    ) {
+        let mut tags = Vec::with_capacity(properties.len() * 2);
+        for (key, value) in properties {
+            let key_index = match self.key_indexes.get(*key) {
+                Some(&index) => index,
+                None => {
+                    let index = self.keys.len() as u32;
+                    self.keys.push((*key).to_owned());
+                    self.key_indexes.insert((*key).to_owned(), index);
+                    index
+                }
+            };
+            let value = value.encode();
+            let value_index = match self.value_indexes.get(&value) {
+                Some(&index) => index,
+                None => {
+                    let index = self.values.len() as u32;
+                    self.values.push(value.clone());
+                    self.value_indexes.insert(value, index);
+                    index
+                }
+            };
+            tags.push(key_index);
+            tags.push(value_index);
+        }
+
+        let mut feature = Vec::new();
+        write_tag(&mut feature, 1, WIRE_VARINT);
+        write_varint(&mut feature, id);
+        write_packed_field(&mut feature, 2, &tags);
+        write_tag(&mut feature, 3, WIRE_VARINT);
+        write_varint(&mut feature, geom_type as u64);
+        write_packed_field(&mut feature, 4, geometry);
+        self.features.push(feature);
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_tag(&mut buf, 15, WIRE_VARINT);
+        write_varint(&mut buf, VERSION as u64);
+        write_bytes_field(&mut buf, 1, self.name.as_bytes());
+        for feature in &self.features {
+            write_bytes_field(&mut buf, 2, feature);
+        }
+        for key in &self.keys {
+            write_bytes_field(&mut buf, 3, key.as_bytes());
+        }
+        for value in &self.values {
+            write_bytes_field(&mut buf, 4, value);
+        }
+        write_tag(&mut buf, 5, WIRE_VARINT);
+        write_varint(&mut buf, EXTENT as u64);
+        buf
+    }
+}
+
+/// Encode the layers as a tile. Empty layers are left out.
+pub fn encode_tile(layers: &[Layer]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for layer in layers.iter().filter(|layer| !layer.is_empty()) {
+        write_bytes_field(&mut buf, 3, &layer.encode());
+    }
+    buf
+}
+
+fn command(id: u32, count: u32) -> u32 {
+    (id & 0x7) | (count << 3)
+}
+fn zigzag(n: i32) -> u32 {
+    ((n << 1) ^ (n >> 31)) as u32
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+fn write_tag(buf: &mut Vec<u8>, field: u32, wire_type: u32) {
+    write_varint(buf, ((field << 3) | wire_type) as u64);
+}
+fn write_bytes_field(buf: &mut Vec<u8>, field: u32, data: &[u8]) {
+    write_tag(buf, field, WIRE_LEN);
+    write_varint(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
+fn write_packed_field(buf: &mut Vec<u8>, field: u32, values: &[u32]) {
+    let mut packed = Vec::with_capacity(values.len());
+    for value in values {
+        write_varint(&mut packed, *value as u64);
+    }
+    write_bytes_field(buf, field, &packed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_point() {
+        let mut layer = Layer::new("panos");
+        layer.add_point(1, (25, 17), &[("kind", Value::String("pano".to_owned()))]);
+        let tile = encode_tile(&[layer]);
+        // the example from the spec: MoveTo(25, 17)
+        let geometry = [0x22, 0x03, 0x09, 0x32, 0x22];
+        assert!(tile.windows(geometry.len()).any(|w| w == geometry));
+        assert_eq!(tile[0], 0x1a);
+    }
+
+    #[test]
+    fn test_project_round_trips_bounds() {
+        let tile = TileId {
+            z: 14,
+            x: 4823,
+            y: 6160,
+        };
+        let (min_lat, min_lng, max_lat, max_lng) = tile.bounds();
+        assert_eq!(tile.project(Location::new_deg(max_lat, min_lng)), (0, 0));
+        assert_eq!(
+            tile.project(Location::new_deg(min_lat, max_lng)),
+            (EXTENT as i32, EXTENT as i32)
+        );
+    }
+}
@@ -0,0 +1,105 @@
+//! Measuring how well our options match the ones that the game actually offers
+//! the car. Unlike [`crate::learned_options`], this compares against the
+//! options with the learned corrections applied, since that's what the
+//! pathfinder uses.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::{db::Db, model::PanoId, roadtrip::PanoOptionRes};
+
+/// Running totals of every comparison we've done.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct AccuracyCounts {
+    pub observations: u64,
+    /// Observations where our options were exactly the ones the game offered.
+    pub exact_matches: u64,
+    /// Options that the game offered but we didn't predict.
+    pub missing_options: u64,
+    /// Options that we predicted but the game didn't offer.
+    pub extra_options: u64,
+}
+impl AccuracyCounts {
+    /// The fraction of observations that matched exactly.
+    pub fn accuracy(&self) -> f64 {
+        if self.observations == 0 {
+            return 0.;
+        }
+        self.exact_matches as f64 / self.observations as f64
+    }
+}
+
+/// A time that our options didn't match the game's.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptionMismatch {
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub pano: PanoId,
+    pub heading: f32,
+    pub missing: Vec<PanoId>,
+    pub extra: Vec<PanoId>,
+}
+
+/// Compare the options we predicted with the ones the game offered, saving the
+/// difference if there is one.
+pub fn record(
+    db: &Db,
+    pano: PanoId,
+    heading: f32,
+    predicted: &[PanoOptionRes],
+    offered: &[PanoOptionRes],
+) -> eyre::Result<()> {
+    let missing = offered
+        .iter()
+        .filter(|o| !predicted.iter().any(|p| p.pano.id == o.pano.id))
+        .map(|o| o.pano.id)
+        .collect::<Vec<_>>();
+    let extra = predicted
+        .iter()
+        .filter(|p| !offered.iter().any(|o| o.pano.id == p.pano.id))
+        .map(|p| p.pano.id)
+        .collect::<Vec<_>>();
+
+    let mismatch = if missing.is_empty() && extra.is_empty() {
+        None
+    } else {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        Some(OptionMismatch {
+            timestamp,
+            pano,
+            heading,
+            missing,
+            extra,
+        })
+    };
+
+    db.record_option_accuracy(mismatch.as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::db::{decode_option_mismatch, encode_option_mismatch};
+
+    #[test]
+    fn test_mismatch_roundtrip() {
+        let mismatch = OptionMismatch {
+            timestamp: 1_747_485_296_000,
+            pano: PanoId(5),
+            heading: 123.5,
+            missing: vec![PanoId(6)],
+            extra: vec![PanoId(7), PanoId(8)],
+        };
+        let encoded = encode_option_mismatch(&mismatch);
+        assert_eq!(
+            decode_option_mismatch(&mut Cursor::new(&encoded[..])),
+            mismatch
+        );
+    }
+}
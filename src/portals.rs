@@ -0,0 +1,82 @@
+//! Finding portals (also called wormholes), which are Streetview links between
+//! panos that are unusually far apart. These are found in the GetMetadata
+//! responses that are already cached, so no requests are made.
+
+use rustc_hash::FxHashSet;
+
+use crate::{
+    db::Db,
+    math,
+    model::{Location, PanoId},
+    streetview::prefetch::BoundingBox,
+};
+
+#[derive(Debug, Clone)]
+pub struct Portal {
+    pub from: PanoId,
+    pub from_loc: Location,
+    pub to: PanoId,
+    pub to_loc: Location,
+    /// The heading of the link.
+    pub heading: f32,
+    /// How far apart the panos are, in meters.
+    pub distance: f64,
+}
+
+pub struct PortalScan {
+    /// The longest portals first.
+    pub portals: Vec<Portal>,
+    pub panos_scanned: usize,
+    /// Whether there were more than `limit` portals, in which case only the
+    /// longest ones are included.
+    pub truncated: bool,
+}
+
+/// Every cached link that starts in the bounding box and goes further than
+/// `min_distance` meters. This goes through every pano in the box, so it's
+/// slow for large boxes.
+pub fn find_portals(db: &Db, bbox: &BoundingBox, min_distance: f64, limit: usize) -> PortalScan {
+    let tiles = db.iter_tiles_in_bbox(bbox);
+
+    let txn = db.read_txn();
+    // tiles of different sizes can overlap, so the same pano might be seen twice
+    let mut seen = FxHashSet::<PanoId>::default();
+    let mut portals = Vec::new();
+    for tile in tiles {
+        let Some(Some(panos)) = db.lookup_listentityphotos_with_txn(&txn, &tile) else {
+            continue;
+        };
+        for pano in panos.iter() {
+            if !bbox.contains(pano.actual_loc) || !seen.insert(pano.id) {
+                continue;
+            }
+            let Some((loc, links)) = db.lookup_getmetadata_with_txn(&txn, &pano.id) else {
+                continue;
+            };
+            for link in links {
+                let distance = math::distance(loc, link.pano.loc);
+                if distance >= min_distance {
+                    portals.push(Portal {
+                        from: pano.id,
+                        from_loc: loc,
+                        to: link.pano.id,
+                        to_loc: link.pano.loc,
+                        heading: link.heading,
+                        distance,
+                    });
+                }
+            }
+        }
+    }
+    txn.commit().unwrap();
+
+    portals.sort_by(|a, b| b.distance.total_cmp(&a.distance));
+    let truncated = portals.len() > limit;
+    portals.truncate(limit);
+
+    PortalScan {
+        portals,
+        panos_scanned: seen.len(),
+        truncated,
+    }
+}
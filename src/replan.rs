@@ -0,0 +1,195 @@
+//! Keeping a path up to date while the car drives along it, by repairing the
+//! search tree that found it.
+//!
+//! The tree from the previous search is kept between moves. When the car moves
+//! to a node in the tree, that node becomes the root: the subtree below it
+//! keeps its g scores, the rest of the tree is dropped, and the nodes that the
+//! subtree leads to go back in the open set (see [`SearchTree::move_start`]).
+//! If the car is still on the path we just drop the part that's behind us,
+//! and otherwise the search continues from the repaired tree, so only the
+//! nodes whose costs changed are expanded again. The car is only searched for
+//! from scratch if it ended up somewhere the tree never reached.
+
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use crate::{
+    ProgressUpdate,
+    astar::{self, PathSettings, RouteNode, SearchTree},
+    db::Db,
+    model::Location,
+    streetview,
+};
+
+/// The car is considered to be at a node of the tree if it's at the node's
+/// pano and facing within this many degrees of the node's heading.
+const ON_PATH_HEADING_TOLERANCE: f32 = 45.;
+
+pub struct Replanner {
+    goal: Location,
+    settings: PathSettings,
+    /// The search that found the path, with the car's position as its start.
+    tree: SearchTree,
+    /// The current path, with costs starting from 0 at the first node.
+    path: Vec<RouteNode>,
+}
+impl Replanner {
+    /// Find the initial path.
+    pub async fn new(
+        db: &Db,
+        start: Location,
+        start_pano_id: Option<String>,
+        heading: f32,
+        goal: Location,
+        progress_update: Arc<Mutex<ProgressUpdate>>,
+        mut settings: PathSettings,
+    ) -> eyre::Result<Self> {
+        // anytime mode skips edges, which the repaired tree would be missing
+        settings.anytime = false;
+
+        let start_pano = streetview::get_start_pano(db, start, start_pano_id.as_deref()).await?;
+        let mut tree = SearchTree::repairable(start_pano, heading, &settings);
+        let path =
+            astar::astar_with_tree(db, &mut tree, goal, progress_update, settings.clone()).await?;
+        Ok(Self {
+            goal,
+            settings,
+            tree,
+            path,
+        })
+    }
+
+    pub fn path(&self) -> &[RouteNode] {
+        &self.path
+    }
+
+    /// Use a different token for cancelling the searches, since each one
+    /// might run in a different task.
+    pub fn set_cancel(&mut self, cancel: CancellationToken) {
+        self.settings.cancel = cancel;
+    }
+
+    /// Update the path for the car's new position, only searching if the car
+    /// isn't on the path anymore.
+    pub async fn move_start(
+        &mut self,
+        db: &Db,
+        start: Location,
+        start_pano_id: Option<String>,
+        heading: f32,
+        progress_update: Arc<Mutex<ProgressUpdate>>,
+    ) -> eyre::Result<&[RouteNode]> {
+        let start_pano = streetview::get_start_pano(db, start, start_pano_id.as_deref()).await?;
+
+        if self
+            .tree
+            .move_start(start_pano.id, heading, ON_PATH_HEADING_TOLERANCE)
+        {
+            let start = self.tree.start();
+            if let Some(i) = self
+                .path
+                .iter()
+                .position(|n| n.pano.id == start.pano.id && n.heading == start.heading)
+            {
+                // still on the path, so the rest of it is still the best way to the goal
+                self.path = rebase_costs(&self.path[i..]);
+                return Ok(&self.path);
+            }
+        } else {
+            info!("The car left the search tree, so searching from scratch");
+            self.tree = SearchTree::repairable(start_pano, heading, &self.settings);
+        }
+
+        self.path = astar::astar_with_tree(
+            db,
+            &mut self.tree,
+            self.goal,
+            progress_update,
+            self.settings.clone(),
+        )
+        .await?;
+        Ok(&self.path)
+    }
+}
+
+/// Shift the costs of the nodes so the first one costs 0.
+fn rebase_costs(nodes: &[RouteNode]) -> Vec<RouteNode> {
+    let Some(first) = nodes.first() else {
+        return Vec::new();
+    };
+    let first_cost = first.cost;
+    nodes
+        .iter()
+        .map(|n| RouteNode {
+            cost: n.cost - first_cost,
+            ..n.clone()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::streetview::fixtures::ReplayProvider;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_repaired_tree_matches_fresh_search() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("benches/fixtures/grid");
+        let db = Db::temp("replan-repair");
+        db.set_pano_provider(Arc::new(ReplayProvider::new(dir)));
+        let grid_loc = |row: u32, col: u32| {
+            Location::new_deg(40. + row as f64 * 0.00018, -100. + col as f64 * 0.00024)
+        };
+        let goal = grid_loc(15, 15);
+
+        let mut replanner = Replanner::new(
+            &db,
+            grid_loc(0, 0),
+            None,
+            90.,
+            goal,
+            Default::default(),
+            Default::default(),
+        )
+        .await
+        .unwrap();
+
+        // the path goes east along the first street, but the car turned north
+        let repaired_progress = Arc::<Mutex<ProgressUpdate>>::default();
+        let repaired = replanner
+            .move_start(
+                &db,
+                grid_loc(2, 5),
+                Some("grid_2_5".to_owned()),
+                0.,
+                repaired_progress.clone(),
+            )
+            .await
+            .unwrap()
+            .to_vec();
+
+        let fresh_progress = Arc::<Mutex<ProgressUpdate>>::default();
+        let fresh = astar::astar(
+            &db,
+            grid_loc(2, 5),
+            Some("grid_2_5".to_owned()),
+            0.,
+            goal,
+            fresh_progress.clone(),
+            Default::default(),
+        )
+        .await
+        .unwrap();
+
+        let panos = |path: &[RouteNode]| path.iter().map(|n| n.pano.id).collect::<Vec<_>>();
+        assert_eq!(panos(&repaired), panos(&fresh));
+        // the repaired costs were rebased, so they can differ by a rounding error
+        assert!((repaired.last().unwrap().cost - fresh.last().unwrap().cost).abs() < 0.01);
+        assert!(repaired_progress.lock().nodes_considered < fresh_progress.lock().nodes_considered);
+    }
+}
@@ -1,36 +1,44 @@
-use std::{hash::BuildHasherDefault, sync::LazyLock};
+use std::{collections::hash_map::Entry, hash::BuildHasherDefault, sync::Arc};
 
-use quick_cache::{UnitWeighter, sync::Cache};
-use rustc_hash::FxHasher;
+use parking_lot::Mutex;
+use quick_cache::{Lifecycle, UnitWeighter, sync::Cache};
+use rustc_hash::{FxHashMap, FxHashSet, FxHasher};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, trace};
 
 use crate::{
+    cache_stats::EvictionCounter,
+    db::Db,
+    learned_options::apply_learned_options,
     math::{self, calculate_heading, calculate_heading_diff},
-    model::{Location, Pano, PanoId, PanoWithBothLocations},
+    model::{Location, Pano, PanoId, PanoWithBothLocations, SmallTile},
     streetview::{self},
 };
 
 /// The option cache makes consecutive searches a lot faster, but it also makes
 /// benchmarking harder.
 const ENABLE_OPTION_CACHE: bool = true;
-const OPTION_CACHE_SIZE: usize = 1024 * 1024 * 8;
 
 // most accurate value is ceil(30 / 0.707 * 2)=85, but lowering it a little
 // doesn't hurt
 const MAX_SEARCH_RADIUS: f64 = 82.;
 
 pub async fn get_options(
+    db: &Db,
     cur_pano: &Pano,
     cur_heading: f32,
     allow_turnaround: bool,
     use_option_cache: bool,
+    cancel: &CancellationToken,
 ) -> eyre::Result<PanoOptionsRes> {
     let mut turnaround = false;
-    let mut res = get_options_no_turnaround(cur_pano, cur_heading, use_option_cache).await?;
+    let mut res =
+        get_options_no_turnaround(db, cur_pano, cur_heading, use_option_cache, cancel).await?;
 
     // turnaround
     if allow_turnaround && res.options.is_empty() {
-        res = get_options_no_turnaround(cur_pano, cur_heading + 180., use_option_cache).await?;
+        res = get_options_no_turnaround(db, cur_pano, cur_heading + 180., use_option_cache, cancel)
+            .await?;
         turnaround = true;
     }
 
@@ -40,39 +48,186 @@ pub async fn get_options(
     })
 }
 
-#[allow(clippy::type_complexity)]
-static GET_OPTIONS_CACHE: LazyLock<
-    Cache<(u32, PanoId), BasePanoOptionsRes, UnitWeighter, BuildHasherDefault<FxHasher>>,
-> = LazyLock::new(|| {
+/// The tile that the pano is in, the heading's bits, and the pano.
+pub type OptionsCacheKey = (SmallTile, u32, PanoId);
+
+pub type OptionsCache = Cache<
+    OptionsCacheKey,
+    BasePanoOptionsRes,
+    UnitWeighter,
+    BuildHasherDefault<FxHasher>,
+    OptionsLifecycle,
+>;
+
+/// Holds the options of up to `size` nodes.
+pub fn new_options_cache(
+    size: usize,
+    evictions: EvictionCounter,
+    by_tile: Arc<OptionsByTile>,
+) -> OptionsCache {
     Cache::with(
-        OPTION_CACHE_SIZE,
-        OPTION_CACHE_SIZE as u64,
-        Default::default(),
+        size,
+        size as u64,
         Default::default(),
         Default::default(),
+        OptionsLifecycle { evictions, by_tile },
     )
-});
+}
+
+/// The keys of the options cache grouped by their tile, so the options around
+/// a tile can be forgotten without going through the whole cache.
+#[derive(Default)]
+pub struct OptionsByTile(Mutex<FxHashMap<SmallTile, FxHashSet<(u32, PanoId)>>>);
+impl OptionsByTile {
+    /// This has to be called after inserting into the cache, since inserting
+    /// can evict other entries, which locks this.
+    fn insert(&self, (tile, heading, pano_id): OptionsCacheKey) {
+        self.0
+            .lock()
+            .entry(tile)
+            .or_default()
+            .insert((heading, pano_id));
+    }
+    fn remove(&self, (tile, heading, pano_id): &OptionsCacheKey) {
+        if let Entry::Occupied(mut entry) = self.0.lock().entry(*tile) {
+            entry.get_mut().remove(&(*heading, *pano_id));
+            if entry.get().is_empty() {
+                entry.remove();
+            }
+        }
+    }
+    /// Remove and return the keys in the tiles.
+    fn take(&self, tiles: &FxHashSet<SmallTile>) -> Vec<OptionsCacheKey> {
+        let mut by_tile = self.0.lock();
+        tiles
+            .iter()
+            .filter_map(|tile| Some((*tile, by_tile.remove(tile)?)))
+            .flat_map(|(tile, keys)| {
+                keys.into_iter()
+                    .map(move |(heading, pano_id)| (tile, heading, pano_id))
+            })
+            .collect()
+    }
+    pub fn clear(&self) {
+        self.0.lock().clear();
+    }
+}
+
+/// Counts the evictions and removes the evicted entries from the
+/// [`OptionsByTile`] index.
+#[derive(Clone)]
+pub struct OptionsLifecycle {
+    evictions: EvictionCounter,
+    by_tile: Arc<OptionsByTile>,
+}
+impl Lifecycle<OptionsCacheKey, BasePanoOptionsRes> for OptionsLifecycle {
+    type RequestState = ();
+
+    fn begin_request(&self) -> Self::RequestState {}
+
+    fn on_evict(
+        &self,
+        _state: &mut Self::RequestState,
+        key: OptionsCacheKey,
+        _val: BasePanoOptionsRes,
+    ) {
+        self.evictions.increment();
+        self.by_tile.remove(&key);
+    }
+}
+
+fn cache_options(db: &Db, pano: &Pano, heading: f32, res: BasePanoOptionsRes) {
+    let key = (SmallTile::from_loc(pano.loc), heading.to_bits(), pano.id);
+    db.options_cache.insert(key, res);
+    db.options_by_tile.insert(key);
+}
+
+/// Forget the cached options of the changed panos, and of every pano that's
+/// close enough to one of them for it to be an option. This is done both in
+/// memory and in the database, and includes the baked graph.
+pub fn invalidate_options_near(db: &Db, changed: &[PanoWithBothLocations]) -> eyre::Result<()> {
+    if changed.is_empty() {
+        return Ok(());
+    }
+    let mut tiles = FxHashSet::default();
+    for pano in changed {
+        for loc in [pano.search_loc, pano.actual_loc] {
+            let (min, max) = streetview::calculate_tile_bounds(loc, MAX_SEARCH_RADIUS);
+            for x in min.x..=max.x {
+                for y in min.y..=max.y {
+                    tiles.insert(SmallTile { x, y });
+                }
+            }
+        }
+    }
+
+    for key in db.options_by_tile.take(&tiles) {
+        db.options_cache.remove(&key);
+    }
+
+    let mut pano_ids = streetview::cached_panos_in_tiles(db, &tiles);
+    pano_ids.extend(changed.iter().map(|p| p.id));
+    let pano_ids = pano_ids.into_iter().collect::<Vec<_>>();
+    if db.persists_options() {
+        db.delete_options_for_panos(&pano_ids)?;
+    }
+    db.delete_baked_options_for_panos(&pano_ids)?;
+    db.delete_shortcuts_through_panos(&pano_ids)?;
+    Ok(())
+}
 
 pub async fn get_options_no_turnaround(
+    db: &Db,
+    cur_pano: &Pano,
+    cur_heading: f32,
+    use_option_cache: bool,
+    cancel: &CancellationToken,
+) -> eyre::Result<BasePanoOptionsRes> {
+    let mut res = emulate_options(db, cur_pano, cur_heading, use_option_cache, cancel).await?;
+    apply_learned_options(db, cur_pano.id, cur_heading, &mut res);
+    Ok(res)
+}
+
+/// Our emulation of the options that the game would give us, without any of
+/// the corrections from [`crate::learned_options`].
+///
+/// Fails with [`streetview::Cancelled`] if the token is cancelled before the
+/// tiles we need are downloaded.
+pub async fn emulate_options(
+    db: &Db,
     cur_pano: &Pano,
     cur_heading: f32,
     use_option_cache: bool,
+    cancel: &CancellationToken,
 ) -> eyre::Result<BasePanoOptionsRes> {
     if ENABLE_OPTION_CACHE
         && use_option_cache
-        && let Some(res) = GET_OPTIONS_CACHE.get(&(cur_heading.to_bits(), cur_pano.id))
+        && let Some(res) = db.options_cache.get(&(
+            SmallTile::from_loc(cur_pano.loc),
+            cur_heading.to_bits(),
+            cur_pano.id,
+        ))
     {
         return Ok(res.clone());
     }
+    if ENABLE_OPTION_CACHE
+        && use_option_cache
+        && db.persists_options()
+        && let Some(res) = db.lookup_options(cur_pano.id, cur_heading)
+    {
+        cache_options(db, cur_pano, cur_heading, res.clone());
+        return Ok(res);
+    }
 
     debug!("Doing get_options with current pano {cur_pano:?} and heading {cur_heading}");
 
     // this has to be done before get_getmetadata_links to make sure that all the
     // panos are cached
-    let nearby_panos = streetview::get_nearby_panos(cur_pano.loc, MAX_SEARCH_RADIUS)
-        .await?
-        .into_iter()
-        .collect::<Box<_>>();
+    let nearby_panos =
+        streetview::get_nearby_panos_until_cancelled(db, cur_pano.loc, MAX_SEARCH_RADIUS, cancel)
+            .await?
+            .into_iter()
+            .collect::<Box<_>>();
     // we need to know this info for an optimization in get_closest_pano_forward
     // that allows us to skip panos that have their underestimated distance is too
     // high
@@ -85,7 +240,7 @@ pub async fn get_options_no_turnaround(
 
     let mut options = Vec::<PanoOptionRes>::new();
 
-    if let Some(links) = streetview::get_getmetadata_links(&cur_pano.id) {
+    if let Some(links) = streetview::get_getmetadata_links(db, &cur_pano.id) {
         for link in links {
             let heading_diff = math::calculate_heading_diff(link.heading, cur_heading);
             if heading_diff > 100. {
@@ -156,7 +311,10 @@ pub async fn get_options_no_turnaround(
         options: options.into(),
     };
     if ENABLE_OPTION_CACHE && use_option_cache {
-        GET_OPTIONS_CACHE.insert((cur_heading.to_bits(), cur_pano.id), res.clone());
+        cache_options(db, cur_pano, cur_heading, res.clone());
+        if db.persists_options() {
+            db.save_options(cur_pano.id, cur_heading, &res);
+        }
     }
     Ok(res)
 }
@@ -242,6 +400,31 @@ fn maybe_get_further_straight(
     }
 }
 
+/// The pano that the game would pick when looking `forward_distance` meters
+/// from the origin in the direction, according to our emulation of
+/// SingleImageSearch. This is what [`get_options`] does for every option, and
+/// it's public so it can be compared with the real thing.
+pub async fn emulate_single_image_search(
+    db: &Db,
+    origin: Location,
+    origin_pano: Option<PanoId>,
+    direction: f32,
+    forward_distance: f64,
+) -> eyre::Result<Option<Pano>> {
+    let nearby_panos = streetview::get_nearby_panos(db, origin, MAX_SEARCH_RADIUS).await?;
+    let origin_pano_offset = origin_pano
+        .and_then(|id| nearby_panos.iter().find(|p| p.id == id))
+        .map(|p| math::distance(p.actual_loc, p.search_loc))
+        .unwrap_or(0.);
+    Ok(get_closest_pano_forward(
+        origin,
+        origin_pano_offset,
+        direction,
+        forward_distance,
+        &nearby_panos,
+    ))
+}
+
 fn get_closest_pano_forward(
     origin_loc: Location,
     origin_pano_offset: f64,
@@ -341,3 +524,42 @@ pub struct PanoOptionRes {
     pub pano: Pano,
     pub heading: f32,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::SizedTile;
+
+    #[test]
+    fn test_invalidate_options_near_reaches_adjacent_tiles() {
+        let db = Db::temp("invalidate-options-near");
+        let corner = SizedTile::from(SmallTile::from_loc(Location::new_deg(10., 10.))).to_coords();
+        let pano = |id, lng_offset| Pano {
+            id: PanoId(id),
+            loc: Location::new_deg(corner.lat.to_deg(), corner.lng.to_deg() + lng_offset),
+        };
+        // ~20m on either side of a tile boundary, and one that's ~1km away
+        let (a, b, far) = (pano(1, -0.0002), pano(2, 0.0002), pano(3, 0.01));
+        assert_ne!(SmallTile::from_loc(a.loc), SmallTile::from_loc(b.loc));
+        let res = BasePanoOptionsRes {
+            options: Box::new([]),
+        };
+        for pano in [&a, &far] {
+            cache_options(&db, pano, 90., res.clone());
+        }
+
+        invalidate_options_near(
+            &db,
+            &[PanoWithBothLocations {
+                id: b.id,
+                search_loc: b.loc,
+                actual_loc: b.loc,
+            }],
+        )
+        .unwrap();
+
+        let key = |pano: &Pano| (SmallTile::from_loc(pano.loc), 90_f32.to_bits(), pano.id);
+        assert!(db.options_cache.get(&key(&a)).is_none());
+        assert!(db.options_cache.get(&key(&far)).is_some());
+    }
+}
@@ -1,74 +1,390 @@
-use std::time::{Duration, Instant};
+use std::{
+    sync::LazyLock,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use futures::StreamExt;
-use simd_json::derived::ValueTryAsScalar;
-use tokio::time::sleep;
+use parking_lot::{Mutex, RwLock};
+use pathfinder_protocol::CarPosition;
+use serde::{Deserialize, Serialize};
+use simd_json::{
+    base::{ValueAsArray, ValueAsScalar},
+    derived::{ValueObjectAccess, ValueTryAsScalar},
+};
+use tokio::{
+    sync::{mpsc, watch},
+    time::sleep,
+};
 use tokio_tungstenite::{
     connect_async,
     tungstenite::{self, client::IntoClientRequest},
 };
 use tracing::{debug, error, info, warn};
 
-use crate::{model::Location, streetview::reset_cache_nearby};
+use crate::{
+    calibration::{self, CarTimer},
+    config::CONFIG,
+    db::DB,
+    learned_options::{self, CarObservation, parse_car_observation},
+    math::{self, random::with_jitter},
+    model::Location,
+    streetview::{
+        pinning::{PinnedRegion, RegionShape, pin_region},
+        refresh_cache_nearby,
+    },
+};
+
+/// The delay before the first reconnection attempt, which doubles after every
+/// failed attempt up to [`MAX_RECONNECT_DELAY`].
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5 * 60);
+/// Connections that lasted at least this long reset the reconnection delay.
+const STABLE_CONNECTION_DURATION: Duration = Duration::from_secs(60);
+/// Tiles within this many meters of [`CAR_REFRESH_LOOKAHEAD`] meters ahead of
+/// the car are downloaded again every
+/// [`Config::irt_cache_clear_interval`](crate::config::Config::irt_cache_clear_interval), unless they
+/// were recently.
+const CAR_REFRESH_RADIUS: f64 = 1000.;
+const CAR_REFRESH_LOOKAHEAD: f64 = 500.;
+/// Tiles within this many meters of the car are never evicted from the tile
+/// cache.
+const CAR_PINNED_RADIUS: f64 = 2000.;
 
-const WEBSOCKET_URL: &str = "wss://internet-roadtrip-listen-eqzms.ondigitalocean.app";
-const CLEAR_CACHE_INTERVAL_SECONDS: u64 = 60 * 3;
+/// A stop that the game announced the car is heading towards.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OfficialStop {
+    pub name: Option<String>,
+    pub lat: f64,
+    pub lng: f64,
+}
+impl OfficialStop {
+    pub fn loc(&self) -> Location {
+        Location::new_deg(self.lat, self.lng)
+    }
+}
+
+/// The upcoming stops that were most recently announced by the game, in order.
+/// The last one is the terminus.
+static OFFICIAL_STOPS: LazyLock<RwLock<Vec<OfficialStop>>> =
+    LazyLock::new(|| RwLock::new(DB.get_official_stops().unwrap_or_default()));
+
+pub fn official_stops() -> Vec<OfficialStop> {
+    OFFICIAL_STOPS.read().clone()
+}
+
+/// The final stop that the game announced, if any.
+pub fn current_terminus() -> Option<OfficialStop> {
+    OFFICIAL_STOPS.read().last().cloned()
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    /// Waiting before the first connection.
+    #[default]
+    Starting,
+    Connecting,
+    Connected,
+    /// Waiting before reconnecting.
+    Backoff,
+}
+
+/// The state of our connection to the IRT WebSocket, and counters of what
+/// it's sent us since we started.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct WebsocketStatus {
+    pub state: ConnectionState,
+    /// Seconds since the Unix epoch.
+    pub connected_since: Option<u64>,
+    /// Seconds since the Unix epoch.
+    pub last_message_at: Option<u64>,
+    /// How many times in a row we've failed to stay connected.
+    pub reconnect_attempt: u32,
+    pub connections: u64,
+    pub failed_connections: u64,
+    pub messages: u64,
+    /// Messages that weren't valid JSON.
+    pub parse_errors: u64,
+    /// Messages that were valid JSON but that we failed to handle.
+    pub handle_errors: u64,
+}
+
+static STATUS: LazyLock<Mutex<WebsocketStatus>> = LazyLock::new(Mutex::default);
+
+/// Whether we're currently connected to the IRT WebSocket.
+pub fn websocket_connected() -> bool {
+    STATUS.lock().state == ConnectionState::Connected
+}
+
+pub fn websocket_status() -> WebsocketStatus {
+    STATUS.lock().clone()
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Times how long the car stays at each pano, for [`calibration`].
+static CAR_TIMER: LazyLock<Mutex<CarTimer>> = LazyLock::new(Mutex::default);
+
+/// Where the game's car was in the most recent IRT WebSocket message.
+static CAR_POSITION: LazyLock<watch::Sender<Option<CarPosition>>> =
+    LazyLock::new(|| watch::Sender::new(None));
+
+/// The car's most recent position, or `None` if we haven't received it yet.
+pub fn car_position() -> Option<CarPosition> {
+    CAR_POSITION.borrow().clone()
+}
+
+/// Get notified whenever the car moves.
+pub fn subscribe_car_position() -> watch::Receiver<Option<CarPosition>> {
+    CAR_POSITION.subscribe()
+}
 
 pub async fn watch_websocket() {
     let mut last_cache_cleared = Instant::now();
+    let observations = learned_options::spawn_observation_recorder(&DB);
 
     // wait some time before connecting to avoid spamming connections if we're
     // repeatedly restarting the pathfinder
-    sleep(Duration::from_secs(CLEAR_CACHE_INTERVAL_SECONDS)).await;
+    sleep(CONFIG.irt_cache_clear_interval).await;
 
+    let mut attempt = 0;
     loop {
-        let request = WEBSOCKET_URL.into_client_request().unwrap();
-        let Ok((mut stream, response)) = connect_async(request).await else {
-            warn!("Failed to connect to IRT WebSocket, retrying");
-            sleep(Duration::from_secs(10)).await;
+        STATUS.lock().state = ConnectionState::Connecting;
+        let connected_at = Instant::now();
+        match connect(&mut last_cache_cleared, &observations).await {
+            Ok(()) => warn!("IRT WebSocket closed"),
+            Err(e) => error!("IRT WebSocket error: {e}"),
+        }
+        if connected_at.elapsed() >= STABLE_CONNECTION_DURATION {
+            attempt = 0;
+        }
+
+        let delay = with_jitter(reconnect_delay(attempt));
+        attempt += 1;
+        {
+            let mut status = STATUS.lock();
+            status.state = ConnectionState::Backoff;
+            status.connected_since = None;
+            status.reconnect_attempt = attempt;
+        }
+        info!("Reconnecting to IRT WebSocket in {delay:?}");
+        sleep(delay).await;
+    }
+}
+
+/// Connect to the IRT WebSocket and handle its messages until it closes.
+async fn connect(
+    last_cache_cleared: &mut Instant,
+    observations: &mpsc::Sender<CarObservation>,
+) -> eyre::Result<()> {
+    let request = CONFIG.irt_websocket_url.as_str().into_client_request()?;
+    let (mut stream, response) = match connect_async(request).await {
+        Ok(res) => res,
+        Err(e) => {
+            STATUS.lock().failed_connections += 1;
+            return Err(e.into());
+        }
+    };
+
+    info!("Connected to IRT WebSocket: {}", response.status());
+    {
+        let mut status = STATUS.lock();
+        status.state = ConnectionState::Connected;
+        status.connected_since = Some(unix_secs());
+        status.connections += 1;
+    }
+    // we don't know how long the car was at its current pano while we were
+    // disconnected
+    *CAR_TIMER.lock() = CarTimer::default();
+
+    while let Some(message) = stream.next().await {
+        let tungstenite::Message::Text(text) = message? else {
+            // pings are answered by tungstenite, and a close ends the stream
             continue;
         };
+        {
+            let mut status = STATUS.lock();
+            status.messages += 1;
+            status.last_message_at = Some(unix_secs());
+        }
 
-        info!("Connected to IRT WebSocket: {}", response.status());
-
-        while let Some(message) = stream.next().await {
-            match message {
-                Ok(msg) => {
-                    if let Err(e) = handle_message(msg, &mut last_cache_cleared).await {
-                        error!("Error handling IRT WebSocket message: {e}");
-                    }
-                }
+        let data =
+            match simd_json::from_slice::<simd_json::OwnedValue>(&mut text.as_bytes().to_vec()) {
+                Ok(data) => data,
                 Err(e) => {
-                    error!("IRT WebSocket error: {}", e);
-                    break;
+                    STATUS.lock().parse_errors += 1;
+                    warn!("Failed to parse IRT WebSocket message: {e}");
+                    debug!("Unparseable message: {}", text.as_str());
+                    continue;
                 }
-            }
+            };
+        if let Err(e) = handle_message(data, last_cache_cleared, observations).await {
+            STATUS.lock().handle_errors += 1;
+            error!("Error handling IRT WebSocket message: {e}");
         }
-
-        sleep(Duration::from_secs(10)).await;
     }
+    Ok(())
+}
+
+/// How long to wait before reconnecting after `attempt` attempts in a row
+/// failed, before jitter is applied.
+fn reconnect_delay(attempt: u32) -> Duration {
+    MIN_RECONNECT_DELAY
+        .saturating_mul(2_u32.saturating_pow(attempt))
+        .min(MAX_RECONNECT_DELAY)
 }
 
 async fn handle_message(
-    msg: tungstenite::Message,
+    data: simd_json::OwnedValue,
     last_cache_cleared: &mut Instant,
+    observations: &mpsc::Sender<CarObservation>,
 ) -> eyre::Result<()> {
-    let text = msg.to_text()?;
-    if last_cache_cleared.elapsed().as_secs() < CLEAR_CACHE_INTERVAL_SECONDS {
+    if let Some(stops) = parse_official_stops(&data)
+        && *OFFICIAL_STOPS.read() != stops
+    {
+        info!("Game announced new stops: {stops:?}");
+        if let Err(e) = DB.save_official_stops(&stops) {
+            warn!("Failed to save official stops: {e}");
+        }
+        *OFFICIAL_STOPS.write() = stops;
+    }
+
+    if let Some(position) = parse_car_position(&data) {
+        let recorded = position.clone();
+        let moved = CAR_POSITION.send_if_modified(|current| {
+            let moved = current.as_ref().is_none_or(|current| {
+                current.loc != position.loc || current.heading != position.heading
+            });
+            *current = Some(position);
+            moved
+        });
+
+        // most messages are just votes, which don't need to be in the history
+        if moved {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64;
+            if let Err(e) = DB.record_car_position(
+                timestamp,
+                Location::from_latlng(recorded.loc),
+                recorded.heading,
+                recorded.pano.as_deref(),
+            ) {
+                warn!("Failed to record car position: {e}");
+            }
+        }
+    }
+
+    if let Some(pano) = data.get("pano").and_then(|p| p.as_str())
+        && let Some(options) = data.get("options").and_then(|o| o.as_array())
+        && let Some(sample) = CAR_TIMER.lock().update(pano, options.len())
+    {
+        calibration::record_recent_sample(sample);
+        if let Err(e) = calibration::record_samples(&DB, &[sample]) {
+            warn!("Failed to record vote delay: {e}");
+        }
+    }
+
+    // compare the options the game gave the car with the ones we'd generate. this
+    // can have to download tiles, so it's done in the background.
+    if let Some(observation) = parse_car_observation(&DB, &data)
+        && observations.try_send(observation).is_err()
+    {
+        debug!("Too many car observations are queued, dropping one");
+    }
+
+    if last_cache_cleared.elapsed() < CONFIG.irt_cache_clear_interval {
         return Ok(());
     }
 
     debug!("Clearing cache around car");
 
-    let data = simd_json::from_slice::<simd_json::OwnedValue>(&mut text.as_bytes().to_vec())?;
     let cur_lat = data["lat"].try_as_f64()?;
     let cur_lng = data["lng"].try_as_f64()?;
 
+    // make sure the tiles around the car stay in memory
+    pin_region(PinnedRegion {
+        name: "car".to_string(),
+        shape: RegionShape::Circle {
+            center: [cur_lat, cur_lng],
+            radius: CAR_PINNED_RADIUS,
+        },
+    });
+
+    // the tiles the car is heading towards are the ones it'll need soon, and the
+    // ones it already passed were refreshed on the way
+    let mut center = Location::new_deg(cur_lat, cur_lng);
+    if let Some(heading) = data.get("heading").and_then(|h| h.as_f64()) {
+        center = math::point_at_distance(center, heading as f32, CAR_REFRESH_LOOKAHEAD);
+    }
+
     let start = Instant::now();
-    reset_cache_nearby(Location::new_deg(cur_lat, cur_lng), 1000.).await?;
+    let stats = refresh_cache_nearby(&DB, center, CAR_REFRESH_RADIUS).await?;
     let end = Instant::now();
-    debug!("Cache cleared in {:?}", end.duration_since(start));
+    debug!(
+        "Cache cleared in {:?}: {stats:?}",
+        end.duration_since(start)
+    );
     *last_cache_cleared = end;
 
     Ok(())
 }
+
+fn parse_car_position(data: &simd_json::OwnedValue) -> Option<CarPosition> {
+    let as_f64 = |v: &simd_json::OwnedValue| v.as_f64().or_else(|| v.as_i64().map(|i| i as f64));
+    Some(CarPosition {
+        id: 0,
+        loc: [as_f64(data.get("lat")?)?, as_f64(data.get("lng")?)?],
+        pano: data.get("pano").and_then(|p| p.as_str()).map(str::to_owned),
+        heading: as_f64(data.get("heading")?)? as f32,
+    })
+}
+
+/// Parse the announced stops from an IRT WebSocket message. Returns `None` if
+/// the message didn't include any.
+fn parse_official_stops(data: &simd_json::OwnedValue) -> Option<Vec<OfficialStop>> {
+    let parse_stop = |v: &simd_json::OwnedValue| {
+        let as_f64 =
+            |v: &simd_json::OwnedValue| v.as_f64().or_else(|| v.as_i64().map(|i| i as f64));
+        Some(OfficialStop {
+            name: v
+                .get("name")
+                .and_then(|n| n.as_str())
+                .map(|n| n.to_string()),
+            lat: v.get("lat").and_then(as_f64)?,
+            lng: v.get("lng").and_then(as_f64)?,
+        })
+    };
+
+    for key in ["stops", "destinations"] {
+        if let Some(stops) = data.get(key).and_then(|s| s.as_array()) {
+            return Some(stops.iter().filter_map(parse_stop).collect());
+        }
+    }
+    if let Some(destination) = data.get("destination") {
+        return Some(parse_stop(destination).into_iter().collect());
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reconnect_delay_doubles_up_to_max() {
+        assert_eq!(reconnect_delay(0), MIN_RECONNECT_DELAY);
+        assert_eq!(reconnect_delay(3), MIN_RECONNECT_DELAY * 8);
+        assert_eq!(reconnect_delay(100), MAX_RECONNECT_DELAY);
+
+        let delay = with_jitter(Duration::from_secs(10));
+        assert!(delay > Duration::from_secs(5) && delay <= Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,158 @@
+//! Contracting chains of the baked graph (see [`crate::bake`]) where every
+//! node only has one option, like long roads without intersections. Searches
+//! can then skip to the end of the chain with one database read, instead of
+//! expanding every pano along it.
+//!
+//! Only the nodes are stored, and the costs are still calculated during the
+//! search so that they match the query's cost model and penalties.
+
+use std::time::Instant;
+
+use rustc_hash::{FxHashMap, FxHashSet};
+use tracing::info;
+
+use crate::{
+    bake,
+    db::Db,
+    learned_options::apply_learned_options,
+    model::PanoId,
+    roadtrip::{BasePanoOptionsRes, PanoOptionRes},
+};
+
+/// Chains shorter than this aren't worth a shortcut.
+const MIN_CHAIN_LENGTH: usize = 4;
+/// Longer chains are split, so a search that can't use a shortcut (because
+/// it goes near the goal, for example) doesn't lose much.
+const MAX_CHAIN_LENGTH: usize = 500;
+
+type BakedNode = (PanoId, u16);
+
+/// Find the chains in the baked graph and replace the saved shortcuts with
+/// them. Returns the number of shortcuts.
+pub fn precompute(db: &Db) -> eyre::Result<usize> {
+    let start = Instant::now();
+    let mut options = FxHashMap::<BakedNode, Box<[PanoOptionRes]>>::default();
+    db.slow_for_each_baked_node(|pano_id, bucket, mut res| {
+        apply_learned_options(db, pano_id, bake::bucket_heading(bucket), &mut res);
+        options.insert((pano_id, bucket), res.options);
+    });
+
+    let shortcuts = find_chains(&options)
+        .into_iter()
+        .map(|((pano_id, bucket), chain)| {
+            (
+                pano_id,
+                bucket,
+                BasePanoOptionsRes {
+                    options: chain.into(),
+                },
+            )
+        })
+        .collect::<Vec<_>>();
+    db.save_shortcuts(&shortcuts)?;
+
+    info!(
+        "Found {} shortcuts in {} baked nodes in {:?}",
+        shortcuts.len(),
+        options.len(),
+        start.elapsed()
+    );
+    Ok(shortcuts.len())
+}
+
+/// The chains that start after each node with one option, keyed by that node.
+/// Every node in a chain except the last one has one option.
+fn find_chains(
+    options: &FxHashMap<BakedNode, Box<[PanoOptionRes]>>,
+) -> Vec<(BakedNode, Vec<PanoOptionRes>)> {
+    let node_of = |option: &PanoOptionRes| (option.pano.id, bake::heading_bucket(option.heading));
+    let is_link = |node: &BakedNode| options.get(node).is_some_and(|o| o.len() == 1);
+
+    // chains only need to start where the search can enter them from a node that
+    // isn't in a chain itself, otherwise every node of a long road would have a
+    // copy of the rest of it
+    let mut entered_from_link = FxHashSet::<BakedNode>::default();
+    let mut entered_otherwise = FxHashSet::<BakedNode>::default();
+    for node_options in options.values() {
+        for option in node_options.iter() {
+            if node_options.len() == 1 {
+                entered_from_link.insert(node_of(option));
+            } else {
+                entered_otherwise.insert(node_of(option));
+            }
+        }
+    }
+
+    let mut starts = options
+        .iter()
+        .filter(|(node, node_options)| {
+            node_options.len() == 1
+                && (entered_otherwise.contains(node) || !entered_from_link.contains(node))
+        })
+        .map(|(node, _)| *node)
+        .collect::<Vec<_>>();
+    let mut started = FxHashSet::from_iter(starts.iter().copied());
+
+    let mut chains = Vec::new();
+    while let Some(node) = starts.pop() {
+        let mut chain = vec![options[&node][0].clone()];
+        let mut visited = FxHashSet::from_iter([node]);
+        loop {
+            let next_node = node_of(chain.last().unwrap());
+            if !is_link(&next_node) || !visited.insert(next_node) {
+                break;
+            }
+            if chain.len() >= MAX_CHAIN_LENGTH {
+                // continue with another chain from the end of this one
+                if started.insert(next_node) {
+                    starts.push(next_node);
+                }
+                break;
+            }
+            chain.push(options[&next_node][0].clone());
+        }
+        if chain.len() >= MIN_CHAIN_LENGTH {
+            chains.push((node, chain));
+        }
+    }
+    chains
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Location, Pano};
+
+    fn option(id: u32) -> PanoOptionRes {
+        PanoOptionRes {
+            pano: Pano {
+                id: PanoId(id),
+                loc: Location::new_deg(0., id as f64 * 0.0001),
+            },
+            heading: 90.,
+        }
+    }
+
+    #[test]
+    fn test_find_chains() {
+        let bucket = bake::heading_bucket(90.);
+        // an intersection at 0 that leads into a road from 1 to 10, which ends at
+        // another intersection at 10
+        let mut options = FxHashMap::default();
+        options.insert((PanoId(0), bucket), [option(1), option(20)].into());
+        for id in 1..10 {
+            options.insert((PanoId(id), bucket), [option(id + 1)].into());
+        }
+        options.insert((PanoId(10), bucket), [option(11), option(21)].into());
+
+        let chains = find_chains(&options);
+        // only the start of the road gets a shortcut
+        assert_eq!(chains.len(), 1);
+        let (node, chain) = &chains[0];
+        assert_eq!(*node, (PanoId(1), bucket));
+        assert_eq!(
+            chain.iter().map(|o| o.pano.id.0).collect::<Vec<_>>(),
+            (2..=10).collect::<Vec<_>>()
+        );
+    }
+}
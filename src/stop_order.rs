@@ -0,0 +1,76 @@
+//! Reordering the stops of a path so the total distance is as short as
+//! possible, which is the travelling salesman problem (except that the start
+//! and end are fixed). We use a nearest neighbor tour improved with 2-opt,
+//! which is fast and usually close to optimal for the number of stops that we
+//! allow.
+
+use crate::{math, model::Location};
+
+/// The maximum number of times that 2-opt goes over every pair of stops.
+const MAX_TWO_OPT_PASSES: usize = 100;
+
+/// Returns the indices of the stops in the order that they should be visited
+/// to go from `start` to `end`.
+pub fn optimize_stop_order(start: Location, stops: &[Location], end: Location) -> Vec<usize> {
+    // nearest neighbor, to get a decent tour to start with
+    let mut order = Vec::with_capacity(stops.len());
+    let mut remaining = (0..stops.len()).collect::<Vec<_>>();
+    let mut cur = start;
+    while !remaining.is_empty() {
+        let (nearest, _) = remaining
+            .iter()
+            .enumerate()
+            .map(|(i, &stop)| (i, math::distance(cur, stops[stop])))
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .unwrap();
+        let stop = remaining.swap_remove(nearest);
+        order.push(stop);
+        cur = stops[stop];
+    }
+
+    // 2-opt, reversing any part of the tour that makes it shorter
+    let loc_at = |order: &[usize], i: usize| -> Location {
+        if i == 0 {
+            start
+        } else if i > order.len() {
+            end
+        } else {
+            stops[order[i - 1]]
+        }
+    };
+    for _ in 0..MAX_TWO_OPT_PASSES {
+        let mut improved = false;
+        // the tour is start, order[0], ..., order[n - 1], end, and i and j are indices into
+        // it
+        for i in 1..order.len() {
+            for j in i + 1..=order.len() {
+                let before = math::distance(loc_at(&order, i - 1), loc_at(&order, i))
+                    + math::distance(loc_at(&order, j), loc_at(&order, j + 1));
+                let after = math::distance(loc_at(&order, i - 1), loc_at(&order, j))
+                    + math::distance(loc_at(&order, i), loc_at(&order, j + 1));
+                if after < before - 0.01 {
+                    order[i - 1..j].reverse();
+                    improved = true;
+                }
+            }
+        }
+        if !improved {
+            break;
+        }
+    }
+
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stops_on_a_line_are_sorted() {
+        let start = Location::new_deg(0., 0.);
+        let end = Location::new_deg(0., 1.);
+        let stops = [0.8, 0.2, 0.6, 0.4].map(|lng| Location::new_deg(0., lng));
+        assert_eq!(optimize_stop_order(start, &stops, end), [1, 3, 2, 0]);
+    }
+}
@@ -1,14 +1,16 @@
 use std::fmt::Write;
-use std::time::Duration;
 use std::{
     borrow::Cow,
-    sync::{LazyLock, OnceLock},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use base64::{Engine, prelude::BASE64_STANDARD};
 use coarsetime::Instant;
 use eyre::bail;
-use http::HeaderMap;
 use reqwest::Url;
 use simd_json::{
     base::{ValueAsArray, ValueAsScalar},
@@ -16,28 +18,54 @@ use simd_json::{
     json,
 };
 use tokio::fs;
-use tokio::time::sleep;
 use tracing::{debug, error, info, trace, warn};
 
-use crate::model::{
-    ApiPano, ApiPanoId, GetMetadataResponse, Location, Pano, PanoLink, SMALL_TILE_SIZE, SizedTile,
+use crate::{
+    db::Db,
+    model::{
+        ApiPano, ApiPanoId, CaptureDate, GetMetadataResponse, Location, Pano, PanoLink,
+        SMALL_TILE_SIZE, SizedTile,
+    },
+    streetview::{
+        proxy::{GOOGLE_CLIENTS, PooledClient},
+        ratelimit::GOOGLE_RATE_LIMITER,
+        retry::{GOOGLE_RETRY_POLICY, Retryable},
+    },
 };
 
-static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
-    reqwest::ClientBuilder::new()
-        .user_agent("Mozilla/5.0 (X11; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0")
-        .default_headers({
-            let mut headers = HeaderMap::new();
-            headers.insert("Accept-Language", "en-US,en;q=0.5".parse().unwrap());
-            if let Ok(nid) = std::fs::read_to_string("nid.txt") {
-                headers.insert("Cookie", format!("NID={nid}").parse().unwrap());
-            }
-            headers
-        })
-        .cookie_store(true)
-        .build()
-        .unwrap()
-});
+/// The unix timestamp of the last response from Google that we could parse, or
+/// 0 if there hasn't been one yet.
+static LAST_SUCCESSFUL_REQUEST: AtomicU64 = AtomicU64::new(0);
+
+fn record_successful_request() {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    LAST_SUCCESSFUL_REQUEST.store(now, Ordering::Relaxed);
+}
+
+tokio::task_local! {
+    /// Where the current search counts the smallest-size tiles that had more
+    /// panos than Google returns, so we had to use the truncated list.
+    static TRUNCATED_TILES: Arc<AtomicU64>;
+}
+
+/// Run `f`, adding the tiles that it downloads with some of their panos
+/// missing to `counter`. Tiles that another task was already downloading
+/// aren't counted.
+pub async fn count_truncated_tiles<F: Future>(counter: Arc<AtomicU64>, f: F) -> F::Output {
+    TRUNCATED_TILES.scope(counter, f).await
+}
+
+/// The unix timestamp of the last successful request to Google since the
+/// pathfinder started, used by `GET /health`.
+pub fn last_successful_request() -> Option<u64> {
+    match LAST_SUCCESSFUL_REQUEST.load(Ordering::Relaxed) {
+        0 => None,
+        timestamp => Some(timestamp),
+    }
+}
 
 pub async fn try_get_panos_at_tile(tile: SizedTile) -> eyre::Result<Option<Box<[ApiPano]>>> {
     // use panos_near_coords
@@ -91,6 +119,7 @@ pub async fn try_get_panos_at_tile(tile: SizedTile) -> eyre::Result<Option<Box<[
 
     // this is important for the optimization that does binary search on panos to
     // find nearby ones
+    #[allow(clippy::unnecessary_sort_by)]
     panos.sort_by(|a, b| a.loc.lat.cmp(&b.loc.lat));
 
     trace!("filtered: {}", panos.len());
@@ -98,7 +127,8 @@ pub async fn try_get_panos_at_tile(tile: SizedTile) -> eyre::Result<Option<Box<[
     Ok(Some(panos))
 }
 
-pub(super) async fn fetch_getmetadata_responses(
+pub async fn fetch_getmetadata_responses(
+    db: &Db,
     pano_ids: &[ApiPanoId],
 ) -> eyre::Result<Vec<GetMetadataResponse>> {
     let pano_ids = pano_ids
@@ -108,52 +138,57 @@ pub(super) async fn fetch_getmetadata_responses(
 
     debug!("requesting getmetadata links for {} panos", pano_ids.len());
 
-    let mut attempt_number = 0;
-
-    loop {
-        attempt_number += 1;
-
-        let url = "https://maps.googleapis.com/$rpc/google.internal.maps.mapsjs.v1.MapsJsInternalService/GetMetadata";
-        let request_data = build_getmetadata_request(&pano_ids);
-        let res = CLIENT
-            .post(url)
-            .header("content-type", "application/json+protobuf")
-            .json(&request_data)
-            .send()
-            .await?;
-
-        let text = res.text().await?;
-        let mut text_bytes = text.into_bytes();
-        let Ok(json) = simd_json::from_slice::<simd_json::OwnedValue>(&mut text_bytes) else {
-            error!(
-                "Failed to parse JSON response: {:?}",
-                String::from_utf8_lossy(&text_bytes)
-            );
-            bail!("Failed to parse JSON response");
-        };
+    let request_data = build_getmetadata_request(&pano_ids);
+    let json = GOOGLE_RETRY_POLICY
+        .run("GetMetadata", || request_getmetadata(&request_data))
+        .await?;
 
-        trace!("json: {}", simd_json::to_string(&json).unwrap());
+    record_successful_request();
+    parse_getmetadata_response(db, &json[1])
+}
 
-        if !json[1].is_array() {
-            // [14, "The service is currently unavailable."]
+/// Send a GetMetadata request, returning the response if it has the panos.
+async fn request_getmetadata(
+    request_data: &simd_json::OwnedValue,
+) -> eyre::Result<simd_json::OwnedValue> {
+    let url = "https://maps.googleapis.com/$rpc/google.internal.maps.mapsjs.v1.MapsJsInternalService/GetMetadata";
+    let client = GOOGLE_CLIENTS.pick();
+    let request = client
+        .client
+        .post(url)
+        .header("content-type", "application/json+protobuf")
+        .json(request_data);
+    let res = send(client, request).await?;
 
-            warn!(
-                "GetMetadata response didn't have array, response: {json}. request_data: {request_data}"
-            );
-            // retry 10 times
-            if attempt_number > 10 {
-                bail!("Invalid GetMetadata response: {json}");
-            } else {
-                sleep(Duration::from_secs(1)).await;
-                continue;
-            }
-        };
+    let text = res.text().await?;
+    let mut text_bytes = text.into_bytes();
+    let Ok(json) = simd_json::from_slice::<simd_json::OwnedValue>(&mut text_bytes) else {
+        error!(
+            "Failed to parse JSON response: {:?}",
+            String::from_utf8_lossy(&text_bytes)
+        );
+        bail!("Failed to parse JSON response");
+    };
 
-        return parse_getmetadata_response(&json[1]);
-    }
+    trace!("json: {}", simd_json::to_string(&json).unwrap());
+
+    if !json[1].is_array() {
+        // [14, "The service is currently unavailable."]
+        warn!(
+            "GetMetadata response didn't have array, response: {json}. request_data: {request_data}"
+        );
+        return Err(Retryable {
+            reason: format!("Invalid GetMetadata response: {json}"),
+            retry_after: None,
+        }
+        .into());
+    };
+
+    Ok(json)
 }
 
 fn parse_getmetadata_response(
+    db: &Db,
     all_responses: &simd_json::OwnedValue,
 ) -> eyre::Result<Vec<GetMetadataResponse>> {
     let all_responses = all_responses.as_array().expect("is_array was checked");
@@ -229,7 +264,7 @@ fn parse_getmetadata_response(
 
                 let link = PanoLink {
                     pano: Pano {
-                        id: link_pano_id.into(),
+                        id: db.get_pano_id(link_pano_id)?,
                         loc: Location::new_deg(lat, lng),
                     },
                     heading: heading as f32,
@@ -239,10 +274,33 @@ fn parse_getmetadata_response(
             }
         }
 
+        // [year, month]
+        let date = &pano_res[6][7];
+        let capture_date = match (date[0].as_u64(), date[1].as_u64()) {
+            (Some(year), Some(month)) => Some(CaptureDate {
+                year: year as u16,
+                month: month as u8,
+            }),
+            _ => {
+                trace!("pano without a capture date: {pano_id}");
+                None
+            }
+        };
+
+        // [["Route 9", "en"], ["Town, State", "en"]], but sometimes only the second one
+        // is there, and that isn't a road name
+        let address = &pano_res[3][2];
+        let road_name = match address.as_array().map(|a| a.len()) {
+            Some(2..) => address[0][0].as_str().map(str::to_owned),
+            _ => None,
+        };
+
         results.push(GetMetadataResponse {
-            id: pano_id.into(),
+            id: db.get_pano_id(pano_id)?,
             loc: Location::new_deg(pano_lat, pano_lng),
             links,
+            capture_date,
+            road_name,
         });
     }
 
@@ -254,13 +312,17 @@ pub async fn panos_near_coords(
     radius_meters: u32,
     bail_on_too_many_panos: bool,
 ) -> eyre::Result<Option<Vec<ApiPano>>> {
-    ensure_nid_cookie_set().await?;
-
     let url = build_listentityphotos_request(coords, radius_meters);
     debug!("url: {url}");
     let start = Instant::now();
-    let res = CLIENT.get(url).send().await?;
-    let text = res.text().await?;
+    let text = GOOGLE_RETRY_POLICY
+        .run("listentityphotos", || async {
+            let client = GOOGLE_CLIENTS.pick();
+            ensure_nid_cookie_set(client).await?;
+            let res = send(client, client.client.get(url.clone())).await?;
+            Ok(res.text().await?)
+        })
+        .await?;
     let mut text_bytes = text.into_bytes();
 
     let Ok(json) = simd_json::from_slice::<simd_json::OwnedValue>(&mut text_bytes[4..]) else {
@@ -272,6 +334,7 @@ pub async fn panos_near_coords(
     };
 
     debug!("Request for listentityphotos took: {:?}", start.elapsed());
+    record_successful_request();
 
     let nearby_panos = &json[0];
     let mut panos = Vec::new();
@@ -284,7 +347,10 @@ pub async fn panos_near_coords(
                 trace!("too many panos");
                 return Ok(None);
             } else {
-                trace!("too many panos, but we're already at the smallest pano size");
+                warn!(
+                    "Too many panos near {coords}, but we're already at the smallest tile size so some will be missing"
+                );
+                let _ = TRUNCATED_TILES.try_with(|count| count.fetch_add(1, Ordering::Relaxed));
             }
         }
 
@@ -363,6 +429,81 @@ fn build_listentityphotos_request(coords: &Location, radius_meters: u32) -> Url
     .unwrap()
 }
 
+/// The pano closest to the location within the radius (in meters), using the
+/// same request that the game uses to pick options. We emulate this with
+/// cached tiles instead (see [`crate::roadtrip`]), so this is only used to
+/// check that the emulation is right.
+pub async fn single_image_search(loc: Location, radius: f64) -> eyre::Result<Option<ApiPano>> {
+    let url = "https://maps.googleapis.com/$rpc/google.internal.maps.mapsjs.v1.MapsJsInternalService/SingleImageSearch";
+    let request_data = json!([
+        [
+            "apiv3",
+            null,
+            null,
+            null,
+            "US",
+            null,
+            null,
+            null,
+            null,
+            null,
+            [[0]]
+        ],
+        [[null, null, loc.lat_deg(), loc.lng_deg()], radius],
+        [
+            null,
+            ["en", "US"],
+            null,
+            null,
+            null,
+            null,
+            null,
+            null,
+            [2],
+            null,
+            [[[2, true, 2], [3, true, 2], [10, true, 2]]]
+        ],
+        [[1, 2, 3, 4, 8, 6]]
+    ]);
+
+    let json = GOOGLE_RETRY_POLICY
+        .run("SingleImageSearch", || async {
+            let client = GOOGLE_CLIENTS.pick();
+            let request = client
+                .client
+                .post(url)
+                .header("content-type", "application/json+protobuf")
+                .json(&request_data);
+            let text = send(client, request).await?.text().await?;
+            let mut text_bytes = text.into_bytes();
+            match simd_json::from_slice::<simd_json::OwnedValue>(&mut text_bytes) {
+                Ok(json) => Ok(json),
+                Err(_) => bail!(
+                    "Failed to parse SingleImageSearch response: {:?}",
+                    String::from_utf8_lossy(&text_bytes)
+                ),
+            }
+        })
+        .await?;
+    record_successful_request();
+
+    // [[5, "generic::not_found", "Search returned no images."]]
+    let pano_res = &json[1];
+    let Some(pano_id) = pano_res[1][1].as_str() else {
+        trace!("SingleImageSearch found nothing: {json}");
+        return Ok(None);
+    };
+    let lat = pano_res[5][0][1][0][2].as_f64();
+    let lng = pano_res[5][0][1][0][3].as_f64();
+    let (Some(lat), Some(lng)) = (lat, lng) else {
+        bail!("SingleImageSearch response didn't have coordinates: {json}");
+    };
+    Ok(Some(ApiPano {
+        id: pano_id.into(),
+        loc: Location::new_deg(lat, lng),
+    }))
+}
+
 fn build_getmetadata_request(pano_ids: &[Cow<'_, str>]) -> simd_json::OwnedValue {
     let mut queries = Vec::new();
     for pano_id in pano_ids {
@@ -430,20 +571,41 @@ pub fn encode_protobuf_pano(pano_id: &str) -> Cow<'_, str> {
     pano_id.into()
 }
 
-static REQUESTED_GOOGLE_MAPS: OnceLock<()> = OnceLock::new();
-async fn ensure_nid_cookie_set() -> eyre::Result<()> {
-    if REQUESTED_GOOGLE_MAPS.get().is_some() {
+/// Send a request to Google with the client, and record whether the client's
+/// proxy worked.
+async fn send(
+    client: &PooledClient,
+    request: reqwest::RequestBuilder,
+) -> eyre::Result<reqwest::Response> {
+    GOOGLE_RATE_LIMITER.acquire().await;
+    let res = match request.send().await {
+        Ok(res) => res,
+        Err(e) => {
+            client.record(false);
+            return Err(e.into());
+        }
+    };
+    if let Some(err) = Retryable::from_status(res.status(), res.headers()) {
+        client.record(false);
+        return Err(err.into());
+    }
+    client.record(true);
+    Ok(res)
+}
+
+async fn ensure_nid_cookie_set(client: &PooledClient) -> eyre::Result<()> {
+    if client.nid_cookie_set.get().is_some() {
         return Ok(());
     }
 
-    info!("doing ensure_nid_cookie_set");
+    info!("doing ensure_nid_cookie_set for {}", client.name);
     let url = "https://www.google.com/maps";
-    let res = CLIENT.head(url).send().await?;
+    let res = send(client, client.client.head(url)).await?;
     if let Some(nid) = res.cookies().find(|c| c.name() == "NID") {
         let nid = nid.value();
-        fs::write("nid.txt", nid).await?;
+        fs::write(&client.nid_path, nid).await?;
     }
-    let _ = REQUESTED_GOOGLE_MAPS.set(());
+    let _ = client.nid_cookie_set.set(());
 
     info!("got nid cookie");
 
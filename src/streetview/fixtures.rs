@@ -0,0 +1,342 @@
+//! Saving what a [`PanoProvider`] returns to a directory, and serving it back
+//! later without network access. This makes searches reproducible, so they can
+//! be used in tests and benchmarks.
+//!
+//! Set `PATHFINDER_RECORD_DIR` to record every tile and pano that's downloaded
+//! (prefetching a region with `POST /admin/prefetch` is an easy way to record
+//! all of it), and `PATHFINDER_REPLAY_DIR` to serve them instead of asking
+//! Google. Tiles are saved in `tiles/{size}_{x}_{y}.json` and panos in
+//! `panos/{pano_id}.json`. Pano IDs are saved as Streetview's IDs, so the
+//! recordings can be replayed with any database.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use eyre::{Context, eyre};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tracing::{info, warn};
+
+use crate::{
+    db::Db,
+    model::{
+        ApiPano, ApiPanoId, CaptureDate, GetMetadataResponse, Location, Pano, PanoLink, SizedTile,
+    },
+    streetview::provider::PanoProvider,
+};
+
+#[derive(Serialize, Deserialize)]
+struct RecordedTile {
+    /// `None` if there were too many panos in the tile.
+    panos: Option<Vec<RecordedPano>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RecordedPano {
+    id: String,
+    lat: f64,
+    lng: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RecordedMetadata {
+    id: String,
+    lat: f64,
+    lng: f64,
+    links: Vec<RecordedLink>,
+    #[serde(default)]
+    capture_date: Option<CaptureDate>,
+    #[serde(default)]
+    road_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RecordedLink {
+    id: String,
+    lat: f64,
+    lng: f64,
+    heading: f32,
+}
+
+/// Replay the panos from `replay_dir`, or record them to `record_dir`, if
+/// either is set.
+pub fn configure(db: &Db, replay_dir: Option<&Path>, record_dir: Option<&Path>) {
+    if let Some(dir) = replay_dir {
+        info!("Replaying panos from {}", dir.display());
+        db.set_pano_provider(Arc::new(ReplayProvider::new(dir)));
+    } else if let Some(dir) = record_dir {
+        info!("Recording panos to {}", dir.display());
+        db.set_pano_provider(Arc::new(RecordingProvider::new(db.pano_provider(), dir)));
+    }
+}
+
+/// Saves everything that the inner provider returns.
+pub struct RecordingProvider {
+    inner: Arc<dyn PanoProvider>,
+    dir: PathBuf,
+}
+impl RecordingProvider {
+    pub fn new(inner: Arc<dyn PanoProvider>, dir: impl Into<PathBuf>) -> Self {
+        Self {
+            inner,
+            dir: dir.into(),
+        }
+    }
+}
+
+impl PanoProvider for RecordingProvider {
+    fn panos_near(&self, tile: SizedTile) -> BoxFuture<'_, eyre::Result<Option<Box<[ApiPano]>>>> {
+        Box::pin(async move {
+            let panos = self.inner.panos_near(tile).await?;
+            let recorded = RecordedTile {
+                panos: panos.as_ref().map(|panos| {
+                    panos
+                        .iter()
+                        .map(|pano| RecordedPano {
+                            id: pano.id.0.to_string(),
+                            lat: pano.loc.lat_deg(),
+                            lng: pano.loc.lng_deg(),
+                        })
+                        .collect()
+                }),
+            };
+            if let Err(e) = write_json(&tile_path(&self.dir, tile), &recorded).await {
+                warn!("Failed to record tile {tile:?}: {e}");
+            }
+            Ok(panos)
+        })
+    }
+
+    fn metadata_for<'a>(
+        &'a self,
+        db: &'a Db,
+        pano_ids: &'a [ApiPanoId],
+    ) -> BoxFuture<'a, eyre::Result<Vec<GetMetadataResponse>>> {
+        Box::pin(async move {
+            let responses = self.inner.metadata_for(db, pano_ids).await?;
+            let pano_id_string = |pano_id| db.lookup_pano_id_string(pano_id).unwrap_or_default();
+            for res in &responses {
+                let id = pano_id_string(res.id);
+                let recorded = RecordedMetadata {
+                    id: id.clone(),
+                    lat: res.loc.lat_deg(),
+                    lng: res.loc.lng_deg(),
+                    links: res
+                        .links
+                        .iter()
+                        .map(|link| RecordedLink {
+                            id: pano_id_string(link.pano.id),
+                            lat: link.pano.loc.lat_deg(),
+                            lng: link.pano.loc.lng_deg(),
+                            heading: link.heading,
+                        })
+                        .collect(),
+                    capture_date: res.capture_date,
+                    road_name: res.road_name.clone(),
+                };
+                if let Err(e) = write_json(&pano_path(&self.dir, &id), &recorded).await {
+                    warn!("Failed to record pano {id}: {e}");
+                }
+            }
+            Ok(responses)
+        })
+    }
+
+    fn max_metadata_batch_size(&self) -> usize {
+        self.inner.max_metadata_batch_size()
+    }
+}
+
+/// Serves what a [`RecordingProvider`] saved. Tiles that weren't recorded are
+/// errors, and panos that weren't recorded are treated as if they don't exist.
+pub struct ReplayProvider {
+    dir: PathBuf,
+}
+impl ReplayProvider {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+impl PanoProvider for ReplayProvider {
+    fn panos_near(&self, tile: SizedTile) -> BoxFuture<'_, eyre::Result<Option<Box<[ApiPano]>>>> {
+        Box::pin(async move {
+            let path = tile_path(&self.dir, tile);
+            let recorded = read_json::<RecordedTile>(&path)
+                .await?
+                .ok_or_else(|| eyre!("tile {tile:?} wasn't recorded"))?;
+            Ok(recorded.panos.map(|panos| {
+                panos
+                    .into_iter()
+                    .map(|pano| ApiPano {
+                        id: pano.id.as_str().into(),
+                        loc: Location::new_deg(pano.lat, pano.lng),
+                    })
+                    .collect()
+            }))
+        })
+    }
+
+    fn metadata_for<'a>(
+        &'a self,
+        db: &'a Db,
+        pano_ids: &'a [ApiPanoId],
+    ) -> BoxFuture<'a, eyre::Result<Vec<GetMetadataResponse>>> {
+        Box::pin(async move {
+            let mut responses = Vec::new();
+            for pano_id in pano_ids {
+                let path = pano_path(&self.dir, &pano_id.0);
+                let Some(recorded) = read_json::<RecordedMetadata>(&path).await? else {
+                    continue;
+                };
+                responses.push(GetMetadataResponse {
+                    id: db.get_pano_id(&recorded.id)?,
+                    loc: Location::new_deg(recorded.lat, recorded.lng),
+                    links: recorded
+                        .links
+                        .into_iter()
+                        .map(|link| {
+                            Ok(PanoLink {
+                                pano: Pano {
+                                    id: db.get_pano_id(&link.id)?,
+                                    loc: Location::new_deg(link.lat, link.lng),
+                                },
+                                heading: link.heading,
+                            })
+                        })
+                        .collect::<eyre::Result<_>>()?,
+                    capture_date: recorded.capture_date,
+                    road_name: recorded.road_name,
+                });
+            }
+            Ok(responses)
+        })
+    }
+}
+
+fn tile_path(dir: &Path, tile: SizedTile) -> PathBuf {
+    dir.join("tiles")
+        .join(format!("{}_{}_{}.json", tile.size, tile.x, tile.y))
+}
+
+fn pano_path(dir: &Path, pano_id: &str) -> PathBuf {
+    // pano IDs can contain slashes, so anything that isn't safe in a file name is
+    // escaped
+    let mut name = String::with_capacity(pano_id.len());
+    for c in pano_id.chars() {
+        if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+            name.push(c);
+        } else {
+            for byte in c.to_string().bytes() {
+                name.push_str(&format!("%{byte:02X}"));
+            }
+        }
+    }
+    dir.join("panos").join(format!("{name}.json"))
+}
+
+async fn write_json<T: Serialize>(path: &Path, value: &T) -> eyre::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    fs::write(path, simd_json::to_string(value)?).await?;
+    Ok(())
+}
+
+/// `None` if the file doesn't exist.
+async fn read_json<T: for<'de> Deserialize<'de>>(path: &Path) -> eyre::Result<Option<T>> {
+    let mut data = match fs::read(path).await {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).wrap_err_with(|| format!("failed to read {}", path.display())),
+    };
+    let value = simd_json::serde::from_slice(&mut data)
+        .wrap_err_with(|| format!("failed to parse {}", path.display()))?;
+    Ok(Some(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FakeProvider;
+    impl PanoProvider for FakeProvider {
+        fn panos_near(
+            &self,
+            _tile: SizedTile,
+        ) -> BoxFuture<'_, eyre::Result<Option<Box<[ApiPano]>>>> {
+            Box::pin(async {
+                Ok(Some(Box::from([ApiPano {
+                    id: "a/b".into(),
+                    loc: Location::new_deg(1., 2.),
+                }])))
+            })
+        }
+
+        fn metadata_for<'a>(
+            &'a self,
+            _db: &'a Db,
+            _pano_ids: &'a [ApiPanoId],
+        ) -> BoxFuture<'a, eyre::Result<Vec<GetMetadataResponse>>> {
+            Box::pin(async { Ok(Vec::new()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_recorded_tile() {
+        let dir = std::env::temp_dir().join(format!("pathfinder-fixtures-{}", std::process::id()));
+        let tile = SizedTile {
+            size: 16,
+            x: 1,
+            y: 2,
+        };
+
+        let recording = RecordingProvider::new(Arc::new(FakeProvider), &dir);
+        let recorded = recording.panos_near(tile).await.unwrap().unwrap();
+
+        let replay = ReplayProvider::new(&dir);
+        let replayed = replay.panos_near(tile).await.unwrap().unwrap();
+        assert_eq!(replayed, recorded);
+        assert!(replay.panos_near(SizedTile { x: 2, ..tile }).await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_astar_on_replayed_grid() {
+        // a 16x16 grid of panos ~20m apart, with a street every 5 panos in both
+        // directions
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("benches/fixtures/grid");
+        let db = Db::temp("replay-astar");
+        db.set_pano_provider(Arc::new(ReplayProvider::new(dir)));
+
+        let grid_loc = |row: u32, col: u32| {
+            Location::new_deg(40. + row as f64 * 0.00018, -100. + col as f64 * 0.00024)
+        };
+        let route = crate::astar::astar(
+            &db,
+            grid_loc(0, 0),
+            None,
+            90.,
+            grid_loc(15, 15),
+            Default::default(),
+            Default::default(),
+        )
+        .await
+        .unwrap();
+        let panos = route
+            .iter()
+            .map(|node| db.lookup_pano_id_string(node.pano.id).unwrap())
+            .collect::<Vec<_>>();
+
+        // east along the first street, then north along the last one
+        let expected = (0..16)
+            .map(|col| format!("grid_0_{col}"))
+            .chain((1..16).map(|row| format!("grid_{row}_15")))
+            .collect::<Vec<_>>();
+        assert_eq!(panos, expected);
+    }
+}
@@ -1,35 +1,133 @@
 pub mod api;
-
-use std::{
-    cmp::Ordering,
-    sync::{Arc, LazyLock},
-};
+pub mod fixtures;
+pub mod pinning;
+pub mod prefetch;
+pub mod provider;
+pub mod proxy;
+pub mod ratelimit;
+pub mod retry;
+pub mod spatial_index;
+pub mod warm_start;
+
+use std::{cmp::Ordering, sync::Arc, time::Duration};
 
 use coarsetime::Instant;
-use quick_cache::sync::Cache;
+use eyre::OptionExt;
+use futures::{StreamExt, future, stream};
+use parking_lot::Mutex;
+use quick_cache::{DefaultHashBuilder, Weighter, sync::Cache};
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, trace, warn};
 
 use crate::{
-    db::DB,
+    cache_stats::EvictionCounter,
+    db::Db,
     math::{self, LAT_M_PER_DEGREE, angle::Angle},
     model::{
         ApiPanoId, GetMetadataResponse, Location, Pano, PanoId, PanoLink, PanoWithBothLocations,
-        SizedTile, SmallTile,
+        SMALL_TILE_SIZE, SizedTile, SmallTile,
+    },
+    roadtrip,
+    streetview::{
+        pinning::{PinnedRegion, TilePinLifecycle},
+        prefetch::BoundingBox,
     },
 };
 
-pub fn get_getmetadata_links(pano_id: &PanoId) -> Option<Box<[PanoLink]>> {
-    DB.lookup_getmetadata(pano_id).map(|(_, l)| l)
+/// How many tiles are downloaded at the same time when refreshing the cache.
+const REFRESH_CONCURRENCY: usize = 6;
+
+pub fn get_getmetadata_links(db: &Db, pano_id: &PanoId) -> Option<Box<[PanoLink]>> {
+    db.lookup_getmetadata(pano_id).map(|(_, l)| l)
 }
 
-pub async fn get_nearest_pano(loc: Location, max_distance: f64) -> eyre::Result<Option<Pano>> {
-    let panos = get_nearby_panos(loc, max_distance).await?;
+pub async fn get_nearest_pano(
+    db: &Db,
+    loc: Location,
+    max_distance: f64,
+) -> eyre::Result<Option<Pano>> {
+    if max_distance >= spatial_index::MIN_INDEXED_RADIUS {
+        return spatial_index::get_nearest_pano(db, loc, max_distance).await;
+    }
+    let panos = get_nearby_panos(db, loc, max_distance).await?;
     Ok(get_nearest_pano_in_array(&panos, loc, None))
 }
 
+/// The pano that a search starts at, which is either the given pano or the
+/// nearest one to `loc`. Pano IDs that we've never seen are rejected instead of
+/// being given an internal ID, since they come from anonymous requests.
+pub async fn get_start_pano(db: &Db, loc: Location, pano_id: Option<&str>) -> eyre::Result<Pano> {
+    match pano_id {
+        Some(pano_id) => {
+            let id = db
+                .lookup_pano_id(pano_id)
+                .ok_or_else(|| UnknownPano(pano_id.to_owned()))?;
+            Ok(Pano { id, loc })
+        }
+        None => get_nearest_pano(db, loc, 500.)
+            .await
+            .unwrap_or_default()
+            .ok_or_eyre("start position isn't near a pano"),
+    }
+}
+
+/// Returned by functions that take a [`CancellationToken`] when it was
+/// cancelled.
+#[derive(Debug)]
+pub struct Cancelled;
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Cancelled")
+    }
+}
+impl std::error::Error for Cancelled {}
+
+/// Returned by [`get_start_pano`] if the pano ID isn't in the database.
+#[derive(Debug)]
+pub struct UnknownPano(pub String);
+impl std::fmt::Display for UnknownPano {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unknown pano {}", self.0)
+    }
+}
+impl std::error::Error for UnknownPano {}
+
+/// Returned by [`get_panos_at_tile`] if even the smallest tile had too many
+/// panos for Google to return them all, and the truncated list couldn't be
+/// downloaded either.
+#[derive(Debug)]
+pub struct TooManyPanos {
+    pub tile: SmallTile,
+}
+impl std::fmt::Display for TooManyPanos {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "tile {:?} had too many panos, SMALL_TILE_SIZE might have to be changed",
+            self.tile
+        )
+    }
+}
+impl std::error::Error for TooManyPanos {}
+
 pub async fn get_nearby_panos(
+    db: &Db,
     loc: Location,
     min_distance: f64,
+) -> eyre::Result<Box<[PanoWithBothLocations]>> {
+    get_nearby_panos_until_cancelled(db, loc, min_distance, &CancellationToken::new()).await
+}
+
+/// Like [`get_nearby_panos`], but fails with [`Cancelled`] instead of starting
+/// to fetch another tile once the token is cancelled. Tiles that are already
+/// being fetched are always finished, so they're saved properly.
+pub async fn get_nearby_panos_until_cancelled(
+    db: &Db,
+    loc: Location,
+    min_distance: f64,
+    cancel: &CancellationToken,
 ) -> eyre::Result<Box<[PanoWithBothLocations]>> {
     let mut found_panos = Vec::<PanoWithBothLocations>::new();
     let mut checked_tiles = Vec::new();
@@ -45,9 +143,13 @@ pub async fn get_nearby_panos(
                 continue;
             }
 
+            if cancel.is_cancelled() {
+                return Err(Cancelled.into());
+            }
+
             // note if you're trying to optimize this: for normal pathfinding, it's not
             // faster to spawn these as tasks
-            let (checked_sized_tile, panos_at_this_tile) = get_panos_at_tile(tile).await?;
+            let (checked_sized_tile, panos_at_this_tile) = get_panos_at_tile(db, tile).await?;
             if checked_tiles.contains(&checked_sized_tile) {
                 continue;
             }
@@ -67,41 +169,243 @@ pub async fn get_nearby_panos(
     Ok(found_panos.into())
 }
 
+/// What [`reset_cache_nearby`] or [`refresh_cache_nearby`] did.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct RefreshStats {
+    /// Tiles that were downloaded again.
+    pub tiles_refreshed: usize,
+    /// Tiles that were skipped because they were refreshed recently.
+    pub tiles_skipped: usize,
+    /// Refreshed tiles whose panos were different from the cached ones.
+    pub tiles_changed: usize,
+    /// Tiles that couldn't be downloaded.
+    pub tiles_failed: usize,
+}
+
 /// Re-download the panos within at least min_distance meters of the given
-/// location.
-pub async fn reset_cache_nearby(loc: Location, min_distance: f64) -> eyre::Result<()> {
+/// location. Tiles are downloaded a few at a time, and this only fails if none
+/// of them could be.
+pub async fn reset_cache_nearby(
+    db: &Db,
+    loc: Location,
+    min_distance: f64,
+) -> eyre::Result<RefreshStats> {
+    refresh_tiles_nearby(db, loc, min_distance, None).await
+}
+
+/// Like [`reset_cache_nearby`], but tiles that were refreshed in the last
+/// [`DbConfig::tile_refresh_interval`](crate::db::config::DbConfig::tile_refresh_interval) are skipped. When this is called
+/// repeatedly as the car moves, only the tiles it newly got close to are
+/// downloaded.
+pub async fn refresh_cache_nearby(
+    db: &Db,
+    loc: Location,
+    min_distance: f64,
+) -> eyre::Result<RefreshStats> {
+    refresh_tiles_nearby(
+        db,
+        loc,
+        min_distance,
+        Some(db.config().tile_refresh_interval),
+    )
+    .await
+}
+
+async fn refresh_tiles_nearby(
+    db: &Db,
+    loc: Location,
+    min_distance: f64,
+    skip_refreshed_within: Option<Duration>,
+) -> eyre::Result<RefreshStats> {
     debug!("doing reset_cache_nearby at {loc:?}");
 
-    let mut checked_tiles = Vec::new();
+    if let Some(interval) = skip_refreshed_within {
+        db.refreshed_tiles
+            .lock()
+            .retain(|_, refreshed_at| refreshed_at.elapsed() < interval);
+    }
 
     let origin_tile = SmallTile::from_loc(loc);
     let (min_tile, max_tile) = calculate_tile_bounds(loc, min_distance);
-
-    for x in min_tile.x..=max_tile.x {
-        for y in min_tile.y..=max_tile.y {
-            let tile = SmallTile { x, y };
-            if tile != origin_tile && !tile.is_maybe_within_radius(loc, min_distance) {
-                continue;
+    let tiles = (min_tile.x..=max_tile.x)
+        .flat_map(|x| (min_tile.y..=max_tile.y).map(move |y| SmallTile { x, y }))
+        .filter(|&tile| tile == origin_tile || tile.is_maybe_within_radius(loc, min_distance))
+        .collect::<Vec<_>>();
+
+    // several small tiles can be part of the same sized tile, which only has to be
+    // refreshed once
+    let checked_tiles = Mutex::new(FxHashSet::default());
+    let mut outcomes = stream::iter(tiles)
+        .map(|tile| refresh_tile(db, tile, &checked_tiles, skip_refreshed_within.is_some()))
+        .buffer_unordered(REFRESH_CONCURRENCY);
+
+    let mut stats = RefreshStats::default();
+    let mut first_error = None;
+    while let Some(outcome) = outcomes.next().await {
+        match outcome {
+            Ok(TileRefresh::AlreadyChecked) => {}
+            Ok(TileRefresh::Skipped) => stats.tiles_skipped += 1,
+            Ok(TileRefresh::Refreshed { changed }) => {
+                stats.tiles_refreshed += 1;
+                if changed {
+                    stats.tiles_changed += 1;
+                }
             }
-
-            let (checked_sized_tile, _) = get_panos_at_tile(tile).await?;
-            if checked_tiles.contains(&checked_sized_tile) {
-                continue;
+            Err(err) => {
+                // one tile failing shouldn't stop the others from being refreshed
+                warn!("Failed to refresh tile near {loc:?}: {err}");
+                stats.tiles_failed += 1;
+                first_error.get_or_insert(err);
             }
-            checked_tiles.push(checked_sized_tile);
+        }
+    }
+
+    debug!("refreshed tiles near {loc:?}: {stats:?}");
+    match first_error {
+        Some(err) if stats.tiles_refreshed == 0 && stats.tiles_skipped == 0 => Err(err),
+        _ => Ok(stats),
+    }
+}
+
+enum TileRefresh {
+    /// The sized tile was already refreshed for another small tile.
+    AlreadyChecked,
+    /// The tile was refreshed recently.
+    Skipped,
+    Refreshed {
+        /// Whether the panos in the tile are different from the cached ones.
+        changed: bool,
+    },
+}
+
+async fn refresh_tile(
+    db: &Db,
+    tile: SmallTile,
+    checked_tiles: &Mutex<FxHashSet<SizedTile>>,
+    skip_recently_refreshed: bool,
+) -> eyre::Result<TileRefresh> {
+    let (checked_sized_tile, old_panos) = get_panos_at_tile(db, tile).await?;
+    if !checked_tiles.lock().insert(checked_sized_tile) {
+        return Ok(TileRefresh::AlreadyChecked);
+    }
+
+    if skip_recently_refreshed && db.refreshed_tiles.lock().contains_key(&checked_sized_tile) {
+        return Ok(TileRefresh::Skipped);
+    }
+
+    // only refetch the one with content
+    db.panos_at_tile_cache.remove(&checked_sized_tile);
+    // it's possible for the tile to be too big now (>3000 panos), but that's fine
+    // since the smaller tile would get requested when next time it's needed anyways
+    let new_panos = refetch_panos_at_sized_tile(db, checked_sized_tile, &old_panos).await?;
+    db.refreshed_tiles
+        .lock()
+        .insert(checked_sized_tile, std::time::Instant::now());
+
+    let changed = invalidate_options_if_changed(db, &old_panos, new_panos.as_ref())?;
+    Ok(TileRefresh::Refreshed { changed })
+}
 
-            // only refetch the one with content
-            PANOS_AT_TILE_CACHE.remove(&checked_sized_tile);
-            // it's possible for the tile to be too big now (>3000 panos), but that's fine
-            // since the smaller tile would get requested when next time it's needed anyways
-            uncached_get_panos_at_sized_tile(checked_sized_tile).await?;
+/// Forget the options around the panos in a tile that was downloaded again
+/// that were added, removed or moved. Returns whether there were any.
+fn invalidate_options_if_changed(
+    db: &Db,
+    old_panos: &[PanoWithBothLocations],
+    new_panos: Option<&Arc<[PanoWithBothLocations]>>,
+) -> eyre::Result<bool> {
+    let changed = match new_panos {
+        Some(new_panos) => changed_panos(old_panos, new_panos),
+        // the tile has too many panos now, so they're in the smaller tiles
+        None => old_panos.to_vec(),
+    };
+    if changed.is_empty() {
+        // nothing moved, so the options we calculated are still right
+        return Ok(false);
+    }
+    roadtrip::invalidate_options_near(db, &changed)?;
+    Ok(true)
+}
+
+/// The panos that are only in one of the two versions of a tile, or that are
+/// at a different location in the other one. Moved panos are returned with
+/// both locations.
+fn changed_panos(
+    old: &[PanoWithBothLocations],
+    new: &[PanoWithBothLocations],
+) -> Vec<PanoWithBothLocations> {
+    let locs = |panos: &[PanoWithBothLocations]| {
+        panos
+            .iter()
+            .map(|p| (p.id, p.actual_loc))
+            .collect::<FxHashMap<_, _>>()
+    };
+    let (old_locs, new_locs) = (locs(old), locs(new));
+    old.iter()
+        .filter(|p| new_locs.get(&p.id) != Some(&p.actual_loc))
+        .chain(
+            new.iter()
+                .filter(|p| old_locs.get(&p.id) != Some(&p.actual_loc)),
+        )
+        .cloned()
+        .collect()
+}
+
+/// The IDs of the cached panos in the tiles. Tiles that aren't cached are
+/// skipped instead of downloaded.
+pub fn cached_panos_in_tiles(db: &Db, tiles: &FxHashSet<SmallTile>) -> FxHashSet<PanoId> {
+    let txn = db.read_txn();
+    let mut used_tiles = FxHashSet::<SizedTile>::default();
+    let mut pano_ids = FxHashSet::default();
+    for tile in tiles {
+        // like get_panos_at_tile, use the largest size that had all the panos
+        for sized_tile in tile.get_all_sizes() {
+            if used_tiles.contains(&sized_tile) {
+                break;
+            }
+            if let Some(Some(panos)) = db.lookup_listentityphotos_with_txn(&txn, &sized_tile) {
+                used_tiles.insert(sized_tile);
+                pano_ids.extend(
+                    panos
+                        .iter()
+                        .filter(|p| {
+                            tiles.contains(&SmallTile::from_loc(p.search_loc))
+                                || tiles.contains(&SmallTile::from_loc(p.actual_loc))
+                        })
+                        .map(|p| p.id),
+                );
+                break;
+            }
         }
     }
+    txn.commit().unwrap();
+    pano_ids
+}
+
+/// Delete every cached tile that intersects the bounding box, so it's
+/// downloaded again the next time it's needed. Returns the number of tiles that
+/// were deleted.
+pub fn purge_tiles_in_bbox(db: &Db, bbox: &BoundingBox) -> eyre::Result<usize> {
+    let tiles = db.iter_tiles_in_bbox(bbox);
+    debug!("purging {} tiles in {bbox:?}", tiles.len());
+
+    // the options around the panos in these tiles might change once they're
+    // downloaded again
+    let changed_panos = tiles
+        .iter()
+        .filter_map(|tile| db.lookup_listentityphotos(tile).flatten())
+        .flat_map(|panos| panos.to_vec())
+        .collect::<Vec<_>>();
+
+    db.delete_tiles(&tiles)?;
+    for tile in &tiles {
+        db.panos_at_tile_cache.remove(tile);
+    }
+    roadtrip::invalidate_options_near(db, &changed_panos)?;
 
-    Ok(())
+    Ok(tiles.len())
 }
 
-fn calculate_tile_bounds(loc: Location, min_distance: f64) -> (SmallTile, SmallTile) {
+pub fn calculate_tile_bounds(loc: Location, min_distance: f64) -> (SmallTile, SmallTile) {
     let (min_lat, max_lat) = calculate_lat_bounds(loc, min_distance);
     let (min_lng, max_lng) = calculate_lng_bounds(loc, min_distance);
 
@@ -224,56 +528,165 @@ pub fn get_nearest_pano_in_array(
     nearest_pano
 }
 
-#[allow(clippy::type_complexity)]
-static PANOS_AT_TILE_CACHE: LazyLock<Cache<SizedTile, Option<Arc<[PanoWithBothLocations]>>>> =
-    LazyLock::new(|| Cache::new(1024));
+/// Used to guess how many tiles fit in the cache.
+const ESTIMATED_PANOS_PER_TILE: u64 = 256;
+
+pub type PanosAtTileCache = Cache<
+    SizedTile,
+    Option<Arc<[PanoWithBothLocations]>>,
+    TileWeighter,
+    DefaultHashBuilder,
+    TilePinLifecycle,
+>;
+
+/// Weighs tiles by how many panos they have, so a few tiles with thousands of
+/// panos can't take up as much memory as a thousand tiles with a few.
+#[derive(Clone)]
+pub struct TileWeighter;
+impl Weighter<SizedTile, Option<Arc<[PanoWithBothLocations]>>> for TileWeighter {
+    fn weight(&self, _key: &SizedTile, val: &Option<Arc<[PanoWithBothLocations]>>) -> u64 {
+        // tiles without panos still have to weigh something so they're evicted
+        val.as_ref().map_or(0, |panos| panos.len() as u64) + 1
+    }
+}
+
+/// Holds up to `capacity` panos in total.
+pub fn new_panos_at_tile_cache(capacity: u64, evictions: EvictionCounter) -> PanosAtTileCache {
+    Cache::with(
+        (capacity / ESTIMATED_PANOS_PER_TILE).max(1) as usize,
+        capacity,
+        TileWeighter,
+        DefaultHashBuilder::default(),
+        TilePinLifecycle { evictions },
+    )
+}
+
+#[derive(Debug, Serialize)]
+pub struct TileCacheStats {
+    pub entries: usize,
+    /// How many panos the cache can hold, see [`TileWeighter`].
+    pub capacity: u64,
+    /// The number of cached tiles that are in a pinned region.
+    pub pinned_entries: usize,
+    pub pinned_regions: Vec<PinnedRegion>,
+}
+
+pub fn tile_cache_stats(db: &Db) -> TileCacheStats {
+    TileCacheStats {
+        entries: db.panos_at_tile_cache.len(),
+        capacity: db.panos_at_tile_cache.capacity(),
+        pinned_entries: db
+            .panos_at_tile_cache
+            .iter()
+            .filter(|(tile, _)| pinning::is_tile_pinned(tile))
+            .count(),
+        pinned_regions: pinning::pinned_regions(),
+    }
+}
 
 /// Returns a list of panos that are at least in the tile (but might be in
 /// surrounding ones), as well as the [`SizedTile`] that contains these tiles.
 pub async fn get_panos_at_tile(
+    db: &Db,
     base_tile: SmallTile,
 ) -> eyre::Result<(SizedTile, Arc<[PanoWithBothLocations]>)> {
     let mut found_tile_and_res = None;
 
     for tile in base_tile.get_all_sizes() {
         trace!("internal_get_panos_at_tile {tile:?}");
-        if let Some(res) = PANOS_AT_TILE_CACHE.get(&tile) {
+        if let Some(res) = db.panos_at_tile_cache.get(&tile) {
             if let Some(res) = res {
                 trace!("got from cache ({} panos), returning", res.len());
                 found_tile_and_res = Some((tile, res.clone()));
                 break;
             }
-            continue;
-        }
-
-        if let Some(res) = DB.lookup_listentityphotos(&tile) {
-            PANOS_AT_TILE_CACHE.insert(tile, res.clone());
+        } else if let Some(res) = db.lookup_fresh_listentityphotos(&tile) {
+            db.panos_at_tile_cache.insert(tile, res.clone());
             if let Some(res) = res {
                 trace!("got from cache ({} panos), returning", res.len());
                 found_tile_and_res = Some((tile, res));
                 break;
             }
             trace!("got from cache (too many panos), continuing");
-        } else if let Some(res) = uncached_get_panos_at_sized_tile(tile).await? {
+        } else if let Some(res) = fetch_tile_once(db, tile).await? {
             found_tile_and_res = Some((tile, res));
             break;
         }
 
+        if tile.size == SMALL_TILE_SIZE {
+            // there's nothing smaller to check, so use whatever Google gives us even if
+            // it's missing some panos. this can happen if the tile was saved as too big
+            // by an older version.
+            warn!(
+                "smallest tile {tile:?} was cached as having too many panos, downloading it again"
+            );
+            if let Some(res) = uncached_get_panos_at_sized_tile(db, tile).await? {
+                found_tile_and_res = Some((tile, res));
+            }
+        }
+
         // it was None so keep checking
     }
 
-    let (tile, res) = found_tile_and_res.unwrap_or_else(|| {
-        panic!("tile {base_tile:?} had too many panos? SMALL_TILE_SIZE might have to be changed")
-    });
+    found_tile_and_res.ok_or_else(|| TooManyPanos { tile: base_tile }.into())
+}
 
-    Ok((tile, res))
+/// Download a tile that wasn't in the cache, or that was but is stale. If
+/// another task is already downloading the same tile, we wait for it to finish
+/// and use its result instead of making another request.
+async fn fetch_tile_once(
+    db: &Db,
+    tile: SizedTile,
+) -> eyre::Result<Option<Arc<[PanoWithBothLocations]>>> {
+    let lock = db.tiles_in_flight.lock().entry(tile).or_default().clone();
+
+    let guard = lock.lock().await;
+    let res = if let Some(res) = db.panos_at_tile_cache.get(&tile) {
+        trace!("tile {tile:?} was fetched by another task");
+        Ok(res)
+    } else if let Some(res) = db.lookup_fresh_listentityphotos(&tile) {
+        trace!("tile {tile:?} was fetched by another task");
+        Ok(res)
+    } else {
+        let stale_panos = db.lookup_listentityphotos(&tile).flatten();
+        let res = uncached_get_panos_at_sized_tile(db, tile).await;
+        if let Some(old_panos) = stale_panos
+            && let Ok(new_panos) = &res
+            && let Err(err) = invalidate_options_if_changed(db, &old_panos, new_panos.as_ref())
+        {
+            warn!("Failed to invalidate options for stale tile {tile:?}: {err}");
+        }
+        res
+    };
+    drop(guard);
+
+    let mut tiles_in_flight = db.tiles_in_flight.lock();
+    // one reference is in the map and one is ours, anything more means that
+    // someone else is still waiting
+    if Arc::strong_count(&lock) <= 2 {
+        tiles_in_flight.remove(&tile);
+    }
+
+    res
 }
 
 async fn uncached_get_panos_at_sized_tile(
+    db: &Db,
     tile: SizedTile,
+) -> eyre::Result<Option<Arc<[PanoWithBothLocations]>>> {
+    refetch_panos_at_sized_tile(db, tile, &[]).await
+}
+
+/// Like [`uncached_get_panos_at_sized_tile`], but GetMetadata is only fetched
+/// for panos that aren't in `old_panos` at the same location, since the ones
+/// that didn't move already have an up to date response cached.
+async fn refetch_panos_at_sized_tile(
+    db: &Db,
+    tile: SizedTile,
+    old_panos: &[PanoWithBothLocations],
 ) -> eyre::Result<Option<Arc<[PanoWithBothLocations]>>> {
     debug!("uncached_get_panos_at_sized_tile at {tile:?}");
-    let res = api::try_get_panos_at_tile(tile).await;
+    let res = db.pano_provider().panos_near(tile).await;
 
     let api_res = match res {
         Ok(r) => r,
@@ -285,44 +698,76 @@ async fn uncached_get_panos_at_sized_tile(
 
     // convert the streetview ids (strings) into pathfinder ones (u32s)
     if let Some(api_res) = api_res {
-        let mut txn = DB.write_txn();
-        let mut converted_res = Vec::new();
-        for pano in api_res.iter() {
-            converted_res.push(Pano {
-                id: DB.get_pano_id_with_txn(&mut txn, &pano.id.0),
-                loc: pano.loc,
-            })
+        let converted_res = db.write(|txn| {
+            api_res
+                .iter()
+                .map(|pano| {
+                    Ok(Pano {
+                        id: db.get_pano_id_with_txn(txn, &pano.id.0)?,
+                        loc: pano.loc,
+                    })
+                })
+                .collect::<heed::Result<Vec<_>>>()
+        })?;
+
+        // do GetMetadata lookups on the new and moved panos and save them in the db
+        let old_locs = old_panos
+            .iter()
+            .map(|p| (p.id, p.search_loc))
+            .collect::<FxHashMap<_, _>>();
+        let pano_ids = {
+            let txn = db.read_txn();
+            api_res
+                .iter()
+                .zip(&converted_res)
+                .filter(|(_, pano)| {
+                    old_locs.get(&pano.id) != Some(&pano.loc)
+                        || db
+                            .lookup_getmetadata_location_with_txn(&txn, &pano.id)
+                            .is_none()
+                })
+                .map(|(api_pano, _)| api_pano.id.clone())
+                .collect::<Box<[_]>>()
+        };
+        if !pano_ids.is_empty() {
+            fetch_getmetadata_with_pano_ids(db, &pano_ids).await?;
         }
-        txn.commit()?;
-
-        // do GetMetadata lookups on all the panos and save them in the db
-        let pano_ids = api_res.iter().map(|p| &p.id).cloned().collect::<Box<[_]>>();
-        fetch_getmetadata_with_pano_ids(&pano_ids).await?;
 
         // now add both types of locations to our panos
-        let res = fetch_actual_locations_for_panos(tile, &converted_res);
+        let res = fetch_actual_locations_for_panos(db, tile, &converted_res);
 
         // we include both types of coordinates when we save the listentityphotos
         // response to reduce the number of lookups we have to do later
-        DB.save_listentityphotos(&tile, Some(res.clone()))?;
+        db.save_listentityphotos(&tile, Some(res.clone()))?;
 
         return Ok(Some(res));
     }
 
-    DB.save_listentityphotos(&tile, None)?;
+    if tile.size == SMALL_TILE_SIZE {
+        // saving this would make it look like the tile is cached
+        return Err(TooManyPanos {
+            tile: SmallTile {
+                x: tile.x,
+                y: tile.y,
+            },
+        }
+        .into());
+    }
+    db.save_listentityphotos(&tile, None)?;
 
     Ok(None)
 }
 
 fn fetch_actual_locations_for_panos(
+    db: &Db,
     tile: SizedTile,
     panos: &[Pano],
 ) -> Arc<[PanoWithBothLocations]> {
-    let txn = DB.read_txn();
+    let txn = db.read_txn();
     let res = panos
         .iter()
         .map(|p| {
-            let actual_loc = DB
+            let actual_loc = db
                 .lookup_getmetadata_location_with_txn(&txn, &p.id)
                 .unwrap_or(p.loc);
 
@@ -333,37 +778,201 @@ fn fetch_actual_locations_for_panos(
             }
         })
         .collect::<Arc<_>>();
-    PANOS_AT_TILE_CACHE.insert(tile, Some(res.clone()));
+    db.panos_at_tile_cache.insert(tile, Some(res.clone()));
     res
 }
 
+/// Make sure that the pano's GetMetadata response is cached, downloading it if
+/// it isn't. Returns `None` if Streetview doesn't know about the pano.
+pub async fn ensure_getmetadata(db: &Db, str_pano_id: &str) -> eyre::Result<Option<PanoId>> {
+    if let Some(pano_id) = db.lookup_pano_id(str_pano_id)
+        && db.lookup_getmetadata_location(&pano_id).is_some()
+    {
+        return Ok(Some(pano_id));
+    }
+    // the pano only gets an internal ID once Google says it exists, so made-up
+    // IDs don't fill up the database
+    let res = fetch_getmetadata_with_pano_ids(db, &[ApiPanoId::from(str_pano_id)]).await?;
+    let Some(pano_id) = db.lookup_pano_id(str_pano_id) else {
+        return Ok(None);
+    };
+    Ok(res.iter().any(|r| r.id == pano_id).then_some(pano_id))
+}
+
 async fn fetch_getmetadata_with_pano_ids(
+    db: &Db,
     pano_ids: &[ApiPanoId],
 ) -> eyre::Result<Arc<[GetMetadataResponse]>> {
     let start = Instant::now();
 
     let mut getmetadata_responses = Vec::new();
 
-    let mut tasks = Vec::new();
-    // getmetadata refuses to reply if we request more than 200 at a time
-    for chunk in pano_ids.chunks(200) {
-        let chunk = chunk.to_vec();
-        tasks.push(tokio::spawn(async move {
-            api::fetch_getmetadata_responses(&chunk).await
-        }));
-        // all_links.extend(api::fetch_getmetadata_links(&chunk).await?);
-    }
-    for task in tasks {
-        getmetadata_responses.extend(task.await??);
+    let provider = db.pano_provider();
+    let requests = pano_ids
+        .chunks(provider.max_metadata_batch_size())
+        .map(|chunk| provider.metadata_for(db, chunk));
+    for res in future::try_join_all(requests).await? {
+        getmetadata_responses.extend(res);
     }
 
     debug!("Requests for GetMetadata took: {:?}", start.elapsed());
 
-    let mut txn = DB.write_txn();
-    for getmetadata_response in &getmetadata_responses {
-        DB.save_getmetadata_with_txn(&mut txn, getmetadata_response)?;
-    }
-    txn.commit()?;
+    db.queue_getmetadata(&getmetadata_responses).await?;
 
     Ok(Arc::<[GetMetadataResponse]>::from(getmetadata_responses))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use futures::future::BoxFuture;
+
+    use super::*;
+    use crate::{
+        model::{ApiPano, GetMetadataResponse},
+        streetview::provider::PanoProvider,
+    };
+
+    /// A provider with a fixed list of panos that counts the GetMetadata
+    /// requests.
+    #[derive(Default)]
+    struct FakeProvider {
+        panos: Mutex<Vec<(&'static str, Location)>>,
+        metadata_requests: AtomicUsize,
+    }
+    impl PanoProvider for FakeProvider {
+        fn panos_near(
+            &self,
+            _tile: SizedTile,
+        ) -> BoxFuture<'_, eyre::Result<Option<Box<[ApiPano]>>>> {
+            let panos = self.panos.lock().clone();
+            Box::pin(async move {
+                Ok(Some(
+                    panos
+                        .into_iter()
+                        .map(|(id, loc)| ApiPano {
+                            id: ApiPanoId::from(id),
+                            loc,
+                        })
+                        .collect(),
+                ))
+            })
+        }
+
+        fn metadata_for<'a>(
+            &'a self,
+            db: &'a Db,
+            pano_ids: &'a [ApiPanoId],
+        ) -> BoxFuture<'a, eyre::Result<Vec<GetMetadataResponse>>> {
+            self.metadata_requests
+                .fetch_add(pano_ids.len(), std::sync::atomic::Ordering::Relaxed);
+            let panos = self.panos.lock().clone();
+            Box::pin(async move {
+                pano_ids
+                    .iter()
+                    .filter_map(|id| panos.iter().find(|(p, _)| *p == id.0))
+                    .map(|(id, loc)| {
+                        Ok(GetMetadataResponse {
+                            id: db.get_pano_id(id)?,
+                            loc: *loc,
+                            links: Vec::new(),
+                            capture_date: None,
+                            road_name: None,
+                        })
+                    })
+                    .collect()
+            })
+        }
+    }
+
+    fn pano(id: u32, lat: f64) -> PanoWithBothLocations {
+        let loc = Location::new_deg(lat, 0.);
+        PanoWithBothLocations {
+            id: PanoId(id),
+            search_loc: loc,
+            actual_loc: loc,
+        }
+    }
+
+    #[test]
+    fn test_changed_panos_ignores_order() {
+        let ids =
+            |panos: Vec<PanoWithBothLocations>| panos.iter().map(|p| p.id.0).collect::<Vec<_>>();
+        assert!(changed_panos(&[pano(1, 0.), pano(2, 1.)], &[pano(2, 1.), pano(1, 0.)]).is_empty());
+        // a pano moved
+        assert_eq!(
+            ids(changed_panos(
+                &[pano(1, 0.), pano(2, 1.)],
+                &[pano(1, 0.), pano(2, 2.)]
+            )),
+            [2, 2]
+        );
+        // a pano was added
+        assert_eq!(
+            ids(changed_panos(&[pano(1, 0.)], &[pano(1, 0.), pano(2, 1.)])),
+            [2]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_refetch_only_gets_metadata_for_changed_panos() {
+        let db = Db::temp("refetch-changed-panos");
+        let provider = Arc::new(FakeProvider::default());
+        *provider.panos.lock() = vec![
+            ("a", Location::new_deg(0., 0.)),
+            ("b", Location::new_deg(0.0001, 0.)),
+        ];
+        db.set_pano_provider(provider.clone());
+        let requests = || {
+            provider
+                .metadata_requests
+                .load(std::sync::atomic::Ordering::Relaxed)
+        };
+        let tile = SizedTile {
+            size: SMALL_TILE_SIZE,
+            x: 0,
+            y: 0,
+        };
+
+        let old = refetch_panos_at_sized_tile(&db, tile, &[])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(requests(), 2);
+
+        // nothing changed
+        let old = refetch_panos_at_sized_tile(&db, tile, &old)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(requests(), 2);
+
+        // one pano moved and one was added
+        *provider.panos.lock() = vec![
+            ("a", Location::new_deg(0., 0.)),
+            ("b", Location::new_deg(0.0002, 0.)),
+            ("c", Location::new_deg(0.0003, 0.)),
+        ];
+        let new = refetch_panos_at_sized_tile(&db, tile, &old)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(requests(), 4);
+        assert_eq!(new.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_start_pano_isnt_stored() {
+        let db = Db::temp("unknown-start-pano");
+        let loc = Location::new_deg(0., 0.);
+        let known = db.get_pano_id("known").unwrap();
+
+        let pano = get_start_pano(&db, loc, Some("known")).await.unwrap();
+        assert_eq!(pano.id, known);
+
+        let err = get_start_pano(&db, loc, Some("made up")).await.unwrap_err();
+        assert!(err.is::<UnknownPano>());
+        assert_eq!(db.lookup_pano_id("made up"), None);
+    }
+}
@@ -0,0 +1,115 @@
+//! Regions whose tiles are never evicted from the in-memory tile cache, so
+//! bursts of unrelated queries can't push out the tiles around the car or along
+//! a route that's actively being searched.
+
+use std::sync::{Arc, LazyLock};
+
+use parking_lot::RwLock;
+use quick_cache::Lifecycle;
+use serde::Serialize;
+
+use crate::{
+    cache_stats::EvictionCounter,
+    math,
+    model::{Location, PanoWithBothLocations, SizedTile},
+};
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RegionShape {
+    Circle {
+        center: [f64; 2],
+        /// In meters.
+        radius: f64,
+    },
+    /// Everything within `width` meters of the line between `from` and `to`.
+    Corridor {
+        from: [f64; 2],
+        to: [f64; 2],
+        /// In meters.
+        width: f64,
+    },
+}
+impl RegionShape {
+    fn contains_tile(&self, tile: &SizedTile) -> bool {
+        let tile_center = tile.coords_at_center();
+        let tile_radius = tile.distance_from_corner_to_center();
+
+        match *self {
+            RegionShape::Circle { center, radius } => {
+                math::distance(Location::from_latlng(center), tile_center) <= radius + tile_radius
+            }
+            RegionShape::Corridor { from, to, width } => {
+                math::approx_distance_to_segment(
+                    tile_center,
+                    Location::from_latlng(from),
+                    Location::from_latlng(to),
+                ) <= width + tile_radius
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PinnedRegion {
+    pub name: String,
+    pub shape: RegionShape,
+}
+
+static PINNED_REGIONS: LazyLock<RwLock<Vec<PinnedRegion>>> =
+    LazyLock::new(|| RwLock::new(Vec::new()));
+
+/// Pin a region, replacing the existing one with the same name if there is
+/// one.
+pub fn pin_region(region: PinnedRegion) {
+    let mut regions = PINNED_REGIONS.write();
+    if let Some(existing) = regions.iter_mut().find(|r| r.name == region.name) {
+        *existing = region;
+    } else {
+        regions.push(region);
+    }
+}
+
+/// Returns whether a region with the name existed.
+pub fn unpin_region(name: &str) -> bool {
+    let mut regions = PINNED_REGIONS.write();
+    let len_before = regions.len();
+    regions.retain(|r| r.name != name);
+    regions.len() != len_before
+}
+
+pub fn pinned_regions() -> Vec<PinnedRegion> {
+    PINNED_REGIONS.read().clone()
+}
+
+pub fn is_tile_pinned(tile: &SizedTile) -> bool {
+    PINNED_REGIONS
+        .read()
+        .iter()
+        .any(|region| region.shape.contains_tile(tile))
+}
+
+/// Makes [`super::PanosAtTileCache`] skip tiles in pinned regions when it's
+/// evicting, and counts the tiles that were evicted.
+#[derive(Clone, Default)]
+pub struct TilePinLifecycle {
+    pub evictions: EvictionCounter,
+}
+impl Lifecycle<SizedTile, Option<Arc<[PanoWithBothLocations]>>> for TilePinLifecycle {
+    type RequestState = ();
+
+    fn is_pinned(&self, key: &SizedTile, _val: &Option<Arc<[PanoWithBothLocations]>>) -> bool {
+        is_tile_pinned(key)
+    }
+
+    fn begin_request(&self) -> Self::RequestState {}
+
+    fn on_evict(
+        &self,
+        _state: &mut Self::RequestState,
+        _key: SizedTile,
+        _val: Option<Arc<[PanoWithBothLocations]>>,
+    ) {
+        self.evictions.increment();
+    }
+}
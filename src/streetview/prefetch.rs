@@ -0,0 +1,273 @@
+//! Downloading every tile in a region ahead of time, so the cache is warm by
+//! the time the car gets there.
+
+use std::{
+    pin::pin,
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Instant,
+};
+
+use futures::{
+    FutureExt, StreamExt,
+    future::BoxFuture,
+    stream::{self, FuturesUnordered},
+};
+use parking_lot::Mutex;
+use rustc_hash::FxHashSet;
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::{
+    db::Db,
+    math,
+    model::{Location, SizedTile, SmallTile},
+    streetview::{api::count_truncated_tiles, get_panos_at_tile},
+};
+
+/// Regions with more tiles than this are rejected. This is about the size of
+/// California.
+pub const MAX_PREFETCH_TILES: usize = 2_000_000;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct BoundingBox {
+    pub min_lat: f64,
+    pub min_lng: f64,
+    pub max_lat: f64,
+    pub max_lng: f64,
+}
+impl BoundingBox {
+    /// The range of small tiles that cover the bounding box, as the min and max
+    /// corners (inclusive).
+    pub fn tile_bounds(&self) -> (SmallTile, SmallTile) {
+        // y increases as lat decreases
+        let top_left = SmallTile::from_loc(Location::new_deg(self.max_lat, self.min_lng));
+        let bottom_right = SmallTile::from_loc(Location::new_deg(self.min_lat, self.max_lng));
+        (top_left, bottom_right)
+    }
+
+    pub fn tile_count(&self) -> usize {
+        let (min, max) = self.tile_bounds();
+        (max.x.saturating_sub(min.x) as usize + 1) * (max.y.saturating_sub(min.y) as usize + 1)
+    }
+
+    pub fn contains(&self, loc: Location) -> bool {
+        (self.min_lat..=self.max_lat).contains(&loc.lat_deg())
+            && (self.min_lng..=self.max_lng).contains(&loc.lng_deg())
+    }
+
+    /// Whether any part of the tile is inside of the bounding box.
+    pub fn intersects(&self, tile: &SizedTile) -> bool {
+        let top_left = tile.to_coords();
+        let bottom_right = SizedTile {
+            size: tile.size,
+            x: tile.x + 1,
+            y: tile.y + 1,
+        }
+        .to_coords();
+        bottom_right.lat_deg() <= self.max_lat
+            && top_left.lat_deg() >= self.min_lat
+            && top_left.lng_deg() <= self.max_lng
+            && bottom_right.lng_deg() >= self.min_lng
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PrefetchProgress {
+    pub bbox: BoundingBox,
+    pub total_tiles: usize,
+    pub done_tiles: AtomicUsize,
+    pub failed_tiles: AtomicUsize,
+    pub finished: AtomicBool,
+    #[serde(skip)]
+    started_at: Instant,
+}
+impl PrefetchProgress {
+    pub fn elapsed_seconds(&self) -> f64 {
+        self.started_at.elapsed().as_secs_f64()
+    }
+}
+
+struct RunningPrefetch {
+    progress: Arc<PrefetchProgress>,
+    cancel: CancellationToken,
+}
+
+/// The prefetch that's currently running (or the last one that finished).
+/// Only one can run at a time.
+static PREFETCH: LazyLock<Mutex<Option<RunningPrefetch>>> = LazyLock::new(Mutex::default);
+
+/// Start prefetching the region in the background. Returns `None` if a prefetch
+/// is already running.
+pub fn start_prefetch(
+    db: &'static Db,
+    bbox: BoundingBox,
+    concurrency: usize,
+) -> Option<Arc<PrefetchProgress>> {
+    let mut prefetch = PREFETCH.lock();
+    if let Some(running) = &*prefetch
+        && !running.progress.finished.load(Ordering::Relaxed)
+    {
+        return None;
+    }
+
+    let progress = Arc::new(PrefetchProgress {
+        bbox,
+        total_tiles: bbox.tile_count(),
+        done_tiles: AtomicUsize::new(0),
+        failed_tiles: AtomicUsize::new(0),
+        finished: AtomicBool::new(false),
+        started_at: Instant::now(),
+    });
+    let cancel = CancellationToken::new();
+    tokio::spawn(prefetch_region(
+        db,
+        progress.clone(),
+        concurrency.max(1),
+        cancel.clone(),
+    ));
+    *prefetch = Some(RunningPrefetch {
+        progress: progress.clone(),
+        cancel,
+    });
+
+    Some(progress)
+}
+
+pub fn prefetch_progress() -> Option<Arc<PrefetchProgress>> {
+    PREFETCH.lock().as_ref().map(|p| p.progress.clone())
+}
+
+/// Stop the running prefetch, returning false if there wasn't one. The tiles
+/// that are already being downloaded are still finished.
+pub fn cancel_prefetch() -> bool {
+    let prefetch = PREFETCH.lock();
+    let Some(running) = &*prefetch else {
+        return false;
+    };
+    if running.progress.finished.swap(true, Ordering::Relaxed) {
+        return false;
+    }
+    running.cancel.cancel();
+    info!("Cancelled prefetch");
+    true
+}
+
+async fn prefetch_region(
+    db: &Db,
+    progress: Arc<PrefetchProgress>,
+    concurrency: usize,
+    cancel: CancellationToken,
+) {
+    let (min, max) = progress.bbox.tile_bounds();
+    info!(
+        "Prefetching {} tiles in {:?}",
+        progress.total_tiles, progress.bbox
+    );
+
+    let tiles = (min.x..=max.x).flat_map(|x| (min.y..=max.y).map(move |y| SmallTile { x, y }));
+    stream::iter(tiles)
+        .take_until(cancel.cancelled())
+        .map(|tile| async move { (tile, get_panos_at_tile(db, tile).await) })
+        .buffer_unordered(concurrency)
+        .for_each(|(tile, res)| {
+            if let Err(err) = res {
+                warn!("Failed to prefetch {tile:?}: {err}");
+                progress.failed_tiles.fetch_add(1, Ordering::Relaxed);
+            }
+            let done = progress.done_tiles.fetch_add(1, Ordering::Relaxed) + 1;
+            if done.is_multiple_of(1000) {
+                info!("Prefetched {done}/{} tiles", progress.total_tiles);
+            }
+            async {}
+        })
+        .await;
+
+    if cancel.is_cancelled() {
+        return;
+    }
+    progress.finished.store(true, Ordering::Relaxed);
+    info!(
+        "Finished prefetching {} tiles in {:.0}s",
+        progress.total_tiles,
+        progress.elapsed_seconds()
+    );
+}
+
+/// How far ahead of the search's best node we prefetch tiles, in meters.
+const SPECULATIVE_DISTANCE: f64 = 2000.;
+const SPECULATIVE_STEP: f64 = 250.;
+/// The maximum number of speculative tile fetches that can run at once.
+const MAX_SPECULATIVE_IN_FLIGHT: usize = 4;
+
+/// Fetches tiles that the search will probably need soon, so the network
+/// latency overlaps with the search instead of stalling it.
+///
+/// The fetches hold the tile's lock while they're downloading it and other
+/// searches might be waiting for it, so the search has to keep them going by
+/// awaiting with [`Self::run_while`]. Fetches that haven't finished when the
+/// prefetcher is dropped are cancelled.
+pub struct SpeculativePrefetcher<'a> {
+    db: &'a Db,
+    requested: FxHashSet<SmallTile>,
+    in_flight: FuturesUnordered<BoxFuture<'a, ()>>,
+    /// The search's [`crate::astar::PathSettings::truncated_tiles`].
+    truncated_tiles: Arc<AtomicU64>,
+}
+impl<'a> SpeculativePrefetcher<'a> {
+    pub fn new(db: &'a Db, truncated_tiles: Arc<AtomicU64>) -> Self {
+        Self {
+            db,
+            requested: FxHashSet::default(),
+            in_flight: FuturesUnordered::new(),
+            truncated_tiles,
+        }
+    }
+
+    /// Make progress on the fetches without waiting for them.
+    pub fn poll(&mut self) {
+        while let Some(Some(())) = self.in_flight.next().now_or_never() {}
+    }
+
+    /// Wait for the future while the fetches keep going.
+    pub async fn run_while<T>(&mut self, fut: impl Future<Output = T>) -> T {
+        self.poll();
+        let mut fut = pin!(fut);
+        loop {
+            tokio::select! {
+                res = &mut fut => return res,
+                Some(()) = self.in_flight.next(), if !self.in_flight.is_empty() => {}
+            }
+        }
+    }
+
+    /// Queue the tiles along the line from `loc` in the direction of
+    /// `heading`.
+    pub fn prefetch_ahead(&mut self, loc: Location, heading: f32) {
+        self.poll();
+
+        let mut distance = SPECULATIVE_STEP;
+        while distance <= SPECULATIVE_DISTANCE && self.in_flight.len() < MAX_SPECULATIVE_IN_FLIGHT {
+            let tile = SmallTile::from_loc(math::point_at_distance(loc, heading, distance));
+            distance += SPECULATIVE_STEP;
+
+            if !self.requested.insert(tile) || self.db.is_tile_cached(&self.db.read_txn(), &tile) {
+                continue;
+            }
+
+            let db = self.db;
+            let fetch = async move {
+                if let Err(err) = get_panos_at_tile(db, tile).await {
+                    debug!("Speculative prefetch of {tile:?} failed: {err}");
+                }
+            };
+            self.in_flight.push(Box::pin(count_truncated_tiles(
+                self.truncated_tiles.clone(),
+                fetch,
+            )));
+        }
+    }
+}
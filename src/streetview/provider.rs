@@ -0,0 +1,53 @@
+//! Where panos come from. The pathfinder only talks to Google through
+//! [`PanoProvider`], so the search code doesn't have to change to use a
+//! different source, like recorded responses in tests or a self-hosted mirror.
+//! The provider is set per database with [`Db::set_pano_provider`], since the
+//! pano IDs it returns are saved there.
+
+use futures::future::BoxFuture;
+
+use crate::{
+    db::Db,
+    model::{ApiPano, ApiPanoId, GetMetadataResponse, SizedTile},
+    streetview::api,
+};
+
+pub trait PanoProvider: Send + Sync {
+    /// Every pano in the tile, sorted by latitude. Returns `None` if there were
+    /// too many panos to list them all, in which case a smaller tile will be
+    /// requested. Providers must always return the panos they can for tiles of
+    /// [`crate::model::SMALL_TILE_SIZE`], since there's nothing smaller.
+    fn panos_near(&self, tile: SizedTile) -> BoxFuture<'_, eyre::Result<Option<Box<[ApiPano]>>>>;
+
+    /// The locations and links of the panos. Panos that the provider doesn't
+    /// know about are left out. The IDs of the panos are converted with
+    /// [`Db::get_pano_id`].
+    fn metadata_for<'a>(
+        &'a self,
+        db: &'a Db,
+        pano_ids: &'a [ApiPanoId],
+    ) -> BoxFuture<'a, eyre::Result<Vec<GetMetadataResponse>>>;
+
+    /// The most pano IDs that can be passed to [`Self::metadata_for`] at once.
+    fn max_metadata_batch_size(&self) -> usize {
+        // google's GetMetadata refuses to reply if we request more than 200 at a time
+        200
+    }
+}
+
+/// Google's internal Maps APIs, the same ones that the game uses.
+pub struct GoogleProvider;
+
+impl PanoProvider for GoogleProvider {
+    fn panos_near(&self, tile: SizedTile) -> BoxFuture<'_, eyre::Result<Option<Box<[ApiPano]>>>> {
+        Box::pin(api::try_get_panos_at_tile(tile))
+    }
+
+    fn metadata_for<'a>(
+        &'a self,
+        db: &'a Db,
+        pano_ids: &'a [ApiPanoId],
+    ) -> BoxFuture<'a, eyre::Result<Vec<GetMetadataResponse>>> {
+        Box::pin(api::fetch_getmetadata_responses(db, pano_ids))
+    }
+}
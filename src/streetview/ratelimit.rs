@@ -0,0 +1,108 @@
+//! A token bucket that limits how many requests we send to Google per second.
+//! Requests that would go over the limit wait for a token instead of failing.
+
+use std::{
+    sync::{
+        LazyLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+use tokio::time::{Instant, sleep};
+
+use crate::config::CONFIG;
+
+/// The limiter for all of our requests to Google, see
+/// [`Config::google_max_rps`](crate::config::Config::google_max_rps).
+pub static GOOGLE_RATE_LIMITER: LazyLock<RateLimiter> =
+    LazyLock::new(|| RateLimiter::new(CONFIG.google_max_rps, CONFIG.google_burst));
+
+pub struct RateLimiter {
+    /// Tokens per second, or 0 if there's no limit.
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+    queued: AtomicUsize,
+}
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        let burst = burst.max(1.);
+        Self {
+            rate: rate.max(0.),
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                last_refill: Instant::now(),
+            }),
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// Wait until we're allowed to make a request.
+    pub async fn acquire(&self) {
+        if self.rate == 0. {
+            return;
+        }
+
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        // decrement the counter even if the future is dropped while waiting
+        let _queued = QueuedGuard(&self.queued);
+
+        while let Some(wait) = self.try_take() {
+            sleep(wait).await;
+        }
+    }
+
+    /// Take a token if there is one, or return how long until there will be.
+    fn try_take(&self) -> Option<Duration> {
+        let mut bucket = self.bucket.lock();
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1. - bucket.tokens) / self.rate))
+        }
+    }
+
+    /// The number of requests that are currently waiting for a token.
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+}
+
+struct QueuedGuard<'a>(&'a AtomicUsize);
+impl Drop for QueuedGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rate_limiter() {
+        let limiter = RateLimiter::new(100., 2.);
+        let start = Instant::now();
+        for _ in 0..12 {
+            limiter.acquire().await;
+        }
+        // the first two are free, and the rest take 10ms each
+        assert!(start.elapsed() >= Duration::from_millis(95));
+        assert_eq!(limiter.queue_depth(), 0);
+    }
+}
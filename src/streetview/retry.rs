@@ -0,0 +1,203 @@
+//! Retrying requests to Google that failed in ways that might not happen again,
+//! like being ratelimited or Google being temporarily unavailable. Retries wait
+//! exponentially longer each time, and only so many are allowed compared to the
+//! number of requests, so that an outage doesn't multiply our traffic.
+//!
+//! Configured with the `google_*` settings in [`crate::config`].
+
+use std::{sync::LazyLock, time::Duration};
+
+use http::{HeaderMap, StatusCode, header};
+use parking_lot::Mutex;
+use tokio::time::sleep;
+use tracing::warn;
+
+use crate::{config::CONFIG, math::random::with_jitter};
+
+pub static GOOGLE_RETRY_POLICY: LazyLock<RetryPolicy> = LazyLock::new(|| RetryPolicy {
+    max_attempts: CONFIG.google_max_attempts,
+    base_delay: CONFIG.google_retry_base_delay,
+    max_delay: CONFIG.google_retry_max_delay,
+    budget: RetryBudget::new(CONFIG.google_retry_ratio),
+});
+
+/// Returned by requests that failed in a way that's worth retrying.
+#[derive(Debug)]
+pub struct Retryable {
+    pub reason: String,
+    /// How long the server asked us to wait before retrying.
+    pub retry_after: Option<Duration>,
+}
+impl std::fmt::Display for Retryable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+impl std::error::Error for Retryable {}
+
+impl Retryable {
+    /// A [`Retryable`] error if the status means that the request might succeed
+    /// later (429 or a 5xx).
+    pub fn from_status(status: StatusCode, headers: &HeaderMap) -> Option<Self> {
+        if status != StatusCode::TOO_MANY_REQUESTS && !status.is_server_error() {
+            return None;
+        }
+        let retry_after = headers
+            .get(header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .map(Duration::from_secs);
+        Some(Self {
+            reason: format!("Google responded with {status}"),
+            retry_after,
+        })
+    }
+}
+
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    budget: RetryBudget,
+}
+
+impl RetryPolicy {
+    /// Run the request until it succeeds, fails with an error that isn't
+    /// [`Retryable`] (or a timeout or connection error), runs out of attempts,
+    /// or the retry budget runs out.
+    pub async fn run<T, Fut>(&self, what: &str, mut request: impl FnMut() -> Fut) -> eyre::Result<T>
+    where
+        Fut: Future<Output = eyre::Result<T>>,
+    {
+        self.budget.deposit();
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let err = match request().await {
+                Ok(res) => return Ok(res),
+                Err(err) => err,
+            };
+            let Some(retry_after) = retry_after(&err) else {
+                return Err(err);
+            };
+            if attempt >= self.max_attempts {
+                warn!("{what} failed after {attempt} attempts: {err}");
+                return Err(err);
+            }
+            if !self.budget.withdraw() {
+                warn!("{what} failed and the retry budget is used up: {err}");
+                return Err(err);
+            }
+
+            let delay = retry_after
+                .unwrap_or_else(|| with_jitter(self.delay(attempt)))
+                .min(self.max_delay);
+            warn!("{what} failed (attempt {attempt}), retrying in {delay:?}: {err}");
+            sleep(delay).await;
+        }
+    }
+
+    /// The delay after the given number of failed attempts, before jitter.
+    fn delay(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(2_u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay)
+    }
+}
+
+/// `None` if the error isn't worth retrying, and otherwise how long the server
+/// asked us to wait (if it did).
+fn retry_after(err: &eyre::Report) -> Option<Option<Duration>> {
+    if let Some(retryable) = err.downcast_ref::<Retryable>() {
+        return Some(retryable.retry_after);
+    }
+    if let Some(err) = err.downcast_ref::<reqwest::Error>()
+        && (err.is_timeout() || err.is_connect())
+    {
+        return Some(None);
+    }
+    None
+}
+
+/// Every request adds `ratio` tokens (up to a limit), and every retry takes
+/// one.
+struct RetryBudget {
+    ratio: f64,
+    tokens: Mutex<f64>,
+}
+/// The most retries that can be saved up.
+const MAX_RETRY_TOKENS: f64 = 100.;
+
+impl RetryBudget {
+    fn new(ratio: f64) -> Self {
+        Self {
+            ratio: ratio.max(0.),
+            // some retries are allowed right after starting
+            tokens: Mutex::new(10.),
+        }
+    }
+
+    fn deposit(&self) {
+        let mut tokens = self.tokens.lock();
+        *tokens = (*tokens + self.ratio).min(MAX_RETRY_TOKENS);
+    }
+
+    fn withdraw(&self) -> bool {
+        let mut tokens = self.tokens.lock();
+        if *tokens < 1. {
+            return false;
+        }
+        *tokens -= 1.;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+            budget: RetryBudget::new(0.2),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retries_only_retryable_errors() {
+        let attempts = AtomicU32::new(0);
+        let res = policy(3)
+            .run("test", || async {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                Err::<(), _>(eyre::Report::new(Retryable {
+                    reason: "503".to_string(),
+                    retry_after: None,
+                }))
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+
+        let attempts = AtomicU32::new(0);
+        let res = policy(3)
+            .run("test", || async {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                Err::<(), _>(eyre::eyre!("bad response"))
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_delay_doubles() {
+        let policy = policy(10);
+        assert_eq!(policy.delay(1), Duration::from_millis(1));
+        assert_eq!(policy.delay(3), Duration::from_millis(4));
+        assert_eq!(policy.delay(20), Duration::from_millis(5));
+    }
+}
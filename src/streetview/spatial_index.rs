@@ -0,0 +1,313 @@
+//! An R-tree over the cached panos of each region, so nearest-pano queries
+//! with a large radius don't have to scan every tile they touch. A region is a
+//! tile at [`REGION_ZOOM`], which always contains whole [`SizedTile`]s. Its
+//! index is built the first time it's needed and dropped whenever one of its
+//! tiles is saved or deleted.
+
+use std::{
+    hash::{BuildHasher, BuildHasherDefault},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use quick_cache::sync::Cache;
+use rstar::{AABB, RTree, primitives::GeomWithData};
+use rustc_hash::{FxHashSet, FxHasher};
+use tokio::runtime::RuntimeFlavor;
+
+use crate::{
+    db::Db,
+    math,
+    model::{
+        LARGEST_TILE_SIZE, Location, Pano, PanoWithBothLocations, SMALL_TILE_SIZE, SizedTile,
+        SmallTile,
+    },
+    streetview::{
+        calculate_lat_bounds, calculate_lng_bounds, calculate_tile_bounds, get_panos_at_tile,
+    },
+};
+
+/// The zoom level of the regions that get their own index. At 10, each one is
+/// 64×64 small tiles.
+pub const REGION_ZOOM: u8 = 10;
+const _: () = assert!(REGION_ZOOM <= LARGEST_TILE_SIZE);
+
+/// How many regions are kept indexed at once.
+const REGION_CACHE_SIZE: usize = 64;
+
+/// [`super::get_nearest_pano`] uses the index when the radius is at least
+/// this many meters, since smaller searches only touch a few tiles anyway.
+pub const MIN_INDEXED_RADIUS: f64 = 500.;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+}
+impl Region {
+    pub fn of_tile(tile: &SizedTile) -> Self {
+        let shift = tile.size - REGION_ZOOM;
+        Self {
+            x: tile.x >> shift,
+            y: tile.y >> shift,
+        }
+    }
+}
+
+pub type PanoIndexCache = Cache<Region, Arc<PanoIndex>>;
+
+pub fn new_pano_index_cache() -> PanoIndexCache {
+    Cache::new(REGION_CACHE_SIZE)
+}
+
+/// How many version counters regions are spread over in [`RegionVersions`].
+const REGION_VERSION_SLOTS: usize = 1024;
+
+/// A version for each region that's bumped whenever its index is dropped, so
+/// an index that was being built from an older snapshot at the time isn't
+/// cached. Regions share counters, which only means that an index is
+/// occasionally built again when it didn't have to be.
+pub struct RegionVersions(Box<[AtomicU64]>);
+impl Default for RegionVersions {
+    fn default() -> Self {
+        Self(
+            (0..REGION_VERSION_SLOTS)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+        )
+    }
+}
+impl RegionVersions {
+    fn slot(&self, region: Region) -> &AtomicU64 {
+        let hash = BuildHasherDefault::<FxHasher>::default().hash_one(region);
+        &self.0[hash as usize % self.0.len()]
+    }
+
+    pub fn get(&self, region: Region) -> u64 {
+        self.slot(region).load(Ordering::Acquire)
+    }
+
+    pub fn bump(&self, region: Region) {
+        self.slot(region).fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub fn bump_all(&self) {
+        for version in &self.0 {
+            version.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+}
+
+impl Db {
+    /// Drop the region's index since one of its tiles changed. This has to be
+    /// called after the change was committed.
+    pub fn invalidate_region_index(&self, region: Region) {
+        self.pano_index_versions.bump(region);
+        self.pano_index_cache.remove(&region);
+    }
+
+    /// Drop every region's index, see [`Self::invalidate_region_index`].
+    pub fn invalidate_all_region_indexes(&self) {
+        self.pano_index_versions.bump_all();
+        self.pano_index_cache.clear();
+    }
+}
+
+/// The panos, keyed by their `[lng, lat]` search location in degrees.
+pub struct PanoIndex {
+    tree: RTree<GeomWithData<[f64; 2], PanoWithBothLocations>>,
+}
+impl PanoIndex {
+    pub fn new(panos: Vec<PanoWithBothLocations>) -> Self {
+        let points = panos
+            .into_iter()
+            .map(|pano| {
+                GeomWithData::new([pano.search_loc.lng_deg(), pano.search_loc.lat_deg()], pano)
+            })
+            .collect();
+        Self {
+            tree: RTree::bulk_load(points),
+        }
+    }
+
+    /// The pano whose search location is closest to `loc` and within
+    /// `max_distance` meters, and how far away it is.
+    pub fn nearest(
+        &self,
+        loc: Location,
+        max_distance: f64,
+    ) -> Option<(&PanoWithBothLocations, f64)> {
+        let (min_lat, max_lat) = calculate_lat_bounds(loc, max_distance);
+        let (min_lng, max_lng) = calculate_lng_bounds(loc, max_distance);
+        let envelope = AABB::from_corners(
+            [min_lng.to_deg(), min_lat.to_deg()],
+            [max_lng.to_deg(), max_lat.to_deg()],
+        );
+        self.tree
+            .locate_in_envelope(&envelope)
+            .map(|point| (&point.data, math::distance(point.data.search_loc, loc)))
+            .filter(|(_, distance)| *distance <= max_distance)
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.size()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Like [`super::get_nearest_pano`], but using the index of every region that
+/// the radius touches. Tiles that aren't cached are downloaded first.
+pub async fn get_nearest_pano(
+    db: &Db,
+    loc: Location,
+    max_distance: f64,
+) -> eyre::Result<Option<Pano>> {
+    let origin_tile = SmallTile::from_loc(loc);
+    let (min_tile, max_tile) = calculate_tile_bounds(loc, max_distance);
+    let uncached_tiles = {
+        let txn = db.read_txn();
+        (min_tile.x..=max_tile.x)
+            .flat_map(|x| (min_tile.y..=max_tile.y).map(move |y| SmallTile { x, y }))
+            .filter(|tile| *tile == origin_tile || tile.is_maybe_within_radius(loc, max_distance))
+            .filter(|tile| !db.is_tile_cached(&txn, tile))
+            .collect::<Vec<_>>()
+    };
+    for tile in uncached_tiles {
+        get_panos_at_tile(db, tile).await?;
+    }
+
+    let min_region = Region::of_tile(&SizedTile::from(min_tile));
+    let max_region = Region::of_tile(&SizedTile::from(max_tile));
+    let mut nearest: Option<(PanoWithBothLocations, f64)> = None;
+    for x in min_region.x..=max_region.x {
+        for y in min_region.y..=max_region.y {
+            let index = region_index(db, Region { x, y }).await?;
+            if let Some((pano, distance)) = index.nearest(loc, max_distance)
+                && nearest.as_ref().is_none_or(|(_, d)| distance < *d)
+            {
+                nearest = Some((pano.clone(), distance));
+            }
+        }
+    }
+
+    Ok(nearest.map(|(pano, _)| Pano {
+        id: pano.id,
+        loc: pano.actual_loc,
+    }))
+}
+
+/// The index of the cached panos in the region, building it if necessary.
+pub async fn region_index(db: &Db, region: Region) -> eyre::Result<Arc<PanoIndex>> {
+    if let Some(index) = db.pano_index_cache.get(&region) {
+        return Ok(index);
+    }
+    // building it reads every tile in the region, so let the runtime move the
+    // other tasks off of this thread while we do
+    let build = || build_and_cache_region_index(db, region);
+    let index = match tokio::runtime::Handle::try_current().map(|rt| rt.runtime_flavor()) {
+        Ok(RuntimeFlavor::MultiThread) => tokio::task::block_in_place(build),
+        _ => build(),
+    };
+    Ok(index)
+}
+
+fn build_and_cache_region_index(db: &Db, region: Region) -> Arc<PanoIndex> {
+    // read before the snapshot is taken, so a tile that's saved after it bumps
+    // the version
+    let version = db.pano_index_versions.get(region);
+    let index = Arc::new(build_region_index(db, region));
+    cache_region_index(db, region, version, index.clone());
+    index
+}
+
+/// Cache the index, unless the region changed since `version`.
+fn cache_region_index(db: &Db, region: Region, version: u64, index: Arc<PanoIndex>) {
+    db.pano_index_cache.insert(region, index);
+    // if a tile was saved while we were building, the index might be missing it.
+    // this is checked after inserting since the tile might've been saved (and the
+    // cache entry removed) right before we inserted.
+    if db.pano_index_versions.get(region) != version {
+        db.pano_index_cache.remove(&region);
+    }
+}
+
+fn build_region_index(db: &Db, region: Region) -> PanoIndex {
+    let txn = db.read_txn();
+    let shift = SMALL_TILE_SIZE - REGION_ZOOM;
+    let mut used_tiles = FxHashSet::<SizedTile>::default();
+    let mut panos = Vec::new();
+    for x in region.x << shift..(region.x + 1) << shift {
+        for y in region.y << shift..(region.y + 1) << shift {
+            // like get_panos_at_tile, use the largest size that had all the panos
+            for tile in (SmallTile { x, y }).get_all_sizes() {
+                if used_tiles.contains(&tile) {
+                    break;
+                }
+                if let Some(Some(tile_panos)) = db.lookup_listentityphotos_with_txn(&txn, &tile) {
+                    used_tiles.insert(tile);
+                    panos.extend(tile_panos.iter().cloned());
+                    break;
+                }
+            }
+        }
+    }
+    PanoIndex::new(panos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::PanoId;
+
+    fn pano(id: u32, lat: f64, lng: f64) -> PanoWithBothLocations {
+        PanoWithBothLocations {
+            id: PanoId(id),
+            search_loc: Location::new_deg(lat, lng),
+            actual_loc: Location::new_deg(lat, lng),
+        }
+    }
+
+    #[test]
+    fn test_nearest() {
+        let index = PanoIndex::new(vec![
+            pano(0, 0., 0.),
+            pano(1, 0., 0.01),
+            pano(2, 0.002, 0.005),
+        ]);
+        let loc = Location::new_deg(0., 0.006);
+        let (nearest, distance) = index.nearest(loc, 1000.).unwrap();
+        assert_eq!(nearest.id, PanoId(2));
+        assert!((distance - 248.).abs() < 5., "{distance}");
+        // the closest one is ~250m away
+        assert!(index.nearest(loc, 200.).is_none());
+    }
+
+    #[test]
+    fn test_stale_region_index_isnt_cached() {
+        let db = Db::temp("stale-region-index");
+        let region = Region { x: 1, y: 2 };
+        build_and_cache_region_index(&db, region);
+        assert!(db.pano_index_cache.get(&region).is_some());
+
+        db.invalidate_region_index(region);
+        assert!(db.pano_index_cache.get(&region).is_none());
+
+        // an index that was being built while the region changed isn't cached
+        let version = db.pano_index_versions.get(region);
+        db.invalidate_region_index(region);
+        cache_region_index(&db, region, version, Arc::new(PanoIndex::new(Vec::new())));
+        assert!(db.pano_index_cache.get(&region).is_none());
+    }
+
+    #[test]
+    fn test_region_contains_sized_tiles() {
+        let small = SizedTile::from(SmallTile { x: 1000, y: 2000 });
+        let large = SmallTile { x: 1000, y: 2000 }.get_all_sizes()[0];
+        assert_eq!(Region::of_tile(&small), Region::of_tile(&large));
+    }
+}
@@ -0,0 +1,62 @@
+//! Remembering which tiles were in the in-memory tile cache, so it can be
+//! filled again in the background after a restart. Otherwise the first search
+//! after a restart has to read every tile it needs from LMDB.
+
+use std::time::Duration;
+
+use tracing::{error, info};
+
+use crate::db::Db;
+
+/// How often the cached tiles are saved.
+const SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Save which tiles are in the in-memory tile cache. Returns the number of
+/// tiles.
+pub fn save_cached_tiles(db: &Db) -> eyre::Result<usize> {
+    let tiles = db
+        .panos_at_tile_cache
+        .iter()
+        .map(|(tile, _)| tile)
+        .collect::<Vec<_>>();
+    db.save_warm_tiles(&tiles)?;
+    Ok(tiles.len())
+}
+
+/// Save the cached tiles every [`SAVE_INTERVAL`].
+pub async fn save_periodically(db: &'static Db) {
+    loop {
+        tokio::time::sleep(SAVE_INTERVAL).await;
+        match tokio::task::spawn_blocking(|| save_cached_tiles(db)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => error!("Failed to save the cached tiles: {err}"),
+            Err(err) => error!("Saving the cached tiles panicked: {err}"),
+        }
+    }
+}
+
+/// Load the tiles that were saved by [`save_cached_tiles`] into the in-memory
+/// cache. Tiles that are already cached or have gone stale are skipped, and it
+/// stops once the cache is full so it doesn't evict anything. Returns the
+/// number of tiles that were loaded.
+pub fn warm_up(db: &Db) -> usize {
+    let tiles = db.get_warm_tiles();
+    let mut loaded = 0;
+    for tile in &tiles {
+        if db.panos_at_tile_cache.weight() >= db.panos_at_tile_cache.capacity() {
+            break;
+        }
+        if db.panos_at_tile_cache.contains_key(tile) {
+            continue;
+        }
+        if let Some(res) = db.lookup_fresh_listentityphotos(tile) {
+            db.panos_at_tile_cache.insert(*tile, res);
+            loaded += 1;
+        }
+    }
+    info!(
+        "Loaded {loaded} of {} saved tiles into the tile cache",
+        tiles.len()
+    );
+    loaded
+}
@@ -0,0 +1,165 @@
+//! Formatting for the human-readable parts of our responses (distances,
+//! durations, summaries), which depend on the unit system and locale that the
+//! client asked for.
+
+pub use pathfinder_protocol::Units;
+
+const METERS_PER_MILE: f64 = 1609.344;
+const FEET_PER_METER: f64 = 3.28084;
+
+/// How numbers should be written. This is derived from a BCP 47 language tag
+/// like `en-US` or `de`, but we only care about the separators.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Locale {
+    pub decimal_separator: char,
+    pub thousands_separator: char,
+}
+impl Default for Locale {
+    fn default() -> Self {
+        Self {
+            decimal_separator: '.',
+            thousands_separator: ',',
+        }
+    }
+}
+impl Locale {
+    pub fn from_tag(tag: &str) -> Self {
+        let language = tag
+            .split(['-', '_'])
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+
+        match language.as_str() {
+            // languages that write 1.234,5
+            "de" | "es" | "it" | "nl" | "pt" | "id" | "tr" | "da" | "el" => Self {
+                decimal_separator: ',',
+                thousands_separator: '.',
+            },
+            // languages that write 1 234,5 (with a narrow no-break space)
+            "fr" | "ru" | "pl" | "cs" | "sv" | "fi" | "nb" | "no" | "uk" => Self {
+                decimal_separator: ',',
+                thousands_separator: '\u{202f}',
+            },
+            _ => Self::default(),
+        }
+    }
+
+    /// Formats the number with the given amount of digits after the decimal
+    /// point.
+    pub fn format_number(&self, n: f64, decimals: usize) -> String {
+        let formatted = format!("{:.*}", decimals, n.abs());
+        let (int_part, frac_part) = match formatted.split_once('.') {
+            Some((i, f)) => (i, Some(f)),
+            None => (formatted.as_str(), None),
+        };
+
+        let mut res = String::new();
+        if n < 0. && formatted.bytes().any(|b| b != b'0' && b != b'.') {
+            res.push('-');
+        }
+        for (i, c) in int_part.chars().enumerate() {
+            if i != 0 && (int_part.len() - i).is_multiple_of(3) {
+                res.push(self.thousands_separator);
+            }
+            res.push(c);
+        }
+        if let Some(frac_part) = frac_part {
+            res.push(self.decimal_separator);
+            res.push_str(frac_part);
+        }
+        res
+    }
+}
+
+/// The unit system and locale that were requested by the client.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Formatter {
+    pub units: Units,
+    pub locale: Locale,
+}
+impl Formatter {
+    pub fn new(units: Units, locale: Option<&str>) -> Self {
+        Self {
+            units,
+            locale: locale.map(Locale::from_tag).unwrap_or_default(),
+        }
+    }
+
+    /// Formats a distance in meters as something like `12.3 km` or `7.6 mi`.
+    pub fn distance(&self, meters: f64) -> String {
+        match self.units {
+            Units::Metric => {
+                if meters < 1000. {
+                    format!("{} m", self.locale.format_number(meters.round(), 0))
+                } else {
+                    let km = meters / 1000.;
+                    let decimals = if km < 100. { 1 } else { 0 };
+                    format!("{} km", self.locale.format_number(km, decimals))
+                }
+            }
+            Units::Imperial => {
+                let miles = meters / METERS_PER_MILE;
+                if miles < 0.1 {
+                    let feet = meters * FEET_PER_METER;
+                    format!("{} ft", self.locale.format_number(feet.round(), 0))
+                } else {
+                    let decimals = if miles < 100. { 1 } else { 0 };
+                    format!("{} mi", self.locale.format_number(miles, decimals))
+                }
+            }
+        }
+    }
+
+    /// Formats a duration as something like `2 h 5 min` or `40 s`.
+    pub fn duration(&self, seconds: f64) -> String {
+        let seconds = seconds.max(0.).round() as u64;
+        let (hours, minutes, seconds) = (seconds / 3600, (seconds / 60) % 60, seconds % 60);
+        if hours > 0 {
+            format!("{hours} h {minutes} min")
+        } else if minutes > 0 {
+            format!("{minutes} min")
+        } else {
+            format!("{seconds} s")
+        }
+    }
+
+    /// A one-line description of a finished path, like `12.3 km, about 2 h 5
+    /// min`.
+    pub fn path_summary(&self, meters: f64, seconds: f64) -> String {
+        format!(
+            "{}, about {}",
+            self.distance(meters),
+            self.duration(seconds)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_distance() {
+        let metric = Formatter::new(Units::Metric, None);
+        assert_eq!(metric.distance(512.3), "512 m");
+        assert_eq!(metric.distance(12_345.), "12.3 km");
+        assert_eq!(metric.distance(1_234_567.), "1,235 km");
+
+        let imperial = Formatter::new(Units::Imperial, Some("en-US"));
+        assert_eq!(imperial.distance(100.), "328 ft");
+        assert_eq!(imperial.distance(16_093.44), "10.0 mi");
+
+        let german = Formatter::new(Units::Metric, Some("de-DE"));
+        assert_eq!(german.distance(1_234_567.), "1.235 km");
+        assert_eq!(german.distance(12_345.), "12,3 km");
+    }
+
+    #[test]
+    fn test_format_duration() {
+        let f = Formatter::default();
+        assert_eq!(f.duration(40.), "40 s");
+        assert_eq!(f.duration(125.), "2 min");
+        assert_eq!(f.duration(7500.), "2 h 5 min");
+    }
+}
@@ -0,0 +1,656 @@
+//! Endpoints for managing the running instance. These all require the `key`
+//! query parameter to match `PATHFINDER_SECRET`, and are disabled if it isn't
+//! set.
+
+use std::{path::PathBuf, sync::atomic::Ordering, time::Instant};
+
+use axum::{
+    Json,
+    body::Bytes,
+    extract::{Query, State},
+    response::{IntoResponse, Response},
+};
+use http::{StatusCode, header};
+use serde::Deserialize;
+use simd_json::json;
+use tracing::warn;
+
+use crate::{
+    backup::{self, BackupError},
+    bake,
+    calibration::{self, DelaySample},
+    db::DB,
+    deviation, landmarks, learned_options, math,
+    model::Location,
+    roadtrip, roadtrip_api, shortcuts,
+    streetview::{
+        self,
+        pinning::{self, PinnedRegion, RegionShape},
+        prefetch::{self, BoundingBox, MAX_PREFETCH_TILES},
+    },
+    web::{config::CONFIG, ratelimit::AppState},
+};
+
+pub fn is_key_valid(key: Option<&str>) -> bool {
+    // in theory this is vulnerable to timing attacks, but the latency difference is
+    // nanoseconds and it's impractical to exploit over the network so it's
+    // acceptable here
+    !CONFIG.secret.is_empty() && key == Some(CONFIG.secret.as_str())
+}
+
+fn incorrect_key() -> Response {
+    (StatusCode::UNAUTHORIZED, "incorrect key\n").into_response()
+}
+
+#[derive(Deserialize)]
+pub struct KeyQuery {
+    key: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ActiveRouteQuery {
+    key: Option<String>,
+    /// Unset to stop tracking.
+    route_id: Option<String>,
+}
+
+/// Set the saved route that the car is compared to, see [`deviation`].
+pub async fn post_active_route(Query(query): Query<ActiveRouteQuery>) -> Response {
+    if !is_key_valid(query.key.as_deref()) {
+        return incorrect_key();
+    }
+
+    match deviation::set_active_route(&DB, query.route_id.as_deref()) {
+        Ok(true) => Json(json!({ "route_id": query.route_id })).into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, "unknown route\n").into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{err}\n")).into_response(),
+    }
+}
+
+pub async fn get_tile_cache(Query(query): Query<KeyQuery>) -> Response {
+    if !is_key_valid(query.key.as_deref()) {
+        return incorrect_key();
+    }
+
+    Json(streetview::tile_cache_stats(&DB)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct PinQuery {
+    key: Option<String>,
+    name: String,
+    lat: f64,
+    lng: f64,
+    /// If this is set, the region is a corridor from lat/lng to here.
+    to_lat: Option<f64>,
+    to_lng: Option<f64>,
+    /// The radius of a circle or the width of a corridor, in meters.
+    radius: f64,
+}
+
+pub async fn post_tile_cache_pin(Query(query): Query<PinQuery>) -> Response {
+    if !is_key_valid(query.key.as_deref()) {
+        return incorrect_key();
+    }
+
+    let shape = match (query.to_lat, query.to_lng) {
+        (Some(to_lat), Some(to_lng)) => RegionShape::Corridor {
+            from: [query.lat, query.lng],
+            to: [to_lat, to_lng],
+            width: query.radius,
+        },
+        _ => RegionShape::Circle {
+            center: [query.lat, query.lng],
+            radius: query.radius,
+        },
+    };
+    pinning::pin_region(PinnedRegion {
+        name: query.name,
+        shape,
+    });
+
+    Json(json!({ "ok": true })).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct UnpinQuery {
+    key: Option<String>,
+    name: String,
+}
+
+pub async fn post_tile_cache_unpin(Query(query): Query<UnpinQuery>) -> Response {
+    if !is_key_valid(query.key.as_deref()) {
+        return incorrect_key();
+    }
+
+    let existed = pinning::unpin_region(&query.name);
+
+    Json(json!({ "ok": existed })).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct PrefetchQuery {
+    key: Option<String>,
+    min_lat: f64,
+    min_lng: f64,
+    max_lat: f64,
+    max_lng: f64,
+    /// How many tiles are fetched at the same time, defaults to 4.
+    concurrency: Option<usize>,
+}
+
+/// Start downloading all the tiles in the bounding box in the background.
+pub async fn post_prefetch(Query(query): Query<PrefetchQuery>) -> Response {
+    if !is_key_valid(query.key.as_deref()) {
+        return incorrect_key();
+    }
+
+    let bbox = BoundingBox {
+        min_lat: query.min_lat.min(query.max_lat),
+        min_lng: query.min_lng.min(query.max_lng),
+        max_lat: query.min_lat.max(query.max_lat),
+        max_lng: query.min_lng.max(query.max_lng),
+    };
+    let tile_count = bbox.tile_count();
+    if tile_count > MAX_PREFETCH_TILES {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("region has too many tiles ({tile_count}, limit is {MAX_PREFETCH_TILES})\n"),
+        )
+            .into_response();
+    }
+
+    let concurrency = query.concurrency.unwrap_or(4).clamp(1, 64);
+    match prefetch::start_prefetch(&DB, bbox, concurrency) {
+        Some(progress) => {
+            Json(json!({ "ok": true, "total_tiles": progress.total_tiles })).into_response()
+        }
+        None => (StatusCode::CONFLICT, "a prefetch is already running\n").into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BboxQuery {
+    key: Option<String>,
+    min_lat: f64,
+    min_lng: f64,
+    max_lat: f64,
+    max_lng: f64,
+}
+
+/// Delete the cached tiles that intersect the bounding box, like after Google
+/// updates the imagery there.
+pub async fn delete_cache(Query(query): Query<BboxQuery>) -> Response {
+    if !is_key_valid(query.key.as_deref()) {
+        return incorrect_key();
+    }
+
+    let bbox = BoundingBox {
+        min_lat: query.min_lat.min(query.max_lat),
+        min_lng: query.min_lng.min(query.max_lng),
+        max_lat: query.min_lat.max(query.max_lat),
+        max_lng: query.min_lng.max(query.max_lng),
+    };
+    match streetview::purge_tiles_in_bbox(&DB, &bbox) {
+        Ok(deleted) => Json(json!({ "ok": true, "deleted_tiles": deleted })).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{err}\n")).into_response(),
+    }
+}
+
+/// The largest radius that can be refreshed at once, in meters.
+const MAX_REFRESH_RADIUS: f64 = 10_000.;
+
+#[derive(Deserialize)]
+pub struct RefreshQuery {
+    key: Option<String>,
+    /// Defaults to where the car is.
+    lat: Option<f64>,
+    lng: Option<f64>,
+    /// In meters, defaults to 1000.
+    radius: Option<f64>,
+}
+
+/// Re-download the tiles around a location now, instead of waiting for the
+/// car to trigger it.
+pub async fn post_refresh(Query(query): Query<RefreshQuery>) -> Response {
+    if !is_key_valid(query.key.as_deref()) {
+        return incorrect_key();
+    }
+
+    let loc = match (query.lat, query.lng) {
+        (Some(lat), Some(lng)) => Location::new_deg(lat, lng),
+        _ => match roadtrip_api::car_position() {
+            Some(position) => Location::from_latlng(position.loc),
+            None => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    "lat and lng are required since the car's position isn't known\n",
+                )
+                    .into_response();
+            }
+        },
+    };
+    let radius = query.radius.unwrap_or(1000.).clamp(0., MAX_REFRESH_RADIUS);
+
+    let start = Instant::now();
+    match streetview::reset_cache_nearby(&DB, loc, radius).await {
+        Ok(stats) => Json(json!({
+            "ok": true,
+            "lat": loc.lat_deg(),
+            "lng": loc.lng_deg(),
+            "radius": radius,
+            "tiles_refreshed": stats.tiles_refreshed,
+            "tiles_changed": stats.tiles_changed,
+            "tiles_failed": stats.tiles_failed,
+            "elapsed_seconds": start.elapsed().as_secs_f64(),
+        }))
+        .into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{err}\n")).into_response(),
+    }
+}
+
+pub async fn get_prefetch(Query(query): Query<KeyQuery>) -> Response {
+    if !is_key_valid(query.key.as_deref()) {
+        return incorrect_key();
+    }
+
+    let Some(progress) = prefetch::prefetch_progress() else {
+        return Json(json!({ "running": false })).into_response();
+    };
+    Json(json!({
+        "running": !progress.finished.load(Ordering::Relaxed),
+        "progress": &*progress,
+        "elapsed_seconds": progress.elapsed_seconds(),
+    }))
+    .into_response()
+}
+
+pub async fn post_prefetch_cancel(Query(query): Query<KeyQuery>) -> Response {
+    if !is_key_valid(query.key.as_deref()) {
+        return incorrect_key();
+    }
+
+    Json(json!({ "ok": prefetch::cancel_prefetch() })).into_response()
+}
+
+/// Start baking the graph of the region in the background, see
+/// [`crate::bake`]. The tiles should already be cached, since any that aren't
+/// are downloaded one at a time.
+pub async fn post_bake(Query(query): Query<BboxQuery>) -> Response {
+    if !is_key_valid(query.key.as_deref()) {
+        return incorrect_key();
+    }
+
+    let bbox = BoundingBox {
+        min_lat: query.min_lat.min(query.max_lat),
+        min_lng: query.min_lng.min(query.max_lng),
+        max_lat: query.min_lat.max(query.max_lat),
+        max_lng: query.min_lng.max(query.max_lng),
+    };
+    let tile_count = bbox.tile_count();
+    if tile_count > MAX_PREFETCH_TILES {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("region has too many tiles ({tile_count}, limit is {MAX_PREFETCH_TILES})\n"),
+        )
+            .into_response();
+    }
+
+    match bake::start_bake(&DB, bbox) {
+        Some(_) => Json(json!({ "ok": true })).into_response(),
+        None => (StatusCode::CONFLICT, "a bake is already running\n").into_response(),
+    }
+}
+
+pub async fn get_bake(Query(query): Query<KeyQuery>) -> Response {
+    if !is_key_valid(query.key.as_deref()) {
+        return incorrect_key();
+    }
+
+    let baked_nodes = DB.baked_node_count();
+    let Some(progress) = bake::bake_progress() else {
+        return Json(json!({
+            "running": false,
+            "baked_nodes": baked_nodes,
+            "shortcuts": DB.shortcut_count(),
+        }))
+        .into_response();
+    };
+    Json(json!({
+        "running": !progress.finished.load(Ordering::Relaxed),
+        "baked_nodes": baked_nodes,
+        "shortcuts": DB.shortcut_count(),
+        "progress": &*progress,
+        "elapsed_seconds": progress.elapsed_seconds(),
+    }))
+    .into_response()
+}
+
+pub async fn post_bake_cancel(Query(query): Query<KeyQuery>) -> Response {
+    if !is_key_valid(query.key.as_deref()) {
+        return incorrect_key();
+    }
+
+    Json(json!({ "ok": bake::cancel_bake() })).into_response()
+}
+
+/// Delete the whole baked graph, so searches calculate every node's options
+/// again.
+pub async fn delete_bake(Query(query): Query<KeyQuery>) -> Response {
+    if !is_key_valid(query.key.as_deref()) {
+        return incorrect_key();
+    }
+
+    match DB.clear_baked_graph() {
+        Ok(deleted) => Json(json!({ "ok": true, "deleted_nodes": deleted })).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{err}\n")).into_response(),
+    }
+}
+
+/// Pick the landmarks and calculate the costs to and from them again in the
+/// background, see [`crate::landmarks`]. This also happens after every bake.
+pub async fn post_landmarks(Query(query): Query<KeyQuery>) -> Response {
+    if !is_key_valid(query.key.as_deref()) {
+        return incorrect_key();
+    }
+
+    landmarks::spawn_precompute(&DB);
+    Json(json!({ "ok": true })).into_response()
+}
+
+/// Learn options from the moves in the car history in the background, see
+/// [`learned_options::learn_from_history`].
+pub async fn post_learn_from_history(Query(query): Query<KeyQuery>) -> Response {
+    if !is_key_valid(query.key.as_deref()) {
+        return incorrect_key();
+    }
+
+    learned_options::spawn_learn_from_history(&DB);
+    Json(json!({ "ok": true })).into_response()
+}
+
+/// Find the chains in the baked graph again, see [`crate::shortcuts`]. This
+/// should be done after baking, since new nodes can start new chains.
+pub async fn post_shortcuts(Query(query): Query<KeyQuery>) -> Response {
+    if !is_key_valid(query.key.as_deref()) {
+        return incorrect_key();
+    }
+
+    match tokio::task::spawn_blocking(|| shortcuts::precompute(&DB)).await {
+        Ok(Ok(count)) => Json(json!({ "ok": true, "shortcuts": count })).into_response(),
+        Ok(Err(err)) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{err}\n")).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{err}\n")).into_response(),
+    }
+}
+
+/// Download the cached tiles in the bounding box and the GetMetadata responses
+/// of their panos, so another instance can import them with
+/// [`post_import_cache`]. See [`crate::db::export`].
+pub async fn get_export_cache(Query(query): Query<BboxQuery>) -> Response {
+    if !is_key_valid(query.key.as_deref()) {
+        return incorrect_key();
+    }
+
+    let bbox = BoundingBox {
+        min_lat: query.min_lat.min(query.max_lat),
+        min_lng: query.min_lng.min(query.max_lng),
+        max_lat: query.min_lat.max(query.max_lat),
+        max_lng: query.min_lng.max(query.max_lng),
+    };
+    let res = tokio::task::spawn_blocking(move || {
+        let mut file = Vec::new();
+        DB.export_cache(&bbox, &mut file).map(|_| file)
+    })
+    .await;
+    match res {
+        Ok(Ok(file)) => (
+            [
+                (header::CONTENT_TYPE, "application/octet-stream"),
+                (
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"pathfinder-cache.bin\"",
+                ),
+            ],
+            file,
+        )
+            .into_response(),
+        Ok(Err(err)) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{err}\n")).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{err}\n")).into_response(),
+    }
+}
+
+/// Import a file from [`get_export_cache`], which is the request body.
+pub async fn post_import_cache(Query(query): Query<KeyQuery>, body: Bytes) -> Response {
+    if !is_key_valid(query.key.as_deref()) {
+        return incorrect_key();
+    }
+
+    match tokio::task::spawn_blocking(move || DB.import_cache(&body[..])).await {
+        Ok(Ok(stats)) => Json(json!({ "ok": true, "imported": stats })).into_response(),
+        Ok(Err(err)) => (StatusCode::BAD_REQUEST, format!("{err}\n")).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{err}\n")).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct FsckQuery {
+    key: Option<String>,
+    /// Delete the entries that are corrupt.
+    #[serde(default)]
+    repair: bool,
+}
+
+/// Check that every entry in the database can be decoded, see
+/// [`crate::db::fsck`]. This reads the whole database, so it can take a while.
+pub async fn post_fsck(Query(query): Query<FsckQuery>) -> Response {
+    if !is_key_valid(query.key.as_deref()) {
+        return incorrect_key();
+    }
+
+    match tokio::task::spawn_blocking(move || DB.fsck(query.repair)).await {
+        Ok(Ok(report)) => Json(report).into_response(),
+        Ok(Err(err)) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{err}\n")).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{err}\n")).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BackupQuery {
+    key: Option<String>,
+    /// Where the copy is written. This has to be a file that doesn't exist yet.
+    path: PathBuf,
+    /// Leave out free pages, which makes the copy smaller but slower to make.
+    #[serde(default)]
+    compact: bool,
+}
+
+/// Start copying the database to a file in the background, see
+/// [`crate::backup`].
+pub async fn post_backup(Query(query): Query<BackupQuery>) -> Response {
+    if !is_key_valid(query.key.as_deref()) {
+        return incorrect_key();
+    }
+    if query.path.exists() {
+        return (StatusCode::BAD_REQUEST, "path already exists\n").into_response();
+    }
+
+    match backup::start_backup(&DB, query.path, query.compact) {
+        Ok(_) => Json(json!({ "ok": true })).into_response(),
+        Err(BackupError::AlreadyRunning) => {
+            (StatusCode::CONFLICT, "a backup is already running\n").into_response()
+        }
+        Err(BackupError::MapNearlyFull { fullness }) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!(
+                "the database map is {:.0}% full, so a backup could block writes until it's done\n",
+                fullness * 100.
+            ),
+        )
+            .into_response(),
+    }
+}
+
+pub async fn get_backup(Query(query): Query<KeyQuery>) -> Response {
+    if !is_key_valid(query.key.as_deref()) {
+        return incorrect_key();
+    }
+
+    let Some(progress) = backup::backup_progress() else {
+        return Json(json!({ "running": false })).into_response();
+    };
+    Json(json!({
+        "running": !progress.finished.load(Ordering::Relaxed),
+        "path": progress.path.display().to_string(),
+        "compact": progress.compact,
+        "bytes_written": progress.bytes_written(),
+        "expected_bytes": progress.expected_bytes,
+        "percent_done": progress.percent_done(),
+        "elapsed_seconds": progress.elapsed_seconds(),
+        "error": progress.error.lock().clone(),
+    }))
+    .into_response()
+}
+
+/// Every pathfinding task that's running.
+pub async fn get_jobs(State(state): State<AppState>, Query(query): Query<KeyQuery>) -> Response {
+    if !is_key_valid(query.key.as_deref()) {
+        return incorrect_key();
+    }
+
+    Json(json!({ "jobs": state.tasks.list() })).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct CancelJobQuery {
+    key: Option<String>,
+    id: u64,
+}
+
+/// Cancel a pathfinding task. Like when it's preempted, it still sends the path
+/// that it found so far.
+pub async fn post_jobs_cancel(
+    State(state): State<AppState>,
+    Query(query): Query<CancelJobQuery>,
+) -> Response {
+    if !is_key_valid(query.key.as_deref()) {
+        return incorrect_key();
+    }
+
+    Json(json!({ "ok": state.tasks.cancel(query.id) })).into_response()
+}
+
+/// The vote delays that the pathfinder is currently using, and how many samples
+/// they're based on.
+pub async fn get_calibration(Query(query): Query<KeyQuery>) -> Response {
+    if !is_key_valid(query.key.as_deref()) {
+        return incorrect_key();
+    }
+
+    let samples = DB.get_delay_samples();
+    Json(json!({
+        "delays": DB.vote_delays().by_option_count,
+        "sample_counts": samples.buckets.map(|(_, count)| count),
+    }))
+    .into_response()
+}
+
+/// Import historical vote delays, as a JSON array of
+/// `{"option_count": 2, "delay": 9.4}` objects.
+pub async fn post_calibration_import(
+    Query(query): Query<KeyQuery>,
+    Json(samples): Json<Vec<DelaySample>>,
+) -> Response {
+    if !is_key_valid(query.key.as_deref()) {
+        return incorrect_key();
+    }
+
+    match calibration::record_samples(&DB, &samples) {
+        Ok(accepted) => Json(json!({
+            "accepted": accepted,
+            "ignored": samples.len() - accepted,
+            "delays": DB.vote_delays().by_option_count,
+        }))
+        .into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{err}\n")).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SingleImageSearchQuery {
+    key: Option<String>,
+    lat: f64,
+    lng: f64,
+    heading: f32,
+    /// The Streetview pano ID at the location, if there is one. This makes our
+    /// emulation more accurate since it accounts for how far the pano's search
+    /// location is from its actual location.
+    pano: Option<String>,
+    /// How far ahead to search, in meters. Defaults to 13, which is what the
+    /// game uses for most options.
+    distance: Option<f64>,
+}
+
+/// Ask Google's SingleImageSearch which pano is ahead of a position, and
+/// compare it with what our emulation of it picks. Mismatches are logged, since
+/// they mean that our options might be wrong.
+pub async fn get_single_image_search(Query(query): Query<SingleImageSearchQuery>) -> Response {
+    if !is_key_valid(query.key.as_deref()) {
+        return incorrect_key();
+    }
+
+    let origin = Location::new_deg(query.lat, query.lng);
+    let distance = query.distance.unwrap_or(13.).clamp(0., 100.);
+    let forward = math::point_at_distance(origin, query.heading, distance);
+    let origin_pano = match query.pano.as_deref().map(|pano| DB.get_pano_id(pano)) {
+        Some(Ok(pano_id)) => Some(pano_id),
+        Some(Err(err)) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, format!("{err}\n")).into_response();
+        }
+        None => None,
+    };
+
+    let (google, emulated) = tokio::join!(
+        streetview::api::single_image_search(forward, distance * 2.),
+        roadtrip::emulate_single_image_search(&DB, origin, origin_pano, query.heading, distance),
+    );
+    let google = match google {
+        Ok(google) => google,
+        Err(err) => return (StatusCode::BAD_GATEWAY, format!("{err}\n")).into_response(),
+    };
+    let emulated = match emulated {
+        Ok(emulated) => emulated.map(|pano| {
+            let id = DB.lookup_pano_id_string(pano.id).unwrap_or_default();
+            (id, pano.loc)
+        }),
+        Err(err) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("{err}\n")).into_response(),
+    };
+    let google = google.map(|pano| (pano.id.0.to_string(), pano.loc));
+
+    let google_id = google.as_ref().map(|(id, _)| id);
+    let emulated_id = emulated.as_ref().map(|(id, _)| id);
+    let matches = google_id == emulated_id;
+    if !matches {
+        warn!(
+            "SingleImageSearch mismatch at {origin:?} heading {}: google picked {google_id:?}, we picked {emulated_id:?}",
+            query.heading
+        );
+    }
+
+    let pano_json = |pano: Option<(String, Location)>| {
+        pano.map(|(id, loc)| {
+            json!({
+                "pano": id,
+                "lat": loc.lat_deg(),
+                "lng": loc.lng_deg(),
+                "distance_from_forward": math::distance(loc, forward),
+            })
+        })
+    };
+    Json(json!({
+        "forward": [forward.lat_deg(), forward.lng_deg()],
+        "matches": matches,
+        "google": pano_json(google),
+        "emulated": pano_json(emulated),
+    }))
+    .into_response()
+}
@@ -0,0 +1,76 @@
+//! `GET /car/history`, where the car has been, for analysis and replaying its
+//! trip on a map.
+
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    Json,
+    extract::Query,
+    response::{IntoResponse, Response},
+};
+use http::StatusCode;
+use simd_json::json;
+
+use crate::db::DB;
+
+/// How far back the history goes if `from` isn't given.
+const DEFAULT_RANGE_MS: u64 = 60 * 60 * 1000;
+const DEFAULT_LIMIT: usize = 10_000;
+const MAX_LIMIT: usize = 100_000;
+
+/// `GET /car/history?from=…&to=…&limit=…`, with the times in milliseconds since
+/// the Unix epoch. Defaults to the last hour. The positions are oldest first,
+/// and if there are more than `limit` of them the rest can be fetched by
+/// setting `from` to one more than the last timestamp.
+pub async fn get_car_history(Query(query): Query<HashMap<String, String>>) -> Response {
+    let parse = |key: &str| query.get(key).map(|v| v.parse::<u64>());
+    let to = match parse("to") {
+        Some(Ok(to)) => to,
+        Some(Err(_)) => return (StatusCode::BAD_REQUEST, "invalid to\n").into_response(),
+        None => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64,
+    };
+    let from = match parse("from") {
+        Some(Ok(from)) => from,
+        Some(Err(_)) => return (StatusCode::BAD_REQUEST, "invalid from\n").into_response(),
+        None => to.saturating_sub(DEFAULT_RANGE_MS),
+    };
+    if from > to {
+        return (StatusCode::BAD_REQUEST, "from must be before to\n").into_response();
+    }
+    let limit = query
+        .get("limit")
+        .and_then(|l| l.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_LIMIT)
+        .min(MAX_LIMIT);
+
+    let history = DB.car_history(from, to, limit);
+    let truncated = history.len() == limit;
+    let txn = DB.read_txn();
+    let positions = history
+        .into_iter()
+        .map(|entry| {
+            json!({
+                "timestamp": entry.timestamp,
+                "lat": entry.loc.lat_deg(),
+                "lng": entry.loc.lng_deg(),
+                "heading": entry.heading,
+                "pano": entry.pano.and_then(|pano| DB.lookup_pano_id_string_with_txn(&txn, pano)),
+            })
+        })
+        .collect::<Vec<_>>();
+    txn.commit().unwrap();
+
+    Json(json!({
+        "from": from,
+        "to": to,
+        "truncated": truncated,
+        "positions": positions,
+    }))
+    .into_response()
+}
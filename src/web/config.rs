@@ -0,0 +1,187 @@
+//! The web server's port and secret, and the limits for its searches, jobs and
+//! ratelimits.
+
+use std::{env, net::IpAddr, sync::LazyLock, time::Duration};
+
+/// The web server's limits, configured from the environment.
+pub static CONFIG: LazyLock<WebConfig> = LazyLock::new(WebConfig::from_env);
+
+#[derive(Debug, Clone)]
+pub struct WebConfig {
+    /// The port that the web server listens on. Set with `PORT`, defaults to
+    /// 2397.
+    pub port: u16,
+    /// The key that unlocks the admin endpoints and the full limits in sandbox
+    /// mode, or empty to disable both. Set with `PATHFINDER_SECRET`.
+    pub secret: String,
+    /// Whether requests without the secret get the sandbox limits, see
+    /// [`super::sandbox`]. Set with `PATHFINDER_SANDBOX=1`, defaults to false.
+    pub sandbox: bool,
+    /// The maximum total distance of a path in sandbox mode, in meters. Set
+    /// with `PATHFINDER_SANDBOX_MAX_DISTANCE_KM`, defaults to 50km.
+    pub sandbox_max_distance: f64,
+    /// The maximum number of nodes that a route may consider in sandbox mode.
+    /// Set with `PATHFINDER_SANDBOX_MAX_NODES`, defaults to 500,000.
+    pub sandbox_max_nodes: usize,
+    /// The maximum number of nodes that a single search may keep in memory, for
+    /// everyone. Searches that hit it stop with the closest path they found.
+    /// Set with `PATHFINDER_NODE_BUDGET`, unlimited by default.
+    pub node_budget: Option<usize>,
+    /// The number of searches that can run at the same time. Searches past this
+    /// wait in a queue. Set with `PATHFINDER_MAX_CONCURRENT_SEARCHES`, defaults
+    /// to 16.
+    pub max_concurrent_searches: usize,
+    /// The number of tasks that each IP can run at the same time. Set with
+    /// `PATHFINDER_MAX_TASKS_PER_IP`, defaults to 4.
+    pub max_tasks_per_ip: usize,
+    /// How long a job is kept running after its WebSocket is closed. Set with
+    /// `PATHFINDER_JOB_GRACE_PERIOD_SECS`, defaults to 2 minutes.
+    pub job_grace_period: Duration,
+    /// How long `POST /path/sync` waits for the path before responding with
+    /// the job ID instead, so it can be polled with `GET /path/job/{job_id}`.
+    /// Set with `PATHFINDER_SYNC_WAIT_SECS`, defaults to 30 seconds.
+    pub sync_wait: Duration,
+    /// The time limit for `POST /path/sync` searches, which is also the longest
+    /// `timeout_seconds` they can ask for since nobody might be polling them.
+    /// Set with `PATHFINDER_REST_TIMEOUT_SECS`, defaults to 10 minutes.
+    pub rest_timeout_seconds: f64,
+    /// How long a `POST /path/sync` job can still be polled for after it
+    /// finished. Set with `PATHFINDER_REST_JOB_TTL_SECS`, defaults to 10
+    /// minutes.
+    pub rest_job_ttl: Duration,
+    /// The number of nodes that each IP range may consider per day (UTC),
+    /// across all of its searches. Set with `PATHFINDER_DAILY_NODE_QUOTA`,
+    /// unlimited by default.
+    pub daily_node_quota: Option<u64>,
+    /// IPs that don't have a quota or a request limit. Only these exact
+    /// addresses are exempt, not the ranges they're in. Set with a
+    /// comma-separated `PATHFINDER_QUOTA_EXEMPT_IPS`.
+    pub quota_exempt_ips: Vec<IpAddr>,
+    /// How many leading bits of an IPv4 address identify a user. Set with
+    /// `PATHFINDER_RATELIMIT_IPV4_PREFIX`, defaults to 24.
+    pub ipv4_prefix: u8,
+    /// How many leading bits of an IPv6 address identify a user, since most
+    /// ISPs give each customer a /48 or a /56. Set with
+    /// `PATHFINDER_RATELIMIT_IPV6_PREFIX`, defaults to 56.
+    pub ipv6_prefix: u8,
+    /// How many requests each IP range may make per minute to endpoints that
+    /// can make requests to Google, like `/pano/{pano_id}`. Set with
+    /// `PATHFINDER_REQUESTS_PER_MINUTE`, defaults to 60.
+    pub requests_per_minute: u32,
+}
+impl Default for WebConfig {
+    fn default() -> Self {
+        Self {
+            port: 2397,
+            secret: String::new(),
+            sandbox: false,
+            sandbox_max_distance: 50_000.,
+            sandbox_max_nodes: 500_000,
+            node_budget: None,
+            max_concurrent_searches: 16,
+            max_tasks_per_ip: 4,
+            job_grace_period: Duration::from_secs(120),
+            sync_wait: Duration::from_secs(30),
+            rest_timeout_seconds: 600.,
+            rest_job_ttl: Duration::from_secs(600),
+            daily_node_quota: None,
+            quota_exempt_ips: Vec::new(),
+            ipv4_prefix: 24,
+            ipv6_prefix: 56,
+            requests_per_minute: 60,
+        }
+    }
+}
+impl WebConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+
+        let port = env_parse("PORT").unwrap_or(default.port);
+        let secret = env::var("PATHFINDER_SECRET").unwrap_or(default.secret);
+        let sandbox = env::var("PATHFINDER_SANDBOX")
+            .map(|v| v == "1" || v == "true")
+            .unwrap_or(default.sandbox);
+        let sandbox_max_distance = env_parse::<f64>("PATHFINDER_SANDBOX_MAX_DISTANCE_KM")
+            .map(|km| km * 1000.)
+            .unwrap_or(default.sandbox_max_distance);
+        let sandbox_max_nodes =
+            env_parse("PATHFINDER_SANDBOX_MAX_NODES").unwrap_or(default.sandbox_max_nodes);
+        let node_budget = env_parse("PATHFINDER_NODE_BUDGET").or(default.node_budget);
+        let max_concurrent_searches = env_parse("PATHFINDER_MAX_CONCURRENT_SEARCHES")
+            .unwrap_or(default.max_concurrent_searches);
+        let max_tasks_per_ip = env_parse::<usize>("PATHFINDER_MAX_TASKS_PER_IP")
+            .unwrap_or(default.max_tasks_per_ip)
+            .max(1);
+        let job_grace_period =
+            env_secs("PATHFINDER_JOB_GRACE_PERIOD_SECS").unwrap_or(default.job_grace_period);
+        let sync_wait = env_secs("PATHFINDER_SYNC_WAIT_SECS").unwrap_or(default.sync_wait);
+        let rest_timeout_seconds =
+            env_parse("PATHFINDER_REST_TIMEOUT_SECS").unwrap_or(default.rest_timeout_seconds);
+        let rest_job_ttl = env_secs("PATHFINDER_REST_JOB_TTL_SECS").unwrap_or(default.rest_job_ttl);
+        let daily_node_quota =
+            env_parse("PATHFINDER_DAILY_NODE_QUOTA").or(default.daily_node_quota);
+        let quota_exempt_ips = match env::var("PATHFINDER_QUOTA_EXEMPT_IPS") {
+            Ok(ips) => ips
+                .split(',')
+                .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+                .map(|ip| ip.to_canonical())
+                .collect(),
+            Err(_) => default.quota_exempt_ips,
+        };
+        let ipv4_prefix =
+            env_parse("PATHFINDER_RATELIMIT_IPV4_PREFIX").unwrap_or(default.ipv4_prefix);
+        let ipv6_prefix =
+            env_parse("PATHFINDER_RATELIMIT_IPV6_PREFIX").unwrap_or(default.ipv6_prefix);
+        let requests_per_minute =
+            env_parse("PATHFINDER_REQUESTS_PER_MINUTE").unwrap_or(default.requests_per_minute);
+
+        Self {
+            port,
+            secret,
+            sandbox,
+            sandbox_max_distance,
+            sandbox_max_nodes,
+            node_budget,
+            max_concurrent_searches,
+            max_tasks_per_ip,
+            job_grace_period,
+            sync_wait,
+            rest_timeout_seconds,
+            rest_job_ttl,
+            daily_node_quota,
+            quota_exempt_ips,
+            ipv4_prefix,
+            ipv6_prefix,
+            requests_per_minute,
+        }
+    }
+
+    /// Whether the IP has no quota or request limit.
+    pub fn is_exempt(&self, ip: IpAddr) -> bool {
+        self.quota_exempt_ips.contains(&ip.to_canonical())
+    }
+}
+
+fn env_parse<T: std::str::FromStr>(key: &str) -> Option<T> {
+    env::var(key).ok().and_then(|v| v.parse().ok())
+}
+
+fn env_secs(key: &str) -> Option<Duration> {
+    env_parse(key).map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exempt_ips_are_exact() {
+        let config = WebConfig {
+            quota_exempt_ips: vec!["1.2.3.4".parse().unwrap()],
+            ..Default::default()
+        };
+        assert!(config.is_exempt("1.2.3.4".parse().unwrap()));
+        assert!(config.is_exempt("::ffff:1.2.3.4".parse().unwrap()));
+        assert!(!config.is_exempt("1.2.3.5".parse().unwrap()));
+    }
+}
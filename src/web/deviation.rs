@@ -0,0 +1,51 @@
+//! `GET /deviation`, how far the car is from the active route, and
+//! `GET /deviation/stream`, the same thing as server-sent events whenever the
+//! car moves.
+
+use std::convert::Infallible;
+
+use axum::{
+    Json,
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use futures::{StreamExt, stream};
+use http::StatusCode;
+use simd_json::json;
+
+use crate::deviation;
+
+pub async fn get_deviation() -> Response {
+    let Some((route_id, positions)) = deviation::active_route_positions() else {
+        return (StatusCode::NOT_FOUND, "there's no active route\n").into_response();
+    };
+    Json(json!({
+        "route_id": route_id,
+        "deviation": deviation::current(),
+        "positions": positions,
+    }))
+    .into_response()
+}
+
+pub async fn get_deviation_stream() -> impl IntoResponse {
+    let mut updates = deviation::subscribe();
+    // start with the current deviation
+    updates.mark_changed();
+    let events = stream::unfold(updates, |mut updates| async move {
+        loop {
+            updates.changed().await.ok()?;
+            let deviation = updates.borrow_and_update().clone();
+            if let Some(deviation) = deviation {
+                return Some((deviation, updates));
+            }
+        }
+    })
+    .map(|deviation| {
+        let data = simd_json::to_string(&deviation)
+            .unwrap_or_else(|_| "Error serializing message".to_string());
+        Ok::<_, Infallible>(Event::default().event("deviation").data(data))
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
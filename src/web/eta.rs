@@ -0,0 +1,131 @@
+//! `GET /eta`, when the car should reach the end of a saved route and each of
+//! its stops, based on where it is now and how fast it's been going.
+
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    Json,
+    extract::Query,
+    response::{IntoResponse, Response},
+};
+use http::StatusCode;
+use pathfinder_protocol::{PathResultNode, Stop};
+use simd_json::json;
+
+use crate::{calibration, db::DB, math, model::Location, roadtrip_api};
+
+/// The car is considered to have left the route if it's further than this many
+/// meters from it.
+const MAX_DISTANCE_FROM_ROUTE: f64 = 500.;
+
+/// `GET /eta?route_id=…`. The costs of the route are in seconds of voting, so
+/// they're scaled by how slow the car has recently been compared to what the
+/// pathfinder expected.
+pub async fn get_eta(Query(query): Query<HashMap<String, String>>) -> Response {
+    let Some(route_id) = query.get("route_id") else {
+        return (StatusCode::BAD_REQUEST, "route_id is required\n").into_response();
+    };
+    let Some(route) = DB.get_route(route_id) else {
+        return (StatusCode::NOT_FOUND, "unknown route\n").into_response();
+    };
+    let Some(car) = roadtrip_api::car_position() else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "the car's position isn't known yet\n",
+        )
+            .into_response();
+    };
+
+    let nodes = &route.result.nodes;
+    let Some((car_index, distance_from_route)) =
+        locate_on_route(nodes, &car.loc, car.pano.as_deref())
+    else {
+        return (StatusCode::UNPROCESSABLE_ENTITY, "the route is empty\n").into_response();
+    };
+    if distance_from_route > MAX_DISTANCE_FROM_ROUTE {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("the car is {distance_from_route:.0}m away from the route\n"),
+        )
+            .into_response();
+    }
+
+    let measured_pace = calibration::recent_pace(&DB.vote_delays());
+    let pace = measured_pace.unwrap_or(1.);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let car_cost = nodes[car_index].cost;
+    let arrival = |index: usize| {
+        let seconds = (nodes[index].cost - car_cost).max(0.) as f64 * pace;
+        json!({
+            "loc": nodes[index].loc,
+            "node_index": index,
+            "passed": index < car_index,
+            "seconds_remaining": seconds,
+            "arrival_timestamp": now + seconds,
+        })
+    };
+
+    let stops = stop_node_indexes(nodes, &route.query.stops)
+        .into_iter()
+        .map(&arrival)
+        .collect::<Vec<_>>();
+
+    Json(json!({
+        "route_id": route.id,
+        "car": {
+            "loc": car.loc,
+            "node_index": car_index,
+            "distance_from_route": distance_from_route,
+        },
+        "pace": measured_pace,
+        "destination": arrival(nodes.len() - 1),
+        "stops": stops,
+    }))
+    .into_response()
+}
+
+/// The index of the node that the car is at, and how far it is from it in
+/// meters. The pano ID is used if it's on the route, since the route can pass
+/// by the same place more than once.
+fn locate_on_route(
+    nodes: &[PathResultNode],
+    car_loc: &[f64; 2],
+    car_pano: Option<&str>,
+) -> Option<(usize, f64)> {
+    if let Some(pano) = car_pano
+        && let Some(index) = nodes.iter().position(|node| node.pano_id == pano)
+    {
+        return Some((index, 0.));
+    }
+    nearest_node(nodes, 0, Location::from_latlng(*car_loc))
+}
+
+/// The node closest to each stop, in order, so a stop can't match a node
+/// before the previous stop.
+fn stop_node_indexes(nodes: &[PathResultNode], stops: &[Stop]) -> Vec<usize> {
+    let mut from = 0;
+    let mut indexes = Vec::with_capacity(stops.len());
+    for stop in stops {
+        let Some((index, _)) = nearest_node(nodes, from, Location::from_latlng(stop.loc())) else {
+            break;
+        };
+        indexes.push(index);
+        from = index;
+    }
+    indexes
+}
+
+fn nearest_node(nodes: &[PathResultNode], from: usize, loc: Location) -> Option<(usize, f64)> {
+    nodes
+        .iter()
+        .enumerate()
+        .skip(from)
+        .map(|(i, node)| (i, math::distance(loc, Location::from_latlng(node.loc))))
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+}
@@ -0,0 +1,81 @@
+//! Conditional GETs for the endpoints that are made from the database, so
+//! clients that poll them don't download the same response again. The ETags
+//! are based on [`Db::tile_generation`], so they change whenever a tile or
+//! GetMetadata response is written, even if it didn't affect the response.
+
+use std::fmt::Display;
+
+use axum::response::{IntoResponse, Response};
+use http::{HeaderMap, HeaderValue, StatusCode, header};
+
+use crate::db::Db;
+
+pub struct ETag(HeaderValue);
+impl ETag {
+    /// For responses that only depend on the cached tiles and GetMetadata
+    /// responses (and the URL). It's weak since the responses might be
+    /// compressed.
+    pub fn of_tiles(db: &Db) -> Self {
+        Self::new(format!("W/\"{:x}\"", db.tile_generation()))
+    }
+
+    /// For responses that also depend on something else, like a counter that
+    /// isn't in the tiles.
+    pub fn of_tiles_and(db: &Db, extra: impl Display) -> Self {
+        Self::new(format!("W/\"{:x}-{extra}\"", db.tile_generation()))
+    }
+
+    fn new(etag: String) -> Self {
+        Self(HeaderValue::from_str(&etag).expect("ETag should be a valid header"))
+    }
+
+    /// Whether the client's `If-None-Match` includes this ETag, in which case
+    /// [`Self::not_modified`] should be returned.
+    pub fn matches(&self, headers: &HeaderMap) -> bool {
+        let ours = strip_weak(self.0.to_str().unwrap());
+        headers
+            .get_all(header::IF_NONE_MATCH)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .any(|theirs| theirs == "*" || strip_weak(theirs) == ours)
+    }
+
+    pub fn not_modified(&self) -> Response {
+        (StatusCode::NOT_MODIFIED, [(header::ETAG, self.0.clone())]).into_response()
+    }
+
+    /// Add the ETag to the response, unless it's an error.
+    pub fn attach(self, mut res: Response) -> Response {
+        if res.status().is_success() {
+            res.headers_mut().insert(header::ETAG, self.0);
+        }
+        res
+    }
+}
+
+fn strip_weak(etag: &str) -> &str {
+    etag.strip_prefix("W/").unwrap_or(etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_if_none_match() {
+        let etag = ETag::new("W/\"1a-2\"".to_owned());
+        let headers = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static(value));
+            headers
+        };
+        assert!(etag.matches(&headers("W/\"1a-2\"")));
+        assert!(etag.matches(&headers("\"1a-2\"")));
+        assert!(etag.matches(&headers("\"0-0\", W/\"1a-2\"")));
+        assert!(etag.matches(&headers("*")));
+        assert!(!etag.matches(&headers("W/\"1b-2\"")));
+        assert!(!etag.matches(&HeaderMap::new()));
+    }
+}
@@ -0,0 +1,453 @@
+//! Paths that are kept up to date as the car moves, see
+//! [`ServerboundMessage::Follow`](pathfinder_protocol::ServerboundMessage::Follow).
+
+use std::{collections::HashMap, pin::pin, sync::Arc, time::Duration};
+
+use futures::{
+    SinkExt, StreamExt,
+    channel::mpsc,
+    future,
+    stream::{self, BoxStream},
+};
+use parking_lot::Mutex;
+use pathfinder_protocol::{
+    CarPosition, ErrorCode, FollowQuery, GetPathQuery, SocketError, SocketEvent,
+};
+use tokio::time::Instant;
+use tracing::{error, info};
+
+use crate::{
+    FullProgressUpdate, ProgressUpdate,
+    astar::{Cost, PathSettings, RouteNode},
+    db::DB,
+    math,
+    model::Location,
+    replan::Replanner,
+    roadtrip_api,
+    units::Formatter,
+    web::{
+        job_manager::{SearchSlot, TaskContext, TaskStatus},
+        path::{
+            BestPathDiff, diff_best_path, find_path_prefix_and_append, no_nearby_pano_error,
+            path_result, path_settings_for_query, path_too_long_error, pathfinding_error,
+            query_end, send_error, snap_end_point_to_pano,
+        },
+        ratelimit,
+        sandbox::PathLimits,
+    },
+};
+
+/// Where the positions for each of a socket's followed paths are sent, by their
+/// query ID.
+#[derive(Default)]
+pub struct FollowedPaths {
+    positions: HashMap<u32, mpsc::UnboundedSender<CarPosition>>,
+}
+impl FollowedPaths {
+    /// Start following a path, which replaces the one with the same ID if there
+    /// was one. The positions for it are received from the returned channel.
+    pub fn start(&mut self, id: u32) -> mpsc::UnboundedReceiver<CarPosition> {
+        // forget the paths whose jobs finished
+        self.positions.retain(|_, positions| !positions.is_closed());
+        let (positions_tx, positions_rx) = mpsc::unbounded();
+        self.positions.insert(id, positions_tx);
+        positions_rx
+    }
+
+    /// Send the car's position to the path with the same ID. Returns false if
+    /// that path isn't being followed.
+    pub fn moved(&mut self, position: CarPosition) -> bool {
+        let id = position.id;
+        let Some(positions) = self.positions.get(&id) else {
+            return false;
+        };
+        if positions.unbounded_send(position).is_err() {
+            // the job finished
+            self.positions.remove(&id);
+            return false;
+        }
+        true
+    }
+
+    pub fn stop(&mut self, id: u32) {
+        self.positions.remove(&id);
+    }
+}
+
+/// The state of the path that the client has, so we only have to send what
+/// changed.
+struct SentPath {
+    id: u32,
+    best_path: Vec<[f32; 2]>,
+    best_path_costs: Vec<Cost>,
+    current_path: Vec<[f32; 2]>,
+}
+
+/// Follow the path with the positions that the client sends, or with the
+/// game's car if the query doesn't have a start, until the token is cancelled.
+pub async fn handle_follow_query(
+    mut tx: mpsc::Sender<SocketEvent>,
+    query: FollowQuery,
+    client_positions: mpsc::UnboundedReceiver<CarPosition>,
+    limits: PathLimits,
+    ctx: TaskContext,
+) {
+    let (start, positions) = match query.start.clone() {
+        Some(start) => (start, client_positions.boxed()),
+        None => {
+            let mut car = roadtrip_api::subscribe_car_position();
+            let Some(start) = car.borrow_and_update().clone() else {
+                return send_error(
+                    &mut tx,
+                    SocketError::new(
+                        ErrorCode::UpstreamUnavailable,
+                        "The car's position isn't known yet",
+                    ),
+                )
+                .await;
+            };
+            let id = query.id;
+            let positions = stream::unfold(car, |mut car| async move {
+                car.changed().await.ok()?;
+                let position = car.borrow_and_update().clone();
+                Some((position, car))
+            })
+            .filter_map(move |position| {
+                future::ready(position.map(|position| CarPosition { id, ..position }))
+            })
+            .boxed();
+            (start, positions)
+        }
+    };
+    let msg = query.path_query(&start);
+
+    follow(tx, msg, positions, limits, ctx).await;
+}
+
+async fn follow(
+    mut tx: mpsc::Sender<SocketEvent>,
+    msg: GetPathQuery,
+    positions: BoxStream<'static, CarPosition>,
+    limits: PathLimits,
+    ctx: TaskContext,
+) {
+    let fmt = Formatter::new(msg.units, msg.locale.as_deref());
+
+    let end = match query_end(&msg) {
+        Ok(end) => end,
+        Err(err) => return send_error(&mut tx, err).await,
+    };
+    let settings = match path_settings_for_query(&msg, limits) {
+        Ok(settings) => PathSettings {
+            cancel: ctx.cancel.clone(),
+            ..settings
+        },
+        Err(err) => {
+            return send_error(&mut tx, SocketError::new(ErrorCode::InvalidRequest, err)).await;
+        }
+    };
+    let Some(end) = snap_end_point_to_pano(&DB, end).await.map(|p| p.loc) else {
+        return send_error(&mut tx, no_nearby_pano_error(end, &fmt)).await;
+    };
+
+    let start = Location::from_latlng(msg.start);
+    let distance = math::distance(start, end);
+    if distance > limits.max_distance {
+        return send_error(&mut tx, path_too_long_error(distance, limits, &fmt)).await;
+    }
+
+    info!("/path follow {start} -> {end}");
+    ctx.status.update(|status| {
+        status.kind = "follow";
+        status.start = Some(start.to_latlng());
+        status.end = Some(end.to_latlng());
+    });
+
+    let mut sent = SentPath {
+        id: msg.id,
+        best_path: Vec::new(),
+        best_path_costs: Vec::new(),
+        current_path: Vec::new(),
+    };
+    let use_option_cache = settings.use_option_cache;
+    let heading = (msg.heading + 360.) % 360.;
+
+    if let Err(err) = ratelimit::check_quota(&DB, &ctx.status) {
+        return send_error(&mut tx, err).await;
+    }
+
+    // following is long-lived, so each search takes a search slot only while
+    // it's running
+    let Some(search_slot) = wait_for_search_slot(&tx, &ctx, msg.id).await else {
+        return;
+    };
+
+    let progress_update = Arc::new(Mutex::new(ProgressUpdate::default()));
+    let replanner = send_progress_while(
+        &mut tx,
+        &mut sent,
+        &progress_update,
+        &ctx.status,
+        Replanner::new(
+            &DB,
+            start,
+            msg.start_pano,
+            heading,
+            end,
+            progress_update.clone(),
+            settings,
+        ),
+    )
+    .await;
+    drop(search_slot);
+    let mut replanner = match replanner {
+        Ok(replanner) => replanner,
+        Err(_) if ctx.cancel.is_cancelled() => return,
+        Err(err) => {
+            error!("{err}");
+            return send_error(&mut tx, pathfinding_error(&err)).await;
+        }
+    };
+    if !send_path(&mut tx, &mut sent, &fmt, replanner.path(), use_option_cache).await {
+        return;
+    }
+
+    // we only care about where the car is now, so skip any positions that piled up
+    // while we were pathfinding
+    let mut positions = positions.ready_chunks(64);
+    loop {
+        let chunk = tokio::select! {
+            chunk = positions.next() => chunk,
+            _ = ctx.cancel.cancelled() => None,
+        };
+        let Some(mut chunk) = chunk else {
+            break;
+        };
+        let Some(position) = chunk.pop() else {
+            continue;
+        };
+        if position.id != msg.id {
+            continue;
+        }
+
+        // the previous searches have to be counted to tell whether they used up the quota
+        ratelimit::record_quota_usage(&DB, &ctx.status);
+        if let Err(err) = ratelimit::check_quota(&DB, &ctx.status) {
+            send_error(&mut tx, err).await;
+            break;
+        }
+        let Some(search_slot) = wait_for_search_slot(&tx, &ctx, msg.id).await else {
+            break;
+        };
+
+        let progress_update = Arc::new(Mutex::new(ProgressUpdate::default()));
+        let res = send_progress_while(
+            &mut tx,
+            &mut sent,
+            &progress_update,
+            &ctx.status,
+            replanner.move_start(
+                &DB,
+                Location::from_latlng(position.loc),
+                position.pano,
+                (position.heading + 360.) % 360.,
+                progress_update.clone(),
+            ),
+        )
+        .await;
+        drop(search_slot);
+        let delivered = match res {
+            Ok(path) => send_path(&mut tx, &mut sent, &fmt, path, use_option_cache).await,
+            // the last path we sent is the best we have
+            Err(_) if ctx.cancel.is_cancelled() => break,
+            Err(err) => {
+                error!("{err}");
+                tx.send(SocketEvent::Error(pathfinding_error(&err)))
+                    .await
+                    .is_ok()
+            }
+        };
+        if !delivered {
+            break;
+        }
+    }
+
+    info!("Stopped following path");
+}
+
+/// Wait for a search slot, telling the client its position in the queue.
+async fn wait_for_search_slot(
+    tx: &mpsc::Sender<SocketEvent>,
+    ctx: &TaskContext,
+    id: u32,
+) -> Option<SearchSlot> {
+    ctx.wait_for_search_slot(|position| {
+        let mut tx = tx.clone();
+        async move {
+            let _ = tx.send(SocketEvent::Queued { id, position }).await;
+        }
+    })
+    .await
+}
+
+/// Wait for the future while sending the progress of the search.
+async fn send_progress_while<T>(
+    tx: &mut mpsc::Sender<SocketEvent>,
+    sent: &mut SentPath,
+    progress_update: &Mutex<ProgressUpdate>,
+    status: &TaskStatus,
+    fut: impl Future<Output = T>,
+) -> T {
+    let mut fut = pin!(fut);
+    let start = Instant::now();
+    let mut interval = tokio::time::interval(Duration::from_millis(100));
+    loop {
+        tokio::select! {
+            res = &mut fut => {
+                status.finish_search(progress_update.lock().nodes_considered);
+                return res;
+            }
+            _ = interval.tick() => {}
+        }
+
+        let update = {
+            let progress = progress_update.lock();
+            status.update(|status| {
+                status.percent_done = progress.percent_done;
+                status.nodes_considered = progress.nodes_considered;
+                status.stored_nodes = progress.stored_nodes;
+            });
+            if progress.percent_done >= 1. {
+                // the final update is sent by send_path
+                continue;
+            }
+            let BestPathDiff {
+                best_path_keep_prefix_length,
+                best_path_append,
+                best_path_costs_append,
+            } = diff_best_path(
+                &sent.best_path,
+                &sent.best_path_costs,
+                &progress.best_path,
+                &progress.best_path_costs,
+            );
+            let (current_path_keep_prefix_length, current_path_append) =
+                find_path_prefix_and_append(&sent.current_path, &progress.current_path);
+            sent.best_path = progress.best_path.to_vec();
+            sent.best_path_costs = progress.best_path_costs.to_vec();
+            sent.current_path = progress.current_path.to_vec();
+
+            FullProgressUpdate {
+                id: sent.id,
+                percent_done: progress.percent_done,
+                estimated_seconds_remaining: progress.estimated_seconds_remaining,
+                best_path_cost: progress.best_path_cost,
+                nodes_considered: progress.nodes_considered,
+                elapsed_seconds: start.elapsed().as_secs_f64(),
+                memory_pressure: progress.memory_pressure,
+                best_path_keep_prefix_length,
+                best_path_append,
+                best_path_costs_append,
+                current_path_keep_prefix_length,
+                current_path_append,
+                summary: None,
+                segments: Vec::new(),
+                warnings: Vec::new(),
+            }
+        };
+        let _ = tx.send(SocketEvent::Progress(update)).await;
+    }
+}
+
+/// Send the final progress update and the result for the new path. Returns
+/// false if the socket was closed.
+async fn send_path(
+    tx: &mut mpsc::Sender<SocketEvent>,
+    sent: &mut SentPath,
+    fmt: &Formatter,
+    path: &[RouteNode],
+    use_option_cache: bool,
+) -> bool {
+    let best_path = path
+        .iter()
+        .map(|n| n.pano.loc.to_geojson())
+        .collect::<Vec<_>>();
+    let best_path_costs = path.iter().map(|n| n.cost).collect::<Vec<_>>();
+    let cost = path.last().map(|n| n.cost).unwrap_or_default();
+
+    let BestPathDiff {
+        best_path_keep_prefix_length,
+        best_path_append,
+        best_path_costs_append,
+    } = diff_best_path(
+        &sent.best_path,
+        &sent.best_path_costs,
+        &best_path,
+        &best_path_costs,
+    );
+    let summary = fmt.path_summary(math::path_length(&best_path), cost as f64);
+    sent.current_path.clear();
+    sent.best_path = best_path;
+    sent.best_path_costs = best_path_costs;
+
+    let update = FullProgressUpdate {
+        id: sent.id,
+        percent_done: 1.,
+        estimated_seconds_remaining: 0.,
+        best_path_cost: cost,
+        nodes_considered: 0,
+        elapsed_seconds: 0.,
+        memory_pressure: 0.,
+        best_path_keep_prefix_length,
+        best_path_append,
+        best_path_costs_append,
+        current_path_keep_prefix_length: 0,
+        current_path_append: Box::new([]),
+        summary: Some(summary),
+        segments: Vec::new(),
+        warnings: Vec::new(),
+    };
+    if tx.send(SocketEvent::Progress(update)).await.is_err() {
+        return false;
+    }
+
+    let result = path_result(&DB, sent.id, path, use_option_cache).await;
+    tx.send(SocketEvent::Result(result)).await.is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(id: u32) -> CarPosition {
+        CarPosition {
+            id,
+            loc: [0., 0.],
+            pano: None,
+            heading: 0.,
+        }
+    }
+
+    #[test]
+    fn test_several_followed_paths() {
+        let mut paths = FollowedPaths::default();
+        let mut first = paths.start(1);
+        let mut second = paths.start(2);
+
+        // starting the second path doesn't close the first one
+        assert!(paths.moved(position(1)));
+        assert!(paths.moved(position(2)));
+        assert_eq!(first.try_next().unwrap().unwrap().id, 1);
+        assert_eq!(second.try_next().unwrap().unwrap().id, 2);
+        assert!(!paths.moved(position(3)));
+
+        // aborted
+        paths.stop(1);
+        assert!(!paths.moved(position(1)));
+        assert!(paths.moved(position(2)));
+
+        // the job finished
+        drop(second);
+        assert!(!paths.moved(position(2)));
+    }
+}
@@ -0,0 +1,106 @@
+//! `GET /health`, for load balancers and uptime checks. Responds with
+//! `503 Service Unavailable` if the database isn't usable, since nothing works
+//! without it. Google and the IRT WebSocket are reported but don't fail the
+//! check, because paths can still be found from the cache without them.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use axum::{
+    Json,
+    extract::State,
+    response::{IntoResponse, Response},
+};
+use http::StatusCode;
+use simd_json::json;
+use tokio::sync::Mutex;
+use tracing::error;
+
+use crate::{
+    db::DB,
+    roadtrip_api,
+    streetview::{api, proxy::GOOGLE_CLIENTS},
+    web::ratelimit::AppState,
+};
+
+/// How long the result of the database check is reused for, since it commits a
+/// write and uptime checks can be frequent.
+const DB_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// When the database was last checked, and the error if it failed.
+static LAST_DB_CHECK: Mutex<Option<(Instant, Option<String>)>> = Mutex::const_new(None);
+
+pub async fn get_health(State(state): State<AppState>) -> Response {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let db_error = check_db(now).await;
+
+    let last_google_success = api::last_successful_request();
+    let irt = roadtrip_api::websocket_status();
+    let irt_connected = irt.state == roadtrip_api::ConnectionState::Connected;
+
+    let (status, status_name) = if db_error.is_some() {
+        (StatusCode::SERVICE_UNAVAILABLE, "down")
+    } else if !irt_connected {
+        (StatusCode::OK, "degraded")
+    } else {
+        (StatusCode::OK, "ok")
+    };
+
+    (
+        status,
+        Json(json!({
+            "status": status_name,
+            "db": {
+                "ok": db_error.is_none(),
+                "error": db_error,
+            },
+            "google": {
+                "last_success_timestamp": last_google_success,
+                "seconds_since_last_success": last_google_success.map(|t| now.saturating_sub(t)),
+                "clients": GOOGLE_CLIENTS.statuses(),
+            },
+            "irt_websocket": {
+                "connected": irt_connected,
+                "state": irt.state,
+                "connected_since": irt.connected_since,
+                "last_message_at": irt.last_message_at,
+                "reconnect_attempt": irt.reconnect_attempt,
+                "connections": irt.connections,
+                "failed_connections": irt.failed_connections,
+                "messages": irt.messages,
+                "parse_errors": irt.parse_errors,
+                "handle_errors": irt.handle_errors,
+            },
+            "jobs_in_flight": state.tasks.list().len(),
+        })),
+    )
+        .into_response()
+}
+
+/// Check the database, or reuse the last result if it's recent enough. Returns
+/// the error if the database isn't usable.
+async fn check_db(now: u64) -> Option<String> {
+    // holding the lock while checking means that concurrent requests wait for
+    // the same check instead of starting their own
+    let mut last_check = LAST_DB_CHECK.lock().await;
+    if let Some((checked_at, db_error)) = &*last_check
+        && checked_at.elapsed() < DB_CHECK_INTERVAL
+    {
+        return db_error.clone();
+    }
+
+    let db_check = tokio::task::spawn_blocking(move || DB.check_health(now)).await;
+    let db_error = match db_check {
+        Ok(Ok(())) => None,
+        Ok(Err(err)) => Some(err.to_string()),
+        Err(err) => Some(err.to_string()),
+    };
+    if let Some(err) = &db_error {
+        error!("Database health check failed: {err}");
+    }
+    *last_check = Some((Instant::now(), db_error.clone()));
+    db_error
+}
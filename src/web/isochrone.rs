@@ -0,0 +1,181 @@
+//! `GET /isochrone`, the area that the car can reach within a time budget.
+//! These run as pathfinding tasks, so they count towards the same limits and
+//! quotas as searches.
+
+use std::collections::HashMap;
+
+use axum::{
+    Json,
+    extract::{Query, State},
+    response::{IntoResponse, Response},
+};
+use http::{HeaderMap, StatusCode};
+use pathfinder_protocol::GetPathQuery;
+use simd_json::json;
+use tokio::sync::oneshot;
+use tracing::{error, info};
+
+use crate::{
+    astar::{Cost, PathSettings},
+    db::DB,
+    isochrone,
+    model::{Location, Pano},
+    streetview::get_nearest_pano,
+    web::{
+        job_manager::TaskContext,
+        parse_latlng,
+        path::path_settings_for_query,
+        ratelimit::{self, AppState},
+        sandbox::PathLimits,
+    },
+};
+
+/// The longest budget that can be requested, in hours.
+const MAX_HOURS: f64 = 24.;
+/// Even without a node limit the search has to stop somewhere.
+const MAX_NODES: usize = 5_000_000;
+/// The query ID that isochrones run with, so each IP only has one at a time
+/// and starting another one stops the old one.
+const ISOCHRONE_QUERY_ID: u32 = u32::MAX;
+
+/// `GET /isochrone?start=lat,lng&heading=0&hours=2`, with an optional
+/// `start_pano`. Returns a GeoJSON polygon feature that covers every pano that
+/// can be reached within the budget.
+pub async fn get_isochrone(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<HashMap<String, String>>,
+) -> Response {
+    let limits = PathLimits::for_query(&query);
+
+    let Some(start) = query.get("start").and_then(|s| parse_latlng(s)) else {
+        return (StatusCode::BAD_REQUEST, "start must be lat,lng\n").into_response();
+    };
+    let heading = query
+        .get("heading")
+        .and_then(|h| h.parse::<f32>().ok())
+        .unwrap_or_default();
+    let heading = (heading + 360.) % 360.;
+    let hours = query
+        .get("hours")
+        .and_then(|h| h.parse::<f64>().ok())
+        .unwrap_or(1.);
+    if !(hours > 0. && hours <= MAX_HOURS) {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("hours must be between 0 and {MAX_HOURS}\n"),
+        )
+            .into_response();
+    }
+
+    let mut settings =
+        match path_settings_for_query(&GetPathQuery::new(start, heading, start), limits) {
+            Ok(settings) => settings,
+            Err(err) => return (StatusCode::BAD_REQUEST, format!("{err}\n")).into_response(),
+        };
+    settings.max_nodes = Some(settings.max_nodes.unwrap_or(MAX_NODES).min(MAX_NODES));
+    let start_pano_id = query.get("start_pano").cloned();
+    let budget = (hours * 3600.) as Cost;
+
+    let (tx, rx) = oneshot::channel();
+    state.start_pathfinding_task(&headers, ISOCHRONE_QUERY_ID, move |ctx| async move {
+        let mut tx = tx;
+        tokio::select! {
+            res = run_isochrone(ctx, start, start_pano_id, heading, budget, settings) => {
+                let _ = tx.send(res);
+            }
+            // the client is gone, so stop searching
+            _ = tx.closed() => {}
+        }
+    });
+    rx.await.unwrap_or_else(|_| {
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            "The isochrone was stopped because another one was requested\n",
+        )
+            .into_response()
+    })
+}
+
+async fn run_isochrone(
+    ctx: TaskContext,
+    start: [f64; 2],
+    start_pano_id: Option<String>,
+    heading: f32,
+    budget: Cost,
+    mut settings: PathSettings,
+) -> Response {
+    if let Err(err) = ratelimit::check_quota(&DB, &ctx.status) {
+        return (StatusCode::TOO_MANY_REQUESTS, format!("{}\n", err.message)).into_response();
+    }
+    ctx.status.update(|status| {
+        status.kind = "isochrone";
+        status.start = Some(start);
+    });
+    settings.cancel = ctx.cancel.clone();
+
+    let start = Location::from_latlng(start);
+    let start_pano = match start_pano_id.as_deref() {
+        Some(pano_id) => match DB.lookup_pano_id(pano_id) {
+            Some(id) => Pano { id, loc: start },
+            None => {
+                return (StatusCode::BAD_REQUEST, format!("Unknown pano {pano_id}\n"))
+                    .into_response();
+            }
+        },
+        None => match get_nearest_pano(&DB, start, 500.).await {
+            Ok(Some(pano)) => pano,
+            Ok(None) => {
+                return (StatusCode::NOT_FOUND, "start isn't near a pano\n").into_response();
+            }
+            Err(err) => {
+                error!("{err}");
+                return (StatusCode::INTERNAL_SERVER_ERROR, format!("{err}\n")).into_response();
+            }
+        },
+    };
+
+    let Some(_search_slot) = ctx.wait_for_search_slot(|_| async {}).await else {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            "The isochrone was cancelled\n",
+        )
+            .into_response();
+    };
+
+    info!("/isochrone {start} heading {heading} for {budget} seconds");
+
+    let reachable = match isochrone::reachable(&DB, start_pano, heading, budget, &settings).await {
+        Ok(reachable) => reachable,
+        Err(err) => {
+            error!("{err}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, format!("{err}\n")).into_response();
+        }
+    };
+
+    ctx.status.update(|status| {
+        status.percent_done = 1.;
+        status.nodes_considered = reachable.nodes_considered;
+    });
+
+    let locations = reachable.panos.iter().map(|p| p.loc).collect::<Vec<_>>();
+    let ring = isochrone::hull(&locations)
+        .into_iter()
+        .map(|loc| [loc.lng_deg(), loc.lat_deg()])
+        .collect::<Vec<_>>();
+
+    Json(json!({
+        "type": "Feature",
+        "geometry": {
+            "type": "Polygon",
+            "coordinates": [ring],
+        },
+        "properties": {
+            "budget_seconds": budget,
+            "reachable_panos": reachable.panos.len(),
+            "nodes_considered": reachable.nodes_considered,
+            "truncated": reachable.truncated,
+        },
+    }))
+    .into_response()
+}
@@ -0,0 +1,433 @@
+//! Every pathfinding task that's running, which IP started it, and how far
+//! along it is. Each [`RatelimitIp`] can run one task per query ID, up to
+//! [`WebConfig::max_tasks_per_ip`] at once. Starting a task with the same ID as
+//! a running one stops the old one, and going over the limit stops the oldest.
+//! Only [`WebConfig::max_concurrent_searches`] searches can run at once across
+//! all IPs.
+//!
+//! [`WebConfig::max_tasks_per_ip`]: crate::web::config::WebConfig::max_tasks_per_ip
+//! [`WebConfig::max_concurrent_searches`]: crate::web::config::WebConfig::max_concurrent_searches
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    pin::pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+use crate::{
+    astar,
+    db::DB,
+    web::{
+        config::CONFIG,
+        ratelimit::{self, RatelimitIp},
+    },
+};
+
+/// How long a cancelled task gets to send its partial result before it's
+/// aborted.
+const CANCEL_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// How often a queued search checks whether its position changed.
+const QUEUE_POSITION_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often the nodes that a running task considered are counted towards its
+/// IP's quota, so its other searches see them before it finishes.
+const QUOTA_RECORD_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Clone)]
+pub struct JobManager {
+    /// Keyed by the IP and the `id` of the client's query.
+    tasks: Arc<Mutex<HashMap<(RatelimitIp, u32), PathfindingTask>>>,
+    search_slots: Arc<Semaphore>,
+    /// The IDs of the tasks that are waiting for a search slot, in order.
+    queue: Arc<Mutex<VecDeque<u64>>>,
+}
+impl Default for JobManager {
+    fn default() -> Self {
+        Self {
+            tasks: Arc::default(),
+            search_slots: Arc::new(Semaphore::new(CONFIG.max_concurrent_searches)),
+            queue: Arc::default(),
+        }
+    }
+}
+
+/// Permission to run a search, which is given back when it's dropped.
+pub struct SearchSlot {
+    _permit: OwnedSemaphorePermit,
+}
+
+/// Removes a task from the queue when it stops waiting, including when its
+/// future is dropped.
+struct QueueEntry<'a> {
+    queue: &'a Mutex<VecDeque<u64>>,
+    id: u64,
+}
+impl QueueEntry<'_> {
+    /// 1 if the task is next.
+    fn position(&self) -> usize {
+        let queue = self.queue.lock();
+        queue.iter().position(|&id| id == self.id).unwrap_or(0) + 1
+    }
+}
+impl Drop for QueueEntry<'_> {
+    fn drop(&mut self) {
+        self.queue.lock().retain(|&id| id != self.id);
+    }
+}
+
+struct PathfindingTask {
+    handle: JoinHandle<()>,
+    cancel: CancellationToken,
+    status: Arc<TaskStatus>,
+}
+impl PathfindingTask {
+    /// Cancel the task and wait for it to finish, returning whether it was
+    /// still running.
+    async fn stop(self) -> bool {
+        if self.handle.is_finished() {
+            return false;
+        }
+        self.cancel.cancel();
+        let abort_handle = self.handle.abort_handle();
+        if tokio::time::timeout(CANCEL_GRACE_PERIOD, self.handle)
+            .await
+            .is_err()
+        {
+            warn!("Pathfinding task didn't stop after being cancelled, aborting it");
+            abort_handle.abort();
+        }
+        true
+    }
+}
+
+/// What a pathfinding task is given when it starts.
+pub struct TaskContext {
+    /// Cancelled when the task is preempted or cancelled by an admin.
+    pub cancel: CancellationToken,
+    /// Whether starting this task stopped one with the same query ID that was
+    /// still running.
+    pub preempted: bool,
+    pub status: Arc<TaskStatus>,
+    manager: JobManager,
+}
+impl TaskContext {
+    /// Wait until there's a free search slot, calling `on_queue_position` with
+    /// the task's position in the queue whenever it changes. Returns `None` if
+    /// the task was cancelled while it was waiting.
+    pub async fn wait_for_search_slot<Fut: Future<Output = ()>>(
+        &self,
+        on_queue_position: impl FnMut(usize) -> Fut,
+    ) -> Option<SearchSlot> {
+        self.manager
+            .wait_for_search_slot(&self.status, &self.cancel, on_queue_position)
+            .await
+    }
+}
+
+pub struct TaskStatus {
+    pub id: u64,
+    pub ip: RatelimitIp,
+    /// Whether the exact address that started the task has no quota.
+    pub exempt: bool,
+    /// The `id` from the client's query.
+    pub query_id: u32,
+    pub started_at: Instant,
+    progress: Mutex<TaskProgress>,
+    /// The nodes considered by the task's searches that already finished, for
+    /// tasks like following that run a new search every time the car moves.
+    /// Only changed while `progress` is locked, so the two add up.
+    finished_nodes: AtomicU64,
+    /// The task's total nodes considered when they were last counted towards
+    /// the IP's quota.
+    recorded_nodes: AtomicU64,
+}
+impl TaskStatus {
+    /// Update the progress that's shown in the job listing.
+    pub fn update(&self, f: impl FnOnce(&mut TaskProgress)) {
+        f(&mut self.progress.lock());
+    }
+
+    /// Add a search's nodes to the task's total once it's done, so the next
+    /// search's progress can count from zero.
+    pub fn finish_search(&self, nodes_considered: usize) {
+        let mut progress = self.progress.lock();
+        self.finished_nodes
+            .fetch_add(nodes_considered as u64, Ordering::Relaxed);
+        progress.nodes_considered = 0;
+    }
+
+    /// The number of nodes that were considered by all of the task's searches.
+    fn total_nodes(&self) -> u64 {
+        let progress = self.progress.lock();
+        self.finished_nodes.load(Ordering::Relaxed) + progress.nodes_considered as u64
+    }
+
+    /// The number of nodes that were considered since this was last called.
+    pub fn take_unrecorded_nodes(&self) -> u64 {
+        let total = self.total_nodes();
+        let recorded = self.recorded_nodes.swap(total, Ordering::Relaxed);
+        total.saturating_sub(recorded)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TaskProgress {
+    /// What the task is doing, like `path` or `follow`.
+    pub kind: &'static str,
+    /// `[lat, lng]`
+    pub start: Option<[f64; 2]>,
+    /// `[lat, lng]`
+    pub end: Option<[f64; 2]>,
+    /// Between 0 and 1
+    pub percent_done: f64,
+    pub nodes_considered: usize,
+    pub stored_nodes: usize,
+    /// Set while the task is waiting for a search slot.
+    pub queue_position: Option<usize>,
+}
+
+/// A running task, as shown in `GET /admin/jobs`.
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskSummary {
+    pub id: u64,
+    pub ip: String,
+    pub query_id: u32,
+    pub running_seconds: f64,
+    #[serde(flatten)]
+    pub progress: TaskProgress,
+    pub estimated_memory_bytes: usize,
+}
+
+impl JobManager {
+    /// Start a pathfinding task for the IP and query ID. The task is given a
+    /// token that's cancelled when it's preempted, and whether it preempted a
+    /// task that was still running.
+    pub fn start<F, Fut>(&self, client_ip: IpAddr, query_id: u32, task: F)
+    where
+        F: FnOnce(TaskContext) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+        let ip = RatelimitIp::from(client_ip);
+        let mut tasks = self.tasks.lock();
+        tasks.retain(|_, task| !task.handle.is_finished());
+        let cancel = CancellationToken::new();
+        let status = Arc::new(TaskStatus {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            ip,
+            exempt: CONFIG.is_exempt(client_ip),
+            query_id,
+            started_at: Instant::now(),
+            progress: Mutex::default(),
+            finished_nodes: AtomicU64::new(0),
+            recorded_nodes: AtomicU64::new(0),
+        });
+        // only one task per query ID is allowed, so stop the existing one. it's
+        // allowed to finish first so it can send the path it found so far.
+        let existing = tasks.remove(&(ip, query_id));
+        // and if the IP is still at its limit, make room by stopping its oldest task
+        let running_for_ip = tasks.keys().filter(|(task_ip, _)| *task_ip == ip).count();
+        let evicted = if running_for_ip >= CONFIG.max_tasks_per_ip {
+            tasks
+                .iter()
+                .filter(|((task_ip, _), _)| *task_ip == ip)
+                .min_by_key(|(_, task)| task.status.id)
+                .map(|(key, _)| *key)
+                .and_then(|key| tasks.remove(&key))
+        } else {
+            None
+        };
+        let task_cancel = cancel.clone();
+        let task_status = status.clone();
+        let manager = self.clone();
+        let handle = tokio::spawn(async move {
+            if let Some(evicted) = evicted {
+                evicted.stop().await;
+            }
+            let preempted = match existing {
+                Some(existing) => existing.stop().await,
+                None => false,
+            };
+            if task_cancel.is_cancelled() {
+                // we were preempted before we even started
+                return;
+            }
+            let mut task = pin!(task(TaskContext {
+                cancel: task_cancel,
+                preempted,
+                status: task_status.clone(),
+                manager,
+            }));
+            let mut record_interval = tokio::time::interval(QUOTA_RECORD_INTERVAL);
+            record_interval.tick().await;
+            loop {
+                tokio::select! {
+                    () = &mut task => break,
+                    _ = record_interval.tick() => ratelimit::record_quota_usage(&DB, &task_status),
+                }
+            }
+            ratelimit::record_quota_usage(&DB, &task_status);
+        });
+        tasks.insert(
+            (ip, query_id),
+            PathfindingTask {
+                handle,
+                cancel,
+                status,
+            },
+        );
+    }
+
+    async fn wait_for_search_slot<Fut: Future<Output = ()>>(
+        &self,
+        status: &TaskStatus,
+        cancel: &CancellationToken,
+        mut on_queue_position: impl FnMut(usize) -> Fut,
+    ) -> Option<SearchSlot> {
+        if let Ok(permit) = self.search_slots.clone().try_acquire_owned() {
+            return Some(SearchSlot { _permit: permit });
+        }
+
+        self.queue.lock().push_back(status.id);
+        let entry = QueueEntry {
+            queue: &self.queue,
+            id: status.id,
+        };
+        // the semaphore is fair, so it's important that we keep waiting with the same
+        // future to hold our place in line
+        let mut acquire = pin!(self.search_slots.clone().acquire_owned());
+        let mut interval = tokio::time::interval(QUEUE_POSITION_INTERVAL);
+        let mut last_position = 0;
+        let permit = loop {
+            tokio::select! {
+                permit = &mut acquire => break permit.ok()?,
+                _ = cancel.cancelled() => {
+                    status.update(|status| status.queue_position = None);
+                    return None;
+                }
+                _ = interval.tick() => {}
+            }
+
+            let position = entry.position();
+            if position != last_position {
+                last_position = position;
+                status.update(|status| status.queue_position = Some(position));
+                on_queue_position(position).await;
+            }
+        };
+        drop(entry);
+        status.update(|status| status.queue_position = None);
+
+        Some(SearchSlot { _permit: permit })
+    }
+
+    /// Cancel the task for the IP and query ID, returning whether it was
+    /// running.
+    pub fn stop(&self, ip: RatelimitIp, query_id: u32) -> bool {
+        match self.tasks.lock().remove(&(ip, query_id)) {
+            Some(task) => {
+                task.cancel.cancel();
+                !task.handle.is_finished()
+            }
+            None => false,
+        }
+    }
+
+    /// Cancel every task for the IP.
+    pub fn stop_for_ip(&self, ip: RatelimitIp) {
+        self.tasks.lock().retain(|(task_ip, _), task| {
+            if *task_ip == ip {
+                task.cancel.cancel();
+            }
+            *task_ip != ip
+        });
+    }
+
+    /// Cancel the task with the given ID, returning whether it was running.
+    pub fn cancel(&self, id: u64) -> bool {
+        let mut tasks = self.tasks.lock();
+        let Some(key) = tasks
+            .iter()
+            .find(|(_, task)| task.status.id == id && !task.handle.is_finished())
+            .map(|(key, _)| *key)
+        else {
+            return false;
+        };
+        if let Some(task) = tasks.remove(&key) {
+            task.cancel.cancel();
+        }
+        true
+    }
+
+    /// Every task that's still running, oldest first.
+    pub fn list(&self) -> Vec<TaskSummary> {
+        let mut summaries = self
+            .tasks
+            .lock()
+            .values()
+            .filter(|task| !task.handle.is_finished())
+            .map(|task| {
+                let progress = task.status.progress.lock().clone();
+                TaskSummary {
+                    id: task.status.id,
+                    ip: task.status.ip.to_string(),
+                    query_id: task.status.query_id,
+                    running_seconds: task.status.started_at.elapsed().as_secs_f64(),
+                    estimated_memory_bytes: astar::estimated_memory_usage(progress.stored_nodes),
+                    progress,
+                }
+            })
+            .collect::<Vec<_>>();
+        summaries.sort_by_key(|s| s.id);
+        summaries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_every_search_is_recorded() {
+        let status = TaskStatus {
+            id: 1,
+            ip: RatelimitIp::from(IpAddr::from(Ipv4Addr::LOCALHOST)),
+            exempt: false,
+            query_id: 0,
+            started_at: Instant::now(),
+            progress: Mutex::default(),
+            finished_nodes: AtomicU64::new(0),
+            recorded_nodes: AtomicU64::new(0),
+        };
+        let set_progress = |nodes| status.update(|status| status.nodes_considered = nodes);
+
+        set_progress(1000);
+        assert_eq!(status.take_unrecorded_nodes(), 1000);
+        // the first search finishes after the quota was recorded
+        status.finish_search(1500);
+        // and the next one gets further before it's recorded again
+        set_progress(1200);
+        assert_eq!(status.take_unrecorded_nodes(), 1700);
+        status.finish_search(1300);
+        assert_eq!(status.take_unrecorded_nodes(), 100);
+        assert_eq!(status.take_unrecorded_nodes(), 0);
+    }
+}
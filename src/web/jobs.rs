@@ -0,0 +1,140 @@
+//! Pathfinding jobs that outlive the WebSocket that started them, so a client
+//! that loses its connection can reconnect and resume receiving updates with a
+//! `resume` message. Other clients can also `watch` a job, which sends them the
+//! same updates without taking it over.
+
+use std::{collections::HashMap, sync::Arc, time::Instant};
+
+use futures::{SinkExt, channel::mpsc};
+use parking_lot::Mutex;
+use pathfinder_protocol::SocketEvent;
+use tokio::sync::broadcast;
+use tracing::info;
+
+use crate::{math::random::random_u64, web::config::CONFIG};
+
+/// How many updates a spectator can fall behind by before it misses some.
+const SPECTATOR_BUFFER: usize = 16;
+
+#[derive(Clone, Default)]
+pub struct Jobs {
+    jobs: Arc<Mutex<HashMap<String, Arc<Job>>>>,
+}
+impl Jobs {
+    /// Register a new job that sends its updates to the given channel. The job
+    /// is unregistered when the returned guard is dropped.
+    pub fn create(&self, query_id: u32, tx: mpsc::Sender<SocketEvent>) -> JobGuard {
+        let job = Arc::new(Job {
+            id: new_job_id(),
+            query_id,
+            spectators: broadcast::Sender::new(SPECTATOR_BUFFER),
+            output: Mutex::new(JobOutput {
+                tx: Some(tx),
+                detached_at: None,
+                resumed: false,
+            }),
+        });
+        self.jobs.lock().insert(job.id.clone(), job.clone());
+        JobGuard {
+            jobs: self.clone(),
+            job,
+        }
+    }
+
+    pub fn get(&self, job_id: &str) -> Option<Arc<Job>> {
+        self.jobs.lock().get(job_id).cloned()
+    }
+}
+
+pub struct Job {
+    pub id: String,
+    /// The `id` from the client's query.
+    pub query_id: u32,
+    /// Every update is also sent here, for the clients that are watching the
+    /// job.
+    spectators: broadcast::Sender<SocketEvent>,
+    output: Mutex<JobOutput>,
+}
+struct JobOutput {
+    /// `None` if the WebSocket for this job was closed.
+    tx: Option<mpsc::Sender<SocketEvent>>,
+    detached_at: Option<Instant>,
+    /// Whether a new WebSocket was attached (or the full path was requested
+    /// for another reason) since the last call to [`Job::take_resumed`].
+    resumed: bool,
+}
+impl Job {
+    /// Start sending updates for this job to a different WebSocket.
+    pub fn attach(&self, tx: mpsc::Sender<SocketEvent>) {
+        let mut output = self.output.lock();
+        output.tx = Some(tx);
+        output.detached_at = None;
+        output.resumed = true;
+    }
+
+    /// Start receiving the job's updates, in addition to its WebSocket.
+    pub fn watch(&self) -> broadcast::Receiver<SocketEvent> {
+        let rx = self.spectators.subscribe();
+        self.request_full_path();
+        rx
+    }
+
+    /// Make the next update contain the full path instead of only what
+    /// changed, for a client that doesn't have the rest of it.
+    pub fn request_full_path(&self) {
+        self.output.lock().resumed = true;
+    }
+
+    /// Returns true if the job was resumed since the last time this was
+    /// called, which means that the next update must contain the full path.
+    pub fn take_resumed(&self) -> bool {
+        std::mem::take(&mut self.output.lock().resumed)
+    }
+
+    /// Try to send an event to the job's WebSocket, returning whether it was
+    /// delivered.
+    pub async fn send(&self, event: SocketEvent) -> bool {
+        if self.spectators.receiver_count() > 0 {
+            let _ = self.spectators.send(event.clone());
+        }
+
+        let Some(mut tx) = self.output.lock().tx.clone() else {
+            return false;
+        };
+        if tx.send(event).await.is_ok() {
+            return true;
+        }
+
+        let mut output = self.output.lock();
+        // make sure we weren't attached to a new socket while we were sending
+        if output.tx.as_ref().is_some_and(|t| t.same_receiver(&tx)) {
+            info!("Job {} was detached from its websocket", self.id);
+            output.tx = None;
+            output.detached_at = Some(Instant::now());
+        }
+        false
+    }
+
+    /// Whether the job has been detached for longer than the grace period, and
+    /// should be given up on.
+    pub fn is_expired(&self) -> bool {
+        self.output
+            .lock()
+            .detached_at
+            .is_some_and(|t| t.elapsed() > CONFIG.job_grace_period)
+    }
+}
+
+pub struct JobGuard {
+    jobs: Jobs,
+    pub job: Arc<Job>,
+}
+impl Drop for JobGuard {
+    fn drop(&mut self) {
+        self.jobs.jobs.lock().remove(&self.job.id);
+    }
+}
+
+fn new_job_id() -> String {
+    format!("{:016x}", random_u64())
+}
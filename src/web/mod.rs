@@ -1,43 +1,140 @@
-use std::{collections::HashMap, env, sync::LazyLock};
+use std::collections::HashMap;
 
 use axum::{
     Json, Router,
-    extract::{Path, Query},
+    extract::{DefaultBodyLimit, Path, Query},
     response::{IntoResponse, Response},
-    routing::get,
+    routing::{delete, get, post},
 };
-use http::{Method, StatusCode, header};
+use http::{HeaderMap, Method, StatusCode, header};
 use simd_json::json;
 use tokio::{fs, net::TcpListener};
 use tower_http::cors::CorsLayer;
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::{
+    cache_stats,
     db::DB,
     model::{PanoId, SizedTile},
-    web::ratelimit::AppState,
+    roadtrip_api,
+    streetview::ratelimit::GOOGLE_RATE_LIMITER,
+    web::{etag::ETag, ratelimit::AppState},
 };
 
+pub mod admin;
+pub mod car_history;
+pub mod config;
+pub mod deviation;
+pub mod eta;
+pub mod etag;
+pub mod follow;
+pub mod health;
+pub mod isochrone;
+pub mod job_manager;
+pub mod jobs;
+pub mod mvt;
+pub mod options;
+pub mod pano;
 pub mod path;
+pub mod portals;
 pub mod ratelimit;
+pub mod recommendation;
+pub mod rest;
+pub mod sandbox;
+pub mod saved_routes;
+pub mod sse;
+pub mod tile;
 
-static SECRET: LazyLock<String> =
-    LazyLock::new(|| env::var("PATHFINDER_SECRET").unwrap_or_default());
+/// The largest cache export that can be imported, in bytes.
+const MAX_IMPORT_SIZE: usize = 1024 * 1024 * 1024;
 
 pub async fn serve() {
     let cors = CorsLayer::new()
-        .allow_methods([Method::GET])
+        .allow_methods([Method::GET, Method::POST, Method::DELETE])
+        // so clients can send the ETag back in If-None-Match
+        .expose_headers([header::ETAG])
         .allow_origin(tower_http::cors::Any);
 
     let app = Router::new()
         .route("/path", get(path::get_path))
+        .route("/path/{job_id}/gpx", get(path::get_path_gpx))
+        .route("/path/sse", get(sse::get_path_sse))
+        .route("/path/sync", post(rest::post_path_sync))
+        .route("/path/job/{job_id}", get(rest::get_path_job))
+        .route("/route/{id}", get(saved_routes::get_route))
+        .route("/route/{id}/geojson", get(saved_routes::get_route_geojson))
+        .route("/eta", get(eta::get_eta))
+        .route("/car/history", get(car_history::get_car_history))
+        .route("/deviation", get(deviation::get_deviation))
+        .route("/deviation/stream", get(deviation::get_deviation_stream))
+        .route("/admin/active-route", post(admin::post_active_route))
+        .route("/recommendation", get(recommendation::get_recommendation))
+        .route("/isochrone", get(isochrone::get_isochrone))
+        .route("/portals", get(portals::get_portals))
+        .route("/options", get(options::get_options))
+        .route("/pano/{pano_id}", get(pano::get_pano))
+        .route("/pano-ids", post(pano::post_pano_ids))
+        .route("/health", get(health::get_health))
         .route("/stats", get(get_stats))
+        .route("/stats/accuracy", get(get_stats_accuracy))
+        .route("/stats/db", get(get_stats_db))
+        .route("/stats/caches", get(get_stats_caches))
+        .route("/stats/tiles", get(get_stats_tiles))
+        .route("/stops", get(get_stops))
         .route("/slow-get-pano-id/{pano_id}", get(get_slow_get_pano_id))
         .route(
             "/internal-pano-id/{internal_pano_id}",
             get(get_internal_pano_id),
         )
-        .route("/tile/{size}/{x}/{z}", get(get_tile))
+        .route("/tile/{size}/{x}/{y}", get(tile::get_tile))
+        .route("/mvt/{z}/{x}/{y}", get(mvt::get_mvt))
+        .route("/admin/tile-cache", get(admin::get_tile_cache))
+        .route("/admin/cache", delete(admin::delete_cache))
+        .route("/admin/refresh", post(admin::post_refresh))
+        .route("/admin/tile-cache/pin", post(admin::post_tile_cache_pin))
+        .route(
+            "/admin/tile-cache/unpin",
+            post(admin::post_tile_cache_unpin),
+        )
+        .route(
+            "/admin/prefetch",
+            get(admin::get_prefetch).post(admin::post_prefetch),
+        )
+        .route("/admin/prefetch/cancel", post(admin::post_prefetch_cancel))
+        .route(
+            "/admin/bake",
+            get(admin::get_bake)
+                .post(admin::post_bake)
+                .delete(admin::delete_bake),
+        )
+        .route("/admin/bake/cancel", post(admin::post_bake_cancel))
+        .route("/admin/landmarks", post(admin::post_landmarks))
+        .route("/admin/shortcuts", post(admin::post_shortcuts))
+        .route(
+            "/admin/learned-options/history",
+            post(admin::post_learn_from_history),
+        )
+        .route("/admin/fsck", post(admin::post_fsck))
+        .route("/admin/export", get(admin::get_export_cache))
+        .route(
+            "/admin/import",
+            post(admin::post_import_cache).layer(DefaultBodyLimit::max(MAX_IMPORT_SIZE)),
+        )
+        .route(
+            "/admin/backup",
+            get(admin::get_backup).post(admin::post_backup),
+        )
+        .route(
+            "/admin/single-image-search",
+            get(admin::get_single_image_search),
+        )
+        .route("/admin/jobs", get(admin::get_jobs))
+        .route("/admin/jobs/cancel", post(admin::post_jobs_cancel))
+        .route("/admin/calibration", get(admin::get_calibration))
+        .route(
+            "/admin/calibration/import",
+            post(admin::post_calibration_import),
+        )
         .route(
             "/meowing",
             get(|| async {
@@ -58,29 +155,180 @@ pub async fn serve() {
                 )
             }),
         )
-        .layer(cors)
-        .with_state(AppState::default());
-
-    let port = env::var("PORT").unwrap_or_else(|_| "2397".to_string());
+        .layer(cors);
+    let state = AppState::default();
+    tokio::spawn(state.rest_jobs.clone().prune_periodically());
+    let app = app.with_state(state);
 
-    let bind_to = format!("[::]:{port}");
+    let bind_to = format!("[::]:{}", config::CONFIG.port);
     info!("binding to {bind_to}");
     let listener = TcpListener::bind(bind_to).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .unwrap();
+}
+
+/// Wait for Ctrl+C or SIGTERM (which is what docker and systemd send), so
+/// queued writes get flushed before exiting either way.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(err) => {
+                warn!("Failed to listen for SIGTERM: {err}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+    info!("shutting down");
 }
 
-async fn get_stats() -> Response {
-    let tiles = DB
-        .slow_list_tiles()
+async fn get_stats(headers: HeaderMap) -> Response {
+    let google_request_queue = GOOGLE_RATE_LIMITER.queue_depth();
+    // pano IDs are given out without the tiles changing, so the count is part of
+    // the etag
+    let pano_count = DB.get_pano_count();
+    let etag = ETag::of_tiles_and(&DB, format!("{pano_count:x}-{google_request_queue}"));
+    if etag.matches(&headers) {
+        return etag.not_modified();
+    }
+
+    etag.attach(
+        Json(json!({
+            "panos": pano_count,
+            "tiles": DB.tile_count(),
+            "google_request_queue": google_request_queue,
+        }))
+        .into_response(),
+    )
+}
+
+/// A page of the cached tiles, as `[x, y, size]`. `after` is the `next` from
+/// the previous page, which is `null` once there are no more tiles.
+async fn get_stats_tiles(
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Response {
+    let etag = ETag::of_tiles(&DB);
+    if etag.matches(&headers) {
+        return etag.not_modified();
+    }
+
+    let limit = query
+        .get("limit")
+        .and_then(|l| l.parse::<usize>().ok())
+        .unwrap_or(10_000)
+        .min(100_000);
+    let after = match query.get("after").map(|after| parse_tile_cursor(after)) {
+        Some(Some(after)) => Some(after),
+        Some(None) => return (StatusCode::BAD_REQUEST, "invalid cursor\n").into_response(),
+        None => None,
+    };
+
+    let tiles = DB.list_tiles_page(after.as_ref(), limit);
+    let next = (tiles.len() == limit)
+        .then(|| tiles.last())
+        .flatten()
+        .map(|tile| format!("{}/{}/{}", tile.size, tile.x, tile.y));
+
+    etag.attach(
+        Json(json!({
+            "tiles": tiles
+                .iter()
+                .map(|tile| [tile.x, tile.y, tile.size as u32])
+                .collect::<Vec<_>>(),
+            "next": next,
+        }))
+        .into_response(),
+    )
+}
+
+/// Parse a `lat,lng` query parameter.
+pub fn parse_latlng(s: &str) -> Option<[f64; 2]> {
+    let (lat, lng) = s.split_once(',')?;
+    Some([lat.trim().parse().ok()?, lng.trim().parse().ok()?])
+}
+
+/// Parse a `size/x/y` cursor from [`get_stats_tiles`].
+fn parse_tile_cursor(cursor: &str) -> Option<SizedTile> {
+    let mut parts = cursor.split('/');
+    let tile = SizedTile {
+        size: parts.next()?.parse().ok()?,
+        x: parts.next()?.parse().ok()?,
+        y: parts.next()?.parse().ok()?,
+    };
+    parts.next().is_none().then_some(tile)
+}
+
+/// Hits, misses and evictions of the in-memory caches.
+async fn get_stats_caches() -> Response {
+    Json(cache_stats::cache_stats(&DB)).into_response()
+}
+
+/// The size of every table in the database and how full it is.
+async fn get_stats_db() -> Response {
+    match tokio::task::spawn_blocking(|| DB.stats()).await {
+        Ok(stats) => Json(stats).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{err}\n")).into_response(),
+    }
+}
+
+/// How often our options match the ones the game offers the car, and the most
+/// recent times they didn't.
+async fn get_stats_accuracy(Query(query): Query<HashMap<String, String>>) -> Response {
+    let limit = query
+        .get("limit")
+        .and_then(|l| l.parse::<usize>().ok())
+        .unwrap_or(50)
+        .min(1000);
+
+    let counts = DB.option_accuracy();
+    let pano_id_string = |pano_id: PanoId| DB.lookup_pano_id_string(pano_id).unwrap_or_default();
+    let mismatches = DB
+        .recent_option_mismatches(limit)
         .into_iter()
-        .map(|tile| [tile.x, tile.y, tile.size as u32])
+        .map(|mismatch| {
+            json!({
+                "timestamp": mismatch.timestamp,
+                "pano": pano_id_string(mismatch.pano),
+                "heading": mismatch.heading,
+                "missing": mismatch.missing.into_iter().map(pano_id_string).collect::<Vec<_>>(),
+                "extra": mismatch.extra.into_iter().map(pano_id_string).collect::<Vec<_>>(),
+            })
+        })
         .collect::<Vec<_>>();
 
-    let pano_count = DB.get_pano_count();
+    Json(json!({
+        "observations": counts.observations,
+        "exact_matches": counts.exact_matches,
+        "accuracy": counts.accuracy(),
+        "missing_options": counts.missing_options,
+        "extra_options": counts.extra_options,
+        "recent_mismatches": mismatches,
+    }))
+    .into_response()
+}
+
+/// The upcoming stops that the game announced, the last of which is the
+/// terminus.
+async fn get_stops() -> Response {
+    let stops = roadtrip_api::official_stops();
+    let terminus = stops.last().cloned();
 
     Json(json!({
-        "panos": pano_count,
-        "tiles": tiles,
+        "stops": stops,
+        "terminus": terminus,
     }))
     .into_response()
 }
@@ -89,13 +337,8 @@ async fn get_slow_get_pano_id(
     Query(query): Query<HashMap<String, String>>,
     Path(pano_id): Path<u32>,
 ) -> String {
-    if !SECRET.is_empty() {
-        // in theory this is vulnerable to timing attacks, but the latency difference is
-        // nanoseconds and it's impractical to exploit over the network so it's
-        // acceptable here
-        if query.get("key").cloned().unwrap_or_default() != *SECRET {
-            return "incorrect key".to_string();
-        }
+    if !admin::is_key_valid(query.get("key").map(|k| k.as_str())) {
+        return "incorrect key".to_string();
     }
 
     let txn = DB.read_txn();
@@ -109,6 +352,7 @@ async fn get_slow_get_pano_id(
     "no result\n".to_string()
 }
 
+/// Prefer [`pano::get_pano`], which returns JSON.
 async fn get_internal_pano_id(Path(pano_id): Path<String>) -> String {
     let txn = DB.read_txn();
     if let Some(pano_id) = DB.pano_ids_db.get(&txn, &pano_id).unwrap() {
@@ -119,15 +363,3 @@ async fn get_internal_pano_id(Path(pano_id): Path<String>) -> String {
 
     "no result\n".to_string()
 }
-
-async fn get_tile(Path((size, x, y)): Path<(u8, u32, u32)>) -> impl IntoResponse {
-    let res = DB.lookup_listentityphotos(&SizedTile { size, x, y });
-
-    let res = if let Some(Some(res)) = res {
-        Some(Some(res.to_vec()))
-    } else {
-        None
-    };
-
-    Json(res)
-}
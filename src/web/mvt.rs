@@ -0,0 +1,179 @@
+//! `GET /mvt/{z}/{x}/{y}`, the cached panos and the links between them as
+//! vector tiles, so the map can show the graph that the pathfinder searches.
+
+use axum::{
+    extract::Path,
+    response::{IntoResponse, Response},
+};
+use http::{HeaderMap, StatusCode, header};
+use rustc_hash::FxHashSet;
+
+use crate::{
+    db::{DB, Db},
+    mvt::{self, Layer, TileId, Value},
+    streetview::prefetch::BoundingBox,
+    web::etag::ETag,
+};
+
+/// Below this zoom level the tiles are empty, since they'd have too many panos.
+const MIN_ZOOM: u8 = 13;
+const MAX_ZOOM: u8 = 24;
+/// How far past the edges of the tile panos are included, as a fraction of the
+/// tile, so links that cross the edge are drawn in both tiles.
+const BUFFER: f64 = 1. / 16.;
+
+/// `GET /mvt/{z}/{x}/{y}`, a vector tile with a `panos` layer of points and a
+/// `links` layer of lines between linked panos. Both are empty below zoom
+/// [`MIN_ZOOM`].
+pub async fn get_mvt(Path((z, x, y)): Path<(u8, u32, u32)>, headers: HeaderMap) -> Response {
+    if z > MAX_ZOOM || x >= 1 << z || y >= 1 << z {
+        return (StatusCode::BAD_REQUEST, "invalid tile\n").into_response();
+    }
+    let etag = ETag::of_tiles(&DB);
+    if etag.matches(&headers) {
+        return etag.not_modified();
+    }
+    let tile = TileId { z, x, y };
+
+    let data = if z < MIN_ZOOM {
+        Vec::new()
+    } else {
+        match tokio::task::spawn_blocking(move || build_tile(&DB, tile)).await {
+            Ok(data) => data,
+            Err(err) => {
+                return (StatusCode::INTERNAL_SERVER_ERROR, format!("{err}\n")).into_response();
+            }
+        }
+    };
+    etag.attach(
+        (
+            [(header::CONTENT_TYPE, "application/vnd.mapbox-vector-tile")],
+            data,
+        )
+            .into_response(),
+    )
+}
+
+fn build_tile(db: &Db, tile: TileId) -> Vec<u8> {
+    let (min_lat, min_lng, max_lat, max_lng) = tile.bounds();
+    let lat_buffer = (max_lat - min_lat) * BUFFER;
+    let lng_buffer = (max_lng - min_lng) * BUFFER;
+    let bbox = BoundingBox {
+        min_lat: min_lat - lat_buffer,
+        min_lng: min_lng - lng_buffer,
+        max_lat: max_lat + lat_buffer,
+        max_lng: max_lng + lng_buffer,
+    };
+
+    let mut panos_layer = Layer::new("panos");
+    let mut links_layer = Layer::new("links");
+    let mut seen_panos = FxHashSet::default();
+    let mut seen_links = FxHashSet::default();
+
+    // this has its own txn, and a thread can only have one at a time
+    let tiles = db.iter_tiles_in_bbox(&bbox);
+    let txn = db.read_txn();
+    for sized_tile in tiles {
+        let Some(Some(panos)) = db.lookup_listentityphotos_with_txn(&txn, &sized_tile) else {
+            continue;
+        };
+        for pano in panos.iter() {
+            // the bigger tiles overlap the smaller ones
+            if !bbox.contains(pano.actual_loc) || !seen_panos.insert(pano.id) {
+                continue;
+            }
+            let point = tile.project(pano.actual_loc);
+            panos_layer.add_point(
+                pano.id.0 as u64,
+                point,
+                &[
+                    ("internal_id", Value::Uint(pano.id.0 as u64)),
+                    ("photosphere", Value::Bool(pano.id.is_photosphere())),
+                ],
+            );
+
+            let Some((_, links)) = db.lookup_getmetadata_with_txn(&txn, &pano.id) else {
+                continue;
+            };
+            for link in links {
+                // most links go both ways, but they only have to be drawn once
+                let pair = (pano.id.0.min(link.pano.id.0), pano.id.0.max(link.pano.id.0));
+                if !seen_links.insert(pair) {
+                    continue;
+                }
+                links_layer.add_line(
+                    seen_links.len() as u64,
+                    &[point, tile.project(link.pano.loc)],
+                    &[
+                        ("from", Value::Uint(pano.id.0 as u64)),
+                        ("to", Value::Uint(link.pano.id.0 as u64)),
+                        ("heading", Value::Double(link.heading as f64)),
+                    ],
+                );
+            }
+        }
+    }
+    txn.commit().unwrap();
+
+    mvt::encode_tile(&[links_layer, panos_layer])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{
+        GetMetadataResponse, Location, Pano, PanoLink, PanoWithBothLocations, SMALL_TILE_SIZE,
+        SizedTile, SmallTile,
+    };
+
+    #[test]
+    fn test_build_tile() {
+        let db = Db::temp("mvt");
+        let first = db.get_pano_id("first").unwrap();
+        let second = db.get_pano_id("second").unwrap();
+        let first_loc = Location::new_deg(40.0001, -75.0001);
+        let second_loc = Location::new_deg(40.0002, -75.0001);
+
+        let small = SmallTile::from_loc(first_loc);
+        let tile = SizedTile {
+            size: SMALL_TILE_SIZE,
+            x: small.x,
+            y: small.y,
+        };
+        let panos = [(first, first_loc), (second, second_loc)]
+            .into_iter()
+            .map(|(id, loc)| PanoWithBothLocations {
+                id,
+                search_loc: loc,
+                actual_loc: loc,
+            })
+            .collect();
+        db.save_listentityphotos(&tile, Some(panos)).unwrap();
+        db.save_getmetadata(&GetMetadataResponse {
+            id: first,
+            loc: first_loc,
+            links: vec![PanoLink {
+                pano: Pano {
+                    id: second,
+                    loc: second_loc,
+                },
+                heading: 0.,
+            }],
+            capture_date: None,
+            road_name: None,
+        })
+        .unwrap();
+
+        // the z=15 tile that has both panos
+        let scale = (1 << 15) as f64;
+        let x = ((first_loc.lng_deg() + 180.) / 360. * scale) as u32;
+        let y =
+            ((1. - first_loc.lat_rad().tan().asinh() / std::f64::consts::PI) / 2. * scale) as u32;
+        let data = build_tile(&db, TileId { z: 15, x, y });
+
+        let contains = |needle: &[u8]| data.windows(needle.len()).any(|w| w == needle);
+        assert!(contains(b"panos"));
+        assert!(contains(b"links"));
+        assert!(contains(b"internal_id"));
+    }
+}
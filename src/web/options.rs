@@ -0,0 +1,87 @@
+//! `GET /options`, the options that we think the game offers at a pano, for
+//! debugging turns that we get wrong.
+
+use axum::{
+    Json,
+    extract::{Query, State},
+    response::{IntoResponse, Response},
+};
+use http::{HeaderMap, StatusCode};
+use serde::Deserialize;
+use simd_json::json;
+use tokio_util::sync::CancellationToken;
+
+use crate::{db::DB, model::Pano, roadtrip, streetview, web::ratelimit::AppState};
+
+#[derive(Deserialize)]
+pub struct OptionsQuery {
+    /// The Streetview pano ID.
+    pano: String,
+    /// The direction the car is facing, in degrees.
+    heading: f32,
+    /// Whether to turn around if there are no options ahead, like the game
+    /// does. Defaults to true.
+    turnaround: Option<bool>,
+    /// Whether cached options can be used. Defaults to false, so they're
+    /// calculated again.
+    cache: Option<bool>,
+}
+
+/// `GET /options?pano=…&heading=…`, the options that
+/// [`roadtrip::get_options`] predicts for the pano and heading.
+pub async fn get_options(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<OptionsQuery>,
+) -> Response {
+    if let Err(err) = state.check_request_limit(&headers) {
+        return (StatusCode::TOO_MANY_REQUESTS, format!("{}\n", err.message)).into_response();
+    }
+    let pano_id = match streetview::ensure_getmetadata(&DB, &query.pano).await {
+        Ok(Some(pano_id)) => pano_id,
+        Ok(None) => return (StatusCode::NOT_FOUND, "unknown pano\n").into_response(),
+        Err(err) => return (StatusCode::BAD_GATEWAY, format!("{err}\n")).into_response(),
+    };
+    let Some(loc) = DB.lookup_getmetadata_location(&pano_id) else {
+        return (StatusCode::NOT_FOUND, "unknown pano\n").into_response();
+    };
+    let pano = Pano { id: pano_id, loc };
+
+    let res = roadtrip::get_options(
+        &DB,
+        &pano,
+        query.heading,
+        query.turnaround.unwrap_or(true),
+        query.cache.unwrap_or(false),
+        &CancellationToken::new(),
+    )
+    .await;
+    let res = match res {
+        Ok(res) => res,
+        Err(err) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, format!("{err}\n")).into_response();
+        }
+    };
+
+    let options = res
+        .options
+        .iter()
+        .map(|option| {
+            json!({
+                "pano": DB.lookup_pano_id_string(option.pano.id),
+                "heading": option.heading,
+                "lat": option.pano.loc.lat_deg(),
+                "lng": option.pano.loc.lng_deg(),
+            })
+        })
+        .collect::<Vec<_>>();
+    Json(json!({
+        "pano": query.pano,
+        "lat": loc.lat_deg(),
+        "lng": loc.lng_deg(),
+        "heading": query.heading,
+        "turnaround": res.turnaround,
+        "options": options,
+    }))
+    .into_response()
+}
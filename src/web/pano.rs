@@ -0,0 +1,104 @@
+//! `GET /pano/{pano_id}`, everything we know about a pano, and `POST
+//! /pano-ids` for translating between Streetview and internal pano IDs.
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+};
+use http::{HeaderMap, StatusCode};
+use serde::Deserialize;
+use simd_json::json;
+
+use crate::{db::DB, model::PanoId, streetview, web::ratelimit::AppState};
+
+/// The most IDs that can be translated in one request.
+const MAX_PANO_IDS: usize = 50_000;
+
+/// `GET /pano/{pano_id}` with a Streetview pano ID, its GetMetadata response
+/// and our internal ID for it. It's downloaded if it isn't cached.
+pub async fn get_pano(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(str_pano_id): Path<String>,
+) -> Response {
+    if let Err(err) = state.check_request_limit(&headers) {
+        return (StatusCode::TOO_MANY_REQUESTS, format!("{}\n", err.message)).into_response();
+    }
+    let pano_id = match streetview::ensure_getmetadata(&DB, &str_pano_id).await {
+        Ok(Some(pano_id)) => pano_id,
+        Ok(None) => return (StatusCode::NOT_FOUND, "unknown pano\n").into_response(),
+        Err(err) => return (StatusCode::BAD_GATEWAY, format!("{err}\n")).into_response(),
+    };
+
+    let txn = DB.read_txn();
+    let Some((loc, links)) = DB.lookup_getmetadata_with_txn(&txn, &pano_id) else {
+        return (StatusCode::NOT_FOUND, "unknown pano\n").into_response();
+    };
+    let capture_date = DB.lookup_capture_date_with_txn(&txn, &pano_id);
+    let road_name = DB.lookup_road_name_with_txn(&txn, &pano_id);
+    txn.commit().unwrap();
+
+    let links = links
+        .iter()
+        .map(|link| {
+            json!({
+                "pano": DB.lookup_pano_id_string(link.pano.id),
+                "internal_id": link.pano.id.0,
+                "heading": link.heading,
+                "lat": link.pano.loc.lat_deg(),
+                "lng": link.pano.loc.lng_deg(),
+            })
+        })
+        .collect::<Vec<_>>();
+    Json(json!({
+        "pano": str_pano_id,
+        "internal_id": pano_id.0,
+        "photosphere": pano_id.is_photosphere(),
+        "lat": loc.lat_deg(),
+        "lng": loc.lng_deg(),
+        "capture_date": capture_date,
+        "road_name": road_name,
+        "links": links,
+    }))
+    .into_response()
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum AnyPanoId {
+    Internal(u32),
+    Streetview(String),
+}
+
+/// `POST /pano-ids` with a JSON list of Streetview pano IDs and/or internal
+/// IDs. Returns `{ "pano": …, "internal_id": … }` for each of them in the same
+/// order, where the other one is `null` if the pano isn't known. Panos that
+/// don't have an internal ID yet aren't given one.
+pub async fn post_pano_ids(Json(ids): Json<Vec<AnyPanoId>>) -> Response {
+    if ids.len() > MAX_PANO_IDS {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("too many pano IDs (limit is {MAX_PANO_IDS})\n"),
+        )
+            .into_response();
+    }
+
+    let txn = DB.read_txn();
+    let mapping = ids
+        .into_iter()
+        .map(|id| match id {
+            AnyPanoId::Internal(internal_id) => json!({
+                "pano": DB.lookup_pano_id_string_with_txn(&txn, PanoId(internal_id)),
+                "internal_id": internal_id,
+            }),
+            AnyPanoId::Streetview(pano) => json!({
+                "internal_id": DB.lookup_pano_id_with_txn(&txn, &pano).map(|id| id.0),
+                "pano": pano,
+            }),
+        })
+        .collect::<Vec<_>>();
+    txn.commit().unwrap();
+
+    Json(mapping).into_response()
+}
@@ -1,118 +1,96 @@
 use std::{
-    sync::Arc,
-    time::{Duration, Instant},
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use axum::{
     extract::{
-        State, WebSocketUpgrade,
+        Path, Query, State, WebSocketUpgrade,
         ws::{self, WebSocket},
     },
-    response::IntoResponse,
+    response::{IntoResponse, Response},
 };
 use futures::{SinkExt, StreamExt, channel::mpsc};
-use http::HeaderMap;
+use http::{HeaderMap, StatusCode, header};
 use parking_lot::Mutex;
-use serde::{Deserialize, Serialize};
-use tokio::{task::JoinSet, time::sleep};
+use pathfinder_protocol::{
+    ErrorCode, GetPathQuery, PathResult, PathResultNode, SegmentProgress, ServerboundMessage,
+    SocketError, SocketEvent, Stop,
+};
+use rustc_hash::FxHashSet;
+use tokio::{sync::broadcast, task::JoinHandle, time::sleep};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info};
 
 use crate::{
     FullProgressUpdate, ProgressUpdate,
     astar::{
-        self, Cost, MAX_HEURISTIC_FACTOR, MIN_HEURISTIC_FACTOR, PathSettings,
-        RECOMMENDED_HEURISTIC_FACTOR,
+        self, Incomplete, MAX_HEURISTIC_FACTOR, MIN_HEURISTIC_FACTOR, NO_LONG_JUMPS_METERS,
+        PathSettings, PhotosphereAvoidance, RouteNode, Waypoint,
+    },
+    config::CONFIG,
+    db::{DB, Db},
+    gpx, instructions,
+    math::{self, Polygon},
+    model::{Location, Pano, SavedPath},
+    roadtrip_api, stop_order,
+    streetview::{UnknownPano, get_nearest_pano},
+    units::Formatter,
+    web::{
+        follow::{self, FollowedPaths},
+        job_manager::TaskContext,
+        jobs::Job,
+        ratelimit::{self, AppState},
+        sandbox::PathLimits,
+        saved_routes,
     },
-    math,
-    model::{Location, Pano},
-    streetview::get_nearest_pano,
-    web::ratelimit::AppState,
 };
 
-#[derive(Deserialize)]
-#[serde(tag = "kind")]
-#[serde(rename_all = "snake_case")]
-enum ServerboundMessage {
-    Path(GetPathQuery),
-    /// Stop calculating the current path.
-    Abort {
-        #[serde(default)]
-        id: u32,
-    },
-}
+/// Soft stops can't be smaller than this, since the path might not go through
+/// any pano that's closer to them.
+const MIN_SOFT_STOP_RADIUS: f64 = 15.;
+const MAX_SOFT_STOP_RADIUS: f64 = 5000.;
 
-#[derive(Deserialize)]
-struct GetPathQuery {
-    #[serde(default)]
-    id: u32,
-    start: [f64; 2],
-    /// Optionally allows us to set the start pano ID, which makes it not snap
-    /// the coordinates to the nearest pano.
-    #[serde(default)]
-    start_pano: Option<String>,
-    end: [f64; 2],
-    heading: f32,
-    #[serde(default)]
-    stops: Vec<[f64; 2]>,
+/// The longest that a client can ask a search to run for with
+/// `timeout_seconds`.
+const MAX_TIMEOUT_SECONDS: f64 = 60. * 60.;
 
-    #[serde(default = "return_true")]
-    use_option_cache: bool,
-    #[serde(default)]
-    no_long_jumps: bool,
-    #[serde(default = "get_recommended_heuristic_factor")]
-    heuristic_factor: f64,
-    #[serde(default)]
-    forward_penalty_on_intersections: Cost,
-    #[serde(default)]
-    non_sharp_turn_penalty: Cost,
-}
-fn get_recommended_heuristic_factor() -> f64 {
-    RECOMMENDED_HEURISTIC_FACTOR
-}
-fn return_true() -> bool {
-    true
-}
+/// The most panos that a client can give in `exclude_panos`.
+const MAX_EXCLUDED_PANOS: usize = 1000;
+
+/// The default for `progress_interval_ms`, and the bounds that it's clamped to.
+const DEFAULT_PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+const MIN_PROGRESS_INTERVAL: Duration = Duration::from_millis(50);
+const MAX_PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
 
 pub async fn get_path(
     ws: WebSocketUpgrade,
     State(state): State<AppState>,
+    Query(query): Query<HashMap<String, String>>,
     headers: HeaderMap,
 ) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, state, headers))
+    let limits = PathLimits::for_query(&query);
+    ws.on_upgrade(move |socket| handle_socket(socket, state, headers, limits))
 }
 
-#[derive(Serialize)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
-pub enum SocketEvent {
-    Progress(FullProgressUpdate),
-    Error { message: String },
-}
-
-async fn handle_socket(socket: WebSocket, state: AppState, headers: HeaderMap) {
+async fn handle_socket(socket: WebSocket, state: AppState, headers: HeaderMap, limits: PathLimits) {
     let (mut sender, mut receiver) = socket.split();
 
     info!("/path websocket opened");
 
-    let (tx, rx) = mpsc::channel::<SocketEvent>(1);
-
-    let is_pathing = Arc::new(Mutex::new(false));
+    let (mut tx, rx) = mpsc::channel::<SocketEvent>(1);
 
-    let is_pathing_ = is_pathing.clone();
     let task = tokio::spawn(async move {
-        let is_pathing = is_pathing_;
         let mut rx = rx;
         while let Some(msg) = rx.next().await {
-            match &msg {
-                SocketEvent::Progress(progress) => {
-                    if progress.percent_done == 1. || progress.percent_done < -0.1 {
-                        *is_pathing.lock() = false;
-                        info!("percent done is {}", progress.percent_done);
-                    }
-                }
-                SocketEvent::Error { .. } => {
-                    *is_pathing.lock() = false;
-                }
+            if let SocketEvent::Progress(progress) = &msg
+                && (progress.percent_done == 1. || progress.percent_done < -0.1)
+            {
+                info!("percent done is {}", progress.percent_done);
             }
 
             let msg = simd_json::to_string(&msg)
@@ -121,6 +99,11 @@ async fn handle_socket(socket: WebSocket, state: AppState, headers: HeaderMap) {
         }
     });
 
+    // forwards the updates for the job that's being watched, if there is one
+    let mut watching: Option<JoinHandle<()>> = None;
+    // where the positions for each of the paths that are being followed are sent
+    let mut followed_paths = FollowedPaths::default();
+
     while let Some(msg) = receiver.next().await {
         let Ok(msg) = msg else {
             break;
@@ -133,177 +116,404 @@ async fn handle_socket(socket: WebSocket, state: AppState, headers: HeaderMap) {
             continue;
         }
 
-        *is_pathing.lock() = true;
-        let task = tokio::spawn(handle_socket_message(tx.clone(), msg));
+        let Ok(text) = msg.to_text() else {
+            send_error(
+                &mut tx,
+                SocketError::new(ErrorCode::InvalidRequest, "Message must be UTF-8"),
+            )
+            .await;
+            continue;
+        };
+        let msg =
+            match simd_json::from_slice::<ServerboundMessage>(&mut text.to_owned().into_bytes()) {
+                Ok(msg) => msg,
+                Err(_) => {
+                    send_error(
+                        &mut tx,
+                        SocketError::new(
+                            ErrorCode::InvalidRequest,
+                            format!("Message must be valid query: '{text}'"),
+                        ),
+                    )
+                    .await;
+                    continue;
+                }
+            };
 
-        state.start_pathfinding_task(&headers, task);
+        match msg {
+            ServerboundMessage::Resume { job_id } => {
+                // resuming doesn't start a new task, so it doesn't stop any running ones
+                resume_job(&state, tx.clone(), &job_id).await;
+            }
+            ServerboundMessage::Watch { id } => {
+                // spectators are read-only, so this doesn't affect the running task either
+                if let Some(watching) = watching.take() {
+                    watching.abort();
+                }
+                watching = watch_job(&state, tx.clone(), &id).await;
+            }
+            ServerboundMessage::Moved(position) => {
+                // same here, the position goes to the path that's already being followed
+                if !followed_paths.moved(position) {
+                    send_error(
+                        &mut tx,
+                        SocketError::new(ErrorCode::InvalidRequest, "Not following a path"),
+                    )
+                    .await;
+                }
+            }
+            ServerboundMessage::Abort { id } => {
+                followed_paths.stop(id);
+                // if a path was being calculated then it sends what it found so far,
+                // otherwise make sure that the latest message the client received from us
+                // was to clear the path
+                if !state.stop_pathfinding_task(&headers, id) {
+                    let _ = tx
+                        .send(SocketEvent::Progress(FullProgressUpdate::clear(id)))
+                        .await;
+                }
+            }
+            ServerboundMessage::Follow(query) => {
+                let positions_rx = followed_paths.start(query.id);
+                let task_tx = tx.clone();
+                state.start_pathfinding_task(&headers, query.id, move |ctx| {
+                    follow::handle_follow_query(task_tx, query, positions_rx, limits, ctx)
+                });
+            }
+            ServerboundMessage::Path(query) => {
+                // this stops the task that was running for the same ID, if there was one
+                let mut task_tx = tx.clone();
+                let task_state = state.clone();
+                state.start_pathfinding_task(&headers, query.id, move |ctx| async move {
+                    handle_get_path_query(&mut task_tx, query, &task_state, limits, &ctx).await;
+                });
+            }
+        }
     }
 
+    // the pathfinding task isn't aborted here, so the client has a chance to
+    // reconnect and resume it. it'll stop by itself once its grace period is over.
     info!("Socket closed!");
-    task.abort();
-    if *is_pathing.lock() {
-        // only abort the task if the pathfinding task was started by this websocket
-        state.stop_pathfinding_task(&headers);
+    if let Some(watching) = watching {
+        watching.abort();
     }
+    task.abort();
 }
 
-async fn send_error(tx: &mut mpsc::Sender<SocketEvent>, error: &str) {
+pub async fn resume_job(state: &AppState, mut tx: mpsc::Sender<SocketEvent>, job_id: &str) {
+    let Some(job) = state.jobs.get(job_id) else {
+        return send_error(
+            &mut tx,
+            SocketError::new(
+                ErrorCode::InvalidRequest,
+                "Unknown job ID, it may have already finished or expired",
+            ),
+        )
+        .await;
+    };
+    info!("Resuming job {job_id}");
+
     let _ = tx
-        .send(SocketEvent::Error {
-            message: error.to_string(),
+        .send(SocketEvent::Job {
+            id: job.query_id,
+            job_id: job.id.clone(),
         })
         .await;
+    job.attach(tx);
 }
 
-async fn handle_socket_message(mut tx: mpsc::Sender<SocketEvent>, msg: ws::Message) {
-    let Ok(msg) = msg.to_text() else {
-        return send_error(&mut tx, "Message must be UTF-8").await;
+/// Start forwarding the updates for someone else's job to this socket.
+async fn watch_job(
+    state: &AppState,
+    mut tx: mpsc::Sender<SocketEvent>,
+    job_id: &str,
+) -> Option<JoinHandle<()>> {
+    let Some(job) = state.jobs.get(job_id) else {
+        send_error(
+            &mut tx,
+            SocketError::new(
+                ErrorCode::InvalidRequest,
+                "Unknown job ID, it may have already finished or expired",
+            ),
+        )
+        .await;
+        return None;
     };
+    info!("Watching job {job_id}");
 
-    // let Ok(msg) = simd_json::from_slice::<ServerboundMessage>(&mut
-    // msg.to_owned().into_bytes()) else {
-    //     return send_error(&mut tx, "Message must be valid query").await;
-    // };
-    let msg = match simd_json::from_slice::<ServerboundMessage>(&mut msg.to_owned().into_bytes()) {
-        Ok(msg) => msg,
-        Err(_) => {
-            return send_error(&mut tx, &format!("Message must be valid query: '{msg}'")).await;
+    let mut events = job.watch();
+    let _ = tx
+        .send(SocketEvent::Job {
+            id: job.query_id,
+            job_id: job.id.clone(),
+        })
+        .await;
+    // don't keep the job alive after it's done
+    let job = Arc::downgrade(&job);
+    Some(tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    if tx.send(event).await.is_err() {
+                        return;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    // we missed part of the path
+                    if let Some(job) = job.upgrade() {
+                        job.request_full_path();
+                    }
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
         }
+    }))
+}
+
+pub async fn send_error(tx: &mut mpsc::Sender<SocketEvent>, error: SocketError) {
+    let _ = tx.send(SocketEvent::Error(error)).await;
+}
+
+/// The error to send for something that went wrong while finding a path.
+pub fn pathfinding_error(err: &eyre::Report) -> SocketError {
+    let code = if err.chain().any(|e| e.is::<reqwest::Error>()) {
+        ErrorCode::UpstreamUnavailable
+    } else if err.chain().any(|e| e.is::<UnknownPano>()) {
+        ErrorCode::InvalidRequest
+    } else {
+        ErrorCode::Internal
     };
+    SocketError::new(code, err.to_string())
+}
 
-    match msg {
-        ServerboundMessage::Path(get_path_query) => {
-            handle_get_path_query(&mut tx, get_path_query).await;
-        }
-        ServerboundMessage::Abort { id } => {
-            // we already implicitly stopped calculating a path, since
-            // start_pathfinding_task is called whenever we receive a websocket
-            // message, and that function makes sure that only one task exists
-            // per IP
-
-            // this is just to make sure that the latest message the client received from us
-            // was to clear the path
-            let _ = tx
-                .send(SocketEvent::Progress(FullProgressUpdate::clear(id)))
-                .await;
-        }
-    }
+pub fn no_nearby_pano_error(loc: Location, fmt: &Formatter) -> SocketError {
+    SocketError::new(
+        ErrorCode::NoNearbyPano,
+        format!("No nearby pano for {loc} (within {})", fmt.distance(2000.)),
+    )
+    .with_location(loc.to_latlng())
 }
 
-async fn handle_get_path_query(tx: &mut mpsc::Sender<SocketEvent>, msg: GetPathQuery) {
-    let start = Location::from_latlng(msg.start);
-    let end = Location::from_latlng(msg.end);
-    let heading = msg.heading;
-    let stops = msg
-        .stops
-        .into_iter()
-        .map(Location::from_latlng)
-        .collect::<Vec<_>>();
+pub fn path_too_long_error(distance: f64, limits: PathLimits, fmt: &Formatter) -> SocketError {
+    SocketError::new(
+        ErrorCode::PathTooLong,
+        format!(
+            "Your path is more than {} long ({}), please segment your path instead.",
+            fmt.distance(limits.max_distance),
+            fmt.distance(distance)
+        ),
+    )
+    .with_limit(limits.max_distance, distance)
+}
 
-    let heuristic_factor = msg
-        .heuristic_factor
-        .clamp(MIN_HEURISTIC_FACTOR, MAX_HEURISTIC_FACTOR);
-    let path_settings = PathSettings {
-        heuristic_factor,
-        no_long_jumps: msg.no_long_jumps,
-        use_option_cache: msg.use_option_cache,
-        forward_penalty_on_intersections: msg.forward_penalty_on_intersections,
-        non_sharp_turn_penalty: msg.non_sharp_turn_penalty,
+pub async fn handle_get_path_query(
+    tx: &mut mpsc::Sender<SocketEvent>,
+    msg: GetPathQuery,
+    state: &AppState,
+    limits: PathLimits,
+    ctx: &TaskContext,
+) {
+    let cancel = &ctx.cancel;
+    let fmt = Formatter::new(msg.units, msg.locale.as_deref());
+
+    let start = Location::from_latlng(msg.start);
+    let end = match query_end(&msg) {
+        Ok(end) => end,
+        Err(err) => return send_error(tx, err).await,
+    };
+    let path_settings = match path_settings_for_query(&msg, limits) {
+        Ok(settings) => PathSettings {
+            cancel: cancel.clone(),
+            ..settings
+        },
+        Err(err) => return send_error(tx, SocketError::new(ErrorCode::InvalidRequest, err)).await,
     };
+    let avoid_areas = path_settings.avoid_areas.clone();
+    let heading = msg.heading;
 
-    if stops.len() > 200 {
-        return send_error(tx, "Too many stops (limit of 200)").await;
+    if msg.stops.len() > 200 {
+        return send_error(
+            tx,
+            SocketError::new(ErrorCode::TooManyStops, "Too many stops (limit of 200)")
+                .with_limit(200., msg.stops.len() as f64),
+        )
+        .await;
     }
 
     // internet roadtrip sometimes has negative headings, just normalize it here
     let heading = (heading + 360.) % 360.;
 
     info!("/path {start} -> {end} heading {heading}");
+    ctx.status.update(|status| {
+        status.kind = "path";
+        status.start = Some(start.to_latlng());
+        status.end = Some(end.to_latlng());
+    });
+
+    let mut stops = msg.stops.clone();
+    if msg.optimize_stop_order && stops.len() > 1 {
+        let locs = stops
+            .iter()
+            .map(|stop| Location::from_latlng(stop.loc()))
+            .collect::<Vec<_>>();
+        let order = stop_order::optimize_stop_order(start, &locs, end);
+        stops = order.into_iter().map(|i| msg.stops[i]).collect();
+    }
 
-    let mut next_stops = stops.clone();
+    // exact stops split the path into segments, and soft stops are waypoints in the
+    // segment that they're in
+    let mut next_stops = Vec::new();
+    let mut segment_waypoints = vec![Vec::new()];
+    for stop in &stops {
+        match *stop {
+            Stop::Exact(loc) => {
+                next_stops.push(Location::from_latlng(loc));
+                segment_waypoints.push(Vec::new());
+            }
+            Stop::Soft { loc, radius } => {
+                segment_waypoints.last_mut().unwrap().push(Waypoint {
+                    loc: Location::from_latlng(loc),
+                    radius: radius.clamp(MIN_SOFT_STOP_RADIUS, MAX_SOFT_STOP_RADIUS),
+                });
+            }
+        }
+    }
     next_stops.push(end);
 
     // validate all the stops to make sure there's panos there
-    for stop in &mut next_stops {
-        let snap_to = snap_end_point_to_pano(*stop).await;
+    let stop_count = next_stops.len();
+    for (i, stop) in next_stops.iter_mut().enumerate() {
+        if i == stop_count - 1 && path_settings.goal_pano.is_some() {
+            // we already know exactly which pano the path ends at
+            continue;
+        }
+
+        let snap_to = snap_end_point_to_pano(&DB, *stop).await;
         let Some(snap_to) = snap_to else {
-            return send_error(tx, &format!("No nearby pano for {stop}")).await;
+            return send_error(tx, no_nearby_pano_error(*stop, &fmt)).await;
         };
         *stop = snap_to.loc;
+
+        if avoid_areas.iter().any(|area| area.contains(*stop)) {
+            return send_error(
+                tx,
+                SocketError::new(
+                    ErrorCode::InvalidRequest,
+                    format!("{stop} is inside of an avoided area"),
+                )
+                .with_location(stop.to_latlng()),
+            )
+            .await;
+        }
     }
 
     // validate total distance
     let mut cur = start;
     let mut total_distance = 0.;
-    for &stop in &next_stops {
-        let distance = math::distance(cur, stop);
-        total_distance += distance;
+    for (&stop, waypoints) in next_stops.iter().zip(&segment_waypoints) {
+        for waypoint in waypoints {
+            total_distance += math::distance(cur, waypoint.loc);
+            cur = waypoint.loc;
+        }
+        total_distance += math::distance(cur, stop);
         cur = stop;
     }
-    if total_distance > 1_000_000. {
-        return send_error(
-            tx,
-            &format!(
-                "Your path is more than 1000km long ({}km), please segment your path instead.",
-                (total_distance / 1000.) as u32
-            ),
-        )
-        .await;
+    if total_distance > limits.max_distance {
+        return send_error(tx, path_too_long_error(total_distance, limits, &fmt)).await;
     }
 
-    let mut progress_updates = Vec::<Arc<Mutex<ProgressUpdate>>>::new();
+    if let Err(err) = ratelimit::check_quota(&DB, &ctx.status) {
+        return send_error(tx, err).await;
+    }
 
-    let mut cur = start;
-    let mut previous_stop = None;
-    let mut task_set = JoinSet::new();
-    for (i, stop) in next_stops.iter().enumerate() {
-        let progress = ProgressUpdate::default();
-        let progress_update = Arc::new(Mutex::new(progress));
-        progress_updates.push(progress_update.clone());
-        let assumed_heading = if i == 0 {
-            heading
-        } else if let Some(previous_stop) = previous_stop {
-            math::calculate_heading(previous_stop, cur)
-        } else {
-            error!("No previous stop!");
-            heading
-        };
+    let job_guard = state.jobs.create(msg.id, tx.clone());
+    let job = job_guard.job.clone();
+    if !job
+        .send(SocketEvent::Job {
+            id: msg.id,
+            job_id: job.id.clone(),
+        })
+        .await
+    {
+        return;
+    }
 
-        info!("pathing from {cur} to {stop} with heading {assumed_heading}",);
+    let search_slot = ctx
+        .wait_for_search_slot(|position| {
+            let job = &job;
+            async move {
+                job.send(SocketEvent::Queued {
+                    id: msg.id,
+                    position,
+                })
+                .await;
+            }
+        })
+        .await;
+    let Some(search_slot) = search_slot else {
+        // we never sent any progress, so make sure the client isn't stuck waiting for it
+        job.send(SocketEvent::Progress(FullProgressUpdate::clear(msg.id)))
+            .await;
+        return;
+    };
 
-        // only makes sense for the first stop in the path
-        let start_pano_id = if i == 0 { msg.start_pano.clone() } else { None };
+    let progress_updates = next_stops
+        .iter()
+        .map(|_| Arc::new(Mutex::new(ProgressUpdate::default())))
+        .collect::<Vec<_>>();
 
-        let stop = *stop;
+    // the segments are found one after another, since each one starts with the
+    // heading that the previous one actually ended with
+    let mut task = Some(tokio::spawn({
+        let progress_updates = progress_updates.clone();
+        let segments = next_stops
+            .iter()
+            .copied()
+            .zip(segment_waypoints)
+            .collect::<Vec<_>>();
         let path_settings = path_settings.clone();
-        task_set.spawn(async move {
-            let result = astar::astar(
-                cur,
+        let start_pano_id = msg.start_pano.clone();
+        let job = job.clone();
+        async move {
+            let _search_slot = search_slot;
+            find_segments(
+                start,
                 start_pano_id,
-                assumed_heading,
-                stop,
-                progress_update,
-                path_settings,
+                heading,
+                &segments,
+                &progress_updates,
+                &path_settings,
+                &job,
             )
-            .await;
-            if let Err(err) = result {
-                error!("{err}");
-            }
-        });
-
-        previous_stop = Some(cur);
-        cur = stop;
-    }
+            .await
+        }
+    }));
 
-    // check for updates every second
+    let progress_interval = msg
+        .progress_interval_ms
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_PROGRESS_INTERVAL)
+        .clamp(MIN_PROGRESS_INTERVAL, MAX_PROGRESS_INTERVAL);
+    let simplify_tolerance = msg.simplify_tolerance_meters.filter(|t| *t > 0.);
 
     let start = Instant::now();
+    let truncated_tiles = path_settings.truncated_tiles.clone();
 
     let mut last_combined_best_path = vec![];
+    let mut last_combined_best_path_costs = vec![];
     let mut last_combined_current_path = vec![];
+    let mut finished = false;
+    let mut result = None;
 
     loop {
-        sleep(Duration::from_millis(100)).await;
+        wait_for_next_update(progress_interval, task.as_ref()).await;
+
+        if job.take_resumed() {
+            // the new socket doesn't have any of the path yet
+            last_combined_best_path.clear();
+            last_combined_best_path_costs.clear();
+            last_combined_current_path.clear();
+        }
 
         let mut reached_unfinished_path = false;
 
@@ -311,36 +521,127 @@ async fn handle_get_path_query(tx: &mut mpsc::Sender<SocketEvent>, msg: GetPathQ
         let mut highest_estimated_seconds_remaining = 0.0_f64;
         let mut best_path_cost = 0 as astar::Cost;
         let mut nodes_considered = 0_usize;
+        let mut memory_pressure = 0.0_f64;
+        let mut stored_nodes = 0_usize;
         let mut combined_best_path = Vec::<[f32; 2]>::new();
+        let mut combined_best_path_costs = Vec::<astar::Cost>::new();
         let mut combined_current_path = Vec::<[f32; 2]>::new();
-        for progress_update in &progress_updates {
+        let mut segments = Vec::with_capacity(progress_updates.len());
+        for (index, progress_update) in progress_updates.iter().enumerate() {
             let progress = progress_update.lock();
 
             lowest_percent_done = lowest_percent_done.min(progress.percent_done);
             highest_estimated_seconds_remaining =
                 highest_estimated_seconds_remaining.max(progress.estimated_seconds_remaining);
             nodes_considered += progress.nodes_considered;
+            memory_pressure = memory_pressure.max(progress.memory_pressure);
+            stored_nodes += progress.stored_nodes;
 
+            let mut best_path_range = None;
             if !reached_unfinished_path {
+                // the costs in each segment start from 0
+                let cost_offset = best_path_cost;
                 best_path_cost += progress.best_path_cost;
-                combined_best_path.extend(progress.best_path.iter());
-                combined_current_path.extend(progress.current_path.iter());
+                let range_start = combined_best_path.len();
+                // each segment is simplified by itself so the ranges still line up
+                match simplify_tolerance {
+                    Some(tolerance) => {
+                        let kept = math::simplify_path_indexes(&progress.best_path, tolerance);
+                        combined_best_path.extend(kept.iter().map(|&i| progress.best_path[i]));
+                        combined_best_path_costs.extend(kept.iter().map(|&i| {
+                            cost_offset
+                                + progress.best_path_costs.get(i).copied().unwrap_or_default()
+                        }));
+                        if msg.include_current_path {
+                            combined_current_path
+                                .extend(math::simplify_path(&progress.current_path, tolerance));
+                        }
+                    }
+                    None => {
+                        combined_best_path.extend(progress.best_path.iter());
+                        combined_best_path_costs.extend(
+                            progress
+                                .best_path_costs
+                                .iter()
+                                .map(|cost| cost_offset + cost),
+                        );
+                        if msg.include_current_path {
+                            combined_current_path.extend(progress.current_path.iter());
+                        }
+                    }
+                }
+                best_path_range = Some([range_start, combined_best_path.len()]);
             }
+            segments.push(SegmentProgress {
+                index,
+                percent_done: progress.percent_done,
+                estimated_seconds_remaining: progress.estimated_seconds_remaining,
+                best_path_cost: progress.best_path_cost,
+                nodes_considered: progress.nodes_considered,
+                best_path_range,
+            });
 
             if progress.percent_done < 1. {
                 reached_unfinished_path = true;
             }
         }
 
-        let (best_path_keep_prefix_length, best_path_append) =
-            find_path_prefix_and_append(&last_combined_best_path, &combined_best_path);
+        ctx.status.update(|status| {
+            status.percent_done = lowest_percent_done;
+            status.nodes_considered = nodes_considered;
+            status.stored_nodes = stored_nodes;
+        });
+
+        let BestPathDiff {
+            best_path_keep_prefix_length,
+            best_path_append,
+            best_path_costs_append,
+        } = diff_best_path(
+            &last_combined_best_path,
+            &last_combined_best_path_costs,
+            &combined_best_path,
+            &combined_best_path_costs,
+        );
         let (current_path_keep_prefix_length, current_path_append) =
             find_path_prefix_and_append(&last_combined_current_path, &combined_current_path);
 
+        let summary = if lowest_percent_done == 1. {
+            // measured on the full path, since simplifying it makes it shorter
+            let length = progress_updates
+                .iter()
+                .map(|progress| math::path_length(&progress.lock().best_path))
+                .sum();
+            Some(fmt.path_summary(length, best_path_cost as f64))
+        } else {
+            None
+        };
+
         last_combined_best_path = combined_best_path;
+        last_combined_best_path_costs = combined_best_path_costs;
         last_combined_current_path = combined_current_path;
 
-        if tx
+        if lowest_percent_done == 1. && !finished {
+            finished = true;
+            // the tasks are done (or about to be), and the path should be saved before
+            // the client is told that it's done so it can be downloaded immediately
+            let routes = match task.take() {
+                Some(task) => task.await.ok().flatten(),
+                None => None,
+            };
+            if let Some((nodes, partial)) = routes.map(combine_routes) {
+                let mut path_result =
+                    path_result(&DB, msg.id, &nodes, path_settings.use_option_cache).await;
+                path_result.partial = partial;
+                if msg.decision_points_only {
+                    path_result = only_decision_points(path_result);
+                }
+                path_result.route_id = saved_routes::save_route(&msg, &path_result).await;
+                result = Some(path_result);
+                save_completed_path(&job.id, nodes).await;
+            }
+        }
+
+        let delivered = job
             .send(SocketEvent::Progress(FullProgressUpdate {
                 id: msg.id,
                 percent_done: lowest_percent_done,
@@ -348,17 +649,34 @@ async fn handle_get_path_query(tx: &mut mpsc::Sender<SocketEvent>, msg: GetPathQ
                 best_path_cost,
                 nodes_considered,
                 elapsed_seconds: start.elapsed().as_secs_f64(),
+                memory_pressure,
                 best_path_keep_prefix_length,
                 best_path_append,
+                best_path_costs_append,
                 current_path_keep_prefix_length,
                 current_path_append,
+                summary,
+                segments,
+                warnings: truncated_tile_warning(&truncated_tiles),
             }))
-            .await
-            .is_err()
-        {
-            debug!("Failed to send progress update, aborting pathfinding.");
-            task_set.abort_all();
-            return;
+            .await;
+        if !delivered {
+            // make sure the full path is sent if the client resumes
+            last_combined_best_path.clear();
+            last_combined_best_path_costs.clear();
+            last_combined_current_path.clear();
+
+            if job.is_expired() {
+                if finished {
+                    return;
+                }
+                if !cancel.is_cancelled() {
+                    // the partial path is still saved, so it can be downloaded with the job ID
+                    debug!("Job {} expired, cancelling pathfinding.", job.id);
+                    cancel.cancel();
+                }
+            }
+            continue;
         }
 
         if lowest_percent_done == 1. {
@@ -367,40 +685,417 @@ async fn handle_get_path_query(tx: &mut mpsc::Sender<SocketEvent>, msg: GetPathQ
                 best_path_cost / 3600.
             );
 
+            if let Some(result) = &result
+                && !job.send(SocketEvent::Result(result.clone())).await
+            {
+                continue;
+            }
+
             break;
         }
     }
 
-    info!("Pathfinding complete! waiting for tasks to finish");
-    task_set.join_all().await;
     info!("Pathfinding complete!");
 }
 
-fn find_path_prefix_and_append(
-    old_path: &[[f32; 2]],
-    new_path: &[[f32; 2]],
-) -> (usize, Box<[[f32; 2]]>) {
-    let mut prefix_len = 0;
+/// A warning if the search had to download tiles with some of their panos
+/// missing, see [`PathSettings::truncated_tiles`].
+fn truncated_tile_warning(truncated_tiles: &AtomicU64) -> Vec<String> {
+    let truncated = truncated_tiles.load(Ordering::Relaxed);
+    if truncated == 0 {
+        return Vec::new();
+    }
+    vec![format!(
+        "{truncated} area(s) had too many panos to download them all, so the path might miss some roads"
+    )]
+}
 
-    for i in 0..old_path.len().min(new_path.len()) {
-        if old_path[i] != new_path[i] {
-            break;
+/// Sleep until the next progress update should be sent. Long intervals are cut
+/// short when the search finishes, so that the result isn't delayed by them.
+async fn wait_for_next_update<T>(interval: Duration, task: Option<&JoinHandle<T>>) {
+    let deadline = Instant::now() + interval;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return;
+        }
+        sleep(remaining.min(DEFAULT_PROGRESS_INTERVAL)).await;
+        if task.is_some_and(|task| task.is_finished()) {
+            return;
         }
-        prefix_len += 1;
     }
+}
+
+/// The end of the path, which defaults to the game's current terminus.
+pub fn query_end(msg: &GetPathQuery) -> Result<Location, SocketError> {
+    match (msg.end, &msg.end_pano) {
+        (Some(end), _) => Ok(Location::from_latlng(end)),
+        (None, Some(end_pano)) => DB
+            .lookup_pano_id(end_pano)
+            .and_then(|pano_id| DB.lookup_getmetadata_location(&pano_id))
+            .ok_or_else(|| {
+                SocketError::new(
+                    ErrorCode::InvalidRequest,
+                    "The end pano isn't cached, so its location has to be given with end",
+                )
+            }),
+        (None, None) => roadtrip_api::current_terminus()
+            .map(|terminus| terminus.loc())
+            .ok_or_else(|| {
+                SocketError::new(
+                    ErrorCode::UpstreamUnavailable,
+                    "No end was given and the game hasn't announced any stops",
+                )
+            }),
+    }
+}
+
+pub fn path_settings_for_query(
+    msg: &GetPathQuery,
+    limits: PathLimits,
+) -> Result<PathSettings, &'static str> {
+    if msg.settings.avoid.len() > 50 || msg.settings.avoid.iter().any(|area| area.len() > 1000) {
+        return Err("Too many avoided areas (limit of 50, with up to 1000 points each)");
+    }
+    let avoid_areas = msg
+        .settings
+        .avoid
+        .iter()
+        .filter(|area| area.len() >= 3)
+        .map(|area| Polygon::new(area.iter().copied().map(Location::from_latlng).collect()))
+        .collect::<Arc<[_]>>();
+
+    if msg.settings.exclude_panos.len() > MAX_EXCLUDED_PANOS {
+        return Err("Too many excluded panos (limit of 1000)");
+    }
+    // panos that we've never seen can't be in the path anyways, so they don't
+    // need IDs
+    let txn = DB.read_txn();
+    let exclude_panos = msg
+        .settings
+        .exclude_panos
+        .iter()
+        .filter_map(|pano_id| DB.lookup_pano_id_with_txn(&txn, pano_id))
+        .collect::<FxHashSet<_>>();
+    let goal_pano = msg
+        .end_pano
+        .as_deref()
+        .and_then(|pano_id| DB.lookup_pano_id_with_txn(&txn, pano_id));
+    txn.commit().unwrap();
+
+    let heuristic_factor = msg
+        .settings
+        .heuristic_factor
+        .clamp(MIN_HEURISTIC_FACTOR, MAX_HEURISTIC_FACTOR);
+    Ok(PathSettings {
+        heuristic_factor,
+        max_jump_meters: msg
+            .settings
+            .max_jump_meters
+            .filter(|m| *m > 0.)
+            .or(msg.settings.no_long_jumps.then_some(NO_LONG_JUMPS_METERS)),
+        use_option_cache: msg.settings.use_option_cache,
+        forward_penalty_on_intersections: msg.settings.forward_penalty_on_intersections,
+        non_sharp_turn_penalty: msg.settings.non_sharp_turn_penalty,
+        intersection_uncertainty: msg.settings.intersection_uncertainty.clamp(0., 1.),
+        max_nodes: limits.max_nodes,
+        node_budget: limits.node_budget,
+        timeout: msg
+            .timeout_seconds
+            .filter(|t| *t > 0.)
+            .map(|t| Duration::from_secs_f64(t.min(MAX_TIMEOUT_SECONDS))),
+        avoid_areas,
+        waypoints: Arc::new([]),
+        exclude_panos: Arc::new(exclude_panos),
+        anytime: msg.anytime,
+        corridor_width: msg.settings.corridor_width_meters.filter(|w| *w > 0.),
+        goal_pano,
+        heading_bucket_size: msg
+            .settings
+            .heading_bucket_degrees
+            .filter(|b| *b >= 0.1 && *b <= 90.),
+        min_capture_year: msg.settings.min_capture_year,
+        prune_dead_ends: CONFIG.prune_dead_ends,
+        use_baked_graph: msg.settings.use_baked_graph,
+        use_landmarks: CONFIG.use_landmarks,
+        photospheres: match (
+            msg.settings.avoid_photospheres,
+            msg.settings.photosphere_penalty,
+        ) {
+            (false, _) => PhotosphereAvoidance::Allow,
+            (true, Some(penalty)) if penalty.is_finite() => {
+                PhotosphereAvoidance::Penalty(penalty.max(0.))
+            }
+            (true, _) => PhotosphereAvoidance::Skip,
+        },
+        cancel: CancellationToken::new(),
+        cost_model: None,
+        truncated_tiles: Arc::default(),
+    })
+}
+
+/// Find the route for every segment (its stop and the soft stops along the
+/// way) in order, and whether each one is partial. Returns `None` if any of the
+/// segments failed.
+///
+/// Finding stops after the first partial segment, since the segments after it
+/// wouldn't start where it ends.
+async fn find_segments(
+    start: Location,
+    start_pano_id: Option<String>,
+    heading: f32,
+    segments: &[(Location, Vec<Waypoint>)],
+    progress_updates: &[Arc<Mutex<ProgressUpdate>>],
+    path_settings: &PathSettings,
+    job: &Job,
+) -> Option<Vec<(Vec<RouteNode>, bool)>> {
+    let mut routes = Vec::<(Vec<RouteNode>, bool)>::new();
+    let mut cur = start;
+    let mut previous_stop = start;
+    // max_nodes is for the whole route, so each segment only gets what the
+    // previous ones left over
+    let mut nodes_considered = 0_usize;
+    for (i, (stop, waypoints)) in segments.iter().enumerate() {
+        let stop = *stop;
+        let assumed_heading = match routes.last() {
+            None => heading,
+            // the route might be too short to have a heading
+            Some((route, _)) => segment_end_heading(route)
+                .unwrap_or_else(|| math::calculate_heading(previous_stop, cur)),
+        };
+
+        info!("pathing from {cur} to {stop} with heading {assumed_heading}",);
+
+        let mut path_settings = path_settings.clone();
+        if i != segments.len() - 1 {
+            // the end pano is only for the last segment
+            path_settings.goal_pano = None;
+        }
+        path_settings.waypoints = waypoints.clone().into();
+        path_settings.max_nodes = path_settings
+            .max_nodes
+            .map(|max_nodes| max_nodes.saturating_sub(nodes_considered));
+        let result = astar::astar(
+            &DB,
+            cur,
+            // only makes sense for the first stop in the path
+            if i == 0 { start_pano_id.clone() } else { None },
+            assumed_heading,
+            stop,
+            progress_updates[i].clone(),
+            path_settings,
+        )
+        .await;
+        nodes_considered += progress_updates[i].lock().nodes_considered;
+        match result {
+            Ok(route) => {
+                previous_stop = cur;
+                cur = route.last().map(|n| n.pano.loc).unwrap_or(stop);
+                routes.push((route, false));
+            }
+            Err(err) => match err.downcast::<Incomplete>() {
+                Ok(incomplete) => {
+                    info!("Segment {i} is partial: {incomplete}");
+                    routes.push((incomplete.partial_path, true));
+                    // the rest of the segments will never be found
+                    for progress_update in &progress_updates[i + 1..] {
+                        progress_update.lock().percent_done = 1.;
+                    }
+                    return Some(routes);
+                }
+                Err(err) => {
+                    error!("{err}");
+                    job.send(SocketEvent::Error(pathfinding_error(&err))).await;
+                    // so the job finishes instead of waiting for segments that won't be found
+                    for progress_update in &progress_updates[i..] {
+                        progress_update.lock().percent_done = 1.;
+                    }
+                    return None;
+                }
+            },
+        }
+    }
+    Some(routes)
+}
+
+/// The heading that the car has at the end of a route, based on its last two
+/// nodes.
+fn segment_end_heading(route: &[RouteNode]) -> Option<f32> {
+    let [.., second_last, last] = route else {
+        return None;
+    };
+    Some(math::calculate_heading(second_last.pano.loc, last.pano.loc))
+}
+
+/// Join the routes for every segment into one path, with costs that are
+/// cumulative over the whole path, and whether it's partial.
+///
+/// The path stops at the first partial segment, since the segments after it
+/// don't start where it ends.
+fn combine_routes(routes: Vec<(Vec<RouteNode>, bool)>) -> (Vec<RouteNode>, bool) {
+    let mut nodes = Vec::new();
+    let mut segment_start_cost = 0 as astar::Cost;
+    for (route, partial) in routes {
+        let segment_cost = route.last().map(|n| n.cost).unwrap_or_default();
+        nodes.extend(route.into_iter().map(|n| RouteNode {
+            cost: segment_start_cost + n.cost,
+            ..n
+        }));
+        segment_start_cost += segment_cost;
+        if partial {
+            return (nodes, true);
+        }
+    }
+    (nodes, false)
+}
+
+/// Save the full path so it can be downloaded later.
+async fn save_completed_path(job_id: &str, nodes: Vec<RouteNode>) {
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let path = SavedPath {
+        created_at,
+        nodes: nodes.into(),
+    };
+    let id = job_id.to_owned();
+    match tokio::task::spawn_blocking(move || DB.save_path(&id, &path)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => error!("Failed to save path for job {job_id}: {err}"),
+        Err(err) => error!("Saving the path for job {job_id} panicked: {err}"),
+    }
+}
+
+pub async fn path_result(
+    db: &Db,
+    id: u32,
+    nodes: &[RouteNode],
+    use_option_cache: bool,
+) -> PathResult {
+    let instructions = instructions::vote_instructions(db, nodes, use_option_cache)
+        .await
+        .unwrap_or_else(|err| {
+            error!("Failed to generate vote instructions: {err}");
+            Vec::new()
+        });
+    let nodes = nodes
+        .iter()
+        .map(|node| PathResultNode {
+            pano_id: db.lookup_pano_id_string(node.pano.id).unwrap_or_default(),
+            loc: [node.pano.loc.lat_deg(), node.pano.loc.lng_deg()],
+            heading: node.heading,
+            cost: node.cost,
+            option_count: node.option_count,
+        })
+        .collect();
+    PathResult {
+        id,
+        nodes,
+        instructions,
+        partial: false,
+        route_id: None,
+    }
+}
+
+/// Remove the nodes where the car only has one option, except for the start and
+/// end, and update the instructions to point at the nodes that are left.
+pub fn only_decision_points(mut result: PathResult) -> PathResult {
+    let last_index = result.nodes.len().saturating_sub(1);
+    let mut new_indexes = vec![None; result.nodes.len()];
+    let mut kept = 0;
+    for (i, node) in result.nodes.iter().enumerate() {
+        if i == 0 || i == last_index || node.option_count != 1 {
+            new_indexes[i] = Some(kept);
+            kept += 1;
+        }
+    }
+
+    let mut i = 0;
+    result.nodes.retain(|_| {
+        i += 1;
+        new_indexes[i - 1].is_some()
+    });
+    result.instructions.retain_mut(|instruction| {
+        match new_indexes.get(instruction.node_index).copied().flatten() {
+            Some(new_index) => {
+                instruction.node_index = new_index;
+                true
+            }
+            None => false,
+        }
+    });
+    result
+}
+
+pub fn find_path_prefix_and_append(
+    old_path: &[[f32; 2]],
+    new_path: &[[f32; 2]],
+) -> (usize, Box<[[f32; 2]]>) {
+    let prefix_len = common_prefix_length(old_path, new_path);
 
     let to_append = new_path[prefix_len..].to_vec().into_boxed_slice();
 
     (prefix_len, to_append)
 }
 
+/// What changed in the best path, see [`diff_best_path`].
+pub struct BestPathDiff {
+    pub best_path_keep_prefix_length: usize,
+    pub best_path_append: Box<[[f32; 2]]>,
+    pub best_path_costs_append: Box<[astar::Cost]>,
+}
+
+/// Like [`find_path_prefix_and_append`], but for a path and the cost at each of
+/// its points, which share the same prefix.
+pub fn diff_best_path(
+    old_path: &[[f32; 2]],
+    old_costs: &[astar::Cost],
+    new_path: &[[f32; 2]],
+    new_costs: &[astar::Cost],
+) -> BestPathDiff {
+    let prefix_len =
+        common_prefix_length(old_path, new_path).min(common_prefix_length(old_costs, new_costs));
+    BestPathDiff {
+        best_path_keep_prefix_length: prefix_len,
+        best_path_append: new_path[prefix_len..].into(),
+        best_path_costs_append: new_costs.get(prefix_len..).unwrap_or_default().into(),
+    }
+}
+
+fn common_prefix_length<T: PartialEq>(a: &[T], b: &[T]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+/// Download a completed path as a GPX track. The ID is the job ID that was
+/// sent when the path started.
+pub async fn get_path_gpx(Path(job_id): Path<String>) -> Response {
+    let Some(path) = DB.get_saved_path(&job_id) else {
+        return (StatusCode::NOT_FOUND, "unknown path\n").into_response();
+    };
+
+    (
+        [
+            (header::CONTENT_TYPE, "application/gpx+xml".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"path-{job_id}.gpx\""),
+            ),
+        ],
+        gpx::to_gpx(&path, &format!("Path {job_id}")),
+    )
+        .into_response()
+}
+
 /// Finds the closest non-photosphere pano near the given coordinates, intended
 /// to be used for determining the end pano in a path.
-pub async fn snap_end_point_to_pano(loc: Location) -> Option<Pano> {
+pub async fn snap_end_point_to_pano(db: &Db, loc: Location) -> Option<Pano> {
     // check at different distances to avoid having to download every nearby tile if
     // there's already a pano immediately nearby
     for distance in [100., 500., 1000., 2000.] {
-        let nearest_pano = get_nearest_pano(loc, distance).await.unwrap_or_default();
+        let nearest_pano = get_nearest_pano(db, loc, distance)
+            .await
+            .unwrap_or_default();
         if let Some(p) = nearest_pano {
             return Some(p);
         }
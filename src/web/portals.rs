@@ -0,0 +1,113 @@
+//! `GET /portals`, the unusually long Streetview links in an area.
+
+use axum::{
+    Json,
+    extract::Query,
+    response::{IntoResponse, Response},
+};
+use http::StatusCode;
+use serde::Deserialize;
+use simd_json::json;
+use tracing::{error, info};
+
+use crate::{
+    astar::NO_LONG_JUMPS_METERS,
+    db::DB,
+    portals::{self, PortalScan},
+    streetview::prefetch::{BoundingBox, MAX_PREFETCH_TILES},
+};
+
+const MAX_LIMIT: usize = 10_000;
+
+#[derive(Deserialize)]
+pub struct PortalsQuery {
+    /// `min_lng,min_lat,max_lng,max_lat`, like a GeoJSON bbox.
+    bbox: String,
+    min_meters: Option<f64>,
+    /// Defaults to 1000.
+    limit: Option<usize>,
+}
+
+/// `GET /portals?bbox=min_lng,min_lat,max_lng,max_lat`, the cached links that
+/// start in the bounding box and are at least `min_meters` long, as a GeoJSON
+/// `FeatureCollection` of `LineString`s from each pano to the one it links to.
+pub async fn get_portals(Query(query): Query<PortalsQuery>) -> Response {
+    let Some(bbox) = parse_bbox(&query.bbox) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            "bbox must be min_lng,min_lat,max_lng,max_lat\n",
+        )
+            .into_response();
+    };
+    let tile_count = bbox.tile_count();
+    if tile_count > MAX_PREFETCH_TILES {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("bbox has too many tiles ({tile_count}, limit is {MAX_PREFETCH_TILES})\n"),
+        )
+            .into_response();
+    }
+    // by default, portals are the links that no_long_jumps would skip
+    let min_meters = query.min_meters.unwrap_or(NO_LONG_JUMPS_METERS).max(0.);
+    let limit = query.limit.unwrap_or(1000).min(MAX_LIMIT);
+
+    info!("/portals in {bbox:?}");
+
+    let scan =
+        tokio::task::spawn_blocking(move || portals::find_portals(&DB, &bbox, min_meters, limit))
+            .await;
+    match scan {
+        Ok(scan) => Json(portals_to_geojson(&scan)).into_response(),
+        Err(err) => {
+            error!("Portal scan failed: {err}");
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("{err}\n")).into_response()
+        }
+    }
+}
+
+fn portals_to_geojson(scan: &PortalScan) -> simd_json::OwnedValue {
+    let features = scan
+        .portals
+        .iter()
+        .map(|portal| {
+            json!({
+                "type": "Feature",
+                "geometry": {
+                    "type": "LineString",
+                    "coordinates": [
+                        [portal.from_loc.lng_deg(), portal.from_loc.lat_deg()],
+                        [portal.to_loc.lng_deg(), portal.to_loc.lat_deg()],
+                    ],
+                },
+                "properties": {
+                    "from": DB.lookup_pano_id_string(portal.from),
+                    "to": DB.lookup_pano_id_string(portal.to),
+                    "heading": portal.heading,
+                    "distance": portal.distance,
+                },
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "type": "FeatureCollection",
+        "features": features,
+        "panos_scanned": scan.panos_scanned,
+        "truncated": scan.truncated,
+    })
+}
+
+fn parse_bbox(s: &str) -> Option<BoundingBox> {
+    let parts = s
+        .split(',')
+        .map(|part| part.trim().parse::<f64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let [lng1, lat1, lng2, lat2] = parts[..] else {
+        return None;
+    };
+    Some(BoundingBox {
+        min_lat: lat1.min(lat2),
+        min_lng: lng1.min(lng2),
+        max_lat: lat1.max(lat2),
+        max_lng: lng1.max(lng2),
+    })
+}
@@ -1,40 +1,173 @@
 use std::{
     collections::HashMap,
-    net::{IpAddr, Ipv4Addr},
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
     sync::Arc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
 use http::HeaderMap;
 use parking_lot::Mutex;
-use tokio::task::JoinHandle;
-use tracing::{info, warn};
+use pathfinder_protocol::{ErrorCode, QuotaStatus, SocketError};
+use tracing::{error, info, warn};
+
+use crate::{
+    db::Db,
+    web::{
+        config::CONFIG,
+        job_manager::{JobManager, TaskContext, TaskStatus},
+        jobs::Jobs,
+        rest::RestJobs,
+    },
+};
+
+pub const SECONDS_PER_DAY: u64 = 60 * 60 * 24;
+
+/// The number of IPs that the [`RequestLimiter`] remembers before it forgets
+/// the ones that haven't made requests recently.
+const MAX_REQUEST_BUCKETS: usize = 10_000;
 
 #[derive(Clone, Default)]
 pub struct AppState {
-    pathfinding_tasks: Arc<Mutex<HashMap<RatelimitIp, JoinHandle<()>>>>,
+    pub tasks: JobManager,
+    pub jobs: Jobs,
+    pub rest_jobs: RestJobs,
+    pub requests: RequestLimiter,
 }
 
 impl AppState {
-    pub fn start_pathfinding_task(&self, headers: &HeaderMap, join_handle: JoinHandle<()>) {
-        let mut pathfinding_ips = self.pathfinding_tasks.lock();
-        let ip = ip_from_headers(headers);
-        // only one task per RatelimitIp is allowed, so abort the existing one
-        if let Some(existing_handle) = pathfinding_ips.get(&ip) {
-            existing_handle.abort();
+    /// Start a pathfinding task for the query ID and the IP that the request
+    /// came from, see [`JobManager::start`].
+    pub fn start_pathfinding_task<F, Fut>(&self, headers: &HeaderMap, query_id: u32, task: F)
+    where
+        F: FnOnce(TaskContext) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        self.tasks.start(ip_from_headers(headers), query_id, task);
+    }
+
+    /// Stop the task for the query ID, returning whether it was running.
+    pub fn stop_pathfinding_task(&self, headers: &HeaderMap, query_id: u32) -> bool {
+        self.tasks
+            .stop(RatelimitIp::from(ip_from_headers(headers)), query_id)
+    }
+
+    /// Count a request from the IP that the request came from, see
+    /// [`RequestLimiter::check`].
+    pub fn check_request_limit(&self, headers: &HeaderMap) -> Result<(), SocketError> {
+        self.requests.check(ip_from_headers(headers))
+    }
+}
+
+/// A token bucket per IP for endpoints that are cheap to call but expensive
+/// for us to answer.
+#[derive(Clone, Default)]
+pub struct RequestLimiter {
+    /// The number of requests each IP has left, and when that was last updated.
+    buckets: Arc<Mutex<HashMap<RatelimitIp, (f64, Instant)>>>,
+}
+impl RequestLimiter {
+    /// Returns an error if the IP made too many requests recently, otherwise
+    /// counts the request.
+    pub fn check(&self, ip: IpAddr) -> Result<(), SocketError> {
+        if CONFIG.is_exempt(ip) {
+            return Ok(());
         }
-        pathfinding_ips.insert(ip, join_handle);
+        self.check_at(
+            RatelimitIp::from(ip),
+            Instant::now(),
+            CONFIG.requests_per_minute,
+        )
     }
 
-    pub fn stop_pathfinding_task(&self, headers: &HeaderMap) {
-        let mut pathfinding_ips = self.pathfinding_tasks.lock();
-        let ip = ip_from_headers(headers);
-        if let Some(existing_handle) = pathfinding_ips.remove(&ip) {
-            existing_handle.abort();
+    fn check_at(&self, ip: RatelimitIp, now: Instant, per_minute: u32) -> Result<(), SocketError> {
+        let capacity = per_minute as f64;
+        let refill = |(tokens, updated): (f64, Instant)| {
+            (tokens + now.duration_since(updated).as_secs_f64() * capacity / 60.).min(capacity)
+        };
+
+        let mut buckets = self.buckets.lock();
+        if buckets.len() >= MAX_REQUEST_BUCKETS && !buckets.contains_key(&ip) {
+            buckets.retain(|_, bucket| refill(*bucket) < capacity);
         }
+        let bucket = buckets.entry(ip).or_insert((capacity, now));
+        let tokens = refill(*bucket);
+        if tokens < 1. {
+            *bucket = (tokens, now);
+            return Err(SocketError::new(
+                ErrorCode::Ratelimited,
+                "You're making too many requests",
+            )
+            .with_retry_after((1. - tokens) * 60. / capacity));
+        }
+        *bucket = (tokens - 1., now);
+        Ok(())
+    }
+}
+
+/// How much of its daily quota an IP has used, as stored in the database.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QuotaUsage {
+    /// Days since the Unix epoch.
+    pub day: u32,
+    pub nodes: u64,
+}
+
+fn now_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn quota_status(limit: u64, usage: QuotaUsage) -> QuotaStatus {
+    let now = now_seconds();
+    let today = (now / SECONDS_PER_DAY) as u32;
+    QuotaStatus {
+        used_nodes: if usage.day == today { usage.nodes } else { 0 },
+        limit_nodes: limit,
+        resets_in_seconds: SECONDS_PER_DAY - now % SECONDS_PER_DAY,
     }
 }
 
-fn ip_from_headers(headers: &HeaderMap) -> RatelimitIp {
+/// Returns an error if the task's IP already used up its quota for today.
+pub fn check_quota(db: &Db, task: &TaskStatus) -> Result<(), SocketError> {
+    let Some(limit) = CONFIG.daily_node_quota else {
+        return Ok(());
+    };
+    if task.exempt {
+        return Ok(());
+    }
+
+    let status = quota_status(limit, db.get_quota_usage(task.ip));
+    if status.used_nodes < limit {
+        return Ok(());
+    }
+    Err(SocketError::new(
+        ErrorCode::Ratelimited,
+        "You've used up your pathfinding quota for today",
+    )
+    .with_retry_after(status.resets_in_seconds as f64)
+    .with_quota(status))
+}
+
+/// Count the nodes that the task considered since this was last called towards
+/// its IP's quota.
+pub fn record_quota_usage(db: &Db, task: &TaskStatus) {
+    if CONFIG.daily_node_quota.is_none() || task.exempt {
+        return;
+    }
+    let nodes = task.take_unrecorded_nodes();
+    if nodes == 0 {
+        return;
+    }
+    let today = (now_seconds() / SECONDS_PER_DAY) as u32;
+    if let Err(err) = db.add_quota_usage(task.ip, today, nodes) {
+        error!("Failed to record quota usage: {err}");
+    }
+}
+
+fn ip_from_headers(headers: &HeaderMap) -> IpAddr {
     let ip = headers
         .get("X-Forwarded-For")
         .and_then(|h| h.to_str().ok())
@@ -48,22 +181,106 @@ fn ip_from_headers(headers: &HeaderMap) -> RatelimitIp {
         info!("got request from ip: {ip}");
     }
 
-    RatelimitIp::from(ip)
+    ip
 }
 
+/// A range of IPs that are treated as the same user for ratelimiting, since
+/// it's trivial for one user to have many addresses in the same range
+/// (especially with IPv6).
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct RatelimitIp(u8, u8, u8, u8);
-impl From<IpAddr> for RatelimitIp {
-    fn from(ip: IpAddr) -> Self {
-        match ip {
+pub enum RatelimitIp {
+    /// The address with everything past the prefix zeroed.
+    V4 {
+        network: Ipv4Addr,
+        prefix: u8,
+    },
+    V6 {
+        network: Ipv6Addr,
+        prefix: u8,
+    },
+}
+impl RatelimitIp {
+    pub fn new(ip: IpAddr, ipv4_prefix: u8, ipv6_prefix: u8) -> Self {
+        match ip.to_canonical() {
             IpAddr::V4(ipv4) => {
-                let octets = ipv4.octets();
-                RatelimitIp(octets[0], octets[1], octets[2], 0)
+                let prefix = ipv4_prefix.min(32);
+                let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                RatelimitIp::V4 {
+                    network: Ipv4Addr::from_bits(ipv4.to_bits() & mask),
+                    prefix,
+                }
             }
             IpAddr::V6(ipv6) => {
-                let octets = ipv6.octets();
-                RatelimitIp(octets[0], octets[1], octets[2], octets[3])
+                let prefix = ipv6_prefix.min(128);
+                let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+                RatelimitIp::V6 {
+                    network: Ipv6Addr::from_bits(ipv6.to_bits() & mask),
+                    prefix,
+                }
             }
         }
     }
+
+    /// The network address, which is 4 bytes for IPv4 and 16 for IPv6.
+    pub fn to_bytes(self) -> Vec<u8> {
+        match self {
+            RatelimitIp::V4 { network, .. } => network.octets().to_vec(),
+            RatelimitIp::V6 { network, .. } => network.octets().to_vec(),
+        }
+    }
+}
+impl fmt::Display for RatelimitIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RatelimitIp::V4 { network, prefix } => write!(f, "{network}/{prefix}"),
+            RatelimitIp::V6 { network, prefix } => write!(f, "{network}/{prefix}"),
+        }
+    }
+}
+impl From<IpAddr> for RatelimitIp {
+    fn from(ip: IpAddr) -> Self {
+        RatelimitIp::new(ip, CONFIG.ipv4_prefix, CONFIG.ipv6_prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_limiter() {
+        let limiter = RequestLimiter::default();
+        let ip = RatelimitIp::new("1.2.3.4".parse().unwrap(), 24, 56);
+        let other_ip = RatelimitIp::new("5.6.7.8".parse().unwrap(), 24, 56);
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.check_at(ip, start, 3).is_ok());
+        }
+        assert!(limiter.check_at(ip, start, 3).is_err());
+        assert!(limiter.check_at(other_ip, start, 3).is_ok());
+        // one request comes back every 20 seconds
+        let later = start + std::time::Duration::from_secs(20);
+        assert!(limiter.check_at(ip, later, 3).is_ok());
+        assert!(limiter.check_at(ip, later, 3).is_err());
+    }
+
+    #[test]
+    fn test_ratelimit_ip_buckets() {
+        let bucket = |ip: &str| RatelimitIp::new(ip.parse().unwrap(), 24, 56);
+
+        assert_eq!(bucket("1.2.3.4"), bucket("1.2.3.200"));
+        assert_ne!(bucket("1.2.3.4"), bucket("1.2.4.4"));
+        assert_eq!(bucket("::ffff:1.2.3.4"), bucket("1.2.3.4"));
+
+        assert_eq!(
+            bucket("2001:db8:0:1200::1"),
+            bucket("2001:db8:0:12ff:abcd::1")
+        );
+        assert_ne!(bucket("2001:db8:0:1200::1"), bucket("2001:db8:0:1300::1"));
+        assert_eq!(
+            bucket("2001:db8:0:12ff::1").to_string(),
+            "2001:db8:0:1200::/56"
+        );
+    }
 }
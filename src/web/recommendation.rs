@@ -0,0 +1,356 @@
+//! A WebSocket that tells the userscript what to vote for whenever the car
+//! moves. Every socket with the same destination and limits shares one
+//! [`Recommender`], so each move of the car is only searched once no matter
+//! how many sockets are open. The searches run as pathfinding tasks of the
+//! socket that asked first, so they count towards its limits and quota.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, Weak},
+};
+
+use axum::{
+    extract::{
+        Query, State, WebSocketUpgrade,
+        ws::{self, WebSocket},
+    },
+    response::IntoResponse,
+};
+use eyre::{bail, eyre};
+use http::HeaderMap;
+use parking_lot::Mutex;
+use pathfinder_protocol::{
+    CarPosition, GetPathQuery, Recommendation, RecommendationEvent, VoteDirection,
+};
+use tokio::sync::oneshot;
+use tracing::{error, info};
+
+use crate::{
+    ProgressUpdate,
+    astar::RouteNode,
+    db::DB,
+    instructions, math,
+    model::Location,
+    replan::Replanner,
+    roadtrip_api,
+    web::{
+        job_manager::TaskContext,
+        parse_latlng,
+        path::{path_settings_for_query, query_end, snap_end_point_to_pano},
+        ratelimit::{self, AppState},
+        sandbox::PathLimits,
+    },
+};
+
+/// The query ID that recommendation searches run with, so each IP only has one
+/// at a time.
+const RECOMMENDATION_QUERY_ID: u32 = u32::MAX - 1;
+
+/// What sockets have to agree on to share a [`Recommender`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct RecommenderKey {
+    end: [u64; 2],
+    max_distance: u64,
+    max_nodes: Option<usize>,
+    node_budget: Option<usize>,
+}
+impl RecommenderKey {
+    fn new([lat, lng]: [f64; 2], limits: PathLimits) -> Self {
+        Self {
+            end: [lat.to_bits(), lng.to_bits()],
+            max_distance: limits.max_distance.to_bits(),
+            max_nodes: limits.max_nodes,
+            node_budget: limits.node_budget,
+        }
+    }
+}
+
+static RECOMMENDERS: LazyLock<Mutex<HashMap<RecommenderKey, Weak<Recommender>>>> =
+    LazyLock::new(Mutex::default);
+
+/// The path to one destination, shared by the sockets that want it.
+struct Recommender {
+    /// `[lat, lng]`
+    end: [f64; 2],
+    limits: PathLimits,
+    state: tokio::sync::Mutex<RecommenderState>,
+}
+#[derive(Default)]
+struct RecommenderState {
+    replanner: Option<Replanner>,
+    /// The last position that was searched for, and its recommendation.
+    last: Option<(CarPosition, RecommendationEvent)>,
+}
+
+impl Recommender {
+    fn get_or_create(end: [f64; 2], limits: PathLimits) -> Arc<Self> {
+        let key = RecommenderKey::new(end, limits);
+        let mut recommenders = RECOMMENDERS.lock();
+        recommenders.retain(|_, recommender| recommender.strong_count() > 0);
+        if let Some(recommender) = recommenders.get(&key).and_then(Weak::upgrade) {
+            return recommender;
+        }
+        let recommender = Arc::new(Self {
+            end,
+            limits,
+            state: tokio::sync::Mutex::default(),
+        });
+        recommenders.insert(key, Arc::downgrade(&recommender));
+        recommender
+    }
+
+    /// The recommendation for the car's position, which is only searched for
+    /// once per position.
+    async fn recommend(
+        &self,
+        app_state: &AppState,
+        headers: &HeaderMap,
+        position: &CarPosition,
+    ) -> RecommendationEvent {
+        let mut state = self.state.lock().await;
+        if let Some((last_position, event)) = &state.last
+            && is_same_position(last_position, position)
+        {
+            return event.clone();
+        }
+
+        let (tx, rx) = oneshot::channel();
+        let replanner = state.replanner.take();
+        let (end, limits, task_position) = (self.end, self.limits, position.clone());
+        app_state.start_pathfinding_task(headers, RECOMMENDATION_QUERY_ID, move |ctx| async move {
+            let res = recommend_in_task(&ctx, replanner, &task_position, end, limits).await;
+            let _ = tx.send(res);
+        });
+        let event = match rx.await {
+            Ok((replanner, res)) => {
+                state.replanner = replanner;
+                res.unwrap_or_else(|err| {
+                    error!("Failed to make recommendation: {err}");
+                    RecommendationEvent::Error {
+                        message: err.to_string(),
+                    }
+                })
+            }
+            // the replanner was lost with the task, so the next move starts over
+            Err(_) => RecommendationEvent::Error {
+                message: "The search was stopped".to_owned(),
+            },
+        };
+        state.last = Some((position.clone(), event.clone()));
+        event
+    }
+}
+
+fn is_same_position(a: &CarPosition, b: &CarPosition) -> bool {
+    a.loc == b.loc && a.pano == b.pano && a.heading == b.heading
+}
+
+/// Where the recommendations lead to, which is the game's current terminus if
+/// the socket didn't ask for an end. It's resolved every time the car moves, so
+/// a new terminus gets its own [`Recommender`].
+fn resolve_end(end: Option<[f64; 2]>, terminus: Option<Location>) -> Option<[f64; 2]> {
+    end.or(terminus.map(|terminus| terminus.to_latlng()))
+}
+
+/// `GET /recommendation?end=lat,lng`. The end defaults to the game's terminus.
+pub async fn get_recommendation(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let limits = PathLimits::for_query(&query);
+    let end = query.get("end").and_then(|end| parse_latlng(end));
+    ws.on_upgrade(move |socket| handle_socket(socket, state, headers, end, limits))
+}
+
+async fn handle_socket(
+    mut socket: WebSocket,
+    state: AppState,
+    headers: HeaderMap,
+    end: Option<[f64; 2]>,
+    limits: PathLimits,
+) {
+    info!("/recommendation websocket opened");
+
+    let mut car = roadtrip_api::subscribe_car_position();
+    // kept so the recommender and its path stay alive between moves
+    let mut recommender = None;
+
+    loop {
+        let Some(position) = car.borrow_and_update().clone() else {
+            if car.changed().await.is_err() {
+                break;
+            }
+            continue;
+        };
+
+        let terminus = roadtrip_api::current_terminus().map(|terminus| terminus.loc());
+        let event = match resolve_end(end, terminus) {
+            Some(end) => {
+                // the old recommender is only dropped after the lookup, so it's
+                // found again if the end didn't change
+                let current = recommender.insert(Recommender::get_or_create(end, limits));
+                current.recommend(&state, &headers, &position).await
+            }
+            None => RecommendationEvent::Error {
+                message: "No end was given and the game hasn't announced any stops".to_owned(),
+            },
+        };
+        let msg = simd_json::to_string(&event)
+            .unwrap_or_else(|_| "Error serializing message".to_string());
+        if socket.send(ws::Message::text(msg)).await.is_err() {
+            break;
+        }
+
+        if car.changed().await.is_err() {
+            break;
+        }
+    }
+
+    info!("/recommendation websocket closed");
+}
+
+/// Run in a pathfinding task, with the replanner given back so the next move
+/// can reuse it.
+async fn recommend_in_task(
+    ctx: &TaskContext,
+    replanner: Option<Replanner>,
+    position: &CarPosition,
+    end: [f64; 2],
+    limits: PathLimits,
+) -> (Option<Replanner>, eyre::Result<RecommendationEvent>) {
+    if let Err(err) = ratelimit::check_quota(&DB, &ctx.status) {
+        return (replanner, Err(eyre!(err.message)));
+    }
+    ctx.status.update(|status| {
+        status.kind = "recommendation";
+        status.start = Some(position.loc);
+        status.end = Some(end);
+    });
+    let Some(_search_slot) = ctx.wait_for_search_slot(|_| async {}).await else {
+        return (replanner, Err(eyre!("The search was cancelled")));
+    };
+
+    let mut replanner = replanner;
+    let progress_update = Arc::new(Mutex::new(ProgressUpdate::default()));
+    let res = recommend(
+        ctx,
+        &mut replanner,
+        position,
+        end,
+        limits,
+        progress_update.clone(),
+    )
+    .await;
+    let nodes_considered = progress_update.lock().nodes_considered;
+    ctx.status.update(|status| {
+        status.percent_done = 1.;
+        status.nodes_considered = nodes_considered;
+    });
+    (replanner, res)
+}
+
+async fn recommend(
+    ctx: &TaskContext,
+    replanner: &mut Option<Replanner>,
+    position: &CarPosition,
+    end: [f64; 2],
+    limits: PathLimits,
+    progress_update: Arc<Mutex<ProgressUpdate>>,
+) -> eyre::Result<RecommendationEvent> {
+    let query = GetPathQuery {
+        end: Some(end),
+        start_pano: position.pano.clone(),
+        ..GetPathQuery::new(position.loc, position.heading, [0., 0.])
+    };
+
+    let start = Location::from_latlng(position.loc);
+    let heading = (position.heading + 360.) % 360.;
+
+    let path = match replanner {
+        Some(replanner) => {
+            replanner.set_cancel(ctx.cancel.clone());
+            replanner
+                .move_start(&DB, start, position.pano.clone(), heading, progress_update)
+                .await?
+        }
+        None => {
+            let mut settings = path_settings_for_query(&query, limits).map_err(|e| eyre!(e))?;
+            settings.cancel = ctx.cancel.clone();
+            let end = query_end(&query).map_err(|e| eyre!(e))?;
+            let end = snap_end_point_to_pano(&DB, end)
+                .await
+                .ok_or_else(|| eyre!("No nearby pano for {end}"))?
+                .loc;
+            if math::distance(start, end) > limits.max_distance {
+                bail!("The destination is too far away");
+            }
+            let new_replanner = Replanner::new(
+                &DB,
+                start,
+                position.pano.clone(),
+                heading,
+                end,
+                progress_update,
+                settings,
+            )
+            .await?;
+            replanner.insert(new_replanner).path()
+        }
+    };
+
+    recommendation_for_path(path, query.settings.use_option_cache).await
+}
+
+async fn recommendation_for_path(
+    path: &[RouteNode],
+    use_option_cache: bool,
+) -> eyre::Result<RecommendationEvent> {
+    let Some(next) = path.get(1) else {
+        return Ok(RecommendationEvent::Arrived);
+    };
+    let instruction = instructions::vote_instructions(&DB, &path[..2], use_option_cache)
+        .await?
+        .into_iter()
+        .next();
+
+    Ok(RecommendationEvent::Recommendation(Recommendation {
+        pano_id: DB.lookup_pano_id_string(next.pano.id).unwrap_or_default(),
+        heading: next.heading,
+        direction: instruction
+            .as_ref()
+            .map(|i| i.direction)
+            .unwrap_or(VoteDirection::Straight),
+        option_index: instruction.and_then(|i| i.option_index),
+        remaining_cost: path.last().map(|n| n.cost).unwrap_or_default(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_terminus_gets_new_recommender() {
+        let limits = PathLimits::full();
+        let old_terminus = Location::new_deg(10., 20.);
+        let new_terminus = Location::new_deg(11., 21.);
+        let recommender_for =
+            |terminus| Recommender::get_or_create(resolve_end(None, terminus).unwrap(), limits);
+
+        let first = recommender_for(Some(old_terminus));
+        assert!(Arc::ptr_eq(&first, &recommender_for(Some(old_terminus))));
+
+        let moved = recommender_for(Some(new_terminus));
+        assert!(!Arc::ptr_eq(&first, &moved));
+        assert_eq!(moved.end, new_terminus.to_latlng());
+
+        // an end that was asked for doesn't follow the terminus
+        assert_eq!(
+            resolve_end(Some([1., 2.]), Some(new_terminus)),
+            Some([1., 2.])
+        );
+        assert_eq!(resolve_end(None, None), None);
+    }
+}
@@ -0,0 +1,244 @@
+//! `POST /path/sync` and `GET /path/job/{job_id}`, for clients that can't keep
+//! a WebSocket open. These run the same search as `/path`, but only report the
+//! latest progress and the final result.
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    response::{IntoResponse, Response},
+};
+use futures::{StreamExt, channel::mpsc};
+use http::{HeaderMap, StatusCode, header};
+use parking_lot::Mutex;
+use pathfinder_protocol::{ErrorCode, GetPathQuery, PathResult, SocketError, SocketEvent};
+use serde::Serialize;
+use tokio::sync::watch;
+use tracing::info;
+
+use crate::{
+    astar::Cost,
+    web::{config::CONFIG, path::handle_get_path_query, ratelimit::AppState, sandbox::PathLimits},
+};
+
+/// How often the expired jobs are removed from [`RestJobs`].
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The jobs that were started with `POST /path/sync`, by job ID.
+#[derive(Clone, Default)]
+pub struct RestJobs {
+    jobs: Arc<Mutex<HashMap<String, RestJob>>>,
+}
+struct RestJob {
+    state: watch::Receiver<RestJobState>,
+    started_at: Instant,
+}
+impl RestJob {
+    /// Finished jobs can be polled for [`WebConfig::rest_job_ttl`] after they
+    /// finish. Searches are limited to [`WebConfig::rest_timeout_seconds`], so
+    /// a job that's still unfinished after that and the TTL is stuck and gets
+    /// dropped too.
+    ///
+    /// [`WebConfig::rest_job_ttl`]: crate::web::config::WebConfig::rest_job_ttl
+    /// [`WebConfig::rest_timeout_seconds`]: crate::web::config::WebConfig::rest_timeout_seconds
+    fn is_expired(&self) -> bool {
+        match self.state.borrow().finished_at {
+            Some(finished_at) => finished_at.elapsed() >= CONFIG.rest_job_ttl,
+            None => {
+                self.started_at.elapsed().as_secs_f64()
+                    >= CONFIG.rest_timeout_seconds + CONFIG.rest_job_ttl.as_secs_f64()
+            }
+        }
+    }
+}
+impl RestJobs {
+    fn insert(&self, job_id: String, state: watch::Receiver<RestJobState>) {
+        self.jobs.lock().insert(
+            job_id,
+            RestJob {
+                state,
+                started_at: Instant::now(),
+            },
+        );
+    }
+
+    fn get(&self, job_id: &str) -> Option<RestJobState> {
+        self.jobs
+            .lock()
+            .get(job_id)
+            .filter(|job| !job.is_expired())
+            .map(|job| job.state.borrow().clone())
+    }
+
+    fn prune(&self) {
+        self.jobs.lock().retain(|_, job| !job.is_expired());
+    }
+
+    /// Remove the expired jobs every minute, so they're freed even when no new
+    /// ones are started.
+    pub async fn prune_periodically(self) {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.prune();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RestJobState {
+    pub job_id: Option<String>,
+    pub finished: bool,
+    /// Between 0 and 1
+    pub percent_done: f64,
+    pub estimated_seconds_remaining: f64,
+    pub best_path_cost: Cost,
+    pub nodes_considered: usize,
+    pub elapsed_seconds: f64,
+    /// The position in the queue, if the search had to wait for others to
+    /// finish before starting.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_position: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<PathResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<SocketError>,
+    #[serde(skip)]
+    finished_at: Option<Instant>,
+}
+impl RestJobState {
+    fn apply(&mut self, event: SocketEvent) {
+        match event {
+            SocketEvent::Progress(progress) => {
+                self.queue_position = None;
+                self.percent_done = progress.percent_done;
+                self.estimated_seconds_remaining = progress.estimated_seconds_remaining;
+                self.best_path_cost = progress.best_path_cost;
+                self.nodes_considered = progress.nodes_considered;
+                self.elapsed_seconds = progress.elapsed_seconds;
+            }
+            SocketEvent::Job { job_id, .. } => self.job_id = Some(job_id),
+            SocketEvent::Queued { position, .. } => self.queue_position = Some(position),
+            SocketEvent::Result(result) => {
+                self.result = Some(result);
+                self.finish();
+            }
+            SocketEvent::Error(error) => {
+                self.error = Some(error);
+                self.finish();
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        self.finished = true;
+        self.finished_at.get_or_insert_with(Instant::now);
+    }
+
+    fn into_response(self, status: StatusCode) -> Response {
+        let status = match &self.error {
+            Some(error) => status_for_error(error.code),
+            None => status,
+        };
+        (status, Json(self)).into_response()
+    }
+}
+
+/// `POST /path/sync` with a [`GetPathQuery`] as the body. Responds with the
+/// path if it's found within `PATHFINDER_SYNC_WAIT_SECS`, and otherwise with
+/// `202 Accepted` and the job ID to poll. The search's `timeout_seconds` is
+/// capped at `PATHFINDER_REST_TIMEOUT_SECS`, since nobody might be polling it.
+pub async fn post_path_sync(
+    State(state): State<AppState>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    Json(mut msg): Json<GetPathQuery>,
+) -> Response {
+    let limits = PathLimits::for_query(&query);
+    msg.timeout_seconds = Some(
+        msg.timeout_seconds
+            .unwrap_or(CONFIG.rest_timeout_seconds)
+            .min(CONFIG.rest_timeout_seconds),
+    );
+
+    info!("/path/sync");
+
+    let (mut tx, mut rx) = mpsc::channel::<SocketEvent>(16);
+    let task_state = state.clone();
+    state.start_pathfinding_task(&headers, msg.id, move |ctx| async move {
+        handle_get_path_query(&mut tx, msg, &task_state, limits, &ctx).await;
+    });
+
+    let (job_state_tx, mut job_state) = watch::channel(RestJobState::default());
+    tokio::spawn(async move {
+        while let Some(event) = rx.next().await {
+            job_state_tx.send_modify(|state| state.apply(event));
+        }
+        job_state_tx.send_modify(|state| {
+            if !state.finished {
+                // only one search can run per IP and query ID, so another request replaced
+                // this one
+                state.error = Some(SocketError::new(
+                    ErrorCode::Ratelimited,
+                    "The search was stopped because another path was requested",
+                ));
+                state.finish();
+            }
+        });
+    });
+
+    let job_id = job_state
+        .wait_for(|s| s.job_id.is_some() || s.finished)
+        .await
+        .ok()
+        .and_then(|s| s.job_id.clone());
+    if let Some(job_id) = job_id {
+        state.rest_jobs.insert(job_id, job_state.clone());
+    }
+
+    let finished = tokio::time::timeout(CONFIG.sync_wait, job_state.wait_for(|s| s.finished))
+        .await
+        .is_ok();
+    let current = job_state.borrow().clone();
+    match &current.job_id {
+        Some(job_id) if !finished => {
+            let location = format!("/path/job/{job_id}");
+            let mut res = current.into_response(StatusCode::ACCEPTED);
+            if let Ok(location) = location.parse() {
+                res.headers_mut().insert(header::LOCATION, location);
+            }
+            res
+        }
+        _ => current.into_response(StatusCode::OK),
+    }
+}
+
+/// `GET /path/job/{job_id}`, the progress of a job that was started with
+/// `POST /path/sync`, and its result once it's finished.
+pub async fn get_path_job(State(state): State<AppState>, Path(job_id): Path<String>) -> Response {
+    match state.rest_jobs.get(&job_id) {
+        Some(job_state) => job_state.into_response(StatusCode::OK),
+        None => (
+            StatusCode::NOT_FOUND,
+            "Unknown job ID, it may have expired\n",
+        )
+            .into_response(),
+    }
+}
+
+fn status_for_error(code: ErrorCode) -> StatusCode {
+    match code {
+        ErrorCode::InvalidRequest | ErrorCode::TooManyStops | ErrorCode::PathTooLong => {
+            StatusCode::BAD_REQUEST
+        }
+        ErrorCode::NoNearbyPano => StatusCode::UNPROCESSABLE_ENTITY,
+        ErrorCode::UpstreamUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+        ErrorCode::Ratelimited => StatusCode::TOO_MANY_REQUESTS,
+        ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
@@ -0,0 +1,47 @@
+//! Limits for pathfinding requests. When sandbox mode is enabled, anonymous
+//! users get much stricter limits than users that provide the
+//! `PATHFINDER_SECRET` key, which lets a public instance stay open without
+//! letting anyone tie it up with huge searches.
+
+use std::collections::HashMap;
+
+use crate::web::{admin::is_key_valid, config::CONFIG};
+
+/// The maximum total distance of a path for authenticated users, or for
+/// everyone if sandbox mode is disabled.
+const FULL_MAX_DISTANCE: f64 = 1_000_000.;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PathLimits {
+    /// The maximum total distance of the path (including stops) in meters.
+    pub max_distance: f64,
+    /// The maximum number of nodes that the pathfinder may consider for the
+    /// whole route (across all of its segments) before giving up.
+    pub max_nodes: Option<usize>,
+    /// The maximum number of nodes that the pathfinder may keep in memory per
+    /// segment.
+    pub node_budget: Option<usize>,
+}
+impl PathLimits {
+    pub fn full() -> Self {
+        Self {
+            max_distance: FULL_MAX_DISTANCE,
+            max_nodes: None,
+            node_budget: CONFIG.node_budget,
+        }
+    }
+
+    /// Determine the limits for a request based on whether it included the
+    /// correct `key` query parameter.
+    pub fn for_query(query: &HashMap<String, String>) -> Self {
+        if !CONFIG.sandbox || is_key_valid(query.get("key").map(String::as_str)) {
+            return Self::full();
+        }
+
+        Self {
+            max_distance: CONFIG.sandbox_max_distance.min(FULL_MAX_DISTANCE),
+            max_nodes: Some(CONFIG.sandbox_max_nodes),
+            node_budget: CONFIG.node_budget,
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! Completed paths that are saved under a short ID, so they can be shared
+//! without having to be found again.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::{
+    Json,
+    extract::Path,
+    response::{IntoResponse, Response},
+};
+use http::StatusCode;
+use pathfinder_protocol::{GetPathQuery, PathResult, Stop};
+use simd_json::json;
+use tracing::error;
+
+use crate::{db::DB, math::random::random_u64, model::SavedRoute};
+
+const ROUTE_ID_LENGTH: usize = 8;
+const ROUTE_ID_ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+/// Save the path and the query that found it, returning the route's ID.
+pub async fn save_route(query: &GetPathQuery, result: &PathResult) -> Option<String> {
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let query = query.clone();
+    let result = result.clone();
+    let res = tokio::task::spawn_blocking(move || {
+        let id = loop {
+            let id = new_route_id();
+            if !DB.has_route(&id) {
+                break id;
+            }
+        };
+        let route = SavedRoute {
+            id: id.clone(),
+            created_at,
+            query,
+            result,
+        };
+        DB.save_route(&route).map(|()| id)
+    })
+    .await;
+    match res {
+        Ok(Ok(id)) => Some(id),
+        Ok(Err(err)) => {
+            error!("Failed to save route: {err}");
+            None
+        }
+        Err(err) => {
+            error!("Saving the route panicked: {err}");
+            None
+        }
+    }
+}
+
+fn new_route_id() -> String {
+    let mut n = random_u64();
+    (0..ROUTE_ID_LENGTH)
+        .map(|_| {
+            let c = ROUTE_ID_ALPHABET[(n % ROUTE_ID_ALPHABET.len() as u64) as usize];
+            n /= ROUTE_ID_ALPHABET.len() as u64;
+            c as char
+        })
+        .collect()
+}
+
+/// `GET /route/{id}`, the saved route as JSON.
+pub async fn get_route(Path(id): Path<String>) -> Response {
+    match DB.get_route(&id) {
+        Some(route) => Json(route).into_response(),
+        None => (StatusCode::NOT_FOUND, "unknown route\n").into_response(),
+    }
+}
+
+/// `GET /route/{id}/geojson`, the saved route as a GeoJSON `FeatureCollection`
+/// with the path as a `LineString` and its start, end and stops as `Point`s.
+pub async fn get_route_geojson(Path(id): Path<String>) -> Response {
+    match DB.get_route(&id) {
+        Some(route) => Json(route_to_geojson(&route)).into_response(),
+        None => (StatusCode::NOT_FOUND, "unknown route\n").into_response(),
+    }
+}
+
+fn route_to_geojson(route: &SavedRoute) -> simd_json::OwnedValue {
+    let nodes = &route.result.nodes;
+    let cost = nodes.last().map(|node| node.cost).unwrap_or_default();
+    // only intersections need a vote, everything else is a single option
+    let votes = route
+        .result
+        .instructions
+        .iter()
+        .filter(|instruction| instruction.option_count > 1)
+        .count();
+
+    let mut features = vec![json!({
+        "type": "Feature",
+        "geometry": {
+            "type": "LineString",
+            "coordinates": nodes.iter().map(|node| lnglat(node.loc)).collect::<Vec<_>>(),
+        },
+        "properties": {
+            "kind": "path",
+            "id": route.id,
+            "created_at": route.created_at,
+            "cost": cost,
+            "hours": cost / 3600.,
+            "votes": votes,
+            "nodes": nodes.len(),
+            "partial": route.result.partial,
+        },
+    })];
+    if let (Some(first), Some(last)) = (nodes.first(), nodes.last()) {
+        features.push(point_feature(
+            first.loc,
+            json!({ "kind": "start", "pano_id": first.pano_id }),
+        ));
+        features.push(point_feature(
+            last.loc,
+            json!({ "kind": "end", "pano_id": last.pano_id }),
+        ));
+    }
+    for (index, stop) in route.query.stops.iter().enumerate() {
+        let radius = match stop {
+            Stop::Exact(_) => None,
+            Stop::Soft { radius, .. } => Some(*radius),
+        };
+        features.push(point_feature(
+            stop.loc(),
+            json!({ "kind": "stop", "index": index, "radius": radius }),
+        ));
+    }
+
+    json!({
+        "type": "FeatureCollection",
+        "features": features,
+    })
+}
+
+fn point_feature(latlng: [f64; 2], properties: simd_json::OwnedValue) -> simd_json::OwnedValue {
+    json!({
+        "type": "Feature",
+        "geometry": {
+            "type": "Point",
+            "coordinates": lnglat(latlng),
+        },
+        "properties": properties,
+    })
+}
+
+/// GeoJSON coordinates are `[lng, lat]`.
+fn lnglat(latlng: [f64; 2]) -> [f64; 2] {
+    [latlng[1], latlng[0]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_ids_are_short_and_url_safe() {
+        let id = new_route_id();
+        assert_eq!(id.len(), ROUTE_ID_LENGTH);
+        assert!(id.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_ne!(id, new_route_id());
+    }
+}
@@ -0,0 +1,91 @@
+//! `GET /path/sse`, the same progress stream as the `/path` WebSocket but sent
+//! as server-sent events, for clients behind proxies that break WebSockets.
+//! Every event's data is the JSON that would've been sent over the WebSocket,
+//! and its name is the message's `type`.
+
+use std::{collections::HashMap, convert::Infallible, future::ready};
+
+use axum::{
+    extract::{Query, State},
+    response::{
+        IntoResponse,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use futures::{StreamExt, channel::mpsc};
+use http::HeaderMap;
+use pathfinder_protocol::{ErrorCode, GetPathQuery, SocketError, SocketEvent};
+use tracing::info;
+
+use crate::web::{
+    path::{handle_get_path_query, resume_job, send_error},
+    ratelimit::AppState,
+    sandbox::PathLimits,
+};
+
+/// `GET /path/sse?query=…`, where `query` is a [`GetPathQuery`] as JSON. A job
+/// that lost its connection can be resumed with `GET /path/sse?job_id=…`
+/// instead. The stream ends after the result or an error is sent.
+pub async fn get_path_sse(
+    State(state): State<AppState>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let limits = PathLimits::for_query(&query);
+    let (mut tx, rx) = mpsc::channel::<SocketEvent>(16);
+
+    if let Some(job_id) = query.get("job_id") {
+        info!("/path/sse resuming {job_id}");
+        resume_job(&state, tx, job_id).await;
+    } else {
+        let msg = query.get("query").and_then(|q| {
+            simd_json::serde::from_slice::<GetPathQuery>(&mut q.clone().into_bytes()).ok()
+        });
+        match msg {
+            Some(msg) => {
+                info!("/path/sse");
+                let task_state = state.clone();
+                state.start_pathfinding_task(&headers, msg.id, move |ctx| async move {
+                    handle_get_path_query(&mut tx, msg, &task_state, limits, &ctx).await;
+                });
+            }
+            None => {
+                send_error(
+                    &mut tx,
+                    SocketError::new(
+                        ErrorCode::InvalidRequest,
+                        "query must be a valid path query as JSON",
+                    ),
+                )
+                .await;
+            }
+        }
+    }
+
+    // the job keeps a sender until it expires, so the stream has to end itself
+    // once there's nothing else to send
+    let events = rx
+        .scan(false, |done, event| {
+            if *done {
+                return ready(None);
+            }
+            *done = matches!(event, SocketEvent::Result(_) | SocketEvent::Error(_));
+            ready(Some(event))
+        })
+        .map(|event| Ok::<_, Infallible>(to_sse_event(&event)));
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+fn to_sse_event(event: &SocketEvent) -> Event {
+    let name = match event {
+        SocketEvent::Progress(_) => "progress",
+        SocketEvent::Error(_) => "error",
+        SocketEvent::Job { .. } => "job",
+        SocketEvent::Queued { .. } => "queued",
+        SocketEvent::Result(_) => "result",
+    };
+    let data =
+        simd_json::to_string(event).unwrap_or_else(|_| "Error serializing message".to_string());
+    Event::default().event(name).data(data)
+}
@@ -0,0 +1,113 @@
+//! `GET /tile/{size}/{x}/{y}`, the cached panos in a tile.
+
+use axum::{
+    Json,
+    extract::{Path, Query},
+    response::{IntoResponse, Response},
+};
+use http::{HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+use simd_json::json;
+
+use crate::{
+    db::DB,
+    model::{PanoWithBothLocations, SizedTile},
+    web::etag::ETag,
+};
+
+#[derive(Deserialize)]
+pub struct TileQuery {
+    /// `geojson` for a `FeatureCollection` of points instead of a list.
+    format: Option<String>,
+    /// Include the Streetview pano IDs, which have to be looked up.
+    #[serde(default)]
+    pano_ids: bool,
+}
+
+#[derive(Serialize)]
+pub struct TilePano {
+    pub internal_id: u32,
+    /// Only set if `pano_ids` was in the query.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pano: Option<String>,
+    /// Where GetMetadata says the pano is, in degrees.
+    pub lat: f64,
+    pub lng: f64,
+    /// Where ListEntityPhotos says the pano is, which is what the tile it's in
+    /// is based on.
+    pub search_lat: f64,
+    pub search_lng: f64,
+}
+impl TilePano {
+    fn new(pano: &PanoWithBothLocations, include_pano_id: bool) -> Self {
+        Self {
+            internal_id: pano.id.0,
+            pano: include_pano_id
+                .then(|| DB.lookup_pano_id_string(pano.id))
+                .flatten(),
+            lat: pano.actual_loc.lat_deg(),
+            lng: pano.actual_loc.lng_deg(),
+            search_lat: pano.search_loc.lat_deg(),
+            search_lng: pano.search_loc.lng_deg(),
+        }
+    }
+}
+
+/// `GET /tile/{size}/{x}/{y}`, the panos in the tile or `null` if it isn't
+/// cached (or had too many panos, so the smaller tiles in it have to be
+/// checked). With `format=geojson`, that's a 404 instead.
+pub async fn get_tile(
+    Path((size, x, y)): Path<(u8, u32, u32)>,
+    Query(query): Query<TileQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let etag = ETag::of_tiles(&DB);
+    if etag.matches(&headers) {
+        return etag.not_modified();
+    }
+
+    let panos = DB
+        .lookup_listentityphotos(&SizedTile { size, x, y })
+        .flatten()
+        .map(|panos| {
+            panos
+                .iter()
+                .map(|pano| TilePano::new(pano, query.pano_ids))
+                .collect::<Vec<_>>()
+        });
+
+    let res = match query.format.as_deref() {
+        None | Some("json") => Json(panos).into_response(),
+        Some("geojson") => match panos {
+            Some(panos) => Json(panos_to_geojson(&panos)).into_response(),
+            None => (StatusCode::NOT_FOUND, "tile isn't cached\n").into_response(),
+        },
+        Some(_) => (StatusCode::BAD_REQUEST, "format must be json or geojson\n").into_response(),
+    };
+    etag.attach(res)
+}
+
+fn panos_to_geojson(panos: &[TilePano]) -> simd_json::OwnedValue {
+    let features = panos
+        .iter()
+        .map(|pano| {
+            json!({
+                "type": "Feature",
+                "geometry": {
+                    "type": "Point",
+                    "coordinates": [pano.lng, pano.lat],
+                },
+                "properties": {
+                    "internal_id": pano.internal_id,
+                    "pano": pano.pano,
+                    "search_lat": pano.search_lat,
+                    "search_lng": pano.search_lng,
+                },
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "type": "FeatureCollection",
+        "features": features,
+    })
+}